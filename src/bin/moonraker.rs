@@ -1,7 +1,29 @@
 use clap::{Parser, ValueEnum};
 use colored::Colorize;
-use moonraker::inputs::Input;
-use moonraker::rlm::{RigProvider, Rlm};
+use moonraker::datetime;
+use moonraker::direct::{Confidence, direct_answer, fits_directly};
+use moonraker::environment::EnvironmentConfig;
+use moonraker::inputs::pipeline::{DedupeLines, NormalizeWhitespace, RedactPii, StripBoilerplate};
+use moonraker::inputs::{
+    Input, InputError, InputMeta, InputPipeline, LoadOptions, default_cache_dir, expand_glob,
+    load_file_function,
+};
+use moonraker::policy::PolicyChecker;
+use moonraker::prefilter::{KeywordScorer, Prefilter, PrefilterConfig};
+use moonraker::prompt::{self, SYSTEM_PROMPT};
+use moonraker::repl::{Cell, ReplObserver, Session, Truncation};
+use moonraker::rlm::{RigProvider, Rlm, RlmConfig};
+use moonraker::sample::{SampleConfig, SampleReport, sample};
+use moonraker::stdlib::StdlibProfile;
+use moonraker::store::{Store, store_path};
+use moonraker::summarize::{SummarizeConfig, summarize};
+use moonraker::tokens::Tokenizer;
+use moonraker::trace::TraceWriter;
+use moonraker::transcript;
+use moonraker::usage::UsageBreakdown;
+use moonraker::window::{WindowConfig, WindowStrategy};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
 enum Provider {
@@ -9,26 +31,119 @@ enum Provider {
     Openrouter,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ContextFormat {
+    /// Detect the format from the file extension
+    Auto,
+    /// Load the context as plain text
+    Text,
+    /// Decode the context as JSON into the `context_json` table
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum PreprocessStage {
+    /// Remove lines repeated on every page, such as headers and footers
+    StripBoilerplate,
+    /// Drop lines identical to an earlier line
+    DedupeLines,
+    /// Collapse runs of spaces and blank lines
+    NormalizeWhitespace,
+    /// Replace email addresses and Social Security numbers with placeholders
+    RedactPii,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Mode {
+    /// Answer directly when the context is small, otherwise run the REPL loop
+    Auto,
+    /// Always answer with a single completion
+    Direct,
+    /// Always run the REPL loop
+    Rlm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CellTruncation {
+    /// Keep the beginning of long output
+    Head,
+    /// Keep the end of long output, e.g. the error at the bottom of a log
+    Tail,
+    /// Keep the beginning and the end, cutting out the middle
+    HeadAndTail,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum WindowEviction {
+    /// Hide the oldest cells until the transcript fits
+    DropOldest,
+    /// Replace the oldest cells with a summary written by the model
+    Summarize,
+    /// Hide every cell that is neither pinned nor recent
+    KeepPinned,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum TranscriptFormat {
+    /// Markdown headings with fenced code and output
+    Markdown,
+    /// Numbered cells without fences, using fewer tokens
+    Compact,
+    /// One JSON object per line
+    JsonLines,
+    /// Alternating user and assistant turns
+    Chat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum TokenizerChoice {
+    /// Pick the encoding from the model name (cl100k for unknown models)
+    Auto,
+    P50k,
+    Cl100k,
+    O200k,
+}
+
 #[derive(Parser, Debug)]
 #[command(name = "moonraker")]
 #[command(about = "Recursive Language Model with Lua REPL", long_about = None)]
 struct Args {
-    /// The prompt/query to answer
-    #[arg(short, long)]
-    prompt: String,
+    /// The prompt/query to answer (defaults to the prompt of a resumed session)
+    #[arg(short, long, required_unless_present = "resume")]
+    prompt: Option<String>,
 
-    /// Path to context file (text or PDF) to load into the Lua environment (optional)
+    /// Path to a context file, a directory of files, or a glob pattern such as `logs/2024-*.log` to load into the Lua environment (optional, may be repeated)
     #[arg(short, long)]
-    context: Option<String>,
+    context: Vec<String>,
+
+    /// Compare two files: load their unified diff as the context, with the full texts in `context_old` and `context_new`
+    #[arg(long, num_args = 2, value_names = ["OLD", "NEW"], conflicts_with = "context")]
+    context_diff: Vec<String>,
 
     /// Model to use
     #[arg(short, long, default_value = "qwen3:30b")]
     model: String,
 
+    /// Embedding model used by embed() in Lua (default: nomic-embed-text with Ollama, openai/text-embedding-3-small with OpenRouter)
+    #[arg(long)]
+    embedding_model: Option<String>,
+
     /// Maximum number of iterations
     #[arg(long, default_value = "10")]
     max_iterations: usize,
 
+    /// Tokens the run may spend, counting generations and llm_query calls; no new iteration starts once they are spent (0 for unlimited)
+    #[arg(long, default_value = "0")]
+    token_budget: u64,
+
+    /// Levels of nested RLMs that rlm_query() in Lua may start (0 disables rlm_query)
+    #[arg(long, default_value = "1")]
+    max_rlm_depth: usize,
+
+    /// Maximum number of iterations of each nested RLM started by rlm_query()
+    #[arg(long, default_value = "10")]
+    sub_rlm_iterations: usize,
+
     /// Log level (trace, debug, info, warn, error)
     #[arg(long, default_value = "warn")]
     log_level: String,
@@ -40,245 +155,366 @@ struct Args {
     /// Path to file containing OpenRouter API key (required if provider is openrouter)
     #[arg(long)]
     api_key_file: Option<String>,
+
+    /// How to answer: directly for small contexts (auto), always directly, or always with the REPL loop
+    #[arg(long, value_enum, default_value = "auto")]
+    mode: Mode,
+
+    /// Largest prompt plus context, in tokens, answered directly in auto mode
+    #[arg(long, default_value = "8000")]
+    direct_max_tokens: usize,
+
+    /// Only load a stratified sample of about this many tokens of the context (head, tail and random middle slices) for a quick first pass
+    #[arg(long)]
+    sample_tokens: Option<usize>,
+
+    /// How to interpret context files
+    #[arg(long, value_enum, default_value = "auto")]
+    context_format: ContextFormat,
+
+    /// Mark the start of each PDF page with a `--- page N ---` line
+    #[arg(long)]
+    pdf_page_markers: bool,
+
+    /// Reconstruct tables in PDFs as pipe-delimited rows using the position of the text
+    #[arg(long)]
+    pdf_tables: bool,
+
+    /// Fail on context text that is malformed in its detected encoding instead of replacing invalid bytes
+    #[arg(long)]
+    strict_encoding: bool,
+
+    /// Preprocessing stages applied to each context, in order (comma-separated)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    preprocess: Vec<PreprocessStage>,
+
+    /// Mask emails, phone numbers, credit card numbers, and Social Security numbers in the context before anything is sent to a model
+    #[arg(long)]
+    redact: bool,
+
+    /// Also mask matches of this regex (may be repeated; implies --redact)
+    #[arg(long = "redact-pattern", value_parser = regex::Regex::new)]
+    redact_patterns: Vec<regex::Regex>,
+
+    /// Approximate context size in tokens above which a warning is shown
+    #[arg(long, default_value = "200000")]
+    context_token_budget: usize,
+
+    /// Pre-summarize contexts over the token budget and give the summary to the model as `context_summary`
+    #[arg(long)]
+    summarize_large_context: bool,
+
+    /// Load binary context files as the base64 encoding of their first N bytes instead of rejecting them
+    #[arg(long)]
+    binary_prefix_bytes: Option<usize>,
+
+    /// Always extract context text instead of reusing text cached by earlier runs
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Let the model load further files in this directory with `load_file(path)` (repeatable)
+    #[arg(long = "allow-load-dir", value_name = "DIR")]
+    allow_load_dirs: Vec<std::path::PathBuf>,
+
+    /// Precompute structural facts about the context (line count, format, frequent words, headings) and give them to the model up front
+    #[arg(long)]
+    warm_start: bool,
+
+    /// Drop chunks of very large contexts (1 MB or more) that share no keyword with the prompt
+    #[arg(long)]
+    prefilter: bool,
+
+    /// Maximum llm_query calls allowed in a single cell (0 for unlimited)
+    #[arg(long, default_value = "20")]
+    max_llm_queries_per_cell: usize,
+
+    /// Maximum llm_query calls allowed over the whole run (0 for unlimited)
+    #[arg(long, default_value = "200")]
+    max_llm_queries_per_run: usize,
+
+    /// Seconds a cell may run, including its llm_query calls, before it fails with a timeout (0 for unlimited)
+    #[arg(long, default_value = "120")]
+    cell_timeout: u64,
+
+    /// Lua instructions a cell may execute before it fails (0 for unlimited)
+    #[arg(long, default_value = "0")]
+    max_cell_instructions: u64,
+
+    /// Megabytes of Lua memory a cell may allocate before it fails (0 for unlimited)
+    #[arg(long, default_value = "1024")]
+    max_cell_memory_mb: usize,
+
+    /// Kilobytes a cell may print; later output is dropped after an "[output limit reached]" line (0 for unlimited)
+    #[arg(long, default_value = "1024")]
+    max_cell_output_kb: usize,
+
+    /// Let cells use this part of the Lua standard library besides math, string, table, coroutine and utf8: a library (os) or a function (os.time, io.read); may be repeated
+    #[arg(long = "allow-lua", value_name = "NAME")]
+    allow_lua: Vec<String>,
+
+    /// Largest string string.rep may build in a cell, in megabytes (0 for unlimited)
+    #[arg(long, default_value = "0")]
+    max_string_rep_mb: usize,
+
+    /// Maximum tokens of each cell's output kept in the transcript shown to the model
+    #[arg(long, default_value = "200")]
+    max_cell_output_tokens: usize,
+
+    /// Which part of cell output longer than --max-cell-output-tokens is kept
+    #[arg(long, value_enum, default_value = "head")]
+    cell_truncation: CellTruncation,
+
+    /// Token encoding used to count and truncate cell output
+    #[arg(long, value_enum, default_value = "auto")]
+    tokenizer: TokenizerChoice,
+
+    /// Token budget of the transcript shown to the model; older cells are evicted above it
+    #[arg(long)]
+    window_tokens: Option<usize>,
+
+    /// How cells leave the transcript when it exceeds --window-tokens
+    #[arg(long, value_enum, default_value = "drop-oldest")]
+    window_eviction: WindowEviction,
+
+    /// How the transcript is formatted for the model
+    #[arg(long, value_enum, default_value = "markdown")]
+    transcript_format: TranscriptFormat,
+
+    /// Show the model only the comment and output of cells more than N iterations old
+    #[arg(long, value_name = "N")]
+    hide_code_after: Option<usize>,
+
+    /// Number of times to retry an iteration when the provider fails
+    #[arg(long, default_value = "3")]
+    max_retries: u32,
+
+    /// Number of times a cell that raised an error is regenerated, with the error pointed out, within the same iteration
+    #[arg(long, default_value = "0")]
+    fix_retries: u32,
+
+    /// Reject generated code that references disallowed identifiers (load, collectgarbage, ...) before running it
+    #[arg(long)]
+    strict_policy: bool,
+
+    /// Attach metadata to the run as key=value (may be repeated)
+    #[arg(long = "tag", value_parser = parse_tag)]
+    tags: Vec<(String, String)>,
+
+    /// Path to a file replacing the built-in system prompt
+    #[arg(long)]
+    system_prompt_file: Option<String>,
+
+    /// Write the transcript as a Jupyter notebook to this path
+    #[arg(long, value_name = "FILE")]
+    save_notebook: Option<String>,
+
+    /// Let http_get() in Lua fetch from this domain and its subdomains (may be repeated; HTTP is disabled by default)
+    #[arg(long = "allow-http", value_name = "DOMAIN")]
+    http_domains: Vec<String>,
+
+    /// Make the `context` global read-only so cells can't overwrite it (copy_context() returns a modifiable copy)
+    #[arg(long)]
+    readonly_context: bool,
+
+    /// Seed math.random in Lua and schedule background tasks deterministically, so replaying a session reproduces its outputs
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Make now() in Lua return this time (e.g. 2024-05-01T09:00:00Z) instead of the current time
+    #[arg(long, value_name = "TIME", value_parser = parse_now)]
+    now: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Persist values saved with store_set() in Lua to this store: a name (kept in ~/.local/share/moonraker/stores) or a JSON file path
+    #[arg(long, value_name = "NAME|FILE")]
+    store: Option<String>,
+
+    /// Continue the session saved in this file (see --save-session) over the same context
+    #[arg(long, value_name = "FILE")]
+    resume: Option<String>,
+
+    /// Save the session, including Lua globals, to this file so it can be resumed
+    #[arg(long, value_name = "FILE")]
+    save_session: Option<String>,
+
+    /// Append each executed cell as a JSON line to this file while the run progresses
+    #[arg(long, value_name = "FILE")]
+    trace_file: Option<String>,
+
+    /// Write a standalone HTML report of the run to this path
+    #[arg(long)]
+    export_html: Option<String>,
+}
+
+/// Print the usage summary broken down by call category
+fn print_usage(usage: &UsageBreakdown) {
+    println!("\n=== Usage ===");
+    for (category, tokens) in usage.iter() {
+        println!(
+            "{category}: {} calls, {} input tokens, {} output tokens",
+            tokens.calls, tokens.input_tokens, tokens.output_tokens
+        );
+    }
+    let total = usage.total();
+    println!(
+        "total: {} calls, {} tokens ({} input, {} output)",
+        total.calls,
+        total.total_tokens(),
+        total.input_tokens,
+        total.output_tokens
+    );
+}
+
+/// Prints each cell to the terminal as it runs
+#[derive(Default)]
+struct TerminalPrinter {
+    cells: AtomicUsize,
+}
+
+impl ReplObserver for TerminalPrinter {
+    fn on_cell_start(&self, comment: &str, code: &str) {
+        // Print horizontal line if not the first cell
+        if self.cells.fetch_add(1, Ordering::SeqCst) > 0 {
+            println!();
+            println!("{}", "─".repeat(80));
+            println!();
+        }
+
+        // Print comment in bold, then code in regular text color
+        println!("{}", comment.bold());
+        println!();
+        println!("{code}");
+        println!();
+    }
+
+    fn on_output(&self, text: &str) {
+        for line in text.lines() {
+            println!("{}", format!("│ {line}").dimmed());
+        }
+    }
+
+    fn on_progress(&self, message: &str) {
+        println!("{}", format!("… {message}").italic());
+    }
+
+    fn on_truncation(&self, full: &str, kept: &str) {
+        let note = format!(
+            "(output cut from {} to {} bytes for the model)",
+            full.len(),
+            kept.len()
+        );
+        println!("{}", note.dimmed());
+    }
+
+    fn on_cell_complete(&self, cell: &Cell) {
+        // Print output in bold with arrow prefix
+        let output_display = match &cell.output {
+            None => format!("→ {}", "(no output)"),
+            Some(out) => format!("→ {out}"),
+        };
+        println!("{}", output_display.bold());
+    }
+}
+
+/// Note appended to the final output when the context was sampled
+fn print_sample_note(report: Option<&SampleReport>) {
+    if let Some(report) = report {
+        println!(
+            "\n[Note: this answer is based on a sample of {:.1}% of the context ({} of {} bytes). Run without --sample-tokens for a full analysis.]",
+            report.fraction() * 100.0,
+            report.sampled_bytes(),
+            report.original_bytes
+        );
+    }
 }
 
-// System prompt adapted for Lua from RLM.md
-const SYSTEM_PROMPT: &str = r#"You are tasked with answering a query with associated context. You can access, transform, and analyze this context interactively in a REPL environment. You will be queried iteratively until you provide a final answer.
-
-The REPL environment is initialized with:
-1. A `context` variable that contains extremely important information about your query. You should check the content of the `context` variable to understand what you are working with. Make sure you look through it sufficiently as you answer your query.
-2. The ability to use `print()` statements to view the output of your REPL code and continue your reasoning.
-
-You will only be able to see truncated outputs from the REPL environment, so make sure to analyze the context carefully. An example strategy is to first look at the context and figure out a chunking strategy, then break up the context into smart chunks, and save the answers to a buffer, then produce your final answer.
-
-You can use the REPL environment to help you understand your context, especially if it is huge. For example, a viable strategy is to examine the structure first. Analyze your input data and understand its format!
-
-RECOMMENDED TECHNIQUES FOR PROCESSING LARGE CONTEXT:
-
-1. PEEKING: Start by examining the structure without seeing all the data
-   Example:
-   -- Peek at the beginning to understand format
-   preview = string.sub(context, 1, 500)
-   print("First 500 chars: " .. preview)
-   print("Total length: " .. string.len(context))
-
-   -- Check what type of data this is
-   if string.find(context, "^%s*{") then
-     print("Looks like JSON data")
-   elseif string.find(context, "^%s*<%?xml") then
-     print("Looks like XML data")
-   end
-
-2. GREPPING: Use patterns to find relevant information
-   Example:
-   -- Find all email addresses
-   emails = {}
-   for email in string.gmatch(context, "[%w%.]+@[%w%.]+") do
-     table.insert(emails, email)
-   end
-   print("Found " .. #emails .. " emails")
-
-   -- Search for specific keywords
-   start_pos = string.find(context, "important keyword")
-   if start_pos then
-     excerpt = string.sub(context, start_pos, start_pos + 200)
-     print("Found at position " .. start_pos .. ": " .. excerpt)
-   end
-
-3. PARTITION + MAP: Break into chunks and process each with llm_query
-   Example:
-   -- Split large context into 5000-char chunks
-   chunk_size = 5000
-   results = {}
-   for i = 1, string.len(context), chunk_size do
-     chunk = string.sub(context, i, i + chunk_size - 1)
-     truncated = token_trunc(chunk, 200)
-     summary = llm_query("Extract key facts from: " .. truncated)
-     table.insert(results, summary)
-   end
-   -- Combine results
-   final_result = table.concat(results, " | ")
-   print(token_trunc(final_result, 100))
-
-4. SUMMARIZATION: Progressively summarize subsets
-   Example:
-   -- Process in chunks, building up a summary
-   summary_buffer = ""
-   chunk_size = 8000
-   for i = 1, string.len(context), chunk_size do
-     chunk = string.sub(context, i, i + chunk_size - 1)
-     truncated = token_trunc(chunk, 300)
-     partial = llm_query("Summarize key points: " .. truncated)
-     summary_buffer = summary_buffer .. partial .. " "
-   end
-   -- Final summary of summaries
-   final = llm_query("Synthesize these summaries into final answer: " .. token_trunc(summary_buffer, 500))
-   print(final)
-
-5. PLANNING: Write down your strategy as comments to track progress
-   Example:
-   --[[
-   PLAN:
-   1. [DONE] Peek at context structure - appears to be CSV with 50k rows
-   2. [CURRENT] Grep for entries matching criteria X
-   3. [TODO] Partition matches into groups by category
-   4. [TODO] Use llm_query to analyze each group
-   5. [TODO] Synthesize final answer from group analyses
-
-   CURRENT STATUS: Found 234 matches, now grouping by category field
-   NEXT STEP: Process each category group separately
-   --]]
-
-   -- Update your plan after each step:
-   -- - Mark completed steps as [DONE]
-   -- - Mark current step as [CURRENT]
-   -- - Add new steps if approach needs adjustment
-   -- - Revise estimates if you discover new information
-   -- - If you see [truncated], revise plan to reduce output
-
-   -- Store plan as a global variable for reference
-   plan = [[
-   Step 1: Peek at structure [DONE]
-   Step 2: Identify key sections [CURRENT]
-   Step 3: Extract and process each section [TODO]
-   ]]
-   print("Current plan: " .. plan)
-
-6. RUNNING NOTES: Maintain a global array of key findings relevant to the prompt
-   Example:
-   -- Initialize notes array if it doesn't exist
-   if not notes then
-     notes = {}
-   end
-
-   -- Add important discoveries at each step
-   table.insert(notes, "Found 3 main categories: A, B, C")
-   table.insert(notes, "Category A has 120 items, largest group")
-   table.insert(notes, "Pattern: All B items contain keyword 'urgent'")
-
-   -- Review notes to guide next steps
-   print("Key findings so far:")
-   for i, note in ipairs(notes) do
-     print(i .. ". " .. note)
-   end
-
-   -- Filter notes to most relevant for the query
-   -- Keep only the top 5 most important findings
-   if #notes > 5 then
-     -- Use llm_query to identify most relevant notes
-     all_notes = table.concat(notes, " | ")
-     relevant = llm_query("Given query: '" .. prompt .. "', which of these findings are most relevant? " .. token_trunc(all_notes, 200))
-     table.insert(notes, "KEY INSIGHT: " .. relevant)
-   end
-
-   -- At each iteration, consider:
-   -- - What have I learned that's relevant to the prompt?
-   -- - What's the most important information to remember?
-   -- - Should I revise my understanding based on new findings?
-   -- - Are my notes helping me answer the original query?
-
-   -- Example of revising approach based on notes:
-   if #notes > 3 then
-     summary = llm_query("Summarize these key points: " .. table.concat(notes, "; "))
-     print("Summary of findings: " .. summary)
-   end
-
-Remember:
-- ALWAYS start with a plan: write it as Lua comments to track your approach
-- MAINTAIN RUNNING NOTES: Keep a global `notes` array with key findings relevant to the prompt
-- At each step, ask: "What have I learned that helps answer the original query?"
-- Update your plan after each iteration: mark [DONE], [CURRENT], [TODO]
-- Review your notes periodically and summarize if they get too long
-- If something isn't working or you see [truncated], revise your plan AND review your notes
-- The context variable contains the full data you need to analyze
-- Use Lua string operations (string.sub, string.find, string.match, string.gmatch, etc.) to explore and process the context
-- Create global variables (NOT local) to store intermediate results that persist across iterations
-- Use print() to output results you want to see
-- Think step by step and break down complex tasks into smaller operations
-- Combine techniques: peek first, grep for relevant sections, then partition+map or summarize
-- Always stay focused on the original prompt/query - don't get lost in details
-
-Available Functions:
-
-- `llm_query(prompt)`: Query a language model with a prompt string. Returns the LLM's response as a string.
-  Example: `response = llm_query("What is 2+2?")` or `answer = llm_query("Summarize this: " .. text)`
-  Use this when you need to:
-  * Ask questions about chunks of data
-  * Get help with complex reasoning tasks
-  * Summarize or analyze text segments
-  * Translate or transform text
-  Note: The LLM called by llm_query does NOT have access to your context variable, so you must include any relevant information in the prompt string.
-
-- `token_trunc(string, n)`: Truncate a string to approximately n tokens using BPE tokenization. Returns the truncated string.
-  Example: `short_text = token_trunc(long_text, 100)` or `chunk = token_trunc(string.sub(context, 1, 5000), 50)`
-  Use this to:
-  * Keep output under the 100 token limit per cell
-  * Prepare text chunks for llm_query (which has its own context limits)
-  * Manage large context data by processing it in token-limited chunks
-  Example usage pattern:
-    -- Process context in manageable chunks
-    for i = 1, string.len(context), 10000 do
-      chunk = string.sub(context, i, i + 9999)
-      truncated = token_trunc(chunk, 200)  -- Limit to 200 tokens
-      summary = llm_query("Summarize: " .. truncated)
-      print(summary)
-    end
-
-TOKEN MANAGEMENT - CRITICAL:
-- The total context window is limited to 30,000 tokens
-- Each cell should output NO MORE than 100 tokens to avoid filling the context
-- Cell outputs are AUTOMATICALLY TRUNCATED to 100 tokens by the system
-- If you see "[truncated]" at the end of an output, you MUST reduce your print() usage in subsequent cells
-- When you see "[truncated]":
-  * Use token_trunc() to explicitly limit output: `print(token_trunc(result, 80))`
-  * Use llm_query() to summarize before printing: `summary = llm_query("Summarize in 50 words: " .. data); print(summary)`
-  * Print less information - only essential results
-  * Break tasks into smaller steps with less output per step
-  * Do not simply try what you previously tried. Change your approach!
-- Use llm_query() to condense large outputs: instead of printing 1000 tokens, use llm_query to summarize to <100 tokens
-- When processing large context, break it into chunks and use llm_query with token_trunc for each chunk
-- Example: `print(token_trunc(result, 100))` instead of `print(result)` for large results
-
-CRITICAL OUTPUT FORMAT: You must format your response EXACTLY as follows using XML tags:
-
-<comment>
-Your description of the current step and reasoning goes here
-</comment>
-
-<code>
-Your Lua code goes here (no backticks needed)
-</code>
-
-<final>
-Either "true" or "false" - use "true" ONLY when you have completed the task and have the final answer
-</final>
-
-When you have completed your analysis and have the final answer ready, set final to "true". This will stop the iteration process. Only set this to true when:
-- You have thoroughly analyzed the context
-- You have arrived at a definitive answer to the query
-- Your code prints out the final result using print()
-
-CRITICAL: When setting final to true, your code MUST use print() to output the final answer. The output from this print statement will be captured as the final result. For example:
-
-<comment>
-Final step: output the answer
-</comment>
-
-<code>
-print("The answer is: 42")
-</code>
-
-<final>
-true
-</final>
-
-Think step by step carefully, plan, and execute this plan immediately in your response. Output to the REPL environment as much as possible. Remember to explicitly work toward answering the original query.
-"#;
+/// Parse a `key=value` tag argument
+fn parse_tag(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("invalid tag '{arg}', expected key=value")),
+    }
+}
+
+fn parse_now(arg: &str) -> Result<chrono::DateTime<chrono::Utc>, String> {
+    datetime::parse(arg)
+        .ok_or_else(|| format!("invalid time '{arg}', expected e.g. 2024-05-01T09:00:00Z"))
+}
+
+/// Resolve `{{env:VAR}}` and `{{file:path}}` placeholders in a prompt template.
+///
+/// Environment variables must be set and files must be readable; anything else
+/// (including other `{{...}}` text) is left untouched.
+fn interpolate(template: &str) -> Result<String, String> {
+    interpolate_with(template, |name| std::env::var(name))
+}
+
+/// [`interpolate`], looking up environment variables with `env`
+fn interpolate_with(
+    template: &str,
+    env: impl Fn(&str) -> Result<String, std::env::VarError>,
+) -> Result<String, String> {
+    let re = regex::Regex::new(r"\{\{\s*(env|file):([^}]+?)\s*\}\}").unwrap();
+    let mut result = String::with_capacity(template.len());
+    let mut last = 0;
+
+    for cap in re.captures_iter(template) {
+        let whole = cap.get(0).unwrap();
+        let value = match &cap[1] {
+            "env" => {
+                env(&cap[2]).map_err(|e| format!("Failed to resolve {}: {e}", whole.as_str()))?
+            }
+            _ => std::fs::read_to_string(&cap[2])
+                .map_err(|e| format!("Failed to resolve {}: {e}", whole.as_str()))?,
+        };
+        result.push_str(&template[last..whole.start()]);
+        result.push_str(&value);
+        last = whole.end();
+    }
+    result.push_str(&template[last..]);
+
+    Ok(result)
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
+    // A resumed session keeps its prompt unless a new one is given
+    let mut session: Option<Session> = match &args.resume {
+        Some(path) => {
+            let json = std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read session from {path}: {e}"))?;
+            Some(
+                serde_json::from_str(&json)
+                    .map_err(|e| format!("Failed to parse session in {path}: {e}"))?,
+            )
+        }
+        None => None,
+    };
+    let prompt = match (&args.prompt, &mut session) {
+        (Some(prompt), session) => {
+            let prompt = interpolate(prompt)?;
+            if let Some(session) = session {
+                session.prompt = prompt.clone();
+            }
+            prompt
+        }
+        (None, Some(session)) => session.prompt.clone(),
+        (None, None) => unreachable!("clap requires --prompt without --resume"),
+    };
+
+    // Load the system prompt, falling back to the built-in one
+    let system_prompt = match &args.system_prompt_file {
+        Some(path) => {
+            let template = std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read system prompt from {path}: {e}"))?;
+            interpolate(&template)?
+        }
+        None => SYSTEM_PROMPT.to_string(),
+    };
+
     // Parse log level from command line argument
     let log_level = match args.log_level.to_lowercase().as_str() {
         "trace" => tracing::Level::TRACE,
@@ -295,28 +531,221 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt().with_max_level(log_level).init();
 
     println!("=== Moonraker RLM ===");
-    println!("Query: {}", args.prompt);
+    println!("Query: {prompt}");
     println!("Provider: {:?}", args.provider);
     println!("Model: {}", args.model);
+    for (key, value) in &args.tags {
+        println!("Tag: {key}={value}");
+    }
     println!("Max iterations: {}\n", args.max_iterations);
 
-    // Load context from file if provided
-    let context_content = if let Some(context_path) = &args.context {
-        let input =
-            Input::from_file(context_path).map_err(|e| format!("Failed to load context: {e}"))?;
+    // Load context from files or directories if provided
+    let load_options = LoadOptions {
+        pdf_page_markers: args.pdf_page_markers,
+        pdf_tables: args.pdf_tables,
+        strict_encoding: args.strict_encoding,
+        binary_prefix_bytes: args.binary_prefix_bytes,
+        cache_dir: if args.no_cache {
+            None
+        } else {
+            default_cache_dir()
+        },
+    };
+    let mut pipeline = InputPipeline::new();
+    if args.redact || !args.redact_patterns.is_empty() {
+        let redact = args
+            .redact_patterns
+            .iter()
+            .fold(RedactPii::default(), |redact, pattern| {
+                redact.with_pattern(pattern.clone())
+            });
+        pipeline = pipeline.with_stage(redact);
+    }
+    for stage in &args.preprocess {
+        pipeline = match stage {
+            PreprocessStage::StripBoilerplate => pipeline.with_stage(StripBoilerplate::default()),
+            PreprocessStage::DedupeLines => pipeline.with_stage(DedupeLines),
+            PreprocessStage::NormalizeWhitespace => pipeline.with_stage(NormalizeWhitespace),
+            PreprocessStage::RedactPii => pipeline.with_stage(RedactPii::default()),
+        };
+    }
+    // Glob patterns expand to their matches in sorted order
+    let mut context_paths = Vec::new();
+    for context_arg in &args.context {
+        context_paths.extend(
+            expand_glob(context_arg)
+                .map_err(|e| format!("Failed to load context {context_arg}: {e}"))?,
+        );
+    }
+    let mut inputs = Vec::new();
+    for context_path in &context_paths {
+        let path = std::path::Path::new(context_path);
+        let input = match args.context_format {
+            _ if path.is_dir() => Input::from_dir_with(path, &load_options),
+            ContextFormat::Auto => Input::from_file_with(path, &load_options),
+            ContextFormat::Text => std::fs::read_to_string(path)
+                .map(Input::from_string)
+                .map_err(|e| InputError::ReadError(e.to_string())),
+            ContextFormat::Json => {
+                Input::from_file_with(path, &load_options).and_then(Input::parse_json)
+            }
+        }
+        .map_err(|e| format!("Failed to load context {context_path}: {e}"))?;
+        inputs.push((context_path.clone(), pipeline.run(input)));
+    }
+    if let [old_path, new_path] = args.context_diff.as_slice() {
+        let load = |path: &String| {
+            Input::from_file_with(path, &load_options)
+                .map(|input| pipeline.run(input))
+                .map_err(|e| format!("Failed to load context {path}: {e}"))
+        };
+        let diff = Input::diff(&load(old_path)?, &load(new_path)?);
+        inputs.push((format!("{old_path} -> {new_path}"), diff));
+    }
+
+    // File metadata: a table for a single context, a list for several
+    let metas: Vec<&InputMeta> = inputs
+        .iter()
+        .filter_map(|(_, input)| input.meta())
+        .collect();
+    let context_meta = match metas.as_slice() {
+        [] => serde_json::Value::Null,
+        [meta] if inputs.len() == 1 => serde_json::to_value(meta)?,
+        metas => serde_json::to_value(metas)?,
+    };
+    let meta_summary = match metas.as_slice() {
+        [meta] if inputs.len() == 1 => Some(meta.summary()),
+        _ => None,
+    };
+
+    // Multiple contexts are concatenated and also exposed individually by
+    // filename, in the `contexts` table and as documents
+    let mut contexts = serde_json::Map::new();
+    let mut documents: Vec<(String, String)> = Vec::new();
+    let input = match inputs.len() {
+        0 => None,
+        1 => inputs.pop().map(|(_, input)| input),
+        _ => {
+            for (path, input) in &inputs {
+                let name = std::path::Path::new(path)
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .filter(|name| documents.iter().all(|(other, _)| other != name))
+                    .unwrap_or_else(|| path.clone());
+                contexts.insert(name.clone(), input.content().into());
+                documents.push((name, input.content().to_string()));
+            }
+            Some(Input::concat(&inputs))
+        }
+    };
+
+    // Structured data derived from a single context (e.g. CSV rows)
+    let context_globals = input
+        .as_ref()
+        .map(|input| input.globals().clone())
+        .unwrap_or_default();
+    // Functions giving access to the source, e.g. `sql_query` for a database
+    let mut context_functions = input
+        .as_ref()
+        .map(|input| input.functions().to_vec())
+        .unwrap_or_default();
+    if !args.allow_load_dirs.is_empty() {
+        context_functions.push(load_file_function(
+            &args.allow_load_dirs,
+            load_options.clone(),
+            pipeline.clone(),
+        ));
+    }
+    let system_prompt = prompt::system_prompt(&system_prompt, &context_functions);
+
+    let (context_content, mut context_description) = if let Some(input) = input {
         let content = input.content().to_string();
-        println!("Loaded context: {} characters\n", content.len());
-        content
+        println!("Loaded context: {} characters", content.len());
+        let description = input.description().map(|d| {
+            if documents.is_empty() {
+                d.to_string()
+            } else {
+                format!(
+                    "{d}; each file is also available in the `contexts` table keyed by filename, and as a document: list them with `list_documents()` and read one with `get_document(filename)`"
+                )
+            }
+        });
+        let description = match (meta_summary, description) {
+            (Some(summary), Some(d)) => {
+                Some(format!("{summary}; {d}; file metadata in `context_meta`"))
+            }
+            (Some(summary), None) => Some(format!("{summary}; file metadata in `context_meta`")),
+            (None, description) => description,
+        };
+        if let Some(description) = &description {
+            println!("Context: {description}");
+        }
+        println!();
+        (content, description)
     } else {
         println!("No context file provided\n");
-        String::new()
+        (String::new(), None)
+    };
+
+    // Token budgets below are counted in the model's encoding
+    let tokenizer = match args.tokenizer {
+        TokenizerChoice::Auto => Tokenizer::for_model(&args.model),
+        TokenizerChoice::P50k => Tokenizer::P50k,
+        TokenizerChoice::Cl100k => Tokenizer::Cl100k,
+        TokenizerChoice::O200k => Tokenizer::O200k,
+    };
+
+    // Sample enormous contexts for a cheap first pass
+    let (context_content, sample_report) = match args.sample_tokens {
+        Some(max_tokens) => {
+            let seed = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or_default();
+            let config = SampleConfig {
+                seed,
+                tokenizer,
+                ..Default::default()
+            };
+            let (sampled, report) = sample(&context_content, max_tokens, &config);
+            if let Some(report) = &report {
+                println!(
+                    "Sampling: using {} of {} bytes in {} slices\n",
+                    report.sampled_bytes(),
+                    report.original_bytes,
+                    report.slices.len()
+                );
+            }
+            (sampled, report)
+        }
+        None => (context_content, None),
+    };
+
+    // Drop chunks of very large contexts that are irrelevant to the prompt
+    let context_content = if !args.prefilter {
+        context_content
+    } else {
+        let prefilter = Prefilter::new(KeywordScorer, PrefilterConfig::default());
+        let (filtered, report) = prefilter.apply(&prompt, &context_content);
+        if !report.excluded.is_empty() {
+            println!(
+                "Pre-filter: kept {} of {} chunks, excluded {} bytes in {} ranges",
+                report.kept_chunks,
+                report.total_chunks,
+                report.excluded_bytes(),
+                report.excluded.len()
+            );
+            for range in &report.excluded {
+                tracing::info!("Excluded context bytes {}-{}", range.start, range.end);
+            }
+            println!();
+        }
+        filtered
     };
 
     // Create the provider with system prompt based on the provider argument
     let provider = match args.provider {
-        Provider::Ollama => {
-            RigProvider::new_ollama_with_system(args.model.clone(), SYSTEM_PROMPT.to_string())
-        }
+        Provider::Ollama => RigProvider::new_ollama_with_system(args.model.clone(), system_prompt),
         Provider::Openrouter => {
             let api_key_file = args.api_key_file.ok_or_else(|| {
                 "API key file is required for OpenRouter provider. Use --api-key-file <PATH>"
@@ -328,64 +757,212 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .to_string();
             RigProvider::new_openrouter_with_system_and_key(
                 args.model.clone(),
-                SYSTEM_PROMPT.to_string(),
+                system_prompt,
                 api_key,
             )
         }
     };
 
+    // Warn about contexts the model cannot meaningfully cover, and optionally
+    // give it a summary of the whole thing
+    let mut context_summary = None;
+    let context_tokens = tokenizer.estimate(&context_content);
+    if context_tokens > args.context_token_budget {
+        println!(
+            "{}",
+            format!(
+                "Warning: the context is about {context_tokens} tokens, over the budget of {}; the model will only see parts of it{}",
+                args.context_token_budget,
+                if args.summarize_large_context {
+                    ""
+                } else {
+                    " (use --summarize-large-context to pre-summarize it)"
+                }
+            )
+            .yellow()
+        );
+        if args.summarize_large_context {
+            println!("Summarizing the context...");
+            let config = SummarizeConfig {
+                tokenizer,
+                ..Default::default()
+            };
+            let summary = summarize(&provider, &context_content, &config)
+                .await
+                .map_err(|e| format!("Failed to summarize context: {e}"))?;
+            println!("Summary: {} characters\n", summary.len());
+            let hint = "a summary of the whole context is in `context_summary`; read it first";
+            context_description = Some(match context_description {
+                Some(description) => format!("{description}; {hint}"),
+                None => hint.to_string(),
+            });
+            context_summary = Some(summary);
+        } else {
+            println!();
+        }
+    }
+
+    // Small contexts can be answered with a single completion, unless the
+    // data is only reachable through context functions
+    let try_direct = match args.mode {
+        Mode::Auto => {
+            session.is_none()
+                && context_functions.is_empty()
+                && fits_directly(&prompt, &context_content, args.direct_max_tokens, tokenizer)
+        }
+        Mode::Direct => true,
+        Mode::Rlm => false,
+    };
+    if try_direct {
+        println!("Answering directly...\n");
+        match direct_answer(&provider, &prompt, &context_content).await {
+            Ok(answer) if args.mode == Mode::Direct || answer.confidence != Confidence::Low => {
+                println!("=== Final Output ===");
+                println!("{}", answer.answer);
+                print_sample_note(sample_report.as_ref());
+                print_usage(&provider.usage());
+                return Ok(());
+            }
+            Ok(_) => println!("Direct answer had low confidence, falling back to RLM\n"),
+            Err(e) if args.mode == Mode::Direct => {
+                return Err(format!("Direct answer failed: {e}").into());
+            }
+            Err(e) => println!("Direct answer failed ({e}), falling back to RLM\n"),
+        }
+    }
+
     // Create the LlmClient for the REPL environment
     let llm_client = provider
         .to_llm_client()
         .map_err(|e| format!("Failed to create LlmClient: {e}"))?;
 
-    // Create the RLM
-    let mut rlm = Rlm::new(
-        provider,
-        args.prompt.clone(),
-        context_content,
-        args.model.clone(),
-        llm_client,
-    )
+    let store = match &args.store {
+        Some(name) => {
+            let path = store_path(name)
+                .ok_or("Cannot locate the store directory: set $MOONRAKER_STORE_DIR or $HOME")?;
+            let store = Store::open(&path)
+                .map_err(|e| format!("Failed to open store {}: {e}", path.display()))?;
+            Some(store)
+        }
+        None => None,
+    };
+
+    // Zero disables a limit
+    let limit = |max: usize| (max > 0).then_some(max);
+    let mut environment = EnvironmentConfig::default()
+        .with_max_llm_queries_per_cell(limit(args.max_llm_queries_per_cell))
+        .with_max_llm_queries_per_run(limit(args.max_llm_queries_per_run))
+        .with_cell_timeout(
+            (args.cell_timeout > 0).then(|| std::time::Duration::from_secs(args.cell_timeout)),
+        )
+        .with_max_cell_instructions(
+            (args.max_cell_instructions > 0).then_some(args.max_cell_instructions),
+        )
+        .with_max_cell_memory(limit(args.max_cell_memory_mb).map(|mb| mb << 20))
+        .with_max_output_bytes(limit(args.max_cell_output_kb).map(|kb| kb << 10))
+        .with_stdlib(
+            args.allow_lua
+                .iter()
+                .fold(StdlibProfile::default(), |profile, entry| {
+                    profile.allow(entry)
+                })
+                .with_max_rep_bytes(limit(args.max_string_rep_mb).map(|mb| mb << 20)),
+        )
+        .with_embedding_model(args.embedding_model.clone())
+        .with_sub_rlm(args.max_rlm_depth, args.sub_rlm_iterations)
+        .with_store(store)
+        .with_http_allowlist(&args.http_domains)
+        .with_seed(args.seed)
+        .with_frozen_now(args.now)
+        .with_readonly_context(args.readonly_context)
+        .with_policy(args.strict_policy.then(PolicyChecker::default))
+        .with_tokenizer(tokenizer)
+        .with_global("contexts", contexts.into())
+        .with_global("context_summary", context_summary.into())
+        .with_global("context_meta", context_meta);
+    for (name, value) in context_globals {
+        environment = environment.with_global(name, value);
+    }
+    for (name, text) in documents {
+        environment = environment.with_document(name, text);
+    }
+    environment.functions.extend(context_functions);
+    let mut config = RlmConfig::default()
+        .with_environment(environment)
+        .with_retries(args.max_retries, std::time::Duration::from_secs(1))
+        .with_fix_retries(args.fix_retries)
+        .with_token_budget((args.token_budget > 0).then_some(args.token_budget))
+        .with_warm_start(args.warm_start)
+        .with_max_output_tokens(args.max_cell_output_tokens)
+        .with_truncation(match args.cell_truncation {
+            CellTruncation::Head => Truncation::Head,
+            CellTruncation::Tail => Truncation::Tail,
+            CellTruncation::HeadAndTail => Truncation::HeadAndTail,
+        })
+        .with_window(args.window_tokens.map(|tokens| {
+            let strategy = match args.window_eviction {
+                WindowEviction::DropOldest => WindowStrategy::DropOldest,
+                WindowEviction::Summarize => WindowStrategy::Summarize,
+                WindowEviction::KeepPinned => WindowStrategy::KeepPinned,
+            };
+            WindowConfig::new(tokens, strategy)
+        }))
+        .with_hide_code_after(args.hide_code_after)
+        .with_context_description(context_description);
+    config = match args.transcript_format {
+        TranscriptFormat::Markdown => config.with_formatter(transcript::Markdown),
+        TranscriptFormat::Compact => config.with_formatter(transcript::Compact),
+        TranscriptFormat::JsonLines => config.with_formatter(transcript::JsonLines),
+        TranscriptFormat::Chat => config.with_formatter(transcript::Chat),
+    };
+    for (key, value) in &args.tags {
+        config = config.with_tag(key, value);
+    }
+
+    // Create the RLM, or continue a saved session
+    let mut rlm = match session {
+        Some(session) => {
+            println!("Resuming session with {} cells", session.entries.len());
+            Rlm::resume(provider, session, context_content, llm_client, config)
+        }
+        None => Rlm::with_config(
+            provider,
+            prompt.clone(),
+            context_content,
+            llm_client,
+            config,
+        ),
+    }
     .map_err(|e| format!("Failed to create RLM: {e}"))?;
 
+    // Print cells as they run, including output while long cells are still running
+    rlm.add_observer(Arc::new(TerminalPrinter::default()));
+    if let Some(path) = &args.trace_file {
+        let trace = TraceWriter::create(path)
+            .map_err(|e| format!("Failed to create trace file {path}: {e}"))?;
+        rlm.add_observer(Arc::new(trace));
+    }
+
     // Execute the RLM using the iterator
     println!("Starting execution...\n");
     let mut iter = rlm.execute(args.max_iterations);
     let mut iteration = 0;
     let mut is_final = false;
+    let mut failure = None;
 
     while let Some(result) = iter.next().await {
         iteration += 1;
 
         match result {
             Ok(cell) => {
-                // Print horizontal line if not the first iteration
-                if iteration > 1 {
-                    println!();
-                    println!("{}", "─".repeat(80));
-                    println!();
+                // Note if the provider had to be retried
+                if cell.retries > 0 {
+                    println!(
+                        "{}",
+                        format!("(generated after {} retries)", cell.retries).dimmed()
+                    );
                 }
 
-                // Print comment in bold
-                println!("{}", cell.comment.bold());
-
-                // Space
-                println!();
-
-                // Print code in regular text color
-                println!("{}", cell.code);
-
-                // Space
-                println!();
-
-                // Print output in bold with arrow prefix
-                let output_display = match &cell.output {
-                    None => format!("→ {}", "(no output)"),
-                    Some(out) => format!("→ {out}"),
-                };
-                println!("{}", output_display.bold());
-
                 // Check if this is the final cell
                 if cell.r#final {
                     println!("\n[Task completed - final flag set]");
@@ -395,13 +972,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
             Err(e) => {
                 eprintln!("Error in iteration {iteration}: {e}");
-                return Err(format!("Execution failed: {e}").into());
+                failure = Some(format!("Execution failed: {e}"));
+                break;
             }
         }
     }
 
+    // Save the session, also after a failure so the run can be resumed
+    if let Some(path) = &args.save_session {
+        let json = serde_json::to_string_pretty(rlm.repl())
+            .map_err(|e| format!("Failed to serialize session: {e}"))?;
+        std::fs::write(path, json)
+            .map_err(|e| format!("Failed to write session to {path}: {e}"))?;
+        println!("\nSession saved to {path}");
+    }
+    if let Some(failure) = failure {
+        return Err(failure.into());
+    }
+
     if !is_final && iteration >= args.max_iterations {
         println!("\n[Reached maximum iterations without completion]");
+    } else if !is_final && rlm.tokens_left() == Some(0) {
+        println!("\n[Spent the token budget without completion]");
     }
 
     // Print final output
@@ -411,6 +1003,97 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     } else {
         println!("No output from final cell");
     }
+    print_sample_note(sample_report.as_ref());
+
+    // Print structured findings
+    if !rlm.findings().is_empty() {
+        println!("\n=== Findings ===");
+        for finding in rlm.findings() {
+            println!("{}: {}", finding.key, finding.value);
+        }
+    }
+
+    // Print usage summary
+    let usage = rlm.usage_breakdown();
+    print_usage(&usage);
+    let tokens = rlm.repl().token_usage();
+    println!(
+        "transcript: {} cells, {} tokens ({} in the next prompt)",
+        tokens.cell_output_tokens.len(),
+        tokens.transcript_tokens,
+        tokens.prompt_tokens
+    );
+
+    // Export HTML report if requested
+    if let Some(path) = &args.export_html {
+        std::fs::write(path, rlm.repl().to_html(&usage))
+            .map_err(|e| format!("Failed to write HTML report to {path}: {e}"))?;
+        println!("\nHTML report written to {path}");
+    }
+
+    // Export a notebook if requested
+    if let Some(path) = &args.save_notebook {
+        let json = serde_json::to_string_pretty(&rlm.repl().to_notebook())
+            .map_err(|e| format!("Failed to serialize notebook: {e}"))?;
+        std::fs::write(path, json)
+            .map_err(|e| format!("Failed to write notebook to {path}: {e}"))?;
+        println!("Notebook written to {path}");
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_parse_tag() {
+        assert_eq!(
+            parse_tag("project=alpha").unwrap(),
+            ("project".to_string(), "alpha".to_string())
+        );
+        assert_eq!(
+            parse_tag("query=a=b").unwrap(),
+            ("query".to_string(), "a=b".to_string())
+        );
+        assert!(parse_tag("novalue").is_err());
+        assert!(parse_tag("=value").is_err());
+    }
+
+    #[test]
+    fn test_interpolate_env() {
+        let env = |name: &str| match name {
+            "TICKET" => Ok("ABC-123".to_string()),
+            _ => Err(std::env::VarError::NotPresent),
+        };
+        let result = interpolate_with("Ticket {{env:TICKET}} status", env).unwrap();
+        assert_eq!(result, "Ticket ABC-123 status");
+        assert!(interpolate_with("{{env:OTHER}}", env).is_err());
+    }
+
+    #[test]
+    fn test_interpolate_file() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "shared snippet").unwrap();
+        let template = format!("Before {{{{file:{}}}}} after", file.path().display());
+        assert_eq!(
+            interpolate(&template).unwrap(),
+            "Before shared snippet after"
+        );
+    }
+
+    #[test]
+    fn test_interpolate_missing() {
+        assert!(interpolate("{{env:MOONRAKER_TEST_DOES_NOT_EXIST}}").is_err());
+        assert!(interpolate("{{file:/nonexistent/snippet.txt}}").is_err());
+    }
+
+    #[test]
+    fn test_interpolate_leaves_other_braces() {
+        let template = "t = {{1, 2}, {3}} and {{other:x}}";
+        assert_eq!(interpolate(template).unwrap(), template);
+    }
+}
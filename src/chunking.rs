@@ -0,0 +1,606 @@
+//! Splitting text into chunks for partition + map processing.
+//!
+//! A [`Chunker`] returns the byte ranges of the chunks of a text. Built-in
+//! strategies pack whole sentences or paragraphs, cut fixed token windows, or
+//! split recursively on paragraph, line, sentence, and word boundaries until
+//! every piece fits. Consecutive chunks can overlap so facts spanning a
+//! boundary are seen whole at least once.
+//!
+//! Sizes are in bytes, except for [`TokenChunker`] which counts tokens of a
+//! [`Tokenizer`], in Lua the environment's.
+//! In Lua the strategies are available as
+//! `chunk_context(strategy, size, overlap)`, and
+//! `for_each_chunk(text, size, overlap, fn)` maps a function over the
+//! recursive chunks of any text.
+
+use crate::tokens::Tokenizer;
+use mlua::{FromLua, Lua};
+use regex::Regex;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+/// Sentence ends: terminal punctuation, closing quotes or brackets, whitespace
+static SENTENCE_END: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"[.!?]+["')\]]*\s+|\n[ \t]*\n\s*"#).unwrap());
+
+/// Paragraph breaks: blank lines
+static PARAGRAPH_END: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\n[ \t]*\n\s*").unwrap());
+
+/// Separators tried in order by [`RecursiveChunker`]
+const RECURSIVE_SEPARATORS: &[&str] = &["\n\n", "\n", ". ", " "];
+
+/// A way of splitting text into chunks
+pub trait Chunker: Send + Sync {
+    /// Byte ranges `(start, end)` of the chunks of `text`, in order.
+    /// Ranges of consecutive chunks may overlap.
+    fn chunk(&self, text: &str) -> Vec<(usize, usize)>;
+}
+
+/// Chunk a text into owned strings
+pub fn chunk_text(chunker: &dyn Chunker, text: &str) -> Vec<String> {
+    chunker
+        .chunk(text)
+        .into_iter()
+        .map(|(start, end)| text[start..end].to_string())
+        .collect()
+}
+
+/// The built-in chunking strategies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    Sentence,
+    Paragraph,
+    Tokens,
+    Recursive,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sentence" | "sentences" => Ok(Self::Sentence),
+            "paragraph" | "paragraphs" => Ok(Self::Paragraph),
+            "token" | "tokens" => Ok(Self::Tokens),
+            "recursive" => Ok(Self::Recursive),
+            _ => Err(format!(
+                "unknown chunking strategy '{s}' (expected sentence, paragraph, tokens, or recursive)"
+            )),
+        }
+    }
+}
+
+impl Strategy {
+    /// A chunker for this strategy with chunks of at most `size` (bytes, or
+    /// tokens of `tokenizer` for [`Strategy::Tokens`]) overlapping by up to
+    /// `overlap`
+    pub fn chunker(
+        self,
+        size: usize,
+        overlap: usize,
+        tokenizer: Tokenizer,
+    ) -> Result<Box<dyn Chunker>, String> {
+        check_sizes(size, overlap)?;
+        Ok(match self {
+            Self::Sentence => Box::new(SentenceChunker { size, overlap }),
+            Self::Paragraph => Box::new(ParagraphChunker { size, overlap }),
+            Self::Tokens => Box::new(TokenChunker {
+                size,
+                overlap,
+                tokenizer,
+            }),
+            Self::Recursive => Box::new(RecursiveChunker { size, overlap }),
+        })
+    }
+}
+
+fn check_sizes(size: usize, overlap: usize) -> Result<(), String> {
+    if size == 0 {
+        return Err("chunk size must be positive".to_string());
+    }
+    if overlap >= size {
+        return Err(format!(
+            "overlap ({overlap}) must be smaller than the chunk size ({size})"
+        ));
+    }
+    Ok(())
+}
+
+/// Packs whole sentences into chunks of at most `size` bytes. A sentence
+/// longer than `size` becomes a chunk of its own.
+#[derive(Debug, Clone)]
+pub struct SentenceChunker {
+    pub size: usize,
+    /// Trailing sentences of up to this many bytes are repeated in the next chunk
+    pub overlap: usize,
+}
+
+impl Chunker for SentenceChunker {
+    fn chunk(&self, text: &str) -> Vec<(usize, usize)> {
+        pack(
+            text,
+            &split_after(text, &SENTENCE_END),
+            self.size,
+            self.overlap,
+        )
+    }
+}
+
+/// Packs whole paragraphs (separated by blank lines) into chunks of at most
+/// `size` bytes. A paragraph longer than `size` becomes a chunk of its own.
+#[derive(Debug, Clone)]
+pub struct ParagraphChunker {
+    pub size: usize,
+    /// Trailing paragraphs of up to this many bytes are repeated in the next chunk
+    pub overlap: usize,
+}
+
+impl Chunker for ParagraphChunker {
+    fn chunk(&self, text: &str) -> Vec<(usize, usize)> {
+        pack(
+            text,
+            &split_after(text, &PARAGRAPH_END),
+            self.size,
+            self.overlap,
+        )
+    }
+}
+
+/// Fixed windows of `size` tokens, each starting `size - overlap` tokens
+/// after the previous one
+#[derive(Debug, Clone)]
+pub struct TokenChunker {
+    pub size: usize,
+    pub overlap: usize,
+    pub tokenizer: Tokenizer,
+}
+
+impl Chunker for TokenChunker {
+    fn chunk(&self, text: &str) -> Vec<(usize, usize)> {
+        // Byte offset of every token boundary
+        let offsets: Vec<usize> = match self.tokenizer.bpe() {
+            Some(bpe) => {
+                let mut offsets = vec![0];
+                for token in bpe._decode_native_and_split(bpe.encode_ordinary(text)) {
+                    offsets.push(offsets[offsets.len() - 1] + token.len());
+                }
+                offsets
+            }
+            // 4 characters per token, as `Tokenizer::count` estimates
+            None => text
+                .char_indices()
+                .map(|(i, _)| i)
+                .step_by(4)
+                .chain([text.len()])
+                .collect(),
+        };
+        let count = offsets.len() - 1;
+        let step = self.size.saturating_sub(self.overlap).max(1);
+
+        let mut chunks = Vec::new();
+        let mut first = 0;
+        while first < count {
+            let last = (first + self.size).min(count);
+            // Tokens can split a multi-byte character, which then belongs
+            // to the window holding its last byte
+            let (start, end) = (
+                ceil_char_boundary(text, offsets[first]),
+                ceil_char_boundary(text, offsets[last]),
+            );
+            if start < end {
+                chunks.push((start, end));
+            }
+            if last == count {
+                break;
+            }
+            first += step;
+        }
+        chunks
+    }
+}
+
+/// Splits on paragraph breaks, then lines, sentences, and words, until every
+/// piece is at most `size` bytes, and packs the pieces back into chunks of at
+/// most `size` bytes
+#[derive(Debug, Clone)]
+pub struct RecursiveChunker {
+    pub size: usize,
+    /// Trailing pieces of up to this many bytes are repeated in the next chunk
+    pub overlap: usize,
+}
+
+impl RecursiveChunker {
+    /// A recursive chunker, or an error if `size` is zero or not larger than
+    /// `overlap`
+    pub fn new(size: usize, overlap: usize) -> Result<Self, String> {
+        check_sizes(size, overlap)?;
+        Ok(Self { size, overlap })
+    }
+}
+
+impl Chunker for RecursiveChunker {
+    fn chunk(&self, text: &str) -> Vec<(usize, usize)> {
+        let mut pieces = Vec::new();
+        split_recursive(
+            text,
+            (0, text.len()),
+            self.size,
+            RECURSIVE_SEPARATORS,
+            &mut pieces,
+        );
+        pack(text, &pieces, self.size, self.overlap)
+    }
+}
+
+/// Split a text into contiguous ranges, each ending after a match
+fn split_after(text: &str, pattern: &Regex) -> Vec<(usize, usize)> {
+    let mut units = Vec::new();
+    let mut start = 0;
+    for m in pattern.find_iter(text) {
+        if m.end() > start {
+            units.push((start, m.end()));
+            start = m.end();
+        }
+    }
+    if start < text.len() {
+        units.push((start, text.len()));
+    }
+    units
+}
+
+fn split_recursive(
+    text: &str,
+    (start, end): (usize, usize),
+    size: usize,
+    separators: &[&str],
+    out: &mut Vec<(usize, usize)>,
+) {
+    if end - start <= size {
+        out.push((start, end));
+        return;
+    }
+    let Some((separator, rest)) = separators.split_first() else {
+        // No separator left: cut at character boundaries
+        let mut at = start;
+        while at < end {
+            let mut next = (at + size).min(end);
+            next = floor_char_boundary(text, next);
+            if next == at {
+                next = at + text[at..].chars().next().map_or(1, char::len_utf8);
+            }
+            out.push((at, next));
+            at = next;
+        }
+        return;
+    };
+
+    let mut pieces = Vec::new();
+    let mut piece_start = start;
+    for (i, _) in text[start..end].match_indices(separator) {
+        let piece_end = start + i + separator.len();
+        pieces.push((piece_start, piece_end));
+        piece_start = piece_end;
+    }
+    if piece_start < end {
+        pieces.push((piece_start, end));
+    }
+
+    if pieces.len() == 1 {
+        split_recursive(text, (start, end), size, rest, out);
+    } else {
+        for piece in pieces {
+            split_recursive(text, piece, size, rest, out);
+        }
+    }
+}
+
+/// Greedily pack contiguous units into chunks of at most `size` bytes (at
+/// least one unit each), then trim whitespace at the edges of every chunk
+fn pack(text: &str, units: &[(usize, usize)], size: usize, overlap: usize) -> Vec<(usize, usize)> {
+    let mut chunks = Vec::new();
+    let mut first = 0;
+    while first < units.len() {
+        let start = units[first].0;
+        let mut next = first + 1;
+        while next < units.len() && text[start..units[next].1].trim_end().len() <= size {
+            next += 1;
+        }
+        let end = units[next - 1].1;
+        chunks.push((start, end));
+        if next == units.len() {
+            break;
+        }
+
+        // Step back over trailing units that fit in the overlap, always
+        // moving forward by at least one unit
+        let mut resume = next;
+        while resume > first + 1 && end - units[resume - 1].0 <= overlap {
+            resume -= 1;
+        }
+        first = resume;
+    }
+
+    chunks
+        .into_iter()
+        .filter_map(|(start, end)| {
+            let chunk = &text[start..end];
+            let trimmed = chunk.trim();
+            if trimmed.is_empty() {
+                return None;
+            }
+            let start = start + (chunk.len() - chunk.trim_start().len());
+            Some((start, start + trimmed.len()))
+        })
+        .collect()
+}
+
+fn ceil_char_boundary(text: &str, mut index: usize) -> usize {
+    index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// Creates the `chunk_context(strategy, size, overlap)` Lua function, which
+/// chunks the current `context` global and returns a list of strings.
+///
+/// `overlap` defaults to 0. The "tokens" strategy counts tokens of
+/// `tokenizer`.
+pub fn create_chunk_context_function(
+    lua: &Lua,
+    tokenizer: Tokenizer,
+) -> mlua::Result<mlua::Function> {
+    lua.create_function(
+        move |lua, (strategy, size, overlap): (String, usize, Option<usize>)| {
+            let strategy: Strategy = strategy.parse().map_err(mlua::Error::RuntimeError)?;
+            let chunker = strategy
+                .chunker(size, overlap.unwrap_or(0), tokenizer)
+                .map_err(mlua::Error::RuntimeError)?;
+            let context: mlua::Value = lua.globals().get("context")?;
+            let Some(context) = context.as_string().map(|s| s.to_string_lossy()) else {
+                return Err(mlua::Error::RuntimeError(
+                    "chunk_context needs `context` to be a string".to_string(),
+                ));
+            };
+            lua.create_sequence_from(chunk_text(chunker.as_ref(), &context))
+        },
+    )
+}
+
+/// Creates the `for_each_chunk(text, size, overlap, fn)` Lua function.
+///
+/// Splits `text` like the "recursive" strategy, into chunks of at most
+/// `size` bytes cut at paragraph, line, sentence, or word boundaries and
+/// sharing up to `overlap` bytes, then calls `fn(chunk, i, n)` for each of
+/// the `n` chunks in order. Returns a list of the values `fn` returned,
+/// in chunk order, leaving out nils. `overlap` may be left out, as in
+/// `for_each_chunk(text, size, fn)`. An error in `fn` names the chunk it
+/// failed on and how many chunks were done.
+pub fn create_for_each_chunk_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(
+        |lua,
+         (text, size, overlap, callback): (
+            mlua::String,
+            usize,
+            mlua::Value,
+            Option<mlua::Function>,
+        )| {
+            let (overlap, callback) = match (overlap, callback) {
+                (mlua::Value::Function(callback), None) => (0, callback),
+                (overlap, Some(callback)) => (
+                    Option::<usize>::from_lua(overlap, lua)?.unwrap_or(0),
+                    callback,
+                ),
+                (_, None) => {
+                    return Err(mlua::Error::RuntimeError(
+                        "for_each_chunk: expected a function as the last argument".to_string(),
+                    ));
+                }
+            };
+            let chunker = RecursiveChunker::new(size, overlap)
+                .map_err(|e| mlua::Error::RuntimeError(format!("for_each_chunk: {e}")))?;
+            let text = text.to_string_lossy();
+            let chunks = chunker.chunk(&text);
+            let results = lua.create_table()?;
+            for (i, &(start, end)) in chunks.iter().enumerate() {
+                let result = callback
+                    .call::<mlua::Value>((&text[start..end], i + 1, chunks.len()))
+                    .map_err(|e| {
+                        mlua::Error::RuntimeError(format!(
+                            "for_each_chunk: fn failed on chunk {} of {} (bytes {}-{}) after \
+                             {i} chunks were done: {e}",
+                            i + 1,
+                            chunks.len(),
+                            start + 1,
+                            end
+                        ))
+                    })?;
+                if !result.is_nil() {
+                    results.raw_push(result)?;
+                }
+            }
+            Ok(results)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(strategy: Strategy, text: &str, size: usize, overlap: usize) -> Vec<String> {
+        chunk_text(
+            strategy
+                .chunker(size, overlap, Tokenizer::default())
+                .unwrap()
+                .as_ref(),
+            text,
+        )
+    }
+
+    #[test]
+    fn test_sentence_chunks() {
+        let text = "One fish. Two fish! Red fish? Blue fish.";
+        assert_eq!(
+            chunks(Strategy::Sentence, text, 20, 0),
+            ["One fish. Two fish!", "Red fish? Blue fish."]
+        );
+        // Each chunk repeats the last sentence of the previous one
+        assert_eq!(
+            chunks(Strategy::Sentence, text, 20, 10),
+            [
+                "One fish. Two fish!",
+                "Two fish! Red fish?",
+                "Red fish? Blue fish."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_paragraph_chunks() {
+        let text = "First para\nstill first.\n\nSecond.\n\n\nThird paragraph is long.";
+        assert_eq!(
+            chunks(Strategy::Paragraph, text, 40, 0),
+            [
+                "First para\nstill first.\n\nSecond.",
+                "Third paragraph is long."
+            ]
+        );
+        // An oversized paragraph is kept whole
+        assert_eq!(
+            chunks(Strategy::Paragraph, text, 10, 0),
+            [
+                "First para\nstill first.",
+                "Second.",
+                "Third paragraph is long."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_token_chunks() {
+        let text = "alpha beta gamma delta epsilon zeta";
+        let windows = chunks(Strategy::Tokens, text, 2, 1);
+        assert_eq!(windows[0], "alpha beta");
+        assert_eq!(windows[1], " beta gamma");
+        assert!(windows.last().unwrap().ends_with(" zeta"));
+
+        // Windows never split a character
+        let windows = chunks(Strategy::Tokens, "日本語のテキスト", 1, 0);
+        assert_eq!(windows.concat(), "日本語のテキスト");
+
+        // Windows count tokens of the given tokenizer
+        let text = "Привет, как дела? Всё хорошо, спасибо.";
+        let windows = |tokenizer: Tokenizer| {
+            let chunker = Strategy::Tokens.chunker(1, 0, tokenizer).unwrap();
+            chunker.chunk(text).len()
+        };
+        assert_eq!(windows(Tokenizer::O200k), Tokenizer::O200k.count(text));
+        assert!(windows(Tokenizer::O200k) < windows(Tokenizer::P50k));
+    }
+
+    #[test]
+    fn test_recursive_chunks() {
+        let text = "Intro line.\nSecond line here.\n\nA paragraph with several words that is too long for one chunk.";
+        let result = chunks(Strategy::Recursive, text, 30, 0);
+        assert!(result.iter().all(|chunk| chunk.len() <= 30), "{result:?}");
+        assert_eq!(result[0], "Intro line.\nSecond line here.");
+        let words: Vec<&str> = result.iter().flat_map(|c| c.split_whitespace()).collect();
+        assert_eq!(words, text.split_whitespace().collect::<Vec<_>>());
+
+        // Text without separators is cut at character boundaries
+        let result = chunks(Strategy::Recursive, &"é".repeat(10), 5, 0);
+        assert_eq!(result, ["éé", "éé", "éé", "éé", "éé"]);
+    }
+
+    #[test]
+    fn test_invalid_chunker() {
+        assert!("words".parse::<Strategy>().is_err());
+        let tokenizer = Tokenizer::default();
+        assert!(Strategy::Sentence.chunker(0, 0, tokenizer).is_err());
+        assert!(Strategy::Tokens.chunker(10, 10, tokenizer).is_err());
+        assert!(RecursiveChunker::new(5, 6).is_err());
+    }
+
+    #[test]
+    fn test_chunk_context_function() {
+        let lua = Lua::new();
+        lua.globals()
+            .set(
+                "chunk_context",
+                create_chunk_context_function(&lua, Tokenizer::default()).unwrap(),
+            )
+            .unwrap();
+        lua.globals().set("context", "A.\n\nB.\n\nC.").unwrap();
+        let chunks: Vec<String> = lua.load(r#"chunk_context("paragraph", 6)"#).eval().unwrap();
+        assert_eq!(chunks, ["A.\n\nB.", "C."]);
+
+        let err = lua.load(r#"chunk_context("lines", 6)"#).exec().unwrap_err();
+        assert!(err.to_string().contains("unknown chunking strategy"));
+        lua.globals().set("context", 42).unwrap();
+        assert!(lua.load(r#"chunk_context("tokens", 6)"#).exec().is_err());
+    }
+
+    #[test]
+    fn test_for_each_chunk_function() {
+        let lua = Lua::new();
+        lua.globals()
+            .set(
+                "for_each_chunk",
+                create_for_each_chunk_function(&lua).unwrap(),
+            )
+            .unwrap();
+        let results: Vec<String> = lua
+            .load(
+                r#"
+                return for_each_chunk("One two. Three four. Five six.", 10, 0, function(chunk, i, n)
+                    return i .. "/" .. n .. ":" .. chunk
+                end)
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(
+            results,
+            ["1/4:One two.", "2/4:Three", "3/4:four.", "4/4:Five six."]
+        );
+
+        // Without an overlap, and with a chunk that returns nothing
+        let (count, first): (usize, String) = lua
+            .load(
+                r#"
+                local r = for_each_chunk("a b c d", 3, function(chunk, i) if i > 1 then return chunk end end)
+                return #r, r[1]
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!((count, first.as_str()), (1, "c d"));
+
+        let err = lua
+            .load(
+                r#"for_each_chunk("a b c d", 3, 0, function(chunk, i) assert(i < 2, "boom") end)"#,
+            )
+            .exec()
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("chunk 2 of 2 (bytes 5-7) after 1 chunks"),
+            "{err}"
+        );
+        assert!(
+            lua.load(r#"for_each_chunk("abc", 3, 3, print)"#)
+                .exec()
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,205 @@
+//! Cheap structural facts about the context, computed in Rust before a run.
+//!
+//! Models usually spend their first iterations peeking at the context to find
+//! out how big it is and what it looks like. [`ContextInfo`] answers those
+//! questions up front: it is exposed to Lua as the `context_info` table and
+//! summarized in a line of the prompt.
+
+use mlua::{IntoLua, Lua, Value};
+use std::collections::HashMap;
+
+/// Number of most frequent tokens reported
+const TOP_TOKENS: usize = 10;
+
+/// Maximum number of headings reported
+const MAX_HEADINGS: usize = 50;
+
+/// Structural summary of a context
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContextInfo {
+    pub bytes: usize,
+    pub lines: usize,
+    /// Detected format: "json", "xml", "csv", "markdown", "log", or "text"
+    pub format: &'static str,
+    /// Most frequent words (at least 3 characters, lowercased) with their counts
+    pub top_tokens: Vec<(String, usize)>,
+    /// Markdown-style section headings, in order of appearance
+    pub headings: Vec<String>,
+}
+
+impl ContextInfo {
+    /// Compute the summary for a context
+    pub fn compute(text: &str) -> Self {
+        let headings = text
+            .lines()
+            .filter(|line| {
+                let hashes = line.chars().take_while(|c| *c == '#').count();
+                (1..=6).contains(&hashes) && line[hashes..].starts_with(' ')
+            })
+            .map(|line| line.trim().to_string())
+            .take(MAX_HEADINGS)
+            .collect();
+
+        Self {
+            bytes: text.len(),
+            lines: text.lines().count(),
+            format: detect_format(text),
+            top_tokens: top_tokens(text, TOP_TOKENS),
+            headings,
+        }
+    }
+
+    /// One-line summary included in the prompt
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} bytes, {} lines, detected format: {}",
+            self.bytes, self.lines, self.format
+        );
+        if !self.headings.is_empty() {
+            summary.push_str(&format!(", {} headings", self.headings.len()));
+        }
+        if !self.top_tokens.is_empty() {
+            let tokens: Vec<&str> = self.top_tokens.iter().map(|(t, _)| t.as_str()).collect();
+            summary.push_str(&format!(", frequent words: {}", tokens.join(", ")));
+        }
+        summary.push_str(". Details in the `context_info` table.");
+        summary
+    }
+}
+
+impl IntoLua for ContextInfo {
+    fn into_lua(self, lua: &Lua) -> mlua::Result<Value> {
+        let table = lua.create_table()?;
+        table.set("bytes", self.bytes)?;
+        table.set("lines", self.lines)?;
+        table.set("format", self.format)?;
+
+        let top_tokens = lua.create_table()?;
+        for (i, (token, count)) in self.top_tokens.into_iter().enumerate() {
+            let entry = lua.create_table()?;
+            entry.set("token", token)?;
+            entry.set("count", count)?;
+            top_tokens.set(i + 1, entry)?;
+        }
+        table.set("top_tokens", top_tokens)?;
+        table.set("headings", lua.create_sequence_from(self.headings)?)?;
+
+        Ok(Value::Table(table))
+    }
+}
+
+/// Guess the format from the first non-empty lines
+fn detect_format(text: &str) -> &'static str {
+    let trimmed = text.trim_start();
+    if trimmed.starts_with('{') || trimmed.starts_with('[') {
+        return "json";
+    }
+    if trimmed.starts_with('<') {
+        return "xml";
+    }
+
+    let sample: Vec<&str> = text
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .take(20)
+        .collect();
+    if sample.is_empty() {
+        return "text";
+    }
+
+    // Same non-zero number of commas (or tabs) on every sampled line
+    for delimiter in [',', '\t'] {
+        let count = sample[0].matches(delimiter).count();
+        if count > 0
+            && sample.len() > 1
+            && sample.iter().all(|l| l.matches(delimiter).count() == count)
+        {
+            return "csv";
+        }
+    }
+
+    if sample
+        .iter()
+        .any(|l| l.starts_with("# ") || l.starts_with("## "))
+    {
+        return "markdown";
+    }
+
+    // Most lines start with a date or time
+    let timestamped = sample
+        .iter()
+        .filter(|l| {
+            let prefix: String = l.trim_start_matches('[').chars().take(5).collect();
+            prefix.len() == 5
+                && prefix.chars().take(2).all(|c| c.is_ascii_digit())
+                && prefix.contains(['-', ':', '/'])
+        })
+        .count();
+    if timestamped * 2 > sample.len() {
+        return "log";
+    }
+
+    "text"
+}
+
+/// Most frequent words of at least 3 characters, ties broken alphabetically
+fn top_tokens(text: &str, n: usize) -> Vec<(String, usize)> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for word in text.split(|c: char| !c.is_alphanumeric() && c != '_') {
+        if word.chars().count() >= 3 && !word.chars().all(|c| c.is_ascii_digit()) {
+            *counts.entry(word.to_lowercase()).or_default() += 1;
+        }
+    }
+
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(n);
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(detect_format("  {\"a\": 1}"), "json");
+        assert_eq!(detect_format("<root/>"), "xml");
+        assert_eq!(detect_format("a,b,c\n1,2,3\n4,5,6\n"), "csv");
+        assert_eq!(detect_format("# Title\n\nSome text\n## Part"), "markdown");
+        assert_eq!(
+            detect_format("2024-01-01 INFO start\n2024-01-01 ERROR boom\n"),
+            "log"
+        );
+        assert_eq!(detect_format("Just some prose, nothing else."), "text");
+    }
+
+    #[test]
+    fn test_compute() {
+        let text = "# Intro\nerror here\n## Details\nerror again, Error!\n";
+        let info = ContextInfo::compute(text);
+        assert_eq!(info.lines, 4);
+        assert_eq!(info.bytes, text.len());
+        assert_eq!(info.format, "markdown");
+        assert_eq!(info.headings, vec!["# Intro", "## Details"]);
+        assert_eq!(info.top_tokens[0], ("error".to_string(), 3));
+        assert!(
+            info.summary()
+                .contains("4 lines, detected format: markdown")
+        );
+    }
+
+    #[test]
+    fn test_into_lua() {
+        let lua = Lua::new();
+        let info = ContextInfo::compute("# A\nword word\n");
+        lua.globals().set("context_info", info).unwrap();
+        let (lines, token, heading): (usize, String, String) = lua
+            .load("return context_info.lines, context_info.top_tokens[1].token, context_info.headings[1]")
+            .eval()
+            .unwrap();
+        assert_eq!(lines, 2);
+        assert_eq!(token, "word");
+        assert_eq!(heading, "# A");
+    }
+}
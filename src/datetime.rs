@@ -0,0 +1,256 @@
+//! Date and time helpers for Lua code, which has no `os` library in the
+//! sandbox.
+//!
+//! Times are Unix timestamps in seconds (UTC), so they compare and subtract
+//! as plain numbers. `parse_datetime(s, fmt)` reads a timestamp from text in
+//! a strftime format or one of the common formats of logs and emails,
+//! `format_datetime(t, fmt)` writes one back, `date_diff(a, b, unit)`
+//! subtracts two times, and `now()` returns the current time, which the
+//! environment can freeze for reproducible runs.
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+use mlua::Lua;
+use std::time::SystemTime;
+
+/// Formats tried in order when `parse_datetime` is given no format, after
+/// RFC 3339 and RFC 2822
+const DEFAULT_FORMATS: &[&str] = &[
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M",
+    "%Y/%m/%d %H:%M:%S",
+    "%d/%b/%Y:%H:%M:%S %z",
+    "%a %b %e %H:%M:%S %Y",
+    "%Y-%m-%d",
+    "%Y/%m/%d",
+    "%d %B %Y",
+    "%B %d, %Y",
+];
+
+/// Parse a time in `format` (strftime syntax). Times without a zone are
+/// taken as UTC, and dates without a time as midnight.
+pub fn parse_with_format(text: &str, format: &str) -> Option<DateTime<Utc>> {
+    let text = text.trim();
+    if let Ok(time) = DateTime::parse_from_str(text, format) {
+        return Some(time.with_timezone(&Utc));
+    }
+    if let Ok(time) = NaiveDateTime::parse_from_str(text, format) {
+        return Some(time.and_utc());
+    }
+    NaiveDate::parse_from_str(text, format)
+        .ok()
+        .map(|date| date.and_time(Default::default()).and_utc())
+}
+
+/// Parse a time in RFC 3339, RFC 2822, or one of [`DEFAULT_FORMATS`]
+pub fn parse(text: &str) -> Option<DateTime<Utc>> {
+    let text = text.trim();
+    DateTime::parse_from_rfc3339(text)
+        .or_else(|_| DateTime::parse_from_rfc2822(text))
+        .map(|time| time.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            DEFAULT_FORMATS
+                .iter()
+                .find_map(|format| parse_with_format(text, format))
+        })
+}
+
+/// A time as a Lua timestamp: an integer unless it has fractional seconds
+fn to_lua(time: DateTime<Utc>) -> mlua::Value {
+    match time.timestamp_subsec_millis() {
+        0 => mlua::Value::Integer(time.timestamp()),
+        millis => mlua::Value::Number(time.timestamp() as f64 + f64::from(millis) / 1000.0),
+    }
+}
+
+/// A time passed from Lua, as a timestamp or as text in a default format
+fn from_lua(function: &str, value: &mlua::Value) -> mlua::Result<DateTime<Utc>> {
+    let time = match value {
+        mlua::Value::Integer(seconds) => DateTime::from_timestamp(*seconds, 0),
+        mlua::Value::Number(seconds) => DateTime::from_timestamp_millis((seconds * 1000.0) as i64),
+        mlua::Value::String(text) => parse(&text.to_string_lossy()),
+        other => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "{function}: expected a timestamp or a date string, got a {}",
+                other.type_name()
+            )));
+        }
+    };
+    time.ok_or_else(|| {
+        mlua::Error::RuntimeError(format!(
+            "{function}: cannot read {} as a time",
+            value.to_string().unwrap_or_default()
+        ))
+    })
+}
+
+/// Creates the `parse_datetime(s, fmt)` Lua function, which returns the time
+/// in `s` as a Unix timestamp, or nil and an error message if it can't be
+/// parsed. `fmt` is a strftime format such as `"%d/%m/%Y %H:%M"`; without
+/// it, RFC 3339, RFC 2822 (email `Date:` headers), and common log formats
+/// are tried (see [`parse`]).
+pub fn create_parse_datetime_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(|_, (text, format): (String, Option<String>)| {
+        let time = match &format {
+            Some(format) => parse_with_format(&text, format),
+            None => parse(&text),
+        };
+        Ok(match time {
+            Some(time) => (to_lua(time), None),
+            None => (
+                mlua::Value::Nil,
+                Some(match format {
+                    Some(format) => format!("cannot parse {text:?} with format {format:?}"),
+                    None => format!("cannot parse {text:?} as a date"),
+                }),
+            ),
+        })
+    })
+}
+
+/// Creates the `format_datetime(t, fmt)` Lua function, which writes a time
+/// (timestamp or date string) in the strftime format `fmt`, in UTC, or as
+/// RFC 3339 without a format.
+pub fn create_format_datetime_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(|_, (time, format): (mlua::Value, Option<String>)| {
+        let time = from_lua("format_datetime", &time)?;
+        let Some(format) = format else {
+            return Ok(time.to_rfc3339_opts(SecondsFormat::AutoSi, true));
+        };
+        // Writing an invalid format fails, which would panic in to_string
+        let mut text = String::new();
+        std::fmt::write(&mut text, format_args!("{}", time.format(&format))).map_err(|_| {
+            mlua::Error::RuntimeError(format!("format_datetime: invalid format {format:?}"))
+        })?;
+        Ok(text)
+    })
+}
+
+/// Creates the `date_diff(a, b, unit)` Lua function, which returns `a - b`
+/// in `unit`: "seconds" (the default), "minutes", "hours", or "days". Times
+/// are timestamps or date strings in a default format.
+pub fn create_date_diff_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(
+        |_, (a, b, unit): (mlua::Value, mlua::Value, Option<String>)| {
+            let a = from_lua("date_diff", &a)?;
+            let b = from_lua("date_diff", &b)?;
+            let seconds = (a - b).num_milliseconds() as f64 / 1000.0;
+            let per_unit = match unit.as_deref().unwrap_or("seconds") {
+                "seconds" | "second" | "s" => 1.0,
+                "minutes" | "minute" | "m" => 60.0,
+                "hours" | "hour" | "h" => 3600.0,
+                "days" | "day" | "d" => 86400.0,
+                other => {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "date_diff: unknown unit {other:?}, expected seconds, minutes, hours, or days"
+                    )));
+                }
+            };
+            Ok(seconds / per_unit)
+        },
+    )
+}
+
+/// Creates the `now()` Lua function, which returns the current Unix
+/// timestamp, or `frozen` if given so that runs are reproducible
+pub fn create_now_function(
+    lua: &Lua,
+    frozen: Option<DateTime<Utc>>,
+) -> mlua::Result<mlua::Function> {
+    lua.create_function(move |_, ()| {
+        Ok(to_lua(frozen.unwrap_or_else(|| {
+            DateTime::<Utc>::from(SystemTime::now())
+        })))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lua(frozen: Option<DateTime<Utc>>) -> Lua {
+        let lua = Lua::new();
+        let globals = lua.globals();
+        globals
+            .set(
+                "parse_datetime",
+                create_parse_datetime_function(&lua).unwrap(),
+            )
+            .unwrap();
+        globals
+            .set(
+                "format_datetime",
+                create_format_datetime_function(&lua).unwrap(),
+            )
+            .unwrap();
+        globals
+            .set("date_diff", create_date_diff_function(&lua).unwrap())
+            .unwrap();
+        globals
+            .set("now", create_now_function(&lua, frozen).unwrap())
+            .unwrap();
+        lua
+    }
+
+    #[test]
+    fn test_parse() {
+        let expected = DateTime::from_timestamp(1_700_000_000, 0);
+        assert_eq!(parse("2023-11-14T22:13:20Z"), expected);
+        assert_eq!(parse("Tue, 14 Nov 2023 23:13:20 +0100"), expected);
+        assert_eq!(parse("2023-11-14 22:13:20"), expected);
+        assert_eq!(parse("14/Nov/2023:22:13:20 +0000"), expected);
+        assert_eq!(
+            parse("2023-11-14"),
+            DateTime::from_timestamp(1_699_920_000, 0)
+        );
+        assert_eq!(parse("yesterday"), None);
+        assert_eq!(
+            parse_with_format("14.11.2023 22:13", "%d.%m.%Y %H:%M"),
+            DateTime::from_timestamp(1_700_000_000 - 20, 0)
+        );
+    }
+
+    #[test]
+    fn test_lua_functions() {
+        let lua = lua(DateTime::from_timestamp(1_700_000_000, 0));
+        let (t, err): (i64, mlua::Value) = lua
+            .load(r#"return parse_datetime("2023-11-14 22:13:20")"#)
+            .eval()
+            .unwrap();
+        assert_eq!(t, 1_700_000_000);
+        assert!(err.is_nil());
+        let (t, err): (mlua::Value, String) = lua
+            .load(r#"return parse_datetime("14 Nov", "%Y-%m-%d")"#)
+            .eval()
+            .unwrap();
+        assert!(t.is_nil());
+        assert!(err.contains("cannot parse"), "{err}");
+
+        let days: f64 = lua
+            .load(r#"return date_diff("2024-03-01", "2024-02-01", "days")"#)
+            .eval()
+            .unwrap();
+        assert_eq!(days, 29.0);
+        let hours: f64 = lua
+            .load(r#"return date_diff(now(), "2023-11-14T20:13:20Z", "hours")"#)
+            .eval()
+            .unwrap();
+        assert_eq!(hours, 2.0);
+
+        let text: String = lua
+            .load(r#"return format_datetime(now(), "%Y-%m-%d %H:%M")"#)
+            .eval()
+            .unwrap();
+        assert_eq!(text, "2023-11-14 22:13");
+        let text: String = lua.load("return format_datetime(1.5)").eval().unwrap();
+        assert_eq!(text, "1970-01-01T00:00:01.500Z");
+        assert!(
+            lua.load(r#"format_datetime(0, "%Q")"#)
+                .exec()
+                .unwrap_err()
+                .to_string()
+                .contains("invalid format")
+        );
+    }
+}
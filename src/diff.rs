@@ -0,0 +1,204 @@
+//! Comparing strings from Lua code.
+//!
+//! `diff(a, b, by)` shows what changed between two passages, as a unified
+//! diff of lines or inline with words marked, and `diff_stats(a, b, by)`
+//! counts the changes. Comparing the input files themselves is done when
+//! loading them (see [`crate::inputs::Input::diff`]).
+
+use mlua::Lua;
+use similar::{ChangeTag, TextDiff};
+use std::str::FromStr;
+
+/// Lines of unchanged text around each hunk of a line diff
+const CONTEXT_LINES: usize = 3;
+
+/// What a diff compares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Granularity {
+    Lines,
+    Words,
+}
+
+impl FromStr for Granularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "line" | "lines" => Ok(Self::Lines),
+            "word" | "words" => Ok(Self::Words),
+            _ => Err(format!("unknown diff unit '{s}' (expected lines or words)")),
+        }
+    }
+}
+
+fn text_diff<'a>(a: &'a str, b: &'a str, by: Granularity) -> TextDiff<'a, 'a, 'a, str> {
+    match by {
+        Granularity::Lines => TextDiff::from_lines(a, b),
+        Granularity::Words => TextDiff::from_words(a, b),
+    }
+}
+
+/// Differences between `a` and `b`, empty if they are equal.
+///
+/// By lines, this is a unified diff with `---`/`+++` headers naming the
+/// texts `a` and `b`. By words, it is `b` with removed words marked
+/// `[-like this-]` and added words `{+like this+}`, as `git diff
+/// --word-diff` shows them.
+pub fn diff(a: &str, b: &str, by: Granularity) -> String {
+    if a == b {
+        return String::new();
+    }
+    let diff = text_diff(a, b, by);
+    if by == Granularity::Lines {
+        return diff
+            .unified_diff()
+            .context_radius(CONTEXT_LINES)
+            .header("a", "b")
+            .to_string();
+    }
+    let mut out = String::new();
+    let mut run: Option<ChangeTag> = None;
+    for change in diff.iter_all_changes() {
+        if run != Some(change.tag()) {
+            close_run(&mut out, run);
+            match change.tag() {
+                ChangeTag::Delete => out.push_str("[-"),
+                ChangeTag::Insert => out.push_str("{+"),
+                ChangeTag::Equal => {}
+            }
+            run = Some(change.tag());
+        }
+        out.push_str(change.value());
+    }
+    close_run(&mut out, run);
+    out
+}
+
+/// End a run of removed or added words, keeping trailing whitespace
+/// outside the marker
+fn close_run(out: &mut String, run: Option<ChangeTag>) {
+    let marker = match run {
+        Some(ChangeTag::Delete) => "-]",
+        Some(ChangeTag::Insert) => "+}",
+        _ => return,
+    };
+    let trimmed = out.trim_end().len();
+    let whitespace = out.split_off(trimmed);
+    out.push_str(marker);
+    out.push_str(&whitespace);
+}
+
+/// Counts of the lines or words of a diff
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiffStats {
+    pub added: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+    /// Similarity of the texts from 0 to 1
+    pub ratio: f32,
+}
+
+pub fn diff_stats(a: &str, b: &str, by: Granularity) -> DiffStats {
+    let diff = text_diff(a, b, by);
+    let mut stats = DiffStats {
+        added: 0,
+        removed: 0,
+        unchanged: 0,
+        ratio: diff.ratio(),
+    };
+    // Whitespace between words is not counted
+    for change in diff
+        .iter_all_changes()
+        .filter(|change| by == Granularity::Lines || !change.value().trim().is_empty())
+    {
+        match change.tag() {
+            ChangeTag::Insert => stats.added += 1,
+            ChangeTag::Delete => stats.removed += 1,
+            ChangeTag::Equal => stats.unchanged += 1,
+        }
+    }
+    stats
+}
+
+fn granularity(by: Option<String>) -> mlua::Result<Granularity> {
+    by.map_or(Ok(Granularity::Lines), |by| {
+        by.parse().map_err(mlua::Error::RuntimeError)
+    })
+}
+
+/// Creates the `diff(a, b, by)` Lua function, which returns the differences
+/// between two strings (see [`diff`]), by "lines" (the default) or "words",
+/// or an empty string if they are equal.
+pub fn create_diff_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(|_, (a, b, by): (String, String, Option<String>)| {
+        Ok(diff(&a, &b, granularity(by)?))
+    })
+}
+
+/// Creates the `diff_stats(a, b, by)` Lua function, which returns a table
+/// with the number of lines or words `added`, `removed`, and `unchanged`
+/// from `a` to `b`, and their similarity `ratio` from 0 to 1.
+pub fn create_diff_stats_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(|lua, (a, b, by): (String, String, Option<String>)| {
+        let stats = diff_stats(&a, &b, granularity(by)?);
+        let result = lua.create_table()?;
+        result.set("added", stats.added)?;
+        result.set("removed", stats.removed)?;
+        result.set("unchanged", stats.unchanged)?;
+        result.set("ratio", stats.ratio)?;
+        Ok(result)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let a = "one\ntwo\nthree\n";
+        let b = "one\n2\nthree\nfour\n";
+        assert_eq!(
+            diff(a, b, Granularity::Lines),
+            "--- a\n+++ b\n@@ -1,3 +1,4 @@\n one\n-two\n+2\n three\n+four\n"
+        );
+        assert_eq!(diff(a, a, Granularity::Lines), "");
+        assert_eq!(
+            diff(
+                "The fee is 5% per year.",
+                "The fee is 7% per month.",
+                Granularity::Words
+            ),
+            "The fee is [-5%-]{+7%+} per [-year.-]{+month.+}"
+        );
+    }
+
+    #[test]
+    fn test_lua_functions() {
+        let lua = Lua::new();
+        lua.globals()
+            .set("diff", create_diff_function(&lua).unwrap())
+            .unwrap();
+        lua.globals()
+            .set("diff_stats", create_diff_stats_function(&lua).unwrap())
+            .unwrap();
+        let (added, removed, unchanged, ratio): (usize, usize, usize, f64) = lua
+            .load(
+                r#"
+                local s = diff_stats("a b c d", "a x c d e", "words")
+                return s.added, s.removed, s.unchanged, s.ratio
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!((added, removed, unchanged), (2, 1, 3));
+        assert!(ratio > 0.5 && ratio < 1.0, "{ratio}");
+        let lines: String = lua
+            .load(r#"return diff("x\ny\n", "x\nz\n")"#)
+            .eval()
+            .unwrap();
+        assert!(lines.contains("-y\n+z\n"), "{lines}");
+        let err = lua.load(r#"diff("a", "b", "chars")"#).exec().unwrap_err();
+        assert!(err.to_string().contains("unknown diff unit"), "{err}");
+    }
+}
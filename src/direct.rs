@@ -0,0 +1,136 @@
+//! Direct answer mode: answer small contexts with a single completion instead of
+//! running the full REPL loop.
+
+use crate::rlm::RigProvider;
+use crate::tokens::Tokenizer;
+use crate::usage::UsageCategory;
+use std::error::Error;
+
+const DIRECT_SYSTEM_PROMPT: &str = r#"You are answering a query using the provided context. Read the context carefully and answer the query as accurately as you can.
+
+Format your response EXACTLY as follows using XML tags:
+
+<answer>
+Your answer to the query
+</answer>
+
+<confidence>
+Either "high", "medium", or "low"
+</confidence>
+
+Use "low" if the context is insufficient, the task requires exhaustive counting or computation over the context, or you are unsure of your answer.
+"#;
+
+/// How confident the model is in its direct answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confidence {
+    High,
+    Medium,
+    Low,
+}
+
+/// Answer produced by a single completion over the full context
+#[derive(Debug, Clone)]
+pub struct DirectAnswer {
+    pub answer: String,
+    pub confidence: Confidence,
+}
+
+/// Check whether the prompt and context fit within `max_tokens` tokens of
+/// `tokenizer`
+pub fn fits_directly(prompt: &str, context: &str, max_tokens: usize, tokenizer: Tokenizer) -> bool {
+    tokenizer.count(prompt) + tokenizer.count(context) <= max_tokens
+}
+
+/// Ask the model to answer the prompt directly from the context.
+///
+/// Usage is recorded under [`UsageCategory::DirectAnswer`].
+pub async fn direct_answer(
+    provider: &RigProvider,
+    prompt: &str,
+    context: &str,
+) -> Result<DirectAnswer, Box<dyn Error>> {
+    let user_prompt = format!("Context:\n{context}\n\nQuery:\n{prompt}");
+    let response = provider
+        .complete(
+            Some(DIRECT_SYSTEM_PROMPT),
+            &user_prompt,
+            UsageCategory::DirectAnswer,
+        )
+        .await?;
+
+    parse_direct_answer(&response)
+}
+
+/// Parse `<answer>` and `<confidence>` tags. A missing confidence is treated as low.
+fn parse_direct_answer(text: &str) -> Result<DirectAnswer, Box<dyn Error>> {
+    let text = crate::parse::strip_reasoning(text);
+
+    let answer = extract(&text, "answer")
+        .filter(|a| !a.is_empty())
+        .ok_or("Failed to parse <answer> tag from response")?;
+
+    let confidence = match extract(&text, "confidence")
+        .map(|c| c.to_lowercase())
+        .as_deref()
+    {
+        Some("high") => Confidence::High,
+        Some("medium") => Confidence::Medium,
+        _ => Confidence::Low,
+    };
+
+    Ok(DirectAnswer { answer, confidence })
+}
+
+fn extract(text: &str, tag: &str) -> Option<String> {
+    let open = format!("<{tag}>");
+    let close = format!("</{tag}>");
+    let start = text.find(&open)? + open.len();
+    let end = start + text[start..].find(&close)?;
+    Some(text[start..end].trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fits_directly() {
+        let tokenizer = Tokenizer::default();
+        assert!(fits_directly(
+            "What is this?",
+            "A short document.",
+            100,
+            tokenizer
+        ));
+        assert!(!fits_directly(
+            "What is this?",
+            &"word ".repeat(1000),
+            100,
+            tokenizer
+        ));
+
+        // The same text is fewer tokens in newer encodings
+        let context = "Привет, как дела? Всё хорошо, спасибо.";
+        let tokens = Tokenizer::O200k.count(context) + 1;
+        assert!(fits_directly("", context, tokens, Tokenizer::O200k));
+        assert!(!fits_directly("", context, tokens, Tokenizer::P50k));
+    }
+
+    #[test]
+    fn test_parse_direct_answer() {
+        let parsed = parse_direct_answer(
+            "<think>hmm</think><answer>\nThe answer is 42\n</answer>\n<confidence>High</confidence>",
+        )
+        .unwrap();
+        assert_eq!(parsed.answer, "The answer is 42");
+        assert_eq!(parsed.confidence, Confidence::High);
+    }
+
+    #[test]
+    fn test_parse_direct_answer_missing_confidence() {
+        let parsed = parse_direct_answer("<answer>maybe</answer>").unwrap();
+        assert_eq!(parsed.confidence, Confidence::Low);
+        assert!(parse_direct_answer("no tags").is_err());
+    }
+}
@@ -0,0 +1,168 @@
+//! Named documents for multi-file workloads.
+//!
+//! A single `context` string forces the model to split concatenated files
+//! apart again by their headers. Documents are kept by name instead:
+//! `list_documents()` names them in the order they were added,
+//! `get_document(name)` returns one, and `add_document(name, text)` adds a
+//! derived one, such as a cleaned-up copy or an intermediate summary. An
+//! environment starts with the documents of
+//! [`crate::environment::EnvironmentConfig::with_document`]; `context` is
+//! still set, to their concatenation when created with
+//! [`crate::environment::Environment::with_documents`].
+
+use mlua::Lua;
+
+/// Concatenate documents, each preceded by a `=== name ===` header line as
+/// in [`crate::inputs::Input::concat`]
+pub fn concat(documents: &[(String, String)]) -> String {
+    let mut content = String::new();
+    for (name, text) in documents {
+        content.push_str(&format!("=== {name} ===\n"));
+        content.push_str(text);
+        if !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push('\n');
+    }
+    content
+}
+
+/// The documents of an environment, kept in Lua so `get_document` returns
+/// them without copying
+#[derive(Debug, Clone)]
+pub struct Documents {
+    /// Texts keyed by name
+    texts: mlua::Table,
+    /// Names in the order they were first added
+    names: mlua::Table,
+}
+
+impl Documents {
+    pub fn new(lua: &Lua) -> mlua::Result<Self> {
+        Ok(Self {
+            texts: lua.create_table()?,
+            names: lua.create_table()?,
+        })
+    }
+
+    /// Add a document, replacing the text of one with the same name
+    pub fn add(&self, name: &str, text: mlua::String) -> mlua::Result<()> {
+        if !self.texts.contains_key(name)? {
+            self.names.raw_push(name)?;
+        }
+        self.texts.raw_set(name, text)
+    }
+}
+
+/// Creates the `add_document(name, text)` Lua function, which adds a
+/// document or replaces the text of the document with that name
+pub fn create_add_document_function(
+    lua: &Lua,
+    documents: Documents,
+) -> mlua::Result<mlua::Function> {
+    lua.create_function(move |_, (name, text): (String, mlua::String)| documents.add(&name, text))
+}
+
+/// Creates the `get_document(name)` Lua function, which returns the text of
+/// a document, or nil and an error message listing the known names if
+/// there is none by that name
+pub fn create_get_document_function(
+    lua: &Lua,
+    documents: Documents,
+) -> mlua::Result<mlua::Function> {
+    lua.create_function(move |_, name: String| {
+        let text: Option<mlua::String> = documents.texts.raw_get(name.as_str())?;
+        if text.is_some() {
+            return Ok((text, None));
+        }
+        let names: Vec<String> = documents
+            .names
+            .sequence_values::<String>()
+            .collect::<mlua::Result<_>>()?;
+        let message = if names.is_empty() {
+            format!("no document named {name:?}; there are no documents")
+        } else {
+            format!(
+                "no document named {name:?}; documents are: {}",
+                names.join(", ")
+            )
+        };
+        Ok((None, Some(message)))
+    })
+}
+
+/// Creates the `list_documents()` Lua function, which returns the names of
+/// the documents in the order they were added
+pub fn create_list_documents_function(
+    lua: &Lua,
+    documents: Documents,
+) -> mlua::Result<mlua::Function> {
+    lua.create_function(move |lua, ()| {
+        lua.create_sequence_from(documents.names.sequence_values::<mlua::String>().flatten())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concat() {
+        let documents = [
+            ("a.txt".to_string(), "alpha".to_string()),
+            ("b.txt".to_string(), "beta\n".to_string()),
+        ];
+        assert_eq!(
+            concat(&documents),
+            "=== a.txt ===\nalpha\n\n=== b.txt ===\nbeta\n\n"
+        );
+    }
+
+    #[test]
+    fn test_document_functions() {
+        let lua = Lua::new();
+        let documents = Documents::new(&lua).unwrap();
+        documents
+            .add("notes.md", lua.create_string("# Notes").unwrap())
+            .unwrap();
+        let globals = lua.globals();
+        globals
+            .set(
+                "add_document",
+                create_add_document_function(&lua, documents.clone()).unwrap(),
+            )
+            .unwrap();
+        globals
+            .set(
+                "get_document",
+                create_get_document_function(&lua, documents.clone()).unwrap(),
+            )
+            .unwrap();
+        globals
+            .set(
+                "list_documents",
+                create_list_documents_function(&lua, documents).unwrap(),
+            )
+            .unwrap();
+
+        let (names, text): (String, String) = lua
+            .load(
+                r#"
+                add_document("summary", "draft")
+                add_document("notes.md", "Notes, revised")
+                return table.concat(list_documents(), ","), get_document("notes.md")
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(names, "notes.md,summary");
+        assert_eq!(text, "Notes, revised");
+
+        let (text, err): (mlua::Value, String) = lua
+            .load(r#"return get_document("missing")"#)
+            .eval()
+            .unwrap();
+        assert!(text.is_nil());
+        assert!(err.ends_with("documents are: notes.md, summary"), "{err}");
+    }
+}
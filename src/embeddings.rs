@@ -0,0 +1,419 @@
+//! Text embeddings for semantic search from Lua.
+//!
+//! An [`Embedder`] sends texts to the embedding endpoint of the run's
+//! provider: Ollama's `/api/embed`, or OpenRouter's OpenAI-compatible
+//! `/embeddings`. In Lua, `embed(text)` returns a vector as a list of numbers
+//! and `cosine_sim(a, b)` compares two of them.
+//!
+//! `index_context(chunk_size)` embeds the chunks of the context into an
+//! in-memory [`VectorIndex`] that `semantic_search(query, k)` then queries,
+//! so a run can retrieve relevant passages without external infrastructure.
+
+use crate::chunking::{Chunker, RecursiveChunker};
+use crate::environment::{LlmClient, LlmQueryError, QueryLimiter, block_on_until};
+use crate::tokens::Tokenizer;
+use crate::usage::{TokenUsage, UsageCategory, UsageTracker};
+use mlua::{Lua, Result};
+use rig::client::EmbeddingsClient;
+use rig::embeddings::{EmbeddingError, EmbeddingModel};
+use rig::providers::{ollama, openai};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Embedding model used with Ollama unless configured otherwise
+pub const DEFAULT_OLLAMA_EMBEDDING_MODEL: &str = "nomic-embed-text";
+
+/// Embedding model used with OpenRouter unless configured otherwise
+pub const DEFAULT_OPENROUTER_EMBEDDING_MODEL: &str = "openai/text-embedding-3-small";
+
+const OPENROUTER_API_BASE_URL: &str = "https://openrouter.ai/api/v1";
+
+/// Texts sent in a single embedding request
+const BATCH_SIZE: usize = 64;
+
+/// Most chunks `index_context` embeds at once
+const MAX_INDEX_CHUNKS: usize = 10_000;
+
+/// Embeds texts with the provider of an [`LlmClient`]
+#[derive(Clone)]
+pub struct Embedder {
+    client: LlmClient,
+    model: String,
+    usage: UsageTracker,
+    tokenizer: Tokenizer,
+    limiter: Option<QueryLimiter>,
+}
+
+impl Embedder {
+    /// Use `model`, or the provider's default embedding model if None
+    pub fn new(client: LlmClient, model: Option<String>) -> Self {
+        let model = model.unwrap_or_else(|| {
+            match client {
+                LlmClient::Ollama(_) => DEFAULT_OLLAMA_EMBEDDING_MODEL,
+                LlmClient::Openrouter(..) => DEFAULT_OPENROUTER_EMBEDDING_MODEL,
+            }
+            .to_string()
+        });
+        Self {
+            client,
+            model,
+            usage: UsageTracker::default(),
+            tokenizer: Tokenizer::default(),
+            limiter: None,
+        }
+    }
+
+    /// Record requests under [`UsageCategory::Embedding`] in `usage`, with
+    /// input tokens counted by `tokenizer`
+    pub fn with_usage(mut self, usage: UsageTracker, tokenizer: Tokenizer) -> Self {
+        self.usage = usage;
+        self.tokenizer = tokenizer;
+        self
+    }
+
+    /// Count every request as an `llm_query` call
+    pub(crate) fn with_limiter(mut self, limiter: QueryLimiter) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// One vector per text, in order, embedded in requests of up to 64 texts.
+    ///
+    /// Fails without sending anything if the requests would exceed the
+    /// `llm_query` limits.
+    pub async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f64>>> {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire_many(texts.len().div_ceil(BATCH_SIZE))?;
+        }
+        match &self.client {
+            LlmClient::Ollama(_) => {
+                let client = ollama::Client::new();
+                self.embed_batches(&client.embedding_model(&self.model), &texts)
+                    .await
+            }
+            LlmClient::Openrouter(_, api_key) => {
+                let client = openai::Client::builder(api_key)
+                    .base_url(OPENROUTER_API_BASE_URL)
+                    .build();
+                self.embed_batches(&client.embedding_model(&self.model), &texts)
+                    .await
+            }
+        }
+        .map_err(|e| {
+            mlua::Error::external(LlmQueryError(format!(
+                "Embedding with {} failed: {e}",
+                self.model
+            )))
+        })
+    }
+
+    async fn embed_batches(
+        &self,
+        model: &impl EmbeddingModel,
+        texts: &[String],
+    ) -> std::result::Result<Vec<Vec<f64>>, EmbeddingError> {
+        let mut vectors = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(BATCH_SIZE) {
+            let embeddings = model.embed_texts(batch.to_vec()).await?;
+            let input_tokens = batch
+                .iter()
+                .map(|text| self.tokenizer.count(text))
+                .sum::<usize>();
+            self.usage.record(
+                UsageCategory::Embedding,
+                TokenUsage {
+                    calls: 1,
+                    input_tokens: input_tokens as u64,
+                    output_tokens: 0,
+                },
+            );
+            vectors.extend(embeddings.into_iter().map(|embedding| embedding.vec));
+        }
+        Ok(vectors)
+    }
+}
+
+/// Cosine similarity of two vectors, or 0 if either is all zeros
+pub fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    let dot: f64 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+/// A chunk of the context and its embedding
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexedChunk {
+    pub text: String,
+    /// Byte offset of the chunk in the context
+    pub offset: usize,
+    pub vector: Vec<f64>,
+}
+
+/// Embedded chunks of the context, searched by cosine similarity
+#[derive(Debug, Clone, Default)]
+pub struct VectorIndex {
+    pub chunks: Vec<IndexedChunk>,
+}
+
+impl VectorIndex {
+    /// The `k` chunks most similar to `query`, most similar first, with
+    /// their positions in the index and similarities
+    pub fn search(&self, query: &[f64], k: usize) -> Vec<(usize, f64)> {
+        let mut scored: Vec<(usize, f64)> = self
+            .chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| (i, cosine_similarity(query, &chunk.vector)))
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.truncate(k);
+        scored
+    }
+}
+
+/// Index shared by `index_context` and `semantic_search`
+pub type SharedIndex = Arc<Mutex<Option<VectorIndex>>>;
+
+/// Creates the `embed(text)` Lua function.
+///
+/// Returns the embedding of `text` as a list of numbers. Given a list of
+/// strings instead, returns a list of embeddings, embedded in batches.
+/// Fails like `llm_query` if the provider cannot embed, or with
+/// [`crate::environment::CellTimeout`] when the cell's deadline passes.
+pub fn create_embed_function(
+    lua: &Lua,
+    embedder: Embedder,
+    deadline: Arc<Mutex<Option<Instant>>>,
+    timeout: Option<Duration>,
+) -> Result<mlua::Function> {
+    lua.create_function(move |lua, input: mlua::Value| {
+        let limit = deadline.lock().unwrap().zip(timeout);
+        match input {
+            mlua::Value::Table(table) => {
+                let texts: Vec<String> = lua.unpack(mlua::Value::Table(table))?;
+                if texts.is_empty() {
+                    return lua.pack(Vec::<Vec<f64>>::new());
+                }
+                lua.pack(block_on_until(limit, embedder.embed(texts))?)
+            }
+            text => {
+                let text: String = lua.unpack(text)?;
+                let mut vectors = block_on_until(limit, embedder.embed(vec![text]))?;
+                lua.pack(vectors.pop().unwrap_or_default())
+            }
+        }
+    })
+}
+
+/// Creates the `index_context(chunk_size, overlap)` Lua function.
+///
+/// Splits the `context` global with the "recursive" strategy of
+/// `chunk_context` into chunks of at most `chunk_size` bytes (overlapping by
+/// `overlap` bytes, 0 by default), embeds them, and replaces the index
+/// searched by `semantic_search`. Returns the number of chunks. Fails
+/// without embedding anything if there are more than 10,000 chunks, or if
+/// their requests would exceed the `llm_query` limits.
+pub fn create_index_context_function(
+    lua: &Lua,
+    embedder: Embedder,
+    index: SharedIndex,
+    deadline: Arc<Mutex<Option<Instant>>>,
+    timeout: Option<Duration>,
+) -> Result<mlua::Function> {
+    lua.create_function(move |lua, (chunk_size, overlap): (usize, Option<usize>)| {
+        let context: mlua::Value = lua.globals().get("context")?;
+        let Some(context) = context.as_string().map(|s| s.to_string_lossy()) else {
+            return Err(mlua::Error::RuntimeError(
+                "index_context needs `context` to be a string".to_string(),
+            ));
+        };
+        let chunker = RecursiveChunker::new(chunk_size, overlap.unwrap_or(0))
+            .map_err(mlua::Error::RuntimeError)?;
+        let ranges = chunker.chunk(&context);
+        if ranges.len() > MAX_INDEX_CHUNKS {
+            return Err(mlua::Error::RuntimeError(format!(
+                "index_context: {} chunks is more than the {MAX_INDEX_CHUNKS} that can be indexed, use a larger chunk_size",
+                ranges.len()
+            )));
+        }
+        let texts: Vec<String> = ranges
+            .iter()
+            .map(|&(start, end)| context[start..end].to_string())
+            .collect();
+
+        let limit = deadline.lock().unwrap().zip(timeout);
+        let vectors = block_on_until(limit, embedder.embed(texts.clone()))?;
+        let chunks: Vec<IndexedChunk> = texts
+            .into_iter()
+            .zip(ranges)
+            .zip(vectors)
+            .map(|((text, (offset, _)), vector)| IndexedChunk {
+                text,
+                offset,
+                vector,
+            })
+            .collect();
+        let count = chunks.len();
+        *index.lock().unwrap() = Some(VectorIndex { chunks });
+        Ok(count)
+    })
+}
+
+/// Creates the `semantic_search(query, k)` Lua function.
+///
+/// Embeds `query` and returns the `k` (5 by default) indexed chunks most
+/// similar to it, most similar first, as tables with `text`, `score` (cosine
+/// similarity), `index` (1-based chunk number), and `offset` (1-based
+/// position in the context, for `string.sub`). Fails if `index_context` has
+/// not been called.
+pub fn create_semantic_search_function(
+    lua: &Lua,
+    embedder: Embedder,
+    index: SharedIndex,
+    deadline: Arc<Mutex<Option<Instant>>>,
+    timeout: Option<Duration>,
+) -> Result<mlua::Function> {
+    lua.create_function(move |lua, (query, k): (String, Option<usize>)| {
+        if index.lock().unwrap().is_none() {
+            return Err(mlua::Error::RuntimeError(
+                "semantic_search: no index yet, call index_context(chunk_size) first".to_string(),
+            ));
+        }
+        let limit = deadline.lock().unwrap().zip(timeout);
+        let query = block_on_until(limit, embedder.embed(vec![query]))?
+            .pop()
+            .unwrap_or_default();
+
+        let index = index.lock().unwrap();
+        let index = index.as_ref().expect("checked above");
+        let results = lua.create_table()?;
+        for (i, score) in index.search(&query, k.unwrap_or(5)) {
+            let chunk = &index.chunks[i];
+            let result = lua.create_table()?;
+            result.set("text", chunk.text.as_str())?;
+            result.set("score", score)?;
+            result.set("index", i + 1)?;
+            result.set("offset", chunk.offset + 1)?;
+            results.push(result)?;
+        }
+        Ok(results)
+    })
+}
+
+/// Creates the `cosine_sim(a, b)` Lua function, comparing two vectors
+/// returned by `embed`. Vectors of different lengths are an error.
+pub fn create_cosine_sim_function(lua: &Lua) -> Result<mlua::Function> {
+    lua.create_function(|_, (a, b): (Vec<f64>, Vec<f64>)| {
+        if a.len() != b.len() {
+            return Err(mlua::Error::RuntimeError(format!(
+                "cosine_sim: vectors have different lengths ({} and {})",
+                a.len(),
+                b.len()
+            )));
+        }
+        Ok(cosine_similarity(&a, &b))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-9);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-9);
+        assert!((cosine_similarity(&[1.0, 1.0], &[-1.0, -1.0]) + 1.0).abs() < 1e-9);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 2.0]), 0.0);
+    }
+
+    #[test]
+    fn test_cosine_sim_function() {
+        let lua = Lua::new();
+        lua.globals()
+            .set("cosine_sim", create_cosine_sim_function(&lua).unwrap())
+            .unwrap();
+        let sim: f64 = lua
+            .load("return cosine_sim({1, 2, 3}, {1, 2, 3})")
+            .eval()
+            .unwrap();
+        assert!((sim - 1.0).abs() < 1e-9);
+        let err = lua
+            .load("return cosine_sim({1, 2}, {1, 2, 3})")
+            .eval::<f64>()
+            .unwrap_err();
+        assert!(err.to_string().contains("different lengths"));
+    }
+
+    #[test]
+    fn test_default_models() {
+        let ollama = Embedder::new(LlmClient::Ollama("qwen3:30b".to_string()), None);
+        assert_eq!(ollama.model, DEFAULT_OLLAMA_EMBEDDING_MODEL);
+        let openrouter = Embedder::new(
+            LlmClient::Openrouter("openai/gpt-4.1".to_string(), "key".to_string()),
+            Some("custom".to_string()),
+        );
+        assert_eq!(openrouter.model, "custom");
+    }
+
+    #[test]
+    fn test_vector_index_search() {
+        let chunk = |text: &str, vector: Vec<f64>| IndexedChunk {
+            text: text.to_string(),
+            offset: 0,
+            vector,
+        };
+        let index = VectorIndex {
+            chunks: vec![
+                chunk("cats", vec![1.0, 0.0]),
+                chunk("dogs", vec![0.0, 1.0]),
+                chunk("pets", vec![0.7, 0.7]),
+            ],
+        };
+        let results = index.search(&[1.0, 0.1], 2);
+        assert_eq!(results.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [0, 2]);
+        assert!(results[0].1 > results[1].1);
+        assert_eq!(index.search(&[1.0, 0.1], 10).len(), 3);
+    }
+
+    #[test]
+    fn test_semantic_search_needs_index() {
+        let lua = Lua::new();
+        let embedder = Embedder::new(LlmClient::Ollama("qwen3:30b".to_string()), None);
+        let search = create_semantic_search_function(
+            &lua,
+            embedder,
+            SharedIndex::default(),
+            Arc::new(Mutex::new(None)),
+            None,
+        )
+        .unwrap();
+        let err = search.call::<mlua::Value>("cats").unwrap_err();
+        assert!(err.to_string().contains("call index_context"));
+    }
+
+    #[test]
+    fn test_index_context_chunk_cap() {
+        let lua = Lua::new();
+        let embedder = Embedder::new(LlmClient::Ollama("qwen3:30b".to_string()), None);
+        let index = SharedIndex::default();
+        let index_context = create_index_context_function(
+            &lua,
+            embedder,
+            index.clone(),
+            Arc::new(Mutex::new(None)),
+            None,
+        )
+        .unwrap();
+        lua.globals()
+            .set("context", "word ".repeat(MAX_INDEX_CHUNKS + 1))
+            .unwrap();
+        let err = index_context.call::<usize>(5).unwrap_err();
+        assert!(err.to_string().contains("use a larger chunk_size"), "{err}");
+        assert!(index.lock().unwrap().is_none());
+    }
+}
@@ -0,0 +1,145 @@
+//! Base64 and hashes for Lua code.
+//!
+//! Contexts such as emails and API dumps embed base64 blobs, and
+//! deduplicating documents is easiest by comparing hashes, but neither is
+//! practical in pure Lua. `base64_encode(s)` and `base64_decode(s)` convert
+//! binary strings, and `sha256(s)` and `md5(s)` return hex digests.
+
+use base64::Engine as _;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::{DecodePaddingMode, general_purpose};
+use base64::prelude::BASE64_STANDARD;
+use md5::Md5;
+use mlua::Lua;
+use sha2::{Digest, Sha256};
+
+/// Decodes the standard alphabet with or without padding
+const STANDARD_LENIENT: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Decodes the URL-safe alphabet with or without padding
+const URL_SAFE_LENIENT: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Decode base64 in the standard or the URL-safe alphabet, with or without
+/// padding, ignoring whitespace such as the line breaks of MIME bodies
+pub fn decode_base64(text: &[u8]) -> Result<Vec<u8>, base64::DecodeError> {
+    let compact: Vec<u8> = text
+        .iter()
+        .copied()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    STANDARD_LENIENT
+        .decode(&compact)
+        .or_else(|e| URL_SAFE_LENIENT.decode(&compact).map_err(|_| e))
+}
+
+/// Lowercase hex digits of a digest
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Creates the `base64_encode(s)` Lua function, which returns `s` in
+/// standard base64 with padding, or in the URL-safe alphabet without
+/// padding when called as `base64_encode(s, true)`.
+pub fn create_base64_encode_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(|_, (data, url_safe): (mlua::String, Option<bool>)| {
+        Ok(if url_safe.unwrap_or(false) {
+            general_purpose::URL_SAFE_NO_PAD.encode(data.as_bytes())
+        } else {
+            BASE64_STANDARD.encode(data.as_bytes())
+        })
+    })
+}
+
+/// Creates the `base64_decode(s)` Lua function, which returns the bytes
+/// encoded in `s` as a string, or nil and an error message if `s` is not
+/// base64 (see [`decode_base64`]).
+pub fn create_base64_decode_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(|lua, text: mlua::String| {
+        Ok(match decode_base64(&text.as_bytes()) {
+            Ok(data) => (mlua::Value::String(lua.create_string(data)?), None),
+            Err(e) => (mlua::Value::Nil, Some(format!("invalid base64: {e}"))),
+        })
+    })
+}
+
+/// Creates the `sha256(s)` Lua function, which returns the SHA-256 digest of
+/// `s` as 64 lowercase hex digits
+pub fn create_sha256_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(|_, data: mlua::String| Ok(hex(&Sha256::digest(data.as_bytes()))))
+}
+
+/// Creates the `md5(s)` Lua function, which returns the MD5 digest of `s` as
+/// 32 lowercase hex digits. MD5 is fine for deduplication and checksums in
+/// the data, not for security.
+pub fn create_md5_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(|_, data: mlua::String| Ok(hex(&Md5::digest(data.as_bytes()))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode_base64(b"aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(decode_base64(b"aGVs\r\nbG8").unwrap(), b"hello");
+        assert_eq!(decode_base64(b"-_8").unwrap(), [0xfb, 0xff]);
+        assert!(decode_base64(b"not base64!").is_err());
+    }
+
+    #[test]
+    fn test_lua_functions() {
+        let lua = Lua::new();
+        let globals = lua.globals();
+        globals
+            .set(
+                "base64_encode",
+                create_base64_encode_function(&lua).unwrap(),
+            )
+            .unwrap();
+        globals
+            .set(
+                "base64_decode",
+                create_base64_decode_function(&lua).unwrap(),
+            )
+            .unwrap();
+        globals
+            .set("sha256", create_sha256_function(&lua).unwrap())
+            .unwrap();
+        globals
+            .set("md5", create_md5_function(&lua).unwrap())
+            .unwrap();
+
+        let (encoded, url, decoded): (String, String, mlua::String) = lua
+            .load(
+                r#"
+                local encoded = base64_encode("\0\255hi")
+                return encoded, base64_encode("\251\255", true), base64_decode(encoded)
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!((encoded.as_str(), url.as_str()), ("AP9oaQ==", "-_8"));
+        assert_eq!(decoded.as_bytes().as_ref(), b"\0\xffhi");
+        let (data, err): (mlua::Value, String) =
+            lua.load(r#"return base64_decode("@@")"#).eval().unwrap();
+        assert!(data.is_nil());
+        assert!(err.contains("invalid base64"), "{err}");
+
+        let (sha, md5): (String, String) = lua
+            .load(r#"return sha256("abc"), md5("abc")"#)
+            .eval()
+            .unwrap();
+        assert_eq!(
+            sha,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(md5, "900150983cd24fb0d6963f7d28e17f72");
+    }
+}
@@ -1,10 +1,58 @@
-use mlua::{IntoLua, Lua, Result};
+use crate::chunking::{create_chunk_context_function, create_for_each_chunk_function};
+use crate::datetime::{
+    create_date_diff_function, create_format_datetime_function, create_now_function,
+    create_parse_datetime_function,
+};
+use crate::diff::{create_diff_function, create_diff_stats_function};
+use crate::documents::{
+    Documents, create_add_document_function, create_get_document_function,
+    create_list_documents_function,
+};
+use crate::embeddings::{
+    Embedder, SharedIndex, create_cosine_sim_function, create_embed_function,
+    create_index_context_function, create_semantic_search_function,
+};
+use crate::encoding::{
+    create_base64_decode_function, create_base64_encode_function, create_md5_function,
+    create_sha256_function,
+};
+use crate::fuzzy::{
+    create_fuzzy_find_function, create_jaro_winkler_function, create_levenshtein_function,
+};
+use crate::http::{HttpAllowlist, create_http_get_function};
+use crate::markup::{
+    create_html_select_function, create_html_text_function, create_xml_parse_function,
+};
+use crate::notes::{
+    Note, Notes, create_note_add_function, create_notes_list_function,
+    create_notes_summarize_function,
+};
+use crate::patterns::{
+    PatternCache, create_grep_function, create_re_find_function, create_re_findall_function,
+    create_re_replace_function,
+};
+use crate::policy::PolicyChecker;
+use crate::repl::{ErrorKind, Truncation};
+use crate::stats::{create_stats_function, create_topk_function};
+use crate::stdlib::StdlibProfile;
+use crate::store::{Store, create_store_get_function, create_store_set_function};
+use crate::tasks::{TaskScheduler, create_start_task_function, create_task_status_function};
+use crate::tokens::Tokenizer;
+use crate::usage::{UsageCategory, UsageTracker};
+use mlua::{
+    FromLuaMulti, HookTriggers, IntoLua, IntoLuaMulti, Lua, LuaSerdeExt, MultiValue, Result,
+    VmState,
+};
+use rig::agent::AgentBuilder;
 use rig::client::CompletionClient;
-use rig::completion::Prompt;
+use rig::completion::{CompletionModel, Prompt};
 use rig::providers::{ollama, openrouter};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::sync::{Arc, Mutex};
-use tiktoken_rs::p50k_base;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
 
 #[derive(Clone)]
 pub enum LlmClient {
@@ -12,26 +60,671 @@ pub enum LlmClient {
     Openrouter(String, String), // Store model name and API key
 }
 
+/// Receives the text of each `print` call as it happens (see
+/// [`Environment::eval_with_sink`]), or each `progress` message (see
+/// [`Environment::set_progress_sink`])
+pub type OutputSink = Arc<dyn Fn(&str) + Send + Sync>;
+
+type HostCallback = Arc<dyn Fn(&Lua, MultiValue) -> Result<MultiValue> + Send + Sync>;
+
+/// A Rust callback exposed to Lua as a global function, registered with
+/// [`EnvironmentConfig::with_function`].
+#[derive(Clone)]
+pub struct HostFunction {
+    pub name: String,
+    /// Usage description included in the Available Functions prompt section
+    pub description: String,
+    callback: HostCallback,
+}
+
+impl HostFunction {
+    /// Wrap a Rust callback; arguments and return values are converted like
+    /// any mlua function
+    pub fn new<F, A, R>(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        callback: F,
+    ) -> Self
+    where
+        F: Fn(&Lua, A) -> Result<R> + Send + Sync + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        let callback: HostCallback = Arc::new(move |lua, args| {
+            let args = A::from_lua_multi(args, lua)?;
+            callback(lua, args)?.into_lua_multi(lua)
+        });
+        Self {
+            name: name.into(),
+            description: description.into(),
+            callback,
+        }
+    }
+
+    /// Post-process the values the function returns to Lua
+    pub fn map_output<F>(self, f: F) -> Self
+    where
+        F: Fn(&Lua, MultiValue) -> Result<MultiValue> + Send + Sync + 'static,
+    {
+        let callback = self.callback;
+        Self {
+            callback: Arc::new(move |lua, args| f(lua, callback(lua, args)?)),
+            ..self
+        }
+    }
+}
+
+impl std::fmt::Debug for HostFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HostFunction")
+            .field("name", &self.name)
+            .field("description", &self.description)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Configuration for an [`Environment`].
+#[derive(Debug, Clone)]
+pub struct EnvironmentConfig {
+    /// Maximum number of `llm_query` calls in a single `eval` (None for
+    /// unlimited). Each embedding request, of up to 64 texts, counts as a call.
+    pub max_llm_queries_per_cell: Option<usize>,
+    /// Maximum number of `llm_query` calls over the lifetime of the
+    /// environment (None for unlimited). Each embedding request counts as a call.
+    pub max_llm_queries_per_run: Option<usize>,
+    /// How long each background task (see [`crate::tasks`]) runs after each
+    /// eval. Tasks run within the cell's limits, and a task that does not
+    /// yield within its slice fails with [`LimitExceeded::TaskSlice`].
+    pub task_time_slice: Duration,
+    /// Static checker run on code before execution (None to skip checking)
+    pub policy: Option<PolicyChecker>,
+    /// Host-provided functions registered as Lua globals
+    pub functions: Vec<HostFunction>,
+    /// Extra global variables, converted from JSON to Lua values
+    pub globals: Vec<(String, serde_json::Value)>,
+    /// Named documents available through `get_document` (see
+    /// [`crate::documents`])
+    pub documents: Vec<(String, String)>,
+    /// Encoding used by `token_trunc` and to count cell output tokens
+    pub tokenizer: Tokenizer,
+    /// Wall-clock time a cell may run, including its `llm_query` calls,
+    /// before it fails with [`CellTimeout`] (None for unlimited)
+    pub cell_timeout: Option<Duration>,
+    /// Lua instructions a cell may execute before it fails with
+    /// [`LimitExceeded`], counted in steps of [`HOOK_INTERVAL`] (None for unlimited)
+    pub max_cell_instructions: Option<u64>,
+    /// Bytes of Lua memory a cell may allocate on top of what is already in
+    /// use, e.g. by the context (None for unlimited)
+    pub max_cell_memory: Option<usize>,
+    /// Model used by `embed`, from the same provider (None for the provider's
+    /// default, see [`crate::embeddings::Embedder::new`])
+    pub embedding_model: Option<String>,
+    /// Levels of nested Rlms `rlm_query` may start below this environment
+    /// (0 disables `rlm_query`)
+    pub max_rlm_depth: usize,
+    /// Steps a nested Rlm started by `rlm_query` may take to answer
+    pub sub_rlm_iterations: usize,
+    /// Store read and written by `store_get` and `store_set`, kept across
+    /// runs (None to make them fail)
+    pub store: Option<Store>,
+    /// Domains `http_get` may fetch from; empty, the default, disables it
+    pub http_allowlist: HttpAllowlist,
+    /// Seed of `math.random`; when set, background tasks also advance by
+    /// [`DETERMINISTIC_TASK_YIELDS`] yields per eval instead of by time, so
+    /// replaying a session reproduces its outputs (None for a random seed)
+    pub seed: Option<u64>,
+    /// Make `context` a read-only global, so code can't overwrite it (see
+    /// [`set_readonly_context`])
+    pub readonly_context: bool,
+    /// Time returned by `now()` instead of the current time, for
+    /// reproducible runs (None for the current time)
+    pub frozen_now: Option<chrono::DateTime<chrono::Utc>>,
+    /// Parts of the Lua standard library cells can use
+    pub stdlib: StdlibProfile,
+    /// Bytes of output a cell may print; later output is dropped after
+    /// [`OUTPUT_LIMIT_MARKER`] (None for unlimited)
+    pub max_output_bytes: Option<usize>,
+    /// Per-run `llm_query` count of the environment whose `rlm_query`
+    /// started this one, so a nested run shares its per-run limit
+    pub(crate) parent_run_queries: Option<Arc<AtomicUsize>>,
+}
+
+/// Yields a background task runs for after each eval when
+/// [`EnvironmentConfig::seed`] is set
+pub const DETERMINISTIC_TASK_YIELDS: usize = 100;
+
+impl Default for EnvironmentConfig {
+    fn default() -> Self {
+        Self {
+            max_llm_queries_per_cell: Some(20),
+            max_llm_queries_per_run: Some(200),
+            task_time_slice: Duration::from_secs(2),
+            policy: None,
+            functions: Vec::new(),
+            globals: Vec::new(),
+            documents: Vec::new(),
+            tokenizer: Tokenizer::default(),
+            cell_timeout: Some(Duration::from_secs(120)),
+            max_cell_instructions: None,
+            max_cell_memory: Some(1 << 30),
+            embedding_model: None,
+            max_rlm_depth: 1,
+            sub_rlm_iterations: 10,
+            store: None,
+            http_allowlist: HttpAllowlist::default(),
+            seed: None,
+            readonly_context: false,
+            frozen_now: None,
+            stdlib: StdlibProfile::default(),
+            max_output_bytes: Some(1 << 20),
+            parent_run_queries: None,
+        }
+    }
+}
+
+impl EnvironmentConfig {
+    /// Set the maximum number of `llm_query` calls per cell
+    pub fn with_max_llm_queries_per_cell(mut self, max: Option<usize>) -> Self {
+        self.max_llm_queries_per_cell = max;
+        self
+    }
+
+    /// Set the maximum number of `llm_query` calls per run
+    pub fn with_max_llm_queries_per_run(mut self, max: Option<usize>) -> Self {
+        self.max_llm_queries_per_run = max;
+        self
+    }
+
+    /// Set how long background tasks run after each eval
+    pub fn with_task_time_slice(mut self, time_slice: Duration) -> Self {
+        self.task_time_slice = time_slice;
+        self
+    }
+
+    /// Set how long a cell may run before it is stopped
+    pub fn with_cell_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.cell_timeout = timeout;
+        self
+    }
+
+    /// Set how many Lua instructions a cell may execute
+    pub fn with_max_cell_instructions(mut self, max: Option<u64>) -> Self {
+        self.max_cell_instructions = max;
+        self
+    }
+
+    /// Set how many bytes of Lua memory a cell may allocate
+    pub fn with_max_cell_memory(mut self, max: Option<usize>) -> Self {
+        self.max_cell_memory = max;
+        self
+    }
+
+    /// Set how many bytes of output a cell may print
+    pub fn with_max_output_bytes(mut self, max: Option<usize>) -> Self {
+        self.max_output_bytes = max;
+        self
+    }
+
+    /// Set the model used by `embed`
+    pub fn with_embedding_model(mut self, model: Option<String>) -> Self {
+        self.embedding_model = model;
+        self
+    }
+
+    /// Set how deeply `rlm_query` may nest and how many steps each nested Rlm may take
+    pub fn with_sub_rlm(mut self, max_depth: usize, iterations: usize) -> Self {
+        self.max_rlm_depth = max_depth;
+        self.sub_rlm_iterations = iterations;
+        self
+    }
+
+    /// Set the persistent store of `store_get` and `store_set`
+    pub fn with_store(mut self, store: Option<Store>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Allow `http_get` to fetch from these domains and their subdomains
+    pub fn with_http_allowlist(
+        mut self,
+        domains: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        self.http_allowlist = HttpAllowlist::new(domains);
+        self
+    }
+
+    /// Seed `math.random` and schedule background tasks by yields rather
+    /// than time, making runs reproducible
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Make `context` a read-only global
+    pub fn with_readonly_context(mut self, readonly: bool) -> Self {
+        self.readonly_context = readonly;
+        self
+    }
+
+    /// Make `now()` return a fixed time
+    pub fn with_frozen_now(mut self, now: Option<chrono::DateTime<chrono::Utc>>) -> Self {
+        self.frozen_now = now;
+        self
+    }
+
+    /// Set the parts of the Lua standard library cells can use
+    pub fn with_stdlib(mut self, stdlib: StdlibProfile) -> Self {
+        self.stdlib = stdlib;
+        self
+    }
+
+    /// Set the encoding used to count and truncate tokens
+    pub fn with_tokenizer(mut self, tokenizer: Tokenizer) -> Self {
+        self.tokenizer = tokenizer;
+        self
+    }
+
+    /// Set the policy checker that code must pass before it is executed
+    pub fn with_policy(mut self, policy: Option<PolicyChecker>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Set a global variable (e.g. structured data derived from the context)
+    /// before any code runs. Objects become tables keyed by name, arrays
+    /// become sequences.
+    pub fn with_global(mut self, name: impl Into<String>, value: serde_json::Value) -> Self {
+        self.globals.push((name.into(), value));
+        self
+    }
+
+    /// Add a named document, e.g. one of several input files, which code
+    /// reads with `get_document(name)`
+    pub fn with_document(mut self, name: impl Into<String>, text: impl Into<String>) -> Self {
+        self.documents.push((name.into(), text.into()));
+        self
+    }
+
+    /// Register a Rust callback as a global Lua function.
+    ///
+    /// Arguments and return values are converted like any mlua function. The
+    /// description is listed under Available Functions in the system prompt
+    /// (see [`crate::prompt::system_prompt`]). Registering a name that clashes
+    /// with a built-in makes environment creation fail.
+    ///
+    /// # Example
+    /// ```
+    /// use moonraker::environment::EnvironmentConfig;
+    ///
+    /// let config = EnvironmentConfig::default().with_function(
+    ///     "lookup_customer",
+    ///     "Returns the customer's name for `lookup_customer(id)`, or nil if unknown.",
+    ///     |_, id: u32| Ok((id == 7).then(|| "Ada".to_string())),
+    /// );
+    /// ```
+    pub fn with_function<F, A, R>(
+        mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        callback: F,
+    ) -> Self
+    where
+        F: Fn(&Lua, A) -> Result<R> + Send + Sync + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        self.functions
+            .push(HostFunction::new(name, description, callback));
+        self
+    }
+
+    /// Let code load further documents with `load_file(path)`, restricted to
+    /// files inside `dirs` (see [`crate::inputs::load_file_function`]).
+    /// Without this the sandbox cannot read any file.
+    pub fn with_load_file_dirs(mut self, dirs: &[std::path::PathBuf]) -> Self {
+        self.functions.push(crate::inputs::load_file_function(
+            dirs,
+            crate::inputs::LoadOptions::default(),
+            crate::inputs::InputPipeline::new(),
+        ));
+        self
+    }
+}
+
+/// Raised by `llm_query` when the model call fails or a call limit is reached
+#[derive(Debug)]
+pub struct LlmQueryError(pub(crate) String);
+
+impl std::fmt::Display for LlmQueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LlmQueryError {}
+
+/// Raised when a cell runs longer than [`EnvironmentConfig::cell_timeout`]
+#[derive(Debug)]
+pub struct CellTimeout(Duration);
+
+impl std::fmt::Display for CellTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Cell timed out after {:?}; make sure loops terminate, and split long work across cells",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for CellTimeout {}
+
+/// Raised when a cell exceeds [`EnvironmentConfig::max_cell_instructions`]
+/// or [`EnvironmentConfig::max_cell_memory`], or a background task runs for
+/// its whole [`EnvironmentConfig::task_time_slice`] without yielding
+#[derive(Debug)]
+pub enum LimitExceeded {
+    Instructions(u64),
+    Memory(usize),
+    TaskSlice(Duration),
+}
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitExceeded::Instructions(max) => write!(
+                f,
+                "Cell exceeded its limit of {max} Lua instructions; make sure loops terminate, and split long work across cells"
+            ),
+            LimitExceeded::Memory(max) => write!(
+                f,
+                "Cell exceeded its limit of {} MiB of memory; avoid copying large strings such as the context, and work on slices of it",
+                max >> 20
+            ),
+            LimitExceeded::TaskSlice(slice) => write!(
+                f,
+                "Task ran for its whole time slice of {slice:?} without yielding; call coroutine.yield() inside long loops"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Number of Lua instructions between checks of a cell's deadline and instruction budget
+pub const HOOK_INTERVAL: u64 = 10_000;
+
+/// The running cell's deadline and instruction budget.
+///
+/// Stored as Lua app data, so host functions doing long work of their own,
+/// such as `sql_query`, can stop at the same limits as Lua code (see
+/// [`CellLimits::of`]).
+#[derive(Clone)]
+pub struct CellLimits {
+    deadline: Arc<Mutex<Option<Instant>>>,
+    instructions_left: Arc<Mutex<Option<u64>>>,
+    timeout: Duration,
+    max_instructions: u64,
+    /// End of the running task's time slice, and the slice
+    task_deadline: Arc<Mutex<Option<(Instant, Duration)>>>,
+}
+
+impl CellLimits {
+    /// The limits of the cell running in `lua`, if it belongs to an [`Environment`]
+    pub fn of(lua: &Lua) -> Option<Self> {
+        lua.app_data_ref::<Self>().map(|limits| limits.clone())
+    }
+
+    /// Count `n` instructions against the cell's budget, failing with
+    /// [`LimitExceeded`] once it is used up or with [`CellTimeout`] once the
+    /// deadline has passed
+    pub fn charge(&self, n: u64) -> Result<()> {
+        if let Some(left) = self.instructions_left.lock().unwrap().as_mut() {
+            *left = left.checked_sub(n).ok_or_else(|| {
+                mlua::Error::external(LimitExceeded::Instructions(self.max_instructions))
+            })?;
+        }
+        if let Some(deadline) = *self.deadline.lock().unwrap()
+            && Instant::now() >= deadline
+        {
+            return Err(mlua::Error::external(CellTimeout(self.timeout)));
+        }
+        match *self.task_deadline.lock().unwrap() {
+            Some((deadline, slice)) if Instant::now() >= deadline => {
+                Err(mlua::Error::external(LimitExceeded::TaskSlice(slice)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Run `f`, a background task's time slice, failing the task with
+    /// [`LimitExceeded::TaskSlice`] if it is still running `slice` from now
+    pub(crate) fn task_slice<T>(&self, slice: Duration, f: impl FnOnce() -> T) -> T {
+        *self.task_deadline.lock().unwrap() = Some((Instant::now() + slice, slice));
+        let result = f();
+        *self.task_deadline.lock().unwrap() = None;
+        result
+    }
+}
+
+/// Counts `llm_query` calls against the limits in [`EnvironmentConfig`].
+#[derive(Clone)]
+pub(crate) struct QueryLimiter {
+    per_cell: Option<usize>,
+    per_run: Option<usize>,
+    cell_count: Arc<AtomicUsize>,
+    run_count: Arc<AtomicUsize>,
+}
+
+impl QueryLimiter {
+    fn new(config: &EnvironmentConfig) -> Self {
+        Self {
+            per_cell: config.max_llm_queries_per_cell,
+            per_run: config.max_llm_queries_per_run,
+            cell_count: Arc::new(AtomicUsize::new(0)),
+            run_count: config.parent_run_queries.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Reset the per-cell counter at the start of an eval
+    fn start_cell(&self) {
+        self.cell_count.store(0, Ordering::SeqCst);
+    }
+
+    /// Count one call, failing if either limit would be exceeded
+    fn acquire(&self) -> Result<()> {
+        self.acquire_many(1)
+    }
+
+    /// Count `n` calls made together, failing without counting any of them
+    /// if either limit would be exceeded
+    pub(crate) fn acquire_many(&self, n: usize) -> Result<()> {
+        let cell = self.cell_count.load(Ordering::SeqCst);
+        if let Some(max) = self.per_cell
+            && cell + n > max
+        {
+            return Err(mlua::Error::external(LlmQueryError(format!(
+                "llm_query limit exceeded: at most {max} calls per cell. Batch your prompts or process fewer chunks per cell."
+            ))));
+        }
+        let run = self.run_count.load(Ordering::SeqCst);
+        if let Some(max) = self.per_run
+            && run + n > max
+        {
+            return Err(mlua::Error::external(LlmQueryError(format!(
+                "llm_query limit exceeded: at most {max} calls per run. Answer with the information gathered so far."
+            ))));
+        }
+        self.cell_count.fetch_add(n, Ordering::SeqCst);
+        self.run_count.fetch_add(n, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Calls left in the run (None when unlimited)
+    fn run_remaining(&self) -> Option<usize> {
+        let run = self.run_count.load(Ordering::SeqCst);
+        self.per_run.map(|max| max.saturating_sub(run))
+    }
+}
+
+/// What is left of a run, reported to Lua by `budget()`. Set by the loop
+/// driving the environment before each step (see [`Environment::set_budget`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Budget {
+    /// Steps left after the current one (None when unknown or unlimited)
+    pub iterations_left: Option<usize>,
+    /// Tokens spent by the run so far
+    pub tokens_used: u64,
+    /// Tokens left in the run's token budget (None when unlimited)
+    pub tokens_left: Option<u64>,
+    /// When the run started (None for when the environment was created)
+    pub started: Option<Instant>,
+}
+
+/// A key/value finding recorded from Lua with `emit_finding`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Finding {
+    pub key: String,
+    pub value: String,
+}
+
+/// A request from Lua to keep something in the model's context window,
+/// applied to the transcript by [`crate::repl::Repl::eval`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PinRequest {
+    /// `pin_last_cell()`: pin the cell before the one running
+    LastCell,
+    /// `note_important(text)`: add a pinned note
+    Note(String),
+}
+
+/// Globals defined by evaluated code, as saved with a session (see [`Environment::snapshot_globals`])
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GlobalsSnapshot {
+    /// Values that could be serialized, by name
+    #[serde(default)]
+    pub values: BTreeMap<String, serde_json::Value>,
+    /// Names of globals that could not be serialized, such as functions
+    #[serde(default)]
+    pub unserializable: Vec<String>,
+}
+
 /// A sandboxed Lua execution environment with LLM integration.
 ///
 /// # Security
 ///
-/// Uses `Lua::new()` which loads only the **safe subset** of standard libraries:
+/// Loads only the **safe subset** of standard libraries by default:
 /// - ✓ Available: `math`, `string`, `table`, `coroutine`, `utf8`
-/// - ✗ Blocked: `io`, `os`, `package`, `debug`, `ffi` (no file/network/system access)
+/// - ✗ Blocked: `io`, `os`, `package`, `debug`, `dofile`, `loadfile` (no
+///   file/network/system access)
+///
+/// [`EnvironmentConfig::stdlib`] can allow more, such as `os.time`, or less
+/// (see [`StdlibProfile`]).
+///
+/// An optional [`PolicyChecker`] in [`EnvironmentConfig::policy`] additionally
+/// rejects code referencing identifiers such as `load` before it runs.
+///
+/// # Runtimes
+///
+/// [`Environment::eval`] is synchronous, and functions such as `llm_query`
+/// block the cell until their requests complete. This works outside any
+/// tokio runtime and on either runtime flavor; on a current-thread runtime,
+/// the runtime's other tasks wait while a cell blocks.
 ///
 /// # Custom Functions
 ///
 /// - `print(...)` - Captures output to buffer (see [`create_print_function`])
 /// - `llm_query(prompt)` - Query LLM provider (see [`create_llm_query_function`])
-/// - `token_trunc(text, n)` - Truncate by token count (see [`create_token_trunc_function`])
+/// - `llm_map(prompts, options)` - Send many queries concurrently (see [`create_llm_map_function`])
+/// - `rlm_query(prompt, sub_context)` - Answer a query with a nested Rlm (see
+///   [`create_rlm_query_function`])
+/// - `llm_query_json(prompt, schema_hint)` - Query for a JSON answer, returned as
+///   a Lua value (see [`create_llm_query_json_function`])
+/// - `retry(fn, attempts, backoff_ms)` / `sleep(ms)` - Recover from transient
+///   failures (see [`create_retry_function`])
+/// - `embed(text)` / `cosine_sim(a, b)` - Embed text and compare embeddings, and
+///   `index_context(chunk_size)` / `semantic_search(query, k)` - Search the
+///   context by meaning (see [`crate::embeddings`])
+/// - `token_trunc(text, n)` / `token_trunc_tail(text, n)` / `token_trunc_middle(text, n)` -
+///   Truncate by token count, keeping the beginning, the end, or both (see
+///   [`create_token_trunc_function`])
+/// - `chunk_context(strategy, size, overlap)` / `for_each_chunk(text, size, overlap, fn)` -
+///   Split the context into chunks, or map a function over the chunks of a
+///   text (see [`crate::chunking`])
+/// - `re_find(s, pattern)` / `re_findall(s, pattern)` / `re_replace(s, pattern, repl)` -
+///   Regular expressions with `regex` crate syntax, and `grep(pattern, opts)` -
+///   Matching lines with line numbers and the lines around them (see
+///   [`crate::patterns`])
+/// - `stats(list)` / `topk(t, k, keyfn)` - Summarize and rank numbers (see
+///   [`crate::stats`])
+/// - `base64_encode(s)` / `base64_decode(s)` / `sha256(s)` / `md5(s)` - Base64
+///   and hex digests of binary strings (see [`crate::encoding`])
+/// - `diff(a, b, by)` / `diff_stats(a, b, by)` - Compare two strings by lines
+///   or words (see [`crate::diff`])
+/// - `html_text(s)` / `html_select(s, selector)` / `xml_parse(s)` - Query HTML
+///   and XML strings structurally (see [`crate::markup`])
+/// - `levenshtein(a, b)` / `jaro_winkler(a, b)` / `fuzzy_find(needle, haystack, threshold)` -
+///   String distances and fuzzy matching (see [`crate::fuzzy`])
+/// - `parse_datetime(s, fmt)` / `format_datetime(t, fmt)` / `date_diff(a, b, unit)` /
+///   `now()` - Dates and times as Unix timestamps (see [`crate::datetime`])
+/// - `start_task(name, fn)` / `task_status(name)` - Background tasks resumed after
+///   each evaluation (see [`crate::tasks`])
+/// - `progress(msg)` - Report status while a cell runs, outside its output (see
+///   [`create_progress_function`])
+/// - `emit_finding(key, value)` - Record a structured finding (see [`create_emit_finding_function`])
+/// - `note_add(text, tags)` / `notes_list(tag)` / `notes_summarize(max_tokens)` -
+///   Running notes shown at the top of every prompt (see [`crate::notes`])
+/// - `http_get(url)` - Fetch a URL from an allowed domain, disabled by default
+///   (see [`crate::http`])
+/// - `add_document(name, text)` / `get_document(name)` / `list_documents()` -
+///   Named documents, e.g. several input files (see [`crate::documents`])
+/// - `lua_check(code)` - Check that a string of Lua compiles, without running
+///   it (see [`create_lua_check_function`])
+/// - `copy_context()` - A copy of `context` that can be modified (see
+///   [`create_copy_context_function`])
+/// - `store_set(key, value)` / `store_get(key)` - Keep values across runs (see
+///   [`crate::store`])
+/// - `budget()` - What is left of the run's iterations, tokens, and queries
+///   (see [`create_budget_function`])
+/// - `final_answer(text)` - Record the answer to the query (see [`create_final_answer_function`])
+/// - `pin_last_cell()` / `note_important(text)` - Keep a cell or a note in the
+///   context window (see [`PinRequest`])
+/// - Host functions registered with [`EnvironmentConfig::with_function`] or
+///   [`Environment::register_function`]
 ///
 /// # Global Variables
 ///
-/// - `context` - Initial context value, persists across evaluations
+/// - `context` - Initial context value, persists across evaluations; read-only
+///   with [`EnvironmentConfig::readonly_context`]
+/// - `task_results` - Return values of finished background tasks, keyed by name
 pub struct Environment {
     lua: Lua,
     output_buffer: Arc<Mutex<String>>,
+    /// Where printed text is streamed during [`Environment::eval_with_sink`]
+    sink: Arc<Mutex<Option<OutputSink>>>,
+    /// Where `progress` messages go (see [`Environment::set_progress_sink`])
+    progress_sink: Arc<Mutex<Option<OutputSink>>>,
+    usage: UsageTracker,
+    limiter: QueryLimiter,
+    tasks: TaskScheduler,
+    findings: Arc<Mutex<Vec<Finding>>>,
+    notes: Notes,
+    pins: Arc<Mutex<Vec<PinRequest>>>,
+    answer: Arc<Mutex<Option<String>>>,
+    /// What is left of the run, reported by `budget()`
+    budget: Arc<Mutex<Budget>>,
+    /// When the running cell times out (see [`EnvironmentConfig::cell_timeout`])
+    deadline: Arc<Mutex<Option<Instant>>>,
+    /// Instructions the running cell may still execute (None when unlimited)
+    instructions_left: Arc<Mutex<Option<u64>>>,
+    /// Names of the globals defined before any code ran
+    builtins: HashSet<String>,
+    /// What the environment was created from, kept for [`Environment::reset`]
+    client: LlmClient,
+    config: EnvironmentConfig,
+    initial_context: mlua::RegistryKey,
+    /// Names of globals set with [`Environment::set_global`]
+    host_globals: Mutex<Vec<String>>,
 }
 
 impl Environment {
@@ -39,42 +732,856 @@ impl Environment {
     where
         T: IntoLua,
     {
-        let lua = Lua::new();
+        Self::with_config(init_context, client, EnvironmentConfig::default())
+    }
+
+    pub fn with_config<T>(
+        init_context: T,
+        client: LlmClient,
+        config: EnvironmentConfig,
+    ) -> Result<Self>
+    where
+        T: IntoLua,
+    {
+        let lua = config.stdlib.create_lua()?;
+        let init_context = init_context.into_lua(&lua)?;
+        let limiter = QueryLimiter::new(&config);
+        Self::build(
+            lua,
+            init_context,
+            client,
+            config,
+            UsageTracker::new(),
+            limiter,
+        )
+    }
+
+    /// Create an environment over several named documents. `context` is
+    /// their concatenation, each preceded by a `=== name ===` header line,
+    /// and each is also available with `get_document(name)`.
+    pub fn with_documents(
+        documents: impl IntoIterator<Item = (String, String)>,
+        client: LlmClient,
+        mut config: EnvironmentConfig,
+    ) -> Result<Self> {
+        let start = config.documents.len();
+        config.documents.extend(documents);
+        let context = crate::documents::concat(&config.documents[start..]);
+        Self::with_config(context, client, config)
+    }
+
+    fn build(
+        lua: Lua,
+        init_context: mlua::Value,
+        client: LlmClient,
+        config: EnvironmentConfig,
+        usage: UsageTracker,
+        limiter: QueryLimiter,
+    ) -> Result<Self> {
         let output_buffer = Arc::new(Mutex::new(String::new()));
+        let sink = Arc::new(Mutex::new(None));
+        let progress_sink = Arc::new(Mutex::new(None));
+        let tasks = TaskScheduler::new(config.task_time_slice)
+            .with_max_yields(config.seed.map(|_| DETERMINISTIC_TASK_YIELDS));
+        let findings = Arc::new(Mutex::new(Vec::new()));
+        let notes = Notes::default();
+        let deadline = Arc::new(Mutex::new(None));
+        let instructions_left: Arc<Mutex<Option<u64>>> = Arc::new(Mutex::new(None));
+
+        // Stop runaway code once the cell's deadline has passed or its
+        // instruction budget is used up, and background tasks that do not
+        // yield within their time slice
+        let limits = CellLimits {
+            deadline: deadline.clone(),
+            instructions_left: instructions_left.clone(),
+            timeout: config.cell_timeout.unwrap_or_default(),
+            max_instructions: config.max_cell_instructions.unwrap_or_default(),
+            task_deadline: Arc::new(Mutex::new(None)),
+        };
+        {
+            let limits = limits.clone();
+            // Global, so the coroutines of tasks and cells are limited too
+            lua.set_global_hook(
+                HookTriggers::new().every_nth_instruction(HOOK_INTERVAL as u32),
+                move |_, _| limits.charge(HOOK_INTERVAL).map(|()| VmState::Continue),
+            )?;
+        }
+        lua.set_app_data(limits);
 
         // Register custom functions
-        lua.globals()
-            .set("print", create_print_function(&lua, output_buffer.clone())?)?;
+        lua.globals().set(
+            "print",
+            create_print_function(
+                &lua,
+                output_buffer.clone(),
+                sink.clone(),
+                config.max_output_bytes,
+            )?,
+        )?;
         lua.globals().set(
             "llm_query",
-            create_llm_query_function(&lua, client.clone())?,
+            create_llm_query_function(
+                &lua,
+                client.clone(),
+                usage.clone(),
+                limiter.clone(),
+                deadline.clone(),
+                config.cell_timeout,
+            )?,
+        )?;
+        lua.globals().set(
+            "llm_map",
+            create_llm_map_function(
+                &lua,
+                client.clone(),
+                usage.clone(),
+                limiter.clone(),
+                deadline.clone(),
+                config.cell_timeout,
+            )?,
+        )?;
+        lua.globals().set(
+            "rlm_query",
+            create_rlm_query_function(
+                &lua,
+                client.clone(),
+                config.clone(),
+                usage.clone(),
+                limiter.clone(),
+                deadline.clone(),
+            )?,
+        )?;
+        lua.globals().set(
+            "llm_query_json",
+            create_llm_query_json_function(
+                &lua,
+                client.clone(),
+                usage.clone(),
+                limiter.clone(),
+                deadline.clone(),
+                config.cell_timeout,
+            )?,
+        )?;
+        lua.globals().set(
+            "retry",
+            create_retry_function(&lua, deadline.clone(), config.cell_timeout)?,
+        )?;
+        lua.globals().set(
+            "sleep",
+            create_sleep_function(&lua, deadline.clone(), config.cell_timeout)?,
+        )?;
+        let embedder = Embedder::new(client.clone(), config.embedding_model.clone())
+            .with_usage(usage.clone(), config.tokenizer)
+            .with_limiter(limiter.clone());
+        lua.globals().set(
+            "embed",
+            create_embed_function(
+                &lua,
+                embedder.clone(),
+                deadline.clone(),
+                config.cell_timeout,
+            )?,
+        )?;
+        let index = SharedIndex::default();
+        lua.globals().set(
+            "index_context",
+            create_index_context_function(
+                &lua,
+                embedder.clone(),
+                index.clone(),
+                deadline.clone(),
+                config.cell_timeout,
+            )?,
+        )?;
+        lua.globals().set(
+            "semantic_search",
+            create_semantic_search_function(
+                &lua,
+                embedder,
+                index,
+                deadline.clone(),
+                config.cell_timeout,
+            )?,
+        )?;
+        lua.globals()
+            .set("cosine_sim", create_cosine_sim_function(&lua)?)?;
+        lua.globals().set(
+            "token_trunc",
+            create_token_trunc_function(&lua, config.tokenizer, Truncation::Head)?,
+        )?;
+        lua.globals().set(
+            "token_trunc_tail",
+            create_token_trunc_function(&lua, config.tokenizer, Truncation::Tail)?,
+        )?;
+        lua.globals().set(
+            "token_trunc_middle",
+            create_token_trunc_function(&lua, config.tokenizer, Truncation::HeadAndTail)?,
+        )?;
+        lua.globals().set(
+            "chunk_context",
+            create_chunk_context_function(&lua, config.tokenizer)?,
+        )?;
+        lua.globals()
+            .set("for_each_chunk", create_for_each_chunk_function(&lua)?)?;
+        lua.globals()
+            .set("parse_datetime", create_parse_datetime_function(&lua)?)?;
+        lua.globals()
+            .set("format_datetime", create_format_datetime_function(&lua)?)?;
+        lua.globals()
+            .set("date_diff", create_date_diff_function(&lua)?)?;
+        lua.globals()
+            .set("now", create_now_function(&lua, config.frozen_now)?)?;
+        lua.globals().set("stats", create_stats_function(&lua)?)?;
+        lua.globals().set("topk", create_topk_function(&lua)?)?;
+        lua.globals()
+            .set("base64_encode", create_base64_encode_function(&lua)?)?;
+        lua.globals()
+            .set("base64_decode", create_base64_decode_function(&lua)?)?;
+        lua.globals().set("sha256", create_sha256_function(&lua)?)?;
+        lua.globals().set("md5", create_md5_function(&lua)?)?;
+        lua.globals().set("diff", create_diff_function(&lua)?)?;
+        lua.globals()
+            .set("diff_stats", create_diff_stats_function(&lua)?)?;
+        lua.globals()
+            .set("html_text", create_html_text_function(&lua)?)?;
+        lua.globals()
+            .set("html_select", create_html_select_function(&lua)?)?;
+        lua.globals()
+            .set("xml_parse", create_xml_parse_function(&lua)?)?;
+        lua.globals()
+            .set("levenshtein", create_levenshtein_function(&lua)?)?;
+        lua.globals()
+            .set("jaro_winkler", create_jaro_winkler_function(&lua)?)?;
+        lua.globals()
+            .set("fuzzy_find", create_fuzzy_find_function(&lua)?)?;
+        let patterns = PatternCache::default();
+        lua.globals()
+            .set("re_find", create_re_find_function(&lua, patterns.clone())?)?;
+        lua.globals().set(
+            "re_findall",
+            create_re_findall_function(&lua, patterns.clone())?,
+        )?;
+        lua.globals().set(
+            "re_replace",
+            create_re_replace_function(&lua, patterns.clone())?,
+        )?;
+        lua.globals()
+            .set("grep", create_grep_function(&lua, patterns)?)?;
+        lua.globals().set(
+            "start_task",
+            create_start_task_function(&lua, tasks.clone())?,
+        )?;
+        lua.globals().set(
+            "task_status",
+            create_task_status_function(&lua, tasks.clone())?,
+        )?;
+        lua.globals().set("task_results", lua.create_table()?)?;
+        lua.globals().set(
+            "progress",
+            create_progress_function(&lua, progress_sink.clone())?,
+        )?;
+        lua.globals().set(
+            "emit_finding",
+            create_emit_finding_function(&lua, findings.clone())?,
         )?;
         lua.globals()
-            .set("token_trunc", create_token_trunc_function(&lua)?)?;
+            .set("note_add", create_note_add_function(&lua, notes.clone())?)?;
+        lua.globals().set(
+            "notes_list",
+            create_notes_list_function(&lua, notes.clone())?,
+        )?;
+        lua.globals().set(
+            "notes_summarize",
+            create_notes_summarize_function(&lua, notes.clone(), config.tokenizer)?,
+        )?;
+        lua.globals().set(
+            "http_get",
+            create_http_get_function(
+                &lua,
+                config.http_allowlist.clone(),
+                deadline.clone(),
+                config.cell_timeout,
+            )?,
+        )?;
+        lua.globals().set(
+            "store_set",
+            create_store_set_function(&lua, config.store.clone())?,
+        )?;
+        lua.globals().set(
+            "store_get",
+            create_store_get_function(&lua, config.store.clone())?,
+        )?;
+        let budget = Arc::new(Mutex::new(Budget::default()));
+        lua.globals().set(
+            "budget",
+            create_budget_function(&lua, budget.clone(), limiter.clone())?,
+        )?;
+        let answer = Arc::new(Mutex::new(None));
+        lua.globals().set(
+            "final_answer",
+            create_final_answer_function(&lua, answer.clone())?,
+        )?;
+        let pins = Arc::new(Mutex::new(Vec::new()));
+        let last_cell_pins = pins.clone();
+        lua.globals().set(
+            "pin_last_cell",
+            lua.create_function(move |_, ()| {
+                last_cell_pins.lock().unwrap().push(PinRequest::LastCell);
+                Ok(())
+            })?,
+        )?;
+        let note_pins = pins.clone();
+        lua.globals().set(
+            "note_important",
+            lua.create_function(move |_, text: String| {
+                note_pins.lock().unwrap().push(PinRequest::Note(text));
+                Ok(())
+            })?,
+        )?;
+
+        if let Some(seed) = config.seed {
+            let math: mlua::Table = lua.globals().get("math")?;
+            math.get::<mlua::Function>("randomseed")?
+                .call::<()>(seed as i64)?;
+        }
 
         // Set the init_context as a global 'context' variable
-        lua.globals().set("context", init_context)?;
+        let initial_context = lua.create_registry_value(init_context.clone())?;
+        if config.readonly_context {
+            set_readonly_context(&lua, init_context)?;
+        } else {
+            lua.globals().set("context", init_context)?;
+        }
+        lua.globals()
+            .set("copy_context", create_copy_context_function(&lua)?)?;
+        let documents = Documents::new(&lua)?;
+        for (name, text) in &config.documents {
+            documents.add(name, lua.create_string(text)?)?;
+        }
+        lua.globals().set(
+            "add_document",
+            create_add_document_function(&lua, documents.clone())?,
+        )?;
+        lua.globals().set(
+            "get_document",
+            create_get_document_function(&lua, documents.clone())?,
+        )?;
+        lua.globals().set(
+            "list_documents",
+            create_list_documents_function(&lua, documents)?,
+        )?;
+        lua.globals().set(
+            "lua_check",
+            create_lua_check_function(&lua, config.policy.clone())?,
+        )?;
 
-        Ok(Environment { lua, output_buffer })
-    }
+        // Set extra globals; JSON nulls become nil rather than a sentinel
+        let options = mlua::SerializeOptions::new()
+            .serialize_none_to_null(false)
+            .serialize_unit_to_null(false);
+        for (name, value) in &config.globals {
+            lua.globals()
+                .set(name.as_str(), lua.to_value_with(value, options)?)?;
+        }
 
-    pub fn eval(&self, code: &str) -> Result<Option<String>> {
-        // Clear the output buffer before execution
-        self.output_buffer.lock().unwrap().clear();
+        // Register host-provided functions, which may not shadow built-ins
+        for function in &config.functions {
+            if lua.globals().contains_key(function.name.as_str())? {
+                return Err(overrides_builtin(&function.name));
+            }
+            set_host_function(&lua, function)?;
+        }
 
-        // Execute the Lua code
-        self.lua.load(code).exec()?;
+        let builtins = lua
+            .globals()
+            .pairs::<String, mlua::Value>()
+            .filter_map(|pair| pair.ok().map(|(name, _)| name))
+            .collect();
 
-        // Get the captured output
-        let output = self.output_buffer.lock().unwrap().clone();
+        Ok(Environment {
+            lua,
+            output_buffer,
+            sink,
+            progress_sink,
+            usage,
+            limiter,
+            tasks,
+            findings,
+            notes,
+            pins,
+            answer,
+            budget,
+            deadline,
+            instructions_left,
+            builtins,
+            client,
+            config,
+            initial_context,
+            host_globals: Mutex::new(Vec::new()),
+        })
+    }
 
-        if output.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(output))
+    /// Replace the Lua state with a fresh one (see [`Environment::fresh`])
+    pub fn reset(&mut self) -> Result<()> {
+        *self = self.fresh()?;
+        Ok(())
+    }
+
+    /// A new environment in the state this one was created in, as if no code
+    /// had run: only the initial context and the globals set with
+    /// [`Environment::set_global`] are carried over. Both share the usage
+    /// tracker and the per-run `llm_query` count, so their calls are counted
+    /// together and starting over does not restore the run's quota.
+    pub fn fresh(&self) -> Result<Self> {
+        let lua = self.config.stdlib.create_lua()?;
+        let context: mlua::Value = self.lua.registry_value(&self.initial_context)?;
+        let context = transfer(&context, &lua)?;
+        let fresh = Self::build(
+            lua,
+            context,
+            self.client.clone(),
+            self.config.clone(),
+            self.usage.clone(),
+            QueryLimiter {
+                cell_count: Arc::new(AtomicUsize::new(0)),
+                ..self.limiter.clone()
+            },
+        )?;
+
+        let names = self.host_globals.lock().unwrap().clone();
+        for name in &names {
+            let value: mlua::Value = self.lua.globals().get(name.as_str())?;
+            fresh.set_global(name, transfer(&value, &fresh.lua)?)?;
         }
+        Ok(fresh)
     }
-}
+
+    /// Set a global variable visible to subsequent evaluations
+    pub fn set_global(&self, name: &str, value: impl IntoLua) -> Result<()> {
+        let mut names = self.host_globals.lock().unwrap();
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+        self.lua.globals().set(name, value)
+    }
+
+    /// Register a Rust callback as a global Lua function, like
+    /// [`EnvironmentConfig::with_function`] but on an environment that
+    /// already exists. Registering a name again replaces the earlier host
+    /// function; a name that clashes with a built-in is an error. The
+    /// function is kept by [`Environment::reset`], and its description is
+    /// listed by [`Environment::functions`] for the system prompt.
+    ///
+    /// # Example
+    /// ```
+    /// use moonraker::environment::{Environment, LlmClient};
+    ///
+    /// let mut env = Environment::new("", LlmClient::Ollama("qwen3:30b".to_string()))?;
+    /// env.register_function(
+    ///     "order_status",
+    ///     "`order_status(id)` returns the status of an order.",
+    ///     |_, id: u32| Ok(if id == 42 { "shipped" } else { "unknown" }),
+    /// )?;
+    /// assert_eq!(env.eval("print(order_status(42))")?, Some("shipped".to_string()));
+    /// # Ok::<(), mlua::Error>(())
+    /// ```
+    pub fn register_function<F, A, R>(
+        &mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        callback: F,
+    ) -> Result<()>
+    where
+        F: Fn(&Lua, A) -> Result<R> + Send + Sync + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        let function = HostFunction::new(name, description, callback);
+        let existing = self
+            .config
+            .functions
+            .iter()
+            .position(|f| f.name == function.name);
+        if existing.is_none() && self.builtins.contains(&function.name) {
+            return Err(overrides_builtin(&function.name));
+        }
+        set_host_function(&self.lua, &function)?;
+        match existing {
+            Some(i) => self.config.functions[i] = function,
+            None => {
+                self.builtins.insert(function.name.clone());
+                self.config.functions.push(function);
+            }
+        }
+        Ok(())
+    }
+
+    /// The host functions available to Lua code, including those added with
+    /// [`Environment::register_function`] (see [`crate::prompt::system_prompt`])
+    pub fn functions(&self) -> &[HostFunction] {
+        &self.config.functions
+    }
+
+    /// Findings recorded with `emit_finding`, in the order their keys were first emitted
+    pub fn findings(&self) -> Vec<Finding> {
+        self.findings.lock().unwrap().clone()
+    }
+
+    /// Notes recorded with `note_add`, in the order they were added
+    pub fn notes(&self) -> Vec<Note> {
+        self.notes.lock().unwrap().clone()
+    }
+
+    /// Take the pin requests made with `pin_last_cell` and `note_important`
+    /// since the last call
+    pub fn take_pin_requests(&self) -> Vec<PinRequest> {
+        std::mem::take(&mut *self.pins.lock().unwrap())
+    }
+
+    /// Send the messages of `progress(msg)` to `sink` from now on, or drop
+    /// them with None. Unlike printed text, they are not part of the output.
+    pub fn set_progress_sink(&self, sink: Option<OutputSink>) {
+        *self.progress_sink.lock().unwrap() = sink;
+    }
+
+    /// Set what `budget()` reports from now on
+    pub fn set_budget(&self, budget: Budget) {
+        *self.budget.lock().unwrap() = budget;
+    }
+
+    /// Take the answer recorded with `final_answer` since the last call
+    pub fn take_final_answer(&self) -> Option<String> {
+        self.answer.lock().unwrap().take()
+    }
+
+    /// Replace the recorded findings, e.g. with those of a resumed session
+    pub fn restore_findings(&self, findings: Vec<Finding>) {
+        *self.findings.lock().unwrap() = findings;
+    }
+
+    /// Replace the recorded notes, e.g. with those of a resumed session
+    pub fn restore_notes(&self, notes: Vec<Note>) {
+        *self.notes.lock().unwrap() = notes;
+    }
+
+    /// Snapshot the globals defined by evaluated code. Values that cannot be
+    /// represented as JSON (functions, or tables containing them) are only
+    /// listed by name.
+    pub fn snapshot_globals(&self) -> Result<GlobalsSnapshot> {
+        let mut snapshot = GlobalsSnapshot::default();
+        for pair in self.lua.globals().pairs::<mlua::Value, mlua::Value>() {
+            let (name, value) = pair?;
+            let mlua::Value::String(name) = name else {
+                continue;
+            };
+            let name = name.to_string_lossy();
+            if self.builtins.contains(&name) {
+                continue;
+            }
+            match serde_json::to_value(&value) {
+                Ok(json) => {
+                    snapshot.values.insert(name, json);
+                }
+                Err(_) => snapshot.unserializable.push(name),
+            }
+        }
+        snapshot.unserializable.sort();
+        Ok(snapshot)
+    }
+
+    /// Set the serializable globals of a snapshot
+    pub fn restore_globals(&self, snapshot: &GlobalsSnapshot) -> Result<()> {
+        let options = mlua::SerializeOptions::new()
+            .serialize_none_to_null(false)
+            .serialize_unit_to_null(false);
+        for (name, value) in &snapshot.values {
+            self.lua
+                .globals()
+                .set(name.as_str(), self.lua.to_value_with(value, options)?)?;
+        }
+        Ok(())
+    }
+
+    /// Usage tracker recording tokens spent by `llm_query` calls from Lua
+    pub fn usage(&self) -> UsageTracker {
+        self.usage.clone()
+    }
+
+    /// Like [`Environment::eval`], but the text of each `print` call is also
+    /// passed to `sink` as soon as it is printed
+    pub fn eval_with_sink(&self, code: &str, sink: OutputSink) -> Result<Option<String>> {
+        *self.sink.lock().unwrap() = Some(sink);
+        let result = self.eval(code);
+        *self.sink.lock().unwrap() = None;
+        result
+    }
+
+    /// Run a cell and return what it printed, or None if it printed nothing.
+    ///
+    /// Like an interactive interpreter, a cell whose last line is an
+    /// expression (see [`compile_cell`]) or that ends with `return` also
+    /// outputs the values it returns, on a line starting with
+    /// [`RESULT_PREFIX`].
+    pub fn eval(&self, code: &str) -> Result<Option<String>> {
+        // Clear the output buffer before execution
+        self.output_buffer.lock().unwrap().clear();
+        self.limiter.start_cell();
+
+        // Reject code that fails the policy check without running any of it
+        if let Some(policy) = &self.config.policy
+            && let Err(violations) = policy.check(code)
+        {
+            return Err(mlua::Error::RuntimeError(format!(
+                "Policy violation: {}. Rewrite the code without these identifiers.",
+                violations.join("; ")
+            )));
+        }
+
+        // Execute the Lua code; errors refer to it as `cell:LINE:`
+        *self.deadline.lock().unwrap() = self.config.cell_timeout.map(|t| Instant::now() + t);
+        *self.instructions_left.lock().unwrap() = self.config.max_cell_instructions;
+        if let Some(max) = self.config.max_cell_memory {
+            self.lua.set_memory_limit(self.lua.used_memory() + max)?;
+        }
+        let result = compile_cell(&self.lua, code).and_then(|cell| cell.call::<MultiValue>(()));
+        // Give background tasks a time slice, still within the cell's limits
+        let task_lines = match &result {
+            Ok(_) if self.tasks.has_pending() => self.tasks.resume_all(&self.lua),
+            _ => Ok(Vec::new()),
+        };
+        *self.deadline.lock().unwrap() = None;
+        *self.instructions_left.lock().unwrap() = None;
+        if let Some(max) = self.config.max_cell_memory {
+            self.lua.set_memory_limit(0)?;
+            if let Err(mlua::Error::MemoryError(_)) = result {
+                return Err(mlua::Error::external(LimitExceeded::Memory(max)));
+            }
+        }
+        let values = result?;
+        let task_lines = task_lines?;
+
+        // Add the values the cell returned and the progress of background
+        // tasks, like printed lines
+        let sink = self.sink.lock().unwrap().clone();
+        let max = self.config.max_output_bytes;
+        for line in format_results(&values).into_iter().chain(task_lines) {
+            let appended = append_output(&mut self.output_buffer.lock().unwrap(), &line, max);
+            if let Some((sink, appended)) = sink.as_ref().zip(appended) {
+                sink(&appended);
+            }
+        }
+
+        // Get the captured output
+        let output = self.output_buffer.lock().unwrap().clone();
+
+        if output.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(output))
+        }
+    }
+}
+
+/// Marks the values a cell returns in its output
+pub const RESULT_PREFIX: &str = "=> ";
+
+/// Ends the output of a cell that printed more than
+/// [`EnvironmentConfig::max_output_bytes`]
+pub const OUTPUT_LIMIT_MARKER: &str = "[output limit reached]";
+
+/// Append `line` to a cell's output on a line of its own and return the
+/// text appended.
+///
+/// The output is kept within `max` bytes: the line that would exceed it is
+/// cut, [`OUTPUT_LIMIT_MARKER`] is added on its own line, and nothing is
+/// appended after that (the output then being longer than `max`).
+fn append_output(output: &mut String, line: &str, max: Option<usize>) -> Option<String> {
+    let separator = if output.is_empty() { "" } else { "\n" };
+    let Some(max) = max else {
+        output.push_str(separator);
+        output.push_str(line);
+        return Some(line.to_string());
+    };
+    if output.len() > max {
+        return None;
+    }
+    if output.len() + separator.len() + line.len() <= max {
+        output.push_str(separator);
+        output.push_str(line);
+        return Some(line.to_string());
+    }
+    let room = max.saturating_sub(output.len() + separator.len());
+    let mut cut = room.min(line.len());
+    while !line.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    let appended = if cut == 0 {
+        OUTPUT_LIMIT_MARKER.to_string()
+    } else {
+        format!("{}\n{OUTPUT_LIMIT_MARKER}", &line[..cut])
+    };
+    output.push_str(separator);
+    // The marker is longer than the room left, so the output now exceeds max
+    output.push_str(&appended);
+    Some(appended)
+}
+
+/// Compile a cell, returning the value of its last line if that line is an
+/// expression, so that `x + 1` on its own line shows its value.
+///
+/// A one-line cell is returned whenever it is an expression. In a longer
+/// cell, the last line must also start like an expression that can't
+/// continue the line before it (a name, a number, `#` or `not`), and the
+/// lines before it must compile on their own, so the rewrite never changes
+/// what a cell does, e.g. by reaching into a long string or comment.
+fn compile_cell(lua: &Lua, code: &str) -> Result<mlua::Function> {
+    let compile = |source: &str| lua.load(source).set_name("=cell").into_function();
+    let code = code.trim_end();
+    let with_return = match code.rsplit_once('\n') {
+        None => Some(format!("return {code}")),
+        Some((body, last)) => {
+            let starts_expression = last
+                .trim_start()
+                .starts_with(|c: char| c.is_alphanumeric() || c == '_' || c == '#');
+            let standalone = || compile(body).is_ok() && compile(&format!("return {last}")).is_ok();
+            (starts_expression && standalone()).then(|| format!("{body}\nreturn {last}"))
+        }
+    };
+    match with_return.map(|source| compile(&source)) {
+        Some(Ok(function)) => Ok(function),
+        _ => compile(code),
+    }
+}
+
+/// The output line for the values a cell returned, or None if it returned
+/// nothing. Tables are shown as JSON when possible.
+fn format_results(values: &MultiValue) -> Option<String> {
+    if values.is_empty() {
+        return None;
+    }
+    let strings: Vec<String> = values
+        .iter()
+        .map(|value| match value {
+            mlua::Value::Table(_) => serde_json::to_string(value)
+                .unwrap_or_else(|_| value.to_string().unwrap_or_default()),
+            value => value.to_string().unwrap_or_else(|_| format!("{value:?}")),
+        })
+        .collect();
+    Some(format!("{RESULT_PREFIX}{}", strings.join("\t")))
+}
+
+/// Set a host function as a global of `lua`
+fn set_host_function(lua: &Lua, function: &HostFunction) -> Result<()> {
+    let callback = function.callback.clone();
+    lua.globals().set(
+        function.name.as_str(),
+        lua.create_function(move |lua, args: MultiValue| callback(lua, args))?,
+    )
+}
+
+fn overrides_builtin(name: &str) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("host function '{name}' would override a built-in"))
+}
+
+/// Copy a value into another Lua state: strings byte for byte, tables via JSON
+fn transfer(value: &mlua::Value, to: &Lua) -> Result<mlua::Value> {
+    match value {
+        mlua::Value::String(s) => Ok(mlua::Value::String(to.create_string(s.as_bytes())?)),
+        mlua::Value::Table(_) => {
+            let json = serde_json::to_value(value).map_err(mlua::Error::external)?;
+            let options = mlua::SerializeOptions::new()
+                .serialize_none_to_null(false)
+                .serialize_unit_to_null(false);
+            to.to_value_with(&json, options)
+        }
+        mlua::Value::Nil
+        | mlua::Value::Boolean(_)
+        | mlua::Value::Integer(_)
+        | mlua::Value::Number(_) => Ok(value.clone()),
+        other => Err(mlua::Error::RuntimeError(format!(
+            "cannot copy a {} into a new environment",
+            other.type_name()
+        ))),
+    }
+}
+
+/// Set `context` as a global that can't be assigned to.
+///
+/// The value is kept in the `__index` table of the globals' metatable rather
+/// than in the globals table itself, so reading `context` finds it while
+/// assigning to it reaches `__newindex`, which raises an error. Other globals
+/// are assigned as usual. The metatable is locked against `setmetatable`.
+/// The contents of a table context can still be modified.
+fn set_readonly_context(lua: &Lua, context: mlua::Value) -> Result<()> {
+    let index = lua.create_table()?;
+    index.set("context", context)?;
+    let metatable = lua.create_table()?;
+    metatable.set("__index", index)?;
+    metatable.set(
+        "__newindex",
+        lua.create_function(
+            |_, (globals, name, value): (mlua::Table, mlua::Value, mlua::Value)| {
+                if matches!(&name, mlua::Value::String(name) if name == "context") {
+                    return Err(mlua::Error::RuntimeError(
+                        "context is read-only: call copy_context() for a copy you can modify, or use another variable".to_string(),
+                    ));
+                }
+                globals.raw_set(name, value)
+            },
+        )?,
+    )?;
+    metatable.set("__metatable", false)?;
+    lua.globals().set_metatable(Some(metatable))
+}
+
+/// Creates the `lua_check(code)` function for validating generated code.
+///
+/// # Lua Signature
+/// ```lua
+/// ok, err = lua_check(code)
+/// ```
+///
+/// # Behavior
+/// - Compiles `code` as Lua source without running it; precompiled
+///   bytecode is rejected
+/// - Also applies the environment's policy, if any, so code that a cell
+///   could not contain fails the check too
+/// - Returns `true`, or `false` and the error, e.g.
+///   `check:3: 'end' expected near <eof>`
+///
+/// # Example
+/// ```lua
+/// local ok, err = lua_check(helper_source)
+/// if not ok then print("helper is invalid: " .. err) end
+/// ```
+fn create_lua_check_function(lua: &Lua, policy: Option<PolicyChecker>) -> Result<mlua::Function> {
+    lua.create_function(move |lua, code: String| {
+        let compiled = lua
+            .load(code.as_str())
+            .set_name("=check")
+            .set_mode(mlua::ChunkMode::Text)
+            .into_function();
+        let error = match compiled {
+            Err(mlua::Error::SyntaxError { message, .. }) => Some(message),
+            Err(e) => Some(e.to_string()),
+            Ok(_) => policy
+                .as_ref()
+                .and_then(|policy| policy.check(&code).err())
+                .map(|violations| format!("Policy violation: {}", violations.join("; "))),
+        };
+        Ok((error.is_none(), error))
+    })
+}
+
+/// Creates the `copy_context()` function, which returns a copy of `context`
+/// that can be modified without affecting it: tables are copied deeply via
+/// JSON, and strings, which are immutable, are returned as they are.
+fn create_copy_context_function(lua: &Lua) -> Result<mlua::Function> {
+    lua.create_function(|lua, ()| {
+        let context: mlua::Value = lua.globals().get("context")?;
+        transfer(&context, lua)
+    })
+}
 
 /// Creates the custom `print(...)` function that captures output to a buffer.
 ///
@@ -88,9 +1595,16 @@ impl Environment {
 /// - Converts arguments to strings and joins them with tabs
 /// - Appends output to internal buffer (doesn't print to stdout)
 /// - Separates multiple print calls with newlines
-fn create_print_function(lua: &Lua, output_buffer: Arc<Mutex<String>>) -> Result<mlua::Function> {
+/// - Passes the text to the output sink, if one is set
+/// - Stops at `max_bytes` of output (see [`append_output`]), so a cell
+///   printing in a tight loop can't build an enormous buffer
+fn create_print_function(
+    lua: &Lua,
+    output_buffer: Arc<Mutex<String>>,
+    sink: Arc<Mutex<Option<OutputSink>>>,
+    max_bytes: Option<usize>,
+) -> Result<mlua::Function> {
     lua.create_function(move |_lua, args: mlua::Variadic<mlua::Value>| {
-        let mut output = output_buffer.lock().unwrap();
         let strings: Vec<String> = args
             .iter()
             .map(|v| {
@@ -98,196 +1612,1458 @@ fn create_print_function(lua: &Lua, output_buffer: Arc<Mutex<String>>) -> Result
                 v.to_string().unwrap_or_else(|_| format!("{v:?}"))
             })
             .collect();
-        if !output.is_empty() {
-            output.push('\n');
+        let line = strings.join("\t");
+        let appended = append_output(&mut output_buffer.lock().unwrap(), &line, max_bytes);
+        let sink = sink.lock().unwrap().clone();
+        if let Some((sink, appended)) = sink.zip(appended) {
+            sink(&appended);
         }
-        output.push_str(&strings.join("\t"));
         Ok(())
     })
 }
 
-/// Creates the custom `llm_query(prompt)` function for querying language models.
+/// Creates the `progress(msg)` function for status messages from long cells.
+///
+/// # Lua Signature
+/// ```lua
+/// progress(msg)
+/// ```
+///
+/// # Behavior
+/// - Passes `msg` (a string or number) to the progress sink right away, so a
+///   cell working for minutes can show how far it got
+/// - Does nothing without a sink, and never adds to the cell's output, so
+///   the model does not see the messages
+///
+/// # Example
+/// ```lua
+/// for i, chunk in ipairs(chunks) do
+///   progress("chunk " .. i .. " of " .. #chunks)
+///   results[i] = llm_query("Summarize: " .. chunk)
+/// end
+/// ```
+fn create_progress_function(
+    lua: &Lua,
+    sink: Arc<Mutex<Option<OutputSink>>>,
+) -> Result<mlua::Function> {
+    lua.create_function(move |_, message: mlua::String| {
+        let sink = sink.lock().unwrap().clone();
+        if let Some(sink) = sink {
+            sink(&message.to_string_lossy());
+        }
+        Ok(())
+    })
+}
+
+/// Creates the `emit_finding(key, value)` function for recording structured findings.
+///
+/// # Lua Signature
+/// ```lua
+/// emit_finding(key, value)
+/// ```
+///
+/// # Parameters
+/// - `key` (string) - Name of the finding; emitting the same key again replaces its value
+/// - `value` (any) - Strings, numbers, and booleans are stored as text; tables are stored as JSON
+///
+/// # Behavior
+/// - Findings are kept outside the print buffer, so they are never truncated
+/// - They are shown to the model in every prompt and returned with the final answer
+///
+/// # Example
+/// ```lua
+/// emit_finding("error_count", 42)
+/// emit_finding("top_errors", {"timeout", "disk full"})
+/// ```
+fn create_emit_finding_function(
+    lua: &Lua,
+    findings: Arc<Mutex<Vec<Finding>>>,
+) -> Result<mlua::Function> {
+    lua.create_function(move |_lua, (key, value): (String, mlua::Value)| {
+        let value = match &value {
+            mlua::Value::Table(_) => serde_json::to_string(&value)
+                .unwrap_or_else(|_| value.to_string().unwrap_or_default()),
+            _ => value.to_string()?,
+        };
+        let mut findings = findings.lock().unwrap();
+        match findings.iter_mut().find(|f| f.key == key) {
+            Some(finding) => finding.value = value,
+            None => findings.push(Finding { key, value }),
+        }
+        Ok(())
+    })
+}
+
+/// Creates the `budget()` function reporting what is left of the run.
+///
+/// # Lua Signature
+/// ```lua
+/// b = budget()
+/// ```
+///
+/// # Returns
+/// - (table) - `iterations_left` (steps after this one), `tokens_used`,
+///   `tokens_left`, `queries_left` (`llm_query` calls left in the run), and
+///   `elapsed` (seconds since the run started); limits that don't apply are nil
+///
+/// # Behavior
+/// - Iterations and tokens come from the [`Budget`] set by the loop driving
+///   the environment, and are nil when nothing set them
+///
+/// # Example
+/// ```lua
+/// if (budget().iterations_left or math.huge) < 2 then final_answer(draft) end
+/// ```
+fn create_budget_function(
+    lua: &Lua,
+    budget: Arc<Mutex<Budget>>,
+    limiter: QueryLimiter,
+) -> Result<mlua::Function> {
+    let created = Instant::now();
+    lua.create_function(move |lua, ()| {
+        let budget = *budget.lock().unwrap();
+        let result = lua.create_table()?;
+        result.set("iterations_left", budget.iterations_left)?;
+        result.set("tokens_used", budget.tokens_used)?;
+        result.set("tokens_left", budget.tokens_left)?;
+        result.set("queries_left", limiter.run_remaining())?;
+        let started = budget.started.unwrap_or(created);
+        result.set("elapsed", started.elapsed().as_secs_f64())?;
+        Ok(result)
+    })
+}
+
+/// Creates the `final_answer(text)` function for recording the answer to the query.
+///
+/// # Lua Signature
+/// ```lua
+/// final_answer(text)
+/// ```
+///
+/// # Parameters
+/// - `text` (string) - The complete answer; numbers are converted to text
+///
+/// # Behavior
+/// - The answer is kept outside the print buffer, so it is never truncated
+/// - Calling it again replaces the answer; the cell that calls it ends the run
+///
+/// # Example
+/// ```lua
+/// final_answer("The log contains " .. count .. " errors")
+/// ```
+fn create_final_answer_function(
+    lua: &Lua,
+    answer: Arc<Mutex<Option<String>>>,
+) -> Result<mlua::Function> {
+    lua.create_function(move |_lua, text: String| {
+        *answer.lock().unwrap() = Some(text);
+        Ok(())
+    })
+}
+
+/// Options of an `llm_query` call, from its optional second argument
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QueryOptions {
+    /// System prompt of the sub-query
+    system: Option<String>,
+    /// Model to query instead of the run's model, from the same provider
+    model: Option<String>,
+    temperature: Option<f64>,
+    max_tokens: Option<u64>,
+    /// Whether a failed sub-query raises an error (the default) rather than
+    /// returning nil and the error message
+    raise: Option<bool>,
+}
+
+impl QueryOptions {
+    /// Read the options table passed from Lua; unknown keys are rejected
+    /// so that typos don't go unnoticed
+    fn from_lua(lua: &Lua, options: Option<mlua::Table>) -> Result<Self> {
+        match options {
+            Some(table) => lua
+                .from_value(mlua::Value::Table(table))
+                .map_err(|e| mlua::Error::RuntimeError(format!("invalid llm_query options: {e}"))),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// The result of a sub-query as Lua values. With `raise=false`, a failed
+    /// query becomes `nil, message`; timeouts and exceeded limits still raise.
+    fn recover(&self, lua: &Lua, result: Result<impl IntoLua>) -> Result<MultiValue> {
+        match result {
+            Ok(value) => value.into_lua_multi(lua),
+            Err(e) if self.raise == Some(false) && ErrorKind::of(&e) == ErrorKind::LlmQuery => {
+                (mlua::Value::Nil, e.to_string()).into_lua_multi(lua)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn apply<M: CompletionModel>(&self, mut agent: AgentBuilder<M>) -> AgentBuilder<M> {
+        if let Some(system) = &self.system {
+            agent = agent.preamble(system);
+        }
+        if let Some(temperature) = self.temperature {
+            agent = agent.temperature(temperature);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            agent = agent.max_tokens(max_tokens);
+        }
+        agent
+    }
+}
+
+/// Send one sub-query to the configured provider, recording its token usage
+async fn query(
+    client: &LlmClient,
+    usage: &UsageTracker,
+    prompt: &str,
+    options: &QueryOptions,
+) -> Result<String> {
+    // Execute prompt based on client type
+    let response = match client {
+        LlmClient::Ollama(model) => {
+            let client = ollama::Client::new();
+            let agent = client
+                .agent(options.model.as_deref().unwrap_or(model))
+                .additional_params(json!({"think": false}));
+            options
+                .apply(agent)
+                .build()
+                .prompt(prompt)
+                .extended_details()
+                .await
+        }
+        LlmClient::Openrouter(model, api_key) => {
+            let client = openrouter::Client::new(api_key);
+            let agent = client.agent(options.model.as_deref().unwrap_or(model));
+            options
+                .apply(agent)
+                .build()
+                .prompt(prompt)
+                .extended_details()
+                .await
+        }
+    };
+
+    match response {
+        Ok(response) => {
+            usage.record(UsageCategory::LlmQuery, response.total_usage);
+            Ok(response.output)
+        }
+        Err(e) => Err(mlua::Error::external(LlmQueryError(format!(
+            "LLM query failed: {e}"
+        )))),
+    }
+}
+
+/// Send sub-queries concurrently, at most `concurrency` at a time, returning
+/// the responses in the order of `prompts`. Fails with the first error, which
+/// cancels the queries still running.
+async fn query_all(
+    client: &LlmClient,
+    usage: &UsageTracker,
+    prompts: Vec<String>,
+    options: &QueryOptions,
+    concurrency: usize,
+) -> Result<Vec<String>> {
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(concurrency.max(1)));
+    let mut tasks = tokio::task::JoinSet::new();
+    let count = prompts.len();
+    for (i, prompt) in prompts.into_iter().enumerate() {
+        let (client, usage, options) = (client.clone(), usage.clone(), options.clone());
+        let semaphore = semaphore.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            (i, query(&client, &usage, &prompt, &options).await)
+        });
+    }
+
+    let mut responses = vec![String::new(); count];
+    while let Some(joined) = tasks.join_next().await {
+        let (i, response) = joined.map_err(mlua::Error::external)?;
+        responses[i] = response.map_err(|e| {
+            mlua::Error::external(LlmQueryError(format!(
+                "llm_map: prompt {} failed: {e}",
+                i + 1
+            )))
+        })?;
+    }
+    Ok(responses)
+}
+
+/// Runtime providing timers, sockets, and spawned tasks to the futures of
+/// Lua callbacks when the caller's runtime can't block (see
+/// [`block_on_until`])
+static BRIDGE: LazyLock<tokio::runtime::Runtime> = LazyLock::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name("moonraker-bridge")
+        .enable_all()
+        .build()
+        .expect("failed to start the runtime for Lua callbacks")
+});
+
+thread_local! {
+    /// Whether this thread is polling a future in [`park_until_ready`], in
+    /// which case it is not a worker of the runtime whose context is entered
+    static PARKING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Run `future` to completion from a Lua callback, failing with
+/// [`CellTimeout`] if the cell's deadline (`limit`) passes first.
+///
+/// On a multi-threaded tokio runtime the future runs on that runtime, with
+/// the worker handing its other tasks off while it blocks. Anywhere else (a
+/// current-thread runtime, or no runtime at all) it is polled on the
+/// calling thread with the [`BRIDGE`] runtime's context entered, so
+/// environments work on any runtime flavor. The caller's runtime then
+/// stalls until the future completes, so the future must not wait on tasks
+/// of that runtime.
+pub(crate) fn block_on_until<T>(
+    limit: Option<(Instant, Duration)>,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let future = async {
+        match limit {
+            Some((deadline, timeout)) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                tokio::time::timeout(remaining, future)
+                    .await
+                    .map_err(|_| mlua::Error::external(CellTimeout(timeout)))?
+            }
+            None => future.await,
+        }
+    };
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle)
+            if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread
+                && !PARKING.get() =>
+        {
+            tokio::task::block_in_place(|| handle.block_on(future))
+        }
+        _ => {
+            let _context = BRIDGE.enter();
+            park_until_ready(future)
+        }
+    }
+}
+
+/// Poll `future` on the current thread, parking it while the future waits.
+///
+/// Each call has its own wake flag, so a nested call (a callback blocking
+/// inside a future that is being polled) can't swallow a wakeup meant for
+/// the call around it.
+fn park_until_ready<F: Future>(future: F) -> F::Output {
+    struct ThreadWaker {
+        thread: std::thread::Thread,
+        woken: AtomicBool,
+    }
+
+    impl std::task::Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.woken.store(true, Ordering::Release);
+            self.thread.unpark();
+        }
+    }
+
+    let waker = Arc::new(ThreadWaker {
+        thread: std::thread::current(),
+        woken: AtomicBool::new(false),
+    });
+    let context_waker = std::task::Waker::from(waker.clone());
+    let mut context = std::task::Context::from_waker(&context_waker);
+    let mut future = std::pin::pin!(future);
+    let parking = PARKING.replace(true);
+    let output = loop {
+        if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            break output;
+        }
+        while !waker.woken.swap(false, Ordering::Acquire) {
+            std::thread::park();
+        }
+    };
+    PARKING.set(parking);
+    output
+}
+
+/// Creates the custom `llm_query(prompt, options)` function for querying language models.
 ///
 /// # Lua Signature
 /// ```lua
 /// response = llm_query(prompt)
+/// response = llm_query(prompt, {system=..., model=..., temperature=..., max_tokens=...})
+/// response, err = llm_query(prompt, {raise=false})
 /// ```
 ///
 /// # Parameters
 /// - `prompt` (string) - The prompt to send to the LLM
+/// - `options` (table, optional) - Any of `system` (system prompt), `model`
+///   (another model of the same provider, e.g. a smaller one), `temperature`,
+///   `max_tokens` (maximum tokens of the response), and `raise` (false to
+///   return provider errors instead of raising them)
 ///
 /// # Returns
 /// - (string) - The LLM's response text
+/// - With `raise=false`, nil and the error message if the query failed
 ///
 /// # Important Notes
 /// - The LLM does **NOT** have access to the `context` variable
 /// - You must include all relevant information in the prompt string
 /// - Uses the configured LLM provider (Ollama or OpenRouter)
 /// - Blocks until response is received
+/// - Token usage is recorded under [`UsageCategory::LlmQuery`]
+/// - Raises an error once the per-cell or per-run call limit in
+///   [`EnvironmentConfig`] is reached, or if `options` has unknown keys
+/// - Raises [`CellTimeout`] if the response does not arrive before the
+///   cell's deadline
 ///
 /// # Example
 /// ```lua
 /// summary = llm_query("Summarize this: " .. context)
+/// label = llm_query("Classify: " .. line, {model="qwen3:4b", temperature=0, max_tokens=5})
+/// summary, err = llm_query("Summarize this: " .. chunk, {raise=false})
+/// if not summary then print("skipped: " .. err) end
+/// ```
+fn create_llm_query_function(
+    lua: &Lua,
+    client: LlmClient,
+    usage: UsageTracker,
+    limiter: QueryLimiter,
+    deadline: Arc<Mutex<Option<Instant>>>,
+    timeout: Option<Duration>,
+) -> Result<mlua::Function> {
+    lua.create_function(
+        move |lua, (prompt, options): (String, Option<mlua::Table>)| {
+            let options = QueryOptions::from_lua(lua, options)?;
+            limiter.acquire()?;
+            let limit = deadline.lock().unwrap().zip(timeout);
+            let response = block_on_until(limit, query(&client, &usage, &prompt, &options));
+            options.recover(lua, response)
+        },
+    )
+}
+
+/// Number of `llm_map` queries in flight at once unless `concurrency` is given
+pub const DEFAULT_MAP_CONCURRENCY: usize = 4;
+
+/// Creates the `llm_map(prompts, options)` function for running many
+/// sub-queries concurrently.
+///
+/// # Lua Signature
+/// ```lua
+/// responses = llm_map(prompts)
+/// responses = llm_map(prompts, {concurrency=8, system=..., model=..., temperature=..., max_tokens=...})
+/// ```
+///
+/// # Parameters
+/// - `prompts` (table) - Sequence of prompt strings
+/// - `options` (table, optional) - `concurrency`, the number of queries in
+///   flight at once ([`DEFAULT_MAP_CONCURRENCY`] by default), and any option of
+///   `llm_query`, applied to every prompt
+///
+/// # Returns
+/// - (table) - The responses, in the order of the prompts
+///
+/// # Behavior
+/// - Each prompt counts as one `llm_query` call; if the batch would exceed a
+///   call limit, none are sent
+/// - Fails with the first failed query, cancelling the rest; with
+///   `raise=false`, returns nil and its error message instead
+///
+/// # Example
+/// ```lua
+/// prompts = {}
+/// for i, chunk in ipairs(chunk_context("paragraph", 8000, 200)) do
+///   prompts[i] = "Extract key facts from: " .. chunk
+/// end
+/// facts = llm_map(prompts, {concurrency=8})
 /// ```
-fn create_llm_query_function(lua: &Lua, client: LlmClient) -> Result<mlua::Function> {
-    lua.create_function(move |_lua, prompt: String| {
-        // Use tokio's block_in_place to call async code from sync context
-        tokio::task::block_in_place(|| {
-            tokio::runtime::Handle::current().block_on(async {
-                // Execute prompt based on client type
-                let response = match &client {
-                    LlmClient::Ollama(model) => {
-                        let client = ollama::Client::new();
-                        let agent = client
-                            .agent(model)
-                            .additional_params(json!({"think": false}))
-                            .build();
-                        agent.prompt(&prompt).await
+fn create_llm_map_function(
+    lua: &Lua,
+    client: LlmClient,
+    usage: UsageTracker,
+    limiter: QueryLimiter,
+    deadline: Arc<Mutex<Option<Instant>>>,
+    timeout: Option<Duration>,
+) -> Result<mlua::Function> {
+    lua.create_function(
+        move |lua, (prompts, options): (Vec<String>, Option<mlua::Table>)| {
+            // `concurrency` is read here; the remaining keys are query options
+            let mut concurrency = DEFAULT_MAP_CONCURRENCY;
+            let options = match options {
+                Some(table) => {
+                    let rest = lua.create_table()?;
+                    for pair in table.pairs::<mlua::Value, mlua::Value>() {
+                        let (key, value) = pair?;
+                        match &key {
+                            mlua::Value::String(name) if name == "concurrency" => {
+                                concurrency = lua.unpack(value)?;
+                            }
+                            _ => rest.raw_set(key, value)?,
+                        }
                     }
-                    LlmClient::Openrouter(model, api_key) => {
-                        let client = openrouter::Client::new(api_key);
-                        let agent = client.agent(model).build();
-                        agent.prompt(&prompt).await
+                    QueryOptions::from_lua(lua, Some(rest))?
+                }
+                None => QueryOptions::default(),
+            };
+
+            if prompts.is_empty() {
+                return lua.create_table()?.into_lua_multi(lua);
+            }
+            limiter.acquire_many(prompts.len())?;
+            let limit = deadline.lock().unwrap().zip(timeout);
+            let responses = block_on_until(
+                limit,
+                query_all(&client, &usage, prompts, &options, concurrency),
+            );
+            options.recover(lua, responses)
+        },
+    )
+}
+
+/// Creates the `llm_query_json(prompt, schema_hint, options)` function for
+/// sub-queries whose answer is structured data.
+///
+/// # Lua Signature
+/// ```lua
+/// result = llm_query_json(prompt, schema_hint)
+/// result = llm_query_json(prompt, schema_hint, options)
+/// ```
+///
+/// # Parameters
+/// - `prompt` (string) - The prompt to send to the LLM
+/// - `schema_hint` (string) - The shape of the expected JSON, e.g. an example
+///   value or a description of its fields
+/// - `options` (table, optional) - As for `llm_query`
+///
+/// # Returns
+/// - (table, string, number, or boolean) - The parsed answer; JSON objects
+///   become tables keyed by name and arrays become sequences
+///
+/// # Behavior
+/// - Asks for JSON only, then parses it leniently (see [`crate::parse::parse_json`]),
+///   so fenced, truncated, or prose-wrapped answers are repaired
+/// - Counts as one `llm_query` call, and fails like it; additionally fails if
+///   no JSON could be recovered from the answer
+///
+/// # Example
+/// ```lua
+/// person = llm_query_json("Who wrote this? " .. chunk, '{"name": string, "year": number}')
+/// print(person.name, person.year)
+/// ```
+fn create_llm_query_json_function(
+    lua: &Lua,
+    client: LlmClient,
+    usage: UsageTracker,
+    limiter: QueryLimiter,
+    deadline: Arc<Mutex<Option<Instant>>>,
+    timeout: Option<Duration>,
+) -> Result<mlua::Function> {
+    lua.create_function(
+        move |lua, (prompt, schema_hint, options): (String, String, Option<mlua::Table>)| {
+            let options = QueryOptions::from_lua(lua, options)?;
+            limiter.acquire()?;
+            let limit = deadline.lock().unwrap().zip(timeout);
+            let prompt = format!(
+                "{prompt}\n\nRespond with only a JSON value, without any other text or code fences, in this format:\n{schema_hint}"
+            );
+            let value = block_on_until(limit, query(&client, &usage, &prompt, &options))
+                .and_then(|response| {
+                    crate::parse::parse_json(&response).ok_or_else(|| {
+                        let start: String = response.chars().take(200).collect();
+                        mlua::Error::external(LlmQueryError(format!(
+                            "llm_query_json: the answer was not valid JSON: {start}"
+                        )))
+                    })
+                })
+                .and_then(|value| {
+                    let serialize = mlua::SerializeOptions::new()
+                        .serialize_none_to_null(false)
+                        .serialize_unit_to_null(false);
+                    lua.to_value_with(&value, serialize)
+                });
+            options.recover(lua, value)
+        },
+    )
+}
+
+/// Creates the `rlm_query(prompt, sub_context)` function, which answers a
+/// query with a nested Rlm over a context of its own.
+///
+/// # Lua Signature
+/// ```lua
+/// answer = rlm_query(prompt, sub_context)
+/// ```
+///
+/// # Parameters
+/// - `prompt` (string) - The query for the nested Rlm
+/// - `sub_context` (string) - The context the nested Rlm explores, e.g. one
+///   chunk of `context`
+///
+/// # Returns
+/// - (string) - The nested Rlm's final output, or an empty string if it
+///   produced none
+///
+/// # Behavior
+/// - The nested Rlm uses the same provider and model, and an environment
+///   configured like this one with one less level of depth; it fails at
+///   depth 0 (see [`EnvironmentConfig::max_rlm_depth`])
+/// - It stops at its final cell or after
+///   [`EnvironmentConfig::sub_rlm_iterations`] steps
+/// - Counts as one `llm_query` call against the limits of this environment,
+///   and the nested Rlm's own calls count against its per-run limit too
+/// - Its cell generations are recorded under [`UsageCategory::SubRlm`], and
+///   its other usage under the usual categories
+/// - Raises [`CellTimeout`] if it does not finish before the cell's deadline
+///
+/// # Example
+/// ```lua
+/// answers = {}
+/// for i, chunk in ipairs(chunk_context("paragraph", 50000)) do
+///   answers[i] = rlm_query("List every date mentioned, with its event", chunk)
+/// end
+/// ```
+fn create_rlm_query_function(
+    lua: &Lua,
+    client: LlmClient,
+    config: EnvironmentConfig,
+    usage: UsageTracker,
+    limiter: QueryLimiter,
+    deadline: Arc<Mutex<Option<Instant>>>,
+) -> Result<mlua::Function> {
+    lua.create_function(move |_, (prompt, sub_context): (String, String)| {
+        if config.max_rlm_depth == 0 {
+            return Err(mlua::Error::RuntimeError(
+                "rlm_query: maximum nesting depth reached, use llm_query instead".to_string(),
+            ));
+        }
+        limiter.acquire()?;
+
+        let environment = nested_config(&config, &limiter);
+        let system_prompt =
+            crate::prompt::system_prompt(crate::prompt::SYSTEM_PROMPT, &environment.functions);
+        let provider = crate::rlm::RigProvider::from_llm_client(&client, system_prompt);
+        let rlm_config = crate::rlm::RlmConfig::default().with_environment(environment);
+        let mut rlm =
+            crate::rlm::Rlm::with_config(provider, prompt, sub_context, client.clone(), rlm_config)
+                .map_err(|e| mlua::Error::external(LlmQueryError(format!("rlm_query: {e}"))))?;
+
+        let limit = deadline.lock().unwrap().zip(config.cell_timeout);
+        let answer = block_on_until(limit, async {
+            rlm.run_until_final(config.sub_rlm_iterations)
+                .await
+                .map_err(|e| mlua::Error::external(LlmQueryError(format!("rlm_query: {e}"))))
+        });
+        // Recorded also when the nested Rlm failed or ran out of time
+        for (category, used) in rlm.usage_breakdown().iter() {
+            match category {
+                UsageCategory::Generation => usage.record(UsageCategory::SubRlm, used),
+                category => usage.record(category, used),
+            }
+        }
+        Ok(answer?.unwrap_or_default())
+    })
+}
+
+/// Configuration of the environment of a nested Rlm started by `rlm_query`:
+/// one level less deep, and counting its `llm_query` calls with `limiter`'s
+/// per-run count
+fn nested_config(config: &EnvironmentConfig, limiter: &QueryLimiter) -> EnvironmentConfig {
+    EnvironmentConfig {
+        max_rlm_depth: config.max_rlm_depth.saturating_sub(1),
+        parent_run_queries: Some(limiter.run_count.clone()),
+        ..config.clone()
+    }
+}
+
+/// Creates the `retry(fn, attempts, backoff_ms)` function for recovering
+/// from transient failures, e.g. of `llm_query`.
+///
+/// # Lua Signature
+/// ```lua
+/// result = retry(fn)
+/// result = retry(fn, attempts, backoff_ms)
+/// ```
+///
+/// # Parameters
+/// - `fn` (function) - Called without arguments
+/// - `attempts` (number, optional) - Calls in total before giving up (3 by default)
+/// - `backoff_ms` (number, optional) - Delay before the second call, doubled
+///   before each further call (1000 by default)
+///
+/// # Returns
+/// - The return values of the first call that did not raise an error
+///
+/// # Behavior
+/// - Raises the error of the last call once all attempts failed
+/// - Timeouts and exceeded limits are raised at once, since calling again
+///   cannot succeed
+/// - Waiting counts towards the cell's timeout
+///
+/// # Example
+/// ```lua
+/// summary = retry(function() return llm_query("Summarize: " .. chunk) end, 4, 500)
+/// ```
+fn create_retry_function(
+    lua: &Lua,
+    deadline: Arc<Mutex<Option<Instant>>>,
+    timeout: Option<Duration>,
+) -> Result<mlua::Function> {
+    lua.create_function(
+        move |_, (f, attempts, backoff_ms): (mlua::Function, Option<u32>, Option<u64>)| {
+            let attempts = attempts.unwrap_or(3).max(1);
+            let mut backoff = Duration::from_millis(backoff_ms.unwrap_or(1000));
+            let mut attempt = 1;
+            loop {
+                match f.call::<MultiValue>(()) {
+                    Ok(values) => return Ok(values),
+                    Err(e)
+                        if attempt >= attempts
+                            || matches!(
+                                ErrorKind::of(&e),
+                                ErrorKind::Timeout | ErrorKind::Limit
+                            ) =>
+                    {
+                        return Err(e);
                     }
-                };
+                    Err(e) => tracing::debug!("retry: attempt {attempt}/{attempts} failed: {e}"),
+                }
+                let limit = deadline.lock().unwrap().zip(timeout);
+                block_on_until(limit, async {
+                    tokio::time::sleep(backoff).await;
+                    Ok(())
+                })?;
+                backoff *= 2;
+                attempt += 1;
+            }
+        },
+    )
+}
+
+/// Creates the `sleep(ms)` function, which pauses the cell for `ms`
+/// milliseconds, e.g. to back off from a rate-limited provider. The pause
+/// counts towards the cell's timeout.
+fn create_sleep_function(
+    lua: &Lua,
+    deadline: Arc<Mutex<Option<Instant>>>,
+    timeout: Option<Duration>,
+) -> Result<mlua::Function> {
+    lua.create_function(move |_, ms: u64| {
+        let limit = deadline.lock().unwrap().zip(timeout);
+        block_on_until(limit, async {
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            Ok(())
+        })
+    })
+}
+
+/// Creates the custom `token_trunc(text, n)` function for truncating strings
+/// by token count, and its `token_trunc_tail` and `token_trunc_middle` variants.
+///
+/// # Lua Signature
+/// ```lua
+/// truncated = token_trunc(text, n)
+/// truncated = token_trunc_tail(text, n)
+/// truncated = token_trunc_middle(text, n)
+/// ```
+///
+/// # Parameters
+/// - `text` (string) - The text to truncate
+/// - `n` (number) - Maximum number of tokens to keep
+///
+/// # Returns
+/// - (string) - The truncated text: `token_trunc` keeps the beginning,
+///   `token_trunc_tail` the end, and `token_trunc_middle` both ends, joined
+///   by a `...` line
+///
+/// # Behavior
+/// - Uses the tokenizer in [`EnvironmentConfig::tokenizer`]
+/// - If text has at most n tokens, returns the original text unchanged
+/// - Cuts only at character boundaries, so a token split across a
+///   multi-byte character may be dropped
+/// - Useful for staying within LLM token limits
+///
+/// # Example
+/// ```lua
+/// short_text = token_trunc(long_text, 100)
+/// chunk = token_trunc(string.sub(context, 1, 5000), 50)
+/// last_lines = token_trunc_tail(log, 200)
+/// ```
+fn create_token_trunc_function(
+    lua: &Lua,
+    tokenizer: Tokenizer,
+    keep: Truncation,
+) -> Result<mlua::Function> {
+    lua.create_function(move |_lua, (s, n): (String, usize)| {
+        // Get the shared BPE tokenizer
+        let bpe = tokenizer.bpe().ok_or_else(|| {
+            mlua::Error::RuntimeError(format!("Failed to load the {tokenizer:?} tokenizer"))
+        })?;
+
+        let tokens = bpe.encode_with_special_tokens(&s);
+        if tokens.len() <= n {
+            return Ok(s);
+        }
+
+        // Byte offset of every token boundary
+        let mut offsets = vec![0];
+        for token in bpe._decode_native_and_split(tokens) {
+            offsets.push(offsets[offsets.len() - 1] + token.len());
+        }
+        let count = offsets.len() - 1;
+        let head = |n: usize| &s[..s.floor_char_boundary(offsets[n])];
+        let tail = |n: usize| &s[s.ceil_char_boundary(offsets[count - n])..];
+
+        Ok(match keep {
+            Truncation::Head => head(n).to_string(),
+            Truncation::Tail => tail(n).to_string(),
+            Truncation::HeadAndTail => format!("{}\n...\n{}", head(n - n / 2), tail(n / 2)),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_basic_print() {
+        let env = Environment::new("initial", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        let result = env.eval(r#"print("hello moon")"#).unwrap();
+        assert_eq!(result, Some("hello moon".to_string()));
+    }
+
+    #[test]
+    fn test_no_output() {
+        let env = Environment::new("initial", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        let result = env.eval("x = 5").unwrap();
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn test_persistent_state() {
+        let env = Environment::new("initial", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+
+        // Set a variable
+        let result = env.eval("x = 5").unwrap();
+        assert_eq!(result, None);
+
+        // Use the variable in a subsequent eval
+        let result = env.eval("print(x * 2)").unwrap();
+        assert_eq!(result, Some("10".to_string()));
+    }
+
+    #[test]
+    fn test_multiple_prints() {
+        let env = Environment::new("initial", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        let result = env.eval(r#"print("first"); print("second")"#).unwrap();
+        assert_eq!(result, Some("first\nsecond".to_string()));
+    }
+
+    #[test]
+    fn test_state_accumulation() {
+        let env = Environment::new("initial", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+
+        env.eval("a = 10").unwrap();
+        env.eval("b = 20").unwrap();
+        let result = env.eval("print(a + b)").unwrap();
+        assert_eq!(result, Some("30".to_string()));
+    }
+
+    #[test]
+    fn test_return_values() {
+        let env = Environment::new("hello", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        let eval = |code: &str| env.eval(code).unwrap();
+        assert_eq!(eval("#context"), Some("=> 5".to_string()));
+        assert_eq!(
+            eval("x = 2\nprint('x is', x)\nx + 1"),
+            Some("x is\t2\n=> 3".to_string())
+        );
+        assert_eq!(
+            eval("local t = {a = 1}\nt"),
+            Some(r#"=> {"a":1}"#.to_string())
+        );
+        assert_eq!(eval("return 1, nil"), Some("=> 1\tnil".to_string()));
+        // Statements and calls returning nothing add no output
+        assert_eq!(eval("y = 1"), None);
+        assert_eq!(eval("print('done')"), Some("done".to_string()));
+
+        // The last line is left alone when it continues the line before it
+        // or sits in a long string
+        assert_eq!(eval("z = 10\n- 4"), None);
+        assert_eq!(eval("z"), Some("=> 6".to_string()));
+        assert_eq!(eval("s = [[\nabc\nz]]\nprint(#s)"), Some("5".to_string()));
+        assert_eq!(eval("s = [[\nz]]"), None);
+        // Errors keep pointing at the right line
+        let err = env.eval("a = 1\nundefined_fn()").unwrap_err();
+        assert!(err.to_string().contains("cell:2:"), "{err}");
+    }
+
+    #[test]
+    fn test_print_with_multiple_args() {
+        let env = Environment::new("initial", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        let result = env.eval(r#"print("hello", "world", 42)"#).unwrap();
+        assert_eq!(result, Some("hello\tworld\t42".to_string()));
+    }
+
+    #[test]
+    fn test_context_variable_string() {
+        let env = Environment::new(
+            "my context value",
+            LlmClient::Ollama("qwen3:30b".to_string()),
+        )
+        .unwrap();
+        let result = env.eval("print(context)").unwrap();
+        assert_eq!(result, Some("my context value".to_string()));
+    }
+
+    #[test]
+    fn test_context_variable_number() {
+        let env = Environment::new(42, LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        let result = env.eval("print(context * 2)").unwrap();
+        assert_eq!(result, Some("84".to_string()));
+    }
+
+    #[test]
+    fn test_context_variable_table() {
+        let env = Environment::new("initial", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        // Create a table and set it as context
+        env.eval("context = {name = 'test', value = 100}").unwrap();
+        let result = env
+            .eval("print(context.name .. ': ' .. context.value)")
+            .unwrap();
+        assert_eq!(result, Some("test: 100".to_string()));
+    }
+
+    #[test]
+    fn test_llm_query_per_cell_limit() {
+        let config = EnvironmentConfig::default().with_max_llm_queries_per_cell(Some(0));
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+        let err = env.eval(r#"llm_query("hello")"#).unwrap_err();
+        assert!(err.to_string().contains("calls per cell"), "got: {err}");
+    }
+
+    #[test]
+    fn test_llm_query_per_run_limit() {
+        let config = EnvironmentConfig::default()
+            .with_max_llm_queries_per_cell(None)
+            .with_max_llm_queries_per_run(Some(0));
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+        let err = env.eval(r#"llm_query("hello")"#).unwrap_err();
+        assert!(err.to_string().contains("calls per run"), "got: {err}");
+    }
+
+    #[test]
+    fn test_embed_counts_against_query_limit() {
+        let config = EnvironmentConfig::default().with_max_llm_queries_per_run(Some(1));
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+        // 65 texts take two requests, refused before either is sent
+        let err = env
+            .eval(
+                r#"
+                local texts = {}
+                for i = 1, 65 do texts[i] = "text " .. i end
+                embed(texts)
+                "#,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("calls per run"), "got: {err}");
+        assert_eq!(env.limiter.run_remaining(), Some(1));
+    }
+
+    #[test]
+    fn test_query_limiter_resets_per_cell() {
+        let config = EnvironmentConfig::default()
+            .with_max_llm_queries_per_cell(Some(2))
+            .with_max_llm_queries_per_run(Some(3));
+        let limiter = QueryLimiter::new(&config);
+
+        limiter.start_cell();
+        assert!(limiter.acquire().is_ok());
+        assert!(limiter.acquire().is_ok());
+        assert!(limiter.acquire().is_err());
+
+        limiter.start_cell();
+        assert!(limiter.acquire().is_ok());
+        assert!(limiter.acquire().is_err());
+    }
+
+    #[test]
+    fn test_reset_keeps_run_query_count() {
+        let config = EnvironmentConfig::default().with_max_llm_queries_per_run(Some(3));
+        let mut env =
+            Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+                .unwrap();
+        env.limiter.acquire_many(2).unwrap();
+        env.reset().unwrap();
+        assert_eq!(
+            env.eval("print(budget().queries_left)").unwrap().as_deref(),
+            Some("1")
+        );
+        let fork = env.fresh().unwrap();
+        fork.limiter.acquire().unwrap();
+        assert_eq!(env.limiter.run_remaining(), Some(0));
+    }
+
+    #[test]
+    fn test_task_runs_across_evals() {
+        let config = EnvironmentConfig::default().with_task_time_slice(Duration::ZERO);
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+
+        let result = env
+            .eval(
+                r#"
+                start_task("sum", function()
+                  total = 0
+                  for i = 1, 3 do
+                    total = total + i
+                    coroutine.yield(i .. "/3")
+                  end
+                  return total
+                end)
+                "#,
+            )
+            .unwrap();
+        assert_eq!(result, Some("[task sum] progress: 1/3".to_string()));
+
+        let result = env.eval(r#"print(task_status("sum"))"#).unwrap();
+        assert_eq!(
+            result,
+            Some("running\n[task sum] progress: 2/3".to_string())
+        );
+
+        env.eval("x = 1").unwrap();
+        let result = env.eval("x = 2").unwrap();
+        assert_eq!(
+            result,
+            Some(r#"[task sum] finished, result in task_results["sum"]"#.to_string())
+        );
+
+        let result = env
+            .eval(r#"print(task_status("sum"), task_results["sum"])"#)
+            .unwrap();
+        assert_eq!(result, Some("finished\t6".to_string()));
+    }
+
+    #[test]
+    fn test_task_stops_at_limits() {
+        // A task that never yields fails at the cell's deadline
+        let config =
+            EnvironmentConfig::default().with_cell_timeout(Some(Duration::from_millis(200)));
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+        let started = Instant::now();
+        let result = env
+            .eval(r#"start_task("spin", function() while true do end end)"#)
+            .unwrap()
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(result.starts_with("[task spin] failed:"), "got: {result}");
+        assert!(result.contains("timed out"), "got: {result}");
+        assert_eq!(
+            env.eval(r#"print(task_status("spin"))"#)
+                .unwrap()
+                .as_deref(),
+            Some(
+                "failed\tCell timed out after 200ms; make sure loops terminate, and split long work across cells"
+            )
+        );
+        // So does a coroutine of the cell itself
+        let err = env
+            .eval("coroutine.wrap(function() while true do end end)()")
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "got: {err}");
+
+        // ... or at the end of its time slice, when that comes first
+        let config = EnvironmentConfig::default()
+            .with_cell_timeout(Some(Duration::from_secs(30)))
+            .with_task_time_slice(Duration::from_millis(100));
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+        let result = env
+            .eval(r#"start_task("spin", function() coroutine.yield() while true do end end)"#)
+            .unwrap()
+            .unwrap();
+        assert!(result.contains("without yielding"), "got: {result}");
+    }
+
+    #[test]
+    fn test_task_completes_within_time_slice() {
+        let env = Environment::new("", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        let result = env
+            .eval(
+                r#"
+                start_task("quick", function()
+                  for i = 1, 10 do coroutine.yield(i) end
+                  return "done"
+                end)
+                "#,
+            )
+            .unwrap();
+        assert_eq!(
+            result,
+            Some(r#"[task quick] finished, result in task_results["quick"]"#.to_string())
+        );
+    }
 
-                match response {
-                    Ok(response) => Ok(response),
-                    Err(e) => Err(mlua::Error::RuntimeError(format!("LLM query failed: {e}"))),
-                }
-            })
-        })
-    })
-}
+    #[test]
+    fn test_task_failure() {
+        let env = Environment::new("", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        let result = env
+            .eval(r#"start_task("bad", function() error("boom") end)"#)
+            .unwrap()
+            .unwrap();
+        assert!(result.starts_with("[task bad] failed:"), "got: {result}");
+        assert!(result.contains("boom"));
 
-/// Creates the custom `token_trunc(text, n)` function for truncating strings by token count.
-///
-/// # Lua Signature
-/// ```lua
-/// truncated = token_trunc(text, n)
-/// ```
-///
-/// # Parameters
-/// - `text` (string) - The text to truncate
-/// - `n` (number) - Maximum number of tokens to keep
-///
-/// # Returns
-/// - (string) - The truncated text, preserving the beginning
-///
-/// # Behavior
-/// - Uses p50k_base BPE tokenizer
-/// - If text has fewer than n tokens, returns the original text unchanged
-/// - Preserves the beginning of the text (truncates from the end)
-/// - Useful for staying within LLM token limits
-///
-/// # Example
-/// ```lua
-/// short_text = token_trunc(long_text, 100)
-/// chunk = token_trunc(string.sub(context, 1, 5000), 50)
-/// ```
-fn create_token_trunc_function(lua: &Lua) -> Result<mlua::Function> {
-    lua.create_function(|_lua, (s, n): (String, usize)| {
-        // Get the BPE tokenizer
-        let bpe = p50k_base()
-            .map_err(|e| mlua::Error::RuntimeError(format!("Failed to load tokenizer: {e}")))?;
+        let result = env.eval(r#"print(task_status("bad"))"#).unwrap().unwrap();
+        assert!(result.starts_with("failed\t"));
+        assert!(result.contains("boom"));
+    }
 
-        // Encode the string
-        let tokens = bpe.encode_with_special_tokens(&s);
+    #[test]
+    fn test_emit_finding() {
+        let env = Environment::new("", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        let result = env
+            .eval(
+                r#"
+                emit_finding("count", 42)
+                emit_finding("names", {"a", "b"})
+                emit_finding("count", 43)
+                "#,
+            )
+            .unwrap();
 
-        // Truncate to n tokens
-        let truncated_tokens = &tokens[..tokens.len().min(n)];
+        // Findings don't go to the print buffer
+        assert_eq!(result, None);
+        assert_eq!(
+            env.findings(),
+            vec![
+                Finding {
+                    key: "count".to_string(),
+                    value: "43".to_string()
+                },
+                Finding {
+                    key: "names".to_string(),
+                    value: r#"["a","b"]"#.to_string()
+                },
+            ]
+        );
+    }
 
-        // Decode back to string
-        let truncated_string = bpe
-            .decode(truncated_tokens.to_vec())
-            .map_err(|e| mlua::Error::RuntimeError(format!("Failed to decode tokens: {e}")))?;
+    #[test]
+    fn test_cell_timeout() {
+        let config =
+            EnvironmentConfig::default().with_cell_timeout(Some(Duration::from_millis(200)));
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+        let started = Instant::now();
+        let err = env.eval("while true do end").unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(
+            err.to_string().contains("Cell timed out after 200ms"),
+            "{err}"
+        );
 
-        Ok(truncated_string)
-    })
-}
+        // The deadline only covers the running cell
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(
+            env.eval("local n = 0 for i = 1, 100000 do n = n + i end print(n)")
+                .unwrap(),
+            Some("5000050000".to_string())
+        );
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn test_sleep_times_out_on_current_thread_runtime() {
+        let config =
+            EnvironmentConfig::default().with_cell_timeout(Some(Duration::from_millis(200)));
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+        assert_eq!(
+            env.eval("sleep(1) print('woke')").unwrap(),
+            Some("woke".to_string())
+        );
+        let started = Instant::now();
+        let err = env.eval("sleep(10000)").unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(err.to_string().contains("Cell timed out"), "{err}");
+    }
 
     #[test]
-    fn test_basic_print() {
-        let env = Environment::new("initial", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
-        let result = env.eval(r#"print("hello moon")"#).unwrap();
-        assert_eq!(result, Some("hello moon".to_string()));
+    fn test_cell_limits() {
+        let config = EnvironmentConfig::default()
+            .with_max_cell_instructions(Some(1_000_000))
+            .with_max_cell_memory(Some(16 << 20));
+        let context = "x".repeat(1 << 20);
+        let env = Environment::with_config(
+            context.as_str(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            config,
+        )
+        .unwrap();
+
+        let err = env
+            .eval("local n = 0 while true do n = n + 1 end")
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("limit of 1000000 Lua instructions"),
+            "{err}"
+        );
+
+        let err = env.eval("big = string.rep(context, 1000)").unwrap_err();
+        assert!(
+            err.to_string().contains("limit of 16 MiB of memory"),
+            "{err}"
+        );
+
+        // Later cells get a fresh budget, and the memory already in use
+        // (such as the context) doesn't count against it
+        assert_eq!(
+            env.eval("local copy = context .. context print(#copy)")
+                .unwrap(),
+            Some("2097152".to_string())
+        );
     }
 
     #[test]
-    fn test_no_output() {
-        let env = Environment::new("initial", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
-        let result = env.eval("x = 5").unwrap();
-        assert_eq!(result, None);
+    fn test_final_answer() {
+        let env = Environment::new("", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        assert_eq!(env.take_final_answer(), None);
+        let answer = "word ".repeat(1000);
+        let output = env
+            .eval(&format!(r#"final_answer("{answer}") print("done")"#))
+            .unwrap();
+        assert_eq!(output, Some("done".to_string()));
+        assert_eq!(env.take_final_answer(), Some(answer));
+        assert_eq!(env.take_final_answer(), None);
+
+        env.eval("final_answer('first') final_answer(42)").unwrap();
+        assert_eq!(env.take_final_answer(), Some("42".to_string()));
     }
 
     #[test]
-    fn test_persistent_state() {
-        let env = Environment::new("initial", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+    fn test_query_options() {
+        let lua = Lua::new();
+        let table: mlua::Table = lua
+            .load("return {system='Be brief', model='qwen3:4b', temperature=0, max_tokens=5}")
+            .eval()
+            .unwrap();
+        assert_eq!(
+            QueryOptions::from_lua(&lua, Some(table)).unwrap(),
+            QueryOptions {
+                system: Some("Be brief".to_string()),
+                model: Some("qwen3:4b".to_string()),
+                temperature: Some(0.0),
+                max_tokens: Some(5),
+                raise: None,
+            }
+        );
+        assert_eq!(
+            QueryOptions::from_lua(&lua, None).unwrap(),
+            QueryOptions::default()
+        );
 
-        // Set a variable
-        let result = env.eval("x = 5").unwrap();
-        assert_eq!(result, None);
+        // Typos are reported, and checked before a call is counted
+        let env = Environment::new("", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        let err = env.eval("llm_query('hi', {temprature=0})").unwrap_err();
+        assert!(
+            err.to_string().contains("invalid llm_query options"),
+            "{err}"
+        );
+        assert_eq!(env.limiter.run_count.load(Ordering::SeqCst), 0);
+    }
 
-        // Use the variable in a subsequent eval
-        let result = env.eval("print(x * 2)").unwrap();
-        assert_eq!(result, Some("10".to_string()));
+    #[test]
+    fn test_llm_query_json_counts_as_query() {
+        let config = EnvironmentConfig::default().with_max_llm_queries_per_cell(Some(0));
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+        let err = env
+            .eval(r#"llm_query_json("List them", "[string]")"#)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("llm_query limit exceeded"),
+            "{err}"
+        );
     }
 
     #[test]
-    fn test_multiple_prints() {
-        let env = Environment::new("initial", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
-        let result = env.eval(r#"print("first"); print("second")"#).unwrap();
-        assert_eq!(result, Some("first\nsecond".to_string()));
+    fn test_query_raise_false() {
+        let lua = Lua::new();
+        let failed = || -> Result<String> {
+            Err(mlua::Error::external(LlmQueryError(
+                "LLM query failed: connection refused".to_string(),
+            )))
+        };
+        let quiet = QueryOptions {
+            raise: Some(false),
+            ..Default::default()
+        };
+        let values = quiet.recover(&lua, failed()).unwrap();
+        let (response, err): (Option<String>, String) = lua.unpack_multi(values).unwrap();
+        assert_eq!(response, None);
+        assert!(err.contains("connection refused"), "{err}");
+        assert!(QueryOptions::default().recover(&lua, failed()).is_err());
+
+        // Timeouts are not failures of the query itself
+        let timeout: Result<String> =
+            Err(mlua::Error::external(CellTimeout(Duration::from_secs(1))));
+        assert!(quiet.recover(&lua, timeout).is_err());
     }
 
     #[test]
-    fn test_state_accumulation() {
-        let env = Environment::new("initial", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+    fn test_retry() {
+        // Needs no tokio runtime of its own
+        let env = Environment::new("", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        let output = env
+            .eval(
+                r#"
+                local calls = 0
+                local function flaky()
+                  calls = calls + 1
+                  if calls < 3 then error("transient") end
+                  return "ok", calls
+                end
+                print(retry(flaky, 3, 1))
+                "#,
+            )
+            .unwrap();
+        assert_eq!(output.as_deref(), Some("ok\t3"));
 
-        env.eval("a = 10").unwrap();
-        env.eval("b = 20").unwrap();
-        let result = env.eval("print(a + b)").unwrap();
-        assert_eq!(result, Some("30".to_string()));
+        let err = env
+            .eval("retry(function() error('still down') end, 2, 1)")
+            .unwrap_err();
+        assert!(err.to_string().contains("still down"), "{err}");
     }
 
     #[test]
-    fn test_print_with_multiple_args() {
-        let env = Environment::new("initial", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
-        let result = env.eval(r#"print("hello", "world", 42)"#).unwrap();
-        assert_eq!(result, Some("hello\tworld\t42".to_string()));
+    fn test_seed() {
+        let client = LlmClient::Ollama("qwen3:30b".to_string());
+        let code = "print(math.random(1000000), math.random(1000000))";
+        let seeded = |seed| {
+            let config = EnvironmentConfig::default().with_seed(Some(seed));
+            Environment::with_config("", client.clone(), config).unwrap()
+        };
+        let mut env = seeded(7);
+        let first = env.eval(code).unwrap();
+        assert_eq!(seeded(7).eval(code).unwrap(), first);
+        assert_ne!(seeded(8).eval(code).unwrap(), first);
+        // A reset environment starts the sequence over
+        env.reset().unwrap();
+        assert_eq!(env.eval(code).unwrap(), first);
+
+        // Tasks advance by a fixed number of yields, however fast they run
+        let output = env
+            .eval(
+                r#"start_task("count", function() for i = 1, 1000 do coroutine.yield(i) end end)"#,
+            )
+            .unwrap();
+        assert_eq!(
+            output,
+            Some(format!(
+                "[task count] progress: {DETERMINISTIC_TASK_YIELDS}"
+            ))
+        );
     }
 
     #[test]
-    fn test_context_variable_string() {
-        let env = Environment::new(
-            "my context value",
+    fn test_readonly_context() {
+        let config = EnvironmentConfig::default().with_readonly_context(true);
+        let mut env = Environment::with_config(
+            "original",
             LlmClient::Ollama("qwen3:30b".to_string()),
+            config,
         )
         .unwrap();
-        let result = env.eval("print(context)").unwrap();
-        assert_eq!(result, Some("my context value".to_string()));
+        let err = env.eval("context = 'summary'").unwrap_err();
+        assert!(err.to_string().contains("context is read-only"), "{err}");
+        let err = env.eval("setmetatable(_G, nil)").unwrap_err();
+        assert!(err.to_string().contains("protected metatable"), "{err}");
+
+        // Other globals work as usual, and copies can be changed
+        let output = env
+            .eval("x = 1 local copy = copy_context() copy = copy .. '!' print(x, copy, context)")
+            .unwrap();
+        assert_eq!(output.as_deref(), Some("1\toriginal!\toriginal"));
+        assert!(env.snapshot_globals().unwrap().values.contains_key("x"));
+        env.reset().unwrap();
+        assert!(env.eval("context = 'again'").is_err());
+
+        // Tables are copied deeply
+        let env = Environment::new("", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        let output = env
+            .eval(
+                "context = {items = {'a'}} local copy = copy_context() copy.items[1] = 'z' print(context.items[1], copy.items[1])",
+            )
+            .unwrap();
+        assert_eq!(output.as_deref(), Some("a\tz"));
     }
 
     #[test]
-    fn test_context_variable_number() {
-        let env = Environment::new(42, LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
-        let result = env.eval("print(context * 2)").unwrap();
-        assert_eq!(result, Some("84".to_string()));
+    fn test_rlm_query_depth() {
+        let config = EnvironmentConfig::default().with_sub_rlm(0, 10);
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+        let err = env.eval(r#"rlm_query("Summarize", "text")"#).unwrap_err();
+        assert!(err.to_string().contains("maximum nesting depth"), "{err}");
+        assert_eq!(env.limiter.run_count.load(Ordering::SeqCst), 0);
     }
 
     #[test]
-    fn test_context_variable_table() {
-        let env = Environment::new("initial", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
-        // Create a table and set it as context
-        env.eval("context = {name = 'test', value = 100}").unwrap();
-        let result = env
-            .eval("print(context.name .. ': ' .. context.value)")
+    fn test_rlm_query_shares_run_limit() {
+        let client = LlmClient::Ollama("qwen3:30b".to_string());
+        let config = EnvironmentConfig::default().with_max_llm_queries_per_run(Some(3));
+        let env = Environment::with_config("", client.clone(), config.clone()).unwrap();
+        // What rlm_query charges before starting the nested run
+        env.limiter.acquire().unwrap();
+
+        let nested =
+            Environment::with_config("", client, nested_config(&config, &env.limiter)).unwrap();
+        nested.limiter.acquire_many(2).unwrap();
+        assert_eq!(env.limiter.run_count.load(Ordering::SeqCst), 3);
+        let err = nested.eval(r#"llm_query("hello")"#).unwrap_err();
+        assert!(err.to_string().contains("calls per run"), "{err}");
+        let err = env.eval(r#"llm_query("hello")"#).unwrap_err();
+        assert!(err.to_string().contains("calls per run"), "{err}");
+    }
+
+    #[test]
+    fn test_llm_map_checks_limits_up_front() {
+        let config = EnvironmentConfig::default().with_max_llm_queries_per_cell(Some(2));
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
             .unwrap();
-        assert_eq!(result, Some("test: 100".to_string()));
+        let err = env.eval(r#"llm_map({"a", "b", "c"})"#).unwrap_err();
+        assert!(
+            err.to_string().contains("llm_query limit exceeded"),
+            "{err}"
+        );
+        assert_eq!(env.limiter.run_count.load(Ordering::SeqCst), 0);
+
+        let err = env
+            .eval(r#"llm_map({"a"}, {concurrency=2, temprature=0})"#)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("invalid llm_query options"),
+            "{err}"
+        );
+
+        // An empty batch sends nothing
+        assert_eq!(
+            env.eval("print(#llm_map({}))").unwrap(),
+            Some("0".to_string())
+        );
     }
 
     #[test]
@@ -415,4 +3191,329 @@ mod tests {
             "Should start with 'The', got: {output}"
         );
     }
+
+    #[test]
+    fn test_token_trunc_tail_and_middle() {
+        let env = Environment::new("", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        let text = "The quick brown fox jumps over the lazy dog";
+
+        let tail = env
+            .eval(&format!(r#"print(token_trunc_tail("{text}", 3))"#))
+            .unwrap()
+            .unwrap();
+        assert_eq!(tail, " the lazy dog");
+
+        let middle = env
+            .eval(&format!(r#"print(token_trunc_middle("{text}", 4))"#))
+            .unwrap()
+            .unwrap();
+        assert_eq!(middle, "The quick\n...\n lazy dog");
+
+        let short = env
+            .eval(r#"print(token_trunc_tail("Short", 10), token_trunc_middle("Short", 10))"#)
+            .unwrap();
+        assert_eq!(short, Some("Short\tShort".to_string()));
+
+        // Never cuts a multi-byte character in half
+        let emoji = env
+            .eval(r#"print(token_trunc_tail("done 😀😀😀", 1))"#)
+            .unwrap()
+            .unwrap_or_default();
+        assert!("done 😀😀😀".ends_with(&emoji));
+    }
+
+    #[test]
+    fn test_policy_rejects_before_execution() {
+        let config = EnvironmentConfig::default().with_policy(Some(PolicyChecker::default()));
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+
+        let err = env
+            .eval("x = 1\nf = load('return 2')")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Policy violation: use of `load` is not allowed"));
+        // Nothing in the rejected cell ran
+        assert_eq!(env.eval("print(x)").unwrap(), Some("nil".to_string()));
+    }
+
+    #[test]
+    fn test_host_function() {
+        let config = EnvironmentConfig::default().with_function(
+            "lookup_customer",
+            "Returns the customer's name for an id.",
+            |_, id: u32| Ok((id == 7).then(|| "Ada".to_string())),
+        );
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+
+        let result = env
+            .eval("print(lookup_customer(7), lookup_customer(8))")
+            .unwrap();
+        assert_eq!(result, Some("Ada\tnil".to_string()));
+    }
+
+    #[test]
+    fn test_host_function_cannot_override_builtin() {
+        let config =
+            EnvironmentConfig::default().with_function("print", "Shadows print.", |_, ()| Ok(()));
+        let err = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("would override a built-in"));
+    }
+
+    #[test]
+    fn test_stdlib_profile() {
+        let client = LlmClient::Ollama("qwen3:30b".to_string());
+        let env = Environment::new("", client.clone()).unwrap();
+        assert_eq!(
+            env.eval("print(os, io, dofile)").unwrap(),
+            Some("nil\tnil\tnil".to_string())
+        );
+
+        let config =
+            EnvironmentConfig::default().with_stdlib(StdlibProfile::default().allow("os.clock"));
+        let env = Environment::with_config("", client, config).unwrap();
+        let fresh = env.fresh().unwrap();
+        assert_eq!(
+            fresh.eval("print(type(os.clock), os.exit)").unwrap(),
+            Some("function\tnil".to_string())
+        );
+    }
+
+    #[test]
+    fn test_documents() {
+        let config = EnvironmentConfig::default().with_document("notes.md", "# Notes");
+        let env = Environment::with_documents(
+            [
+                ("a.txt".to_string(), "alpha".to_string()),
+                ("b.txt".to_string(), "beta".to_string()),
+            ],
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            config,
+        )
+        .unwrap();
+        assert_eq!(
+            env.eval("print(table.concat(list_documents(), ','), (get_document('b.txt')))")
+                .unwrap(),
+            Some("notes.md,a.txt,b.txt\tbeta".to_string())
+        );
+        // `context` concatenates the documents given to the constructor
+        assert_eq!(
+            env.eval("print(context)").unwrap(),
+            Some("=== a.txt ===\nalpha\n\n=== b.txt ===\nbeta\n\n".to_string())
+        );
+
+        // Documents added by code are gone in a fresh environment
+        env.eval("add_document('draft', 'x')").unwrap();
+        let fresh = env.fresh().unwrap();
+        assert_eq!(
+            fresh
+                .eval("print(#list_documents(), get_document('draft'))")
+                .unwrap(),
+            Some(
+                "3\tnil\tno document named \"draft\"; documents are: notes.md, a.txt, b.txt"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_lua_check() {
+        let client = LlmClient::Ollama("qwen3:30b".to_string());
+        let env = Environment::new("", client.clone()).unwrap();
+        assert_eq!(
+            env.eval("print(lua_check('return 1 + 1'))").unwrap(),
+            Some("true\tnil".to_string())
+        );
+        let output = env
+            .eval("print(lua_check('for i = 1, 3 do\\n  print(i)'))")
+            .unwrap()
+            .unwrap();
+        assert!(
+            output.starts_with("false\tcheck:2: 'end' expected"),
+            "{output}"
+        );
+        // The code is never run
+        assert_eq!(
+            env.eval("lua_check('ran = true') print(ran)").unwrap(),
+            Some("nil".to_string())
+        );
+
+        let config = EnvironmentConfig {
+            policy: Some(PolicyChecker::default()),
+            ..Default::default()
+        };
+        let env = Environment::with_config("", client, config).unwrap();
+        let output = env
+            .eval("print(lua_check('return collectgarbage()'))")
+            .unwrap()
+            .unwrap();
+        assert!(output.contains("Policy violation"), "{output}");
+    }
+
+    #[test]
+    fn test_output_limit() {
+        let config = EnvironmentConfig::default().with_max_output_bytes(Some(10));
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+        let streamed = Arc::new(Mutex::new(Vec::new()));
+        let lines = streamed.clone();
+        let sink: OutputSink = Arc::new(move |line| lines.lock().unwrap().push(line.to_string()));
+        let output = env
+            .eval_with_sink(
+                "print('1234') for i = 1, 1e5 do print('abcdé') end",
+                sink.clone(),
+            )
+            .unwrap();
+        assert_eq!(output, Some(format!("1234\nabcd\n{OUTPUT_LIMIT_MARKER}")));
+        assert_eq!(
+            *streamed.lock().unwrap(),
+            ["1234", &format!("abcd\n{OUTPUT_LIMIT_MARKER}")]
+        );
+        // The limit applies to each cell
+        assert_eq!(env.eval("print('ok')").unwrap(), Some("ok".to_string()));
+
+        // Task progress counts towards it and is streamed too
+        streamed.lock().unwrap().clear();
+        let code = r#"start_task("t", function() end)"#;
+        let output = env.eval_with_sink(code, sink).unwrap();
+        assert_eq!(output, Some(format!("[task t] f\n{OUTPUT_LIMIT_MARKER}")));
+        assert_eq!(*streamed.lock().unwrap(), [output.unwrap()]);
+    }
+
+    #[test]
+    fn test_budget() {
+        let config = EnvironmentConfig::default().with_max_llm_queries_per_run(Some(5));
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+        let code = "local b = budget() print(b.iterations_left, b.tokens_left, b.queries_left, b.elapsed >= 0)";
+        assert_eq!(
+            env.eval(code).unwrap(),
+            Some("nil\tnil\t5\ttrue".to_string())
+        );
+
+        env.set_budget(Budget {
+            iterations_left: Some(1),
+            tokens_used: 900,
+            tokens_left: Some(100),
+            started: Some(Instant::now() - Duration::from_secs(60)),
+        });
+        let code = "local b = budget() print(b.iterations_left, b.tokens_used, b.tokens_left, b.elapsed >= 60)";
+        assert_eq!(
+            env.eval(code).unwrap(),
+            Some("1\t900\t100\ttrue".to_string())
+        );
+    }
+
+    #[test]
+    fn test_register_function() {
+        let mut env = Environment::new("", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        env.register_function("double", "Doubles a number.", |_, n: i64| Ok(n * 2))
+            .unwrap();
+        assert_eq!(
+            env.eval("print(double(21))").unwrap(),
+            Some("42".to_string())
+        );
+        // Registering again replaces the function
+        env.register_function(
+            "double",
+            "Doubles a string.",
+            |_, s: String| Ok(s.repeat(2)),
+        )
+        .unwrap();
+        assert_eq!(env.functions().len(), 1);
+        assert_eq!(env.functions()[0].description, "Doubles a string.");
+
+        let err = env
+            .register_function("llm_query", "Shadows llm_query.", |_, ()| Ok(()))
+            .unwrap_err();
+        assert!(err.to_string().contains("would override a built-in"));
+
+        // Kept after a reset, and not reported as a user global
+        env.reset().unwrap();
+        assert_eq!(
+            env.eval("print(double('ab'))").unwrap(),
+            Some("abab".to_string())
+        );
+        let snapshot = env.snapshot_globals().unwrap();
+        assert!(snapshot.unserializable.is_empty(), "{snapshot:?}");
+    }
+
+    #[test]
+    fn test_extra_globals() {
+        let config = EnvironmentConfig::default().with_global(
+            "contexts",
+            json!({"a.txt": "first", "b.txt": "second", "missing": null}),
+        );
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+
+        let result = env
+            .eval(r#"print(contexts["a.txt"], contexts["b.txt"], contexts.missing)"#)
+            .unwrap();
+        assert_eq!(result, Some("first\tsecond\tnil".to_string()));
+    }
+
+    #[test]
+    fn test_snapshot_and_restore_globals() {
+        let env = Environment::new("", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        env.eval(
+            r#"
+            count = 3
+            names = {"a", "b"}
+            config = {depth = 2, label = "x"}
+            function helper() return 1 end
+            context = "changed"
+            "#,
+        )
+        .unwrap();
+
+        let snapshot = env.snapshot_globals().unwrap();
+        assert_eq!(snapshot.values.len(), 3);
+        assert_eq!(snapshot.values["names"], json!(["a", "b"]));
+        assert_eq!(snapshot.unserializable, ["helper"]);
+
+        let restored = Environment::new("", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        restored.restore_globals(&snapshot).unwrap();
+        let result = restored
+            .eval("print(count, names[2], config.label, helper)")
+            .unwrap();
+        assert_eq!(result, Some("3\tb\tx\tnil".to_string()));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut env =
+            Environment::new("the context", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        let info = env.lua.to_value(&json!({"lines": 3})).unwrap();
+        env.set_global("info", info).unwrap();
+        env.eval(r#"x = 1 context = "changed" emit_finding("k", "v")"#)
+            .unwrap();
+
+        env.reset().unwrap();
+        let result = env.eval("print(x, context, info.lines)").unwrap();
+        assert_eq!(result, Some("nil\tthe context\t3".to_string()));
+        assert!(env.findings().is_empty());
+    }
+
+    #[test]
+    fn test_eval_with_sink() {
+        let env = Environment::new("", LlmClient::Ollama("qwen3:30b".to_string())).unwrap();
+        let streamed = Arc::new(Mutex::new(Vec::new()));
+        let sink_lines = streamed.clone();
+        let sink: OutputSink =
+            Arc::new(move |line| sink_lines.lock().unwrap().push(line.to_string()));
+
+        let result = env
+            .eval_with_sink("print('step', 1) print('step', 2) error('stop')", sink)
+            .unwrap_err();
+        assert!(result.to_string().contains("stop"));
+        assert_eq!(*streamed.lock().unwrap(), ["step\t1", "step\t2"]);
+
+        // The sink only applies to that evaluation
+        env.eval("print('quiet')").unwrap();
+        assert_eq!(streamed.lock().unwrap().len(), 2);
+    }
 }
@@ -0,0 +1,200 @@
+//! String distances and fuzzy matching for Lua code.
+//!
+//! Deduplicating names or matching entities across documents compares every
+//! string with every other, which is too slow in Lua for more than a few
+//! hundred strings. `levenshtein(a, b)` and `jaro_winkler(a, b)` compare two
+//! strings, and `fuzzy_find(needle, haystack, threshold)` scores a needle
+//! against a whole list, or against every span of a text, in one call.
+
+use mlua::Lua;
+
+/// Score a `fuzzy_find` match must reach when no threshold is given
+pub const DEFAULT_THRESHOLD: f64 = 0.85;
+
+/// Similarity of two strings from 0 to 1, ignoring case
+pub fn similarity(a: &str, b: &str) -> f64 {
+    strsim::jaro_winkler(&a.to_lowercase(), &b.to_lowercase())
+}
+
+/// A candidate scoring at least the threshold
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzyMatch {
+    pub text: String,
+    pub score: f64,
+    /// Index of the candidate in a list, or byte offset of the span in a text
+    pub position: usize,
+}
+
+/// Score `needle` against each candidate, best first
+pub fn find_in_list(needle: &str, candidates: &[String], threshold: f64) -> Vec<FuzzyMatch> {
+    let mut matches: Vec<FuzzyMatch> = candidates
+        .iter()
+        .enumerate()
+        .map(|(i, candidate)| FuzzyMatch {
+            text: candidate.clone(),
+            score: similarity(needle, candidate),
+            position: i,
+        })
+        .filter(|m| m.score >= threshold)
+        .collect();
+    matches.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then(a.position.cmp(&b.position))
+    });
+    matches
+}
+
+/// Score `needle` against each run of as many words in `text`, and return
+/// the best spans that don't overlap, best first
+pub fn find_in_text(needle: &str, text: &str, threshold: f64) -> Vec<FuzzyMatch> {
+    let width = needle.split_whitespace().count().max(1);
+    let words: Vec<(usize, &str)> = text
+        .split_whitespace()
+        .map(|word| (word.as_ptr() as usize - text.as_ptr() as usize, word))
+        .collect();
+    let mut spans: Vec<(usize, usize, f64)> = Vec::new();
+    for window in words.windows(width.min(words.len())) {
+        let (start, _) = window[0];
+        let (last, word) = window[window.len() - 1];
+        let end = last + word.len();
+        let score = similarity(needle, &text[start..end]);
+        if score >= threshold {
+            spans.push((start, end, score));
+        }
+    }
+    spans.sort_by(|a, b| b.2.total_cmp(&a.2).then(a.0.cmp(&b.0)));
+    let mut taken: Vec<(usize, usize)> = Vec::new();
+    let mut matches = Vec::new();
+    for (start, end, score) in spans {
+        if taken.iter().all(|&(s, e)| end <= s || start >= e) {
+            taken.push((start, end));
+            matches.push(FuzzyMatch {
+                text: text[start..end].to_string(),
+                score,
+                position: start,
+            });
+        }
+    }
+    matches
+}
+
+/// Creates the `levenshtein(a, b)` Lua function, which returns the number of
+/// single-character insertions, deletions, and substitutions turning `a`
+/// into `b`.
+pub fn create_levenshtein_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(|_, (a, b): (String, String)| Ok(strsim::levenshtein(&a, &b)))
+}
+
+/// Creates the `jaro_winkler(a, b)` Lua function, which returns the
+/// Jaro-Winkler similarity of `a` and `b`, from 0 (nothing in common) to 1
+/// (equal). Unlike [`similarity`] it is case-sensitive.
+pub fn create_jaro_winkler_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(|_, (a, b): (String, String)| Ok(strsim::jaro_winkler(&a, &b)))
+}
+
+/// Creates the `fuzzy_find(needle, haystack, threshold)` Lua function.
+///
+/// With a list of strings as `haystack`, returns the entries whose
+/// [`similarity`] to `needle` is at least `threshold` (0.85 by default) as
+/// `{text, score, index}` tables, best first. With a string, compares
+/// `needle` to each run of as many words and returns the best
+/// non-overlapping spans as `{text, score, offset}` tables, where `offset`
+/// is the 1-based byte position of the span for `string.sub`.
+pub fn create_fuzzy_find_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(
+        |lua, (needle, haystack, threshold): (String, mlua::Value, Option<f64>)| {
+            let threshold = threshold.unwrap_or(DEFAULT_THRESHOLD);
+            let (matches, key) = match haystack {
+                mlua::Value::String(text) => (
+                    find_in_text(&needle, &text.to_string_lossy(), threshold),
+                    "offset",
+                ),
+                mlua::Value::Table(list) => {
+                    let candidates = list
+                        .sequence_values::<String>()
+                        .collect::<mlua::Result<Vec<_>>>()?;
+                    (find_in_list(&needle, &candidates, threshold), "index")
+                }
+                other => {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "fuzzy_find: haystack must be a string or a list of strings, not {}",
+                        other.type_name()
+                    )));
+                }
+            };
+            let results = lua.create_table()?;
+            for m in matches {
+                let entry = lua.create_table()?;
+                entry.set("text", m.text)?;
+                entry.set("score", m.score)?;
+                entry.set(key, m.position + 1)?;
+                results.push(entry)?;
+            }
+            Ok(results)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_in_list() {
+        let names = ["Jon Smith", "Jane Doe", "john smith", "J. Smith"].map(String::from);
+        let matches = find_in_list("John Smith", &names, 0.9);
+        let found: Vec<_> = matches.iter().map(|m| m.position).collect();
+        assert_eq!(found, [2, 0]);
+        assert_eq!(matches[0].score, 1.0);
+    }
+
+    #[test]
+    fn test_find_in_text() {
+        let text = "Signed by Jon Smyth on Monday; witnessed by John Smith.";
+        let matches = find_in_text("John Smith", text, 0.85);
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].text, "John Smith.");
+        assert_eq!(matches[1].text, "Jon Smyth");
+        assert_eq!(&text[matches[1].position..][..9], "Jon Smyth");
+        assert!(find_in_text("Jane Doe", text, 0.85).is_empty());
+    }
+
+    #[test]
+    fn test_lua_functions() {
+        let lua = Lua::new();
+        let globals = lua.globals();
+        globals
+            .set("levenshtein", create_levenshtein_function(&lua).unwrap())
+            .unwrap();
+        globals
+            .set("jaro_winkler", create_jaro_winkler_function(&lua).unwrap())
+            .unwrap();
+        globals
+            .set("fuzzy_find", create_fuzzy_find_function(&lua).unwrap())
+            .unwrap();
+        let distance: usize = lua
+            .load(r#"return levenshtein("kitten", "sitting")"#)
+            .eval()
+            .unwrap();
+        assert_eq!(distance, 3);
+        let score: f64 = lua
+            .load(r#"return jaro_winkler("martha", "marhta")"#)
+            .eval()
+            .unwrap();
+        assert!((score - 0.961).abs() < 0.001, "{score}");
+        let (index, offset): (usize, usize) = lua
+            .load(
+                r#"
+                local names = fuzzy_find("acme corp", {"Globex", "ACME Corp.", "Initech"})
+                local spans = fuzzy_find("acme corp", "Invoice from Acme Corp, paid")
+                return names[1].index, spans[1].offset
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!((index, offset), (2, 14));
+        let err = lua.load("fuzzy_find('x', 1)").exec().unwrap_err();
+        assert!(err.to_string().contains("haystack must be"), "{err}");
+    }
+}
@@ -0,0 +1,168 @@
+//! Opt-in HTTP access for Lua code.
+//!
+//! Cells are fully sandboxed by default. Giving the environment a list of
+//! allowed domains (see [`crate::environment::EnvironmentConfig::with_http_allowlist`])
+//! enables `http_get(url)` for those domains and their subdomains only, e.g.
+//! to fetch a page referenced in the context. Redirects are followed only
+//! while they stay on allowed domains.
+
+use crate::environment::block_on_until;
+use mlua::Lua;
+use reqwest::Url;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Largest response body `http_get` reads; longer bodies are cut off
+pub const MAX_RESPONSE_BYTES: usize = 10 << 20;
+
+/// Time a single request may take, within the cell's own timeout
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Domains `http_get` may fetch from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HttpAllowlist {
+    domains: Vec<String>,
+}
+
+impl HttpAllowlist {
+    pub fn new(domains: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        Self {
+            domains: domains
+                .into_iter()
+                .map(|domain| domain.as_ref().trim().trim_matches('.').to_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Whether no domain is allowed, which disables `http_get`
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+
+    /// Whether `url` is an HTTP(S) URL on an allowed domain or a subdomain of one
+    pub fn allows(&self, url: &Url) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        let host = host.to_lowercase();
+        matches!(url.scheme(), "http" | "https")
+            && self.domains.iter().any(|domain| {
+                host == *domain
+                    || host
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|sub| sub.ends_with('.'))
+            })
+    }
+}
+
+fn http_error(message: impl std::fmt::Display) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("http_get: {message}"))
+}
+
+/// Fetch `url`, returning the status and up to [`MAX_RESPONSE_BYTES`] of the body
+async fn get(client: &reqwest::Client, url: Url) -> mlua::Result<(u16, Vec<u8>)> {
+    let mut response = client.get(url).send().await.map_err(http_error)?;
+    let status = response.status().as_u16();
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(http_error)? {
+        body.extend_from_slice(&chunk);
+        if body.len() >= MAX_RESPONSE_BYTES {
+            body.truncate(MAX_RESPONSE_BYTES);
+            break;
+        }
+    }
+    Ok((status, body))
+}
+
+/// Creates the `http_get(url)` Lua function.
+///
+/// Returns the body of the response as a string and the HTTP status code;
+/// error statuses are returned, not raised. Fails if `url` is not on an
+/// allowed domain, if the allowlist is empty, or if the request fails.
+pub fn create_http_get_function(
+    lua: &Lua,
+    allowlist: HttpAllowlist,
+    deadline: Arc<Mutex<Option<Instant>>>,
+    timeout: Option<Duration>,
+) -> mlua::Result<mlua::Function> {
+    // Only built when enabled, since loading TLS certificates is slow
+    let client = if allowlist.is_empty() {
+        None
+    } else {
+        let redirects = allowlist.clone();
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= 10 {
+                    attempt.error("too many redirects")
+                } else if redirects.allows(attempt.url()) {
+                    attempt.follow()
+                } else {
+                    let message = format!("redirected to {}, which is not allowed", attempt.url());
+                    attempt.error(message)
+                }
+            }))
+            .build()
+            .map_err(mlua::Error::external)?;
+        Some(client)
+    };
+
+    lua.create_function(move |lua, url: String| {
+        let Some(client) = &client else {
+            return Err(http_error(
+                "HTTP access is disabled for this run (allow domains with --allow-http)",
+            ));
+        };
+        let url = Url::parse(&url).map_err(|e| http_error(format!("invalid URL {url}: {e}")))?;
+        if !allowlist.allows(&url) {
+            return Err(http_error(format!("{url} is not on an allowed domain")));
+        }
+        let limit = deadline.lock().unwrap().zip(timeout);
+        let (status, body) = block_on_until(limit, get(client, url))?;
+        Ok((lua.create_string(body)?, status))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowlist() {
+        let allowlist = HttpAllowlist::new(["Example.com", ".docs.rs"]);
+        let allows = |url: &str| allowlist.allows(&Url::parse(url).unwrap());
+        assert!(allows("https://example.com/page"));
+        assert!(allows("http://api.EXAMPLE.com/v1"));
+        assert!(allows("https://docs.rs/mlua"));
+        assert!(!allows("https://notexample.com/"));
+        assert!(!allows("https://example.com.evil.net/"));
+        assert!(!allows("ftp://example.com/file"));
+        assert!(!allows("file:///etc/passwd"));
+        assert!(HttpAllowlist::new([""]).is_empty());
+    }
+
+    #[test]
+    fn test_http_get_refuses_without_network() {
+        let lua = Lua::new();
+        let deadline = Arc::new(Mutex::new(None));
+        let disabled =
+            create_http_get_function(&lua, HttpAllowlist::default(), deadline.clone(), None)
+                .unwrap();
+        let err = disabled
+            .call::<mlua::Value>("https://example.com")
+            .unwrap_err();
+        assert!(err.to_string().contains("disabled"), "{err}");
+
+        let allowed =
+            create_http_get_function(&lua, HttpAllowlist::new(["example.com"]), deadline, None)
+                .unwrap();
+        let err = allowed
+            .call::<mlua::Value>("https://example.org/")
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("not on an allowed domain"),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! On-disk cache of extracted text for formats that are slow to load.
+//!
+//! Entries are keyed by a SHA-256 hash of the file's bytes together with the
+//! extension, the options that change extraction, and the crate version, so an
+//! edited file, a different option, or an upgrade misses the cache. Entries
+//! hold the content, description, and Lua globals of the loaded input; file
+//! metadata is always read fresh.
+
+use super::{Input, InputError, LoadOptions};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    content: String,
+    description: Option<String>,
+    globals: BTreeMap<String, Value>,
+}
+
+/// The default cache directory: `$MOONRAKER_CACHE_DIR`, else
+/// `$XDG_CACHE_HOME/moonraker`, else `~/.cache/moonraker`
+pub fn default_cache_dir() -> Option<PathBuf> {
+    let var = |name| std::env::var_os(name).filter(|v| !v.is_empty());
+    var("MOONRAKER_CACHE_DIR")
+        .map(PathBuf::from)
+        .or_else(|| var("XDG_CACHE_HOME").map(|dir| Path::new(&dir).join("moonraker")))
+        .or_else(|| var("HOME").map(|home| Path::new(&home).join(".cache/moonraker")))
+}
+
+/// Whether loading a file with this (lowercased) extension is worth caching.
+/// Formats whose inputs carry host functions are never cached.
+pub fn cacheable(ext: &str) -> bool {
+    matches!(
+        ext,
+        "pdf"
+            | "png"
+            | "jpg"
+            | "jpeg"
+            | "tif"
+            | "tiff"
+            | "docx"
+            | "odt"
+            | "xlsx"
+            | "xlsm"
+            | "xls"
+            | "ods"
+    )
+}
+
+/// Cache key for a file loaded with the given options
+pub fn key(path: &Path, ext: &str, options: &LoadOptions) -> Result<String, InputError> {
+    let bytes = fs::read(path).map_err(|e| InputError::ReadError(e.to_string()))?;
+    let mut hasher = Sha256::new();
+    hasher.update(&bytes);
+    hasher.update(
+        format!(
+            "\0{}\0{ext}\0{}\0{}",
+            env!("CARGO_PKG_VERSION"),
+            options.pdf_page_markers,
+            options.pdf_tables
+        )
+        .as_bytes(),
+    );
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Read a cached input; unreadable entries count as misses
+pub fn load(dir: &Path, key: &str) -> Option<Input> {
+    let text = fs::read_to_string(entry_path(dir, key)).ok()?;
+    match serde_json::from_str::<Entry>(&text) {
+        Ok(entry) => Some(Input {
+            content: entry.content,
+            description: entry.description,
+            globals: entry.globals,
+            functions: Vec::new(),
+            meta: None,
+        }),
+        Err(e) => {
+            tracing::warn!("Ignoring corrupt cache entry {key}: {e}");
+            None
+        }
+    }
+}
+
+/// Write an input to the cache. Failures are logged, since the input itself
+/// loaded fine.
+pub fn store(dir: &Path, key: &str, input: &Input) {
+    let entry = Entry {
+        content: input.content.clone(),
+        description: input.description.clone(),
+        globals: input.globals.clone(),
+    };
+    let result = fs::create_dir_all(dir).and_then(|()| {
+        // Write then rename, so concurrent runs never read a partial entry
+        let path = entry_path(dir, key);
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&tmp, serde_json::to_vec(&entry)?)?;
+        fs::rename(&tmp, &path)
+    });
+    if let Err(e) = result {
+        tracing::warn!("Failed to write cache entry in {}: {e}", dir.display());
+    }
+}
+
+fn entry_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{key}.json"))
+}
@@ -0,0 +1,77 @@
+//! Email messages (`.eml`) and mailboxes (`.mbox`): headers and plain-text
+//! bodies, as text and as the `messages` Lua table.
+
+use super::InputError;
+use mail_parser::mailbox::mbox::MessageIterator;
+use mail_parser::{Address, MessageParser};
+use serde_json::{Value, json};
+
+/// Parse a single message into {from, to, date, subject, body}
+pub fn parse_message(raw: &[u8]) -> Result<Value, InputError> {
+    let message = MessageParser::default()
+        .parse(raw)
+        .ok_or_else(|| InputError::EmailError("not an email message".to_string()))?;
+
+    // Plain-text parts, with HTML-only bodies converted to text
+    let body: Vec<String> = (0..)
+        .map_while(|i| message.body_text(i))
+        .map(|part| part.trim_end().to_string())
+        .collect();
+
+    Ok(json!({
+        "from": format_address(message.from()),
+        "to": format_address(message.to()),
+        "date": message.date().map(|d| d.to_rfc3339()).unwrap_or_default(),
+        "subject": message.subject().unwrap_or_default(),
+        "body": body.join("\n\n"),
+    }))
+}
+
+/// Parse every message of an mbox mailbox
+pub fn parse_mbox(raw: &[u8]) -> Result<Vec<Value>, InputError> {
+    MessageIterator::new(raw)
+        .map(|message| {
+            let message = message.map_err(|e| InputError::EmailError(e.to_string()))?;
+            parse_message(message.contents())
+        })
+        .collect()
+}
+
+/// Render messages as text, each starting with a `--- message N ---` line
+pub fn to_text(messages: &[Value]) -> String {
+    let mut out = String::new();
+    for (i, message) in messages.iter().enumerate() {
+        out.push_str(&format!("--- message {} ---\n", i + 1));
+        for (header, field) in [
+            ("From", "from"),
+            ("To", "to"),
+            ("Date", "date"),
+            ("Subject", "subject"),
+        ] {
+            if let Some(value) = message[field].as_str().filter(|v| !v.is_empty()) {
+                out.push_str(&format!("{header}: {value}\n"));
+            }
+        }
+        out.push('\n');
+        out.push_str(message["body"].as_str().unwrap_or_default());
+        out.push_str("\n\n");
+    }
+    out
+}
+
+/// Format addresses as `Name <address>`, comma-separated
+fn format_address(address: Option<&Address>) -> String {
+    let Some(address) = address else {
+        return String::new();
+    };
+    address
+        .iter()
+        .map(|addr| match (addr.name(), addr.address()) {
+            (Some(name), Some(email)) => format!("{name} <{email}>"),
+            (Some(name), None) => name.to_string(),
+            (None, Some(email)) => email.to_string(),
+            (None, None) => String::new(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,105 @@
+//! The `load_file(path)` Lua function, which loads further documents from
+//! allowlisted directories, e.g. attachments referenced by the context.
+
+use super::{Input, InputPipeline, LoadOptions};
+use crate::environment::HostFunction;
+use std::path::{Path, PathBuf};
+
+const LOAD_FILE_DESCRIPTION: &str = "Load another document with `text, description = load_file(path)`, e.g. a file referenced by the context. PDFs, office documents, HTML, CSV, and other supported formats are converted to text. Relative paths are resolved against the allowed directories; files outside them cannot be loaded.";
+
+/// Create the `load_file(path)` function, which loads files inside `dirs`
+/// with [`Input::from_file_with`] and runs them through `pipeline`.
+///
+/// Paths are resolved (following symlinks and `..`) before the allowlist is
+/// checked, so nothing outside the directories is reachable.
+pub fn load_file_function(
+    dirs: &[PathBuf],
+    options: LoadOptions,
+    pipeline: InputPipeline,
+) -> HostFunction {
+    let dirs = canonical_dirs(dirs);
+
+    HostFunction::new(
+        "load_file",
+        LOAD_FILE_DESCRIPTION,
+        move |_, path: String| {
+            let resolved = resolve(&dirs, Path::new(&path)).ok_or_else(|| {
+                mlua::Error::RuntimeError(format!(
+                    "load_file: {path} does not exist in an allowed directory"
+                ))
+            })?;
+            if resolved.is_dir() {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "load_file: {path} is a directory"
+                )));
+            }
+
+            let input = Input::from_file_with(&resolved, &options)
+                .map_err(|e| mlua::Error::RuntimeError(format!("load_file: {e}")))?;
+            let input = pipeline.run(input);
+            tracing::info!("load_file loaded {}", resolved.display());
+            Ok((
+                input.content().to_string(),
+                input.description().map(str::to_string),
+            ))
+        },
+    )
+}
+
+/// The canonical forms of allowed directories, leaving out directories that
+/// do not exist, which allow nothing
+pub(crate) fn canonical_dirs(dirs: &[PathBuf]) -> Vec<PathBuf> {
+    dirs.iter()
+        .filter_map(|dir| dir.canonicalize().ok())
+        .collect()
+}
+
+/// Resolve a path inside one of the allowed (canonical) directories.
+/// Relative paths are tried against each directory in order.
+pub(crate) fn resolve(dirs: &[PathBuf], path: &Path) -> Option<PathBuf> {
+    let candidates: Vec<PathBuf> = if path.is_absolute() {
+        vec![path.to_path_buf()]
+    } else {
+        dirs.iter().map(|dir| dir.join(path)).collect()
+    };
+    candidates
+        .into_iter()
+        .filter_map(|candidate| candidate.canonicalize().ok())
+        .find(|resolved| dirs.iter().any(|dir| resolved.starts_with(dir)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::environment::{Environment, EnvironmentConfig, LlmClient};
+    use std::fs;
+
+    #[test]
+    fn test_load_file() {
+        let root = tempfile::tempdir().unwrap();
+        let allowed = root.path().join("docs");
+        fs::create_dir_all(allowed.join("sub")).unwrap();
+        fs::write(allowed.join("sub/appendix.csv"), "id,name\n1,Ada\n").unwrap();
+        fs::write(root.path().join("secret.txt"), "hunter2").unwrap();
+
+        let config = EnvironmentConfig::default().with_load_file_dirs(&[allowed]);
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+
+        let result = env
+            .eval(r#"text, description = load_file("sub/appendix.csv") print(text)"#)
+            .unwrap();
+        assert_eq!(result, Some("id,name\n1,Ada\n".to_string()));
+        let result = env.eval("print(description)").unwrap().unwrap();
+        assert!(result.starts_with("table with 1 rows"));
+
+        // Escaping the allowed directory fails, however the path is written
+        let secret = root.path().join("secret.txt");
+        for path in ["../secret.txt", secret.to_str().unwrap(), "sub"] {
+            let err = env
+                .eval(&format!("load_file({path:?})"))
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("load_file"), "{path}: {err}");
+        }
+    }
+}
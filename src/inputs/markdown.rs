@@ -0,0 +1,155 @@
+//! Section index for Markdown contexts: the heading tree is exposed as the
+//! `context_sections` Lua table and sections are fetched with `get_section`.
+
+use crate::environment::HostFunction;
+use serde_json::{Value, json};
+
+/// Usage description listed under Available Functions in the system prompt
+const GET_SECTION_DESCRIPTION: &str = "Returns the text of a Markdown section, heading and subsections included, with `get_section(title)`, or nil if no heading matches. Titles match exactly first, then case-insensitively, then as a substring. `context_sections` lists every heading as {title, level, start, stop, parent}, where `context:sub(start, stop)` is the section and `parent` is the index of the enclosing section.";
+
+/// A heading and the byte range of its section
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub title: String,
+    pub level: usize,
+    /// Byte offset of the heading line
+    pub start: usize,
+    /// Byte offset just past the section, before the next heading of the same
+    /// or a higher level
+    pub end: usize,
+    /// Index of the enclosing section
+    pub parent: Option<usize>,
+}
+
+/// Find the ATX (`#`-prefixed) headings outside fenced code blocks
+pub fn sections(text: &str) -> Vec<Section> {
+    let mut sections: Vec<Section> = Vec::new();
+    let mut fence: Option<&str> = None;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+
+        let trimmed = line.trim_start();
+        if let Some(marker) = fence {
+            if trimmed.starts_with(marker) {
+                fence = None;
+            }
+            continue;
+        }
+        if let Some(marker) = ["```", "~~~"].into_iter().find(|m| trimmed.starts_with(m)) {
+            fence = Some(marker);
+            continue;
+        }
+
+        let hashes = line.chars().take_while(|c| *c == '#').count();
+        let rest = &line[hashes..];
+        if !(1..=6).contains(&hashes) || !(rest.starts_with(' ') || rest.trim().is_empty()) {
+            continue;
+        }
+        let title = rest.trim().trim_end_matches('#').trim_end().to_string();
+
+        // Close the sections this heading ends and find its parent
+        let mut parent = None;
+        for (i, section) in sections.iter_mut().enumerate().rev() {
+            if section.end != usize::MAX {
+                continue;
+            }
+            if section.level >= hashes {
+                section.end = start;
+            } else {
+                parent = Some(i);
+                break;
+            }
+        }
+        sections.push(Section {
+            title,
+            level: hashes,
+            start,
+            end: usize::MAX,
+            parent,
+        });
+    }
+
+    for section in &mut sections {
+        if section.end == usize::MAX {
+            section.end = text.len();
+        }
+    }
+    sections
+}
+
+/// Find a section by title: exact match, then case-insensitive, then substring
+pub fn find<'a>(sections: &'a [Section], title: &str) -> Option<&'a Section> {
+    let lower = title.to_lowercase();
+    sections
+        .iter()
+        .find(|s| s.title == title)
+        .or_else(|| sections.iter().find(|s| s.title.to_lowercase() == lower))
+        .or_else(|| {
+            sections
+                .iter()
+                .find(|s| s.title.to_lowercase().contains(&lower))
+        })
+}
+
+/// The `context_sections` table, with 1-based inclusive ranges for
+/// `string.sub` and 1-based parent indices
+pub fn sections_value(sections: &[Section]) -> Value {
+    sections
+        .iter()
+        .map(|s| {
+            json!({
+                "title": s.title,
+                "level": s.level,
+                "start": s.start + 1,
+                "stop": s.end,
+                "parent": s.parent.map(|p| p + 1),
+            })
+        })
+        .collect()
+}
+
+/// Create the `get_section(title)` function over a document
+pub fn get_section_function(text: String, sections: Vec<Section>) -> HostFunction {
+    HostFunction::new(
+        "get_section",
+        GET_SECTION_DESCRIPTION,
+        move |_, title: String| {
+            Ok(find(&sections, &title).map(|s| text[s.start..s.end].to_string()))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOC: &str = "Intro text\n# Guide\nOverview\n## Install\nRun it\n```\n# not a heading\n```\n## Usage ##\nFlags\n# Appendix\nEnd\n";
+
+    #[test]
+    fn test_sections() {
+        let sections = sections(DOC);
+        let titles: Vec<&str> = sections.iter().map(|s| s.title.as_str()).collect();
+        assert_eq!(titles, vec!["Guide", "Install", "Usage", "Appendix"]);
+
+        let parents: Vec<Option<usize>> = sections.iter().map(|s| s.parent).collect();
+        assert_eq!(parents, vec![None, Some(0), Some(0), None]);
+
+        assert_eq!(
+            &DOC[sections[1].start..sections[1].end],
+            "## Install\nRun it\n```\n# not a heading\n```\n"
+        );
+        assert_eq!(sections[0].end, sections[3].start);
+        assert_eq!(sections[3].end, DOC.len());
+    }
+
+    #[test]
+    fn test_find() {
+        let sections = sections(DOC);
+        assert_eq!(find(&sections, "usage").unwrap().title, "Usage");
+        assert_eq!(find(&sections, "Append").unwrap().title, "Appendix");
+        assert!(find(&sections, "Missing").is_none());
+    }
+}
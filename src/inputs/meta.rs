@@ -0,0 +1,120 @@
+//! File metadata exposed to Lua as the `context_meta` table.
+
+use super::InputError;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use std::path::Path;
+
+/// Facts about the file or directory an input was loaded from
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InputMeta {
+    pub filename: String,
+    pub path: String,
+    /// Size on disk in bytes (for directories, the total of the loaded files)
+    pub size: u64,
+    pub mime_type: &'static str,
+    /// Last modification time in RFC 3339, UTC
+    pub modified: Option<String>,
+    /// Page count, for PDFs
+    pub pages: Option<usize>,
+    /// Number of files loaded, for directories
+    pub files: Option<usize>,
+}
+
+impl InputMeta {
+    /// Read the metadata of a file or directory
+    pub fn for_path(path: &Path) -> Result<Self, InputError> {
+        let metadata = std::fs::metadata(path).map_err(|e| InputError::ReadError(e.to_string()))?;
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        Ok(Self {
+            filename: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| path.display().to_string()),
+            path: path.display().to_string(),
+            size: metadata.len(),
+            mime_type: if metadata.is_dir() {
+                "inode/directory"
+            } else {
+                mime_type(&ext)
+            },
+            modified: metadata
+                .modified()
+                .ok()
+                .map(|time| DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Secs, true)),
+            pages: None,
+            files: None,
+        })
+    }
+
+    /// One-line summary included in the prompt, e.g.
+    /// "report.pdf (application/pdf, 48213 bytes, 200 pages)"
+    pub fn summary(&self) -> String {
+        let mut facts = vec![self.mime_type.to_string(), format!("{} bytes", self.size)];
+        if let Some(pages) = self.pages {
+            facts.push(format!("{pages} pages"));
+        }
+        if let Some(files) = self.files {
+            facts.push(format!("{files} files"));
+        }
+        format!("{} ({})", self.filename, facts.join(", "))
+    }
+}
+
+/// MIME types by lowercased file extension
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    ),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    (
+        "xlsx",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    ),
+    ("xlsm", "application/vnd.ms-excel.sheet.macroEnabled.12"),
+    ("xls", "application/vnd.ms-excel"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("ipynb", "application/x-ipynb+json"),
+    ("eml", "message/rfc822"),
+    ("mbox", "application/mbox"),
+    ("md", "text/markdown"),
+    ("markdown", "text/markdown"),
+    ("db", "application/vnd.sqlite3"),
+    ("sqlite", "application/vnd.sqlite3"),
+    ("sqlite3", "application/vnd.sqlite3"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("srt", "application/x-subrip"),
+    ("vtt", "text/vtt"),
+];
+
+/// MIME type for a lowercased file extension; unknown files are read as text
+fn mime_type(ext: &str) -> &'static str {
+    MIME_TYPES
+        .iter()
+        .find(|(e, _)| *e == ext)
+        .map_or("text/plain", |(_, mime_type)| mime_type)
+}
+
+/// The extension of the loader for a lowercased MIME type
+pub(crate) fn extension_for_mime(mime_type: &str) -> Option<&'static str> {
+    MIME_TYPES
+        .iter()
+        .find(|(_, m)| m.eq_ignore_ascii_case(mime_type))
+        .map(|(ext, _)| *ext)
+}
@@ -1,12 +1,86 @@
+mod cache;
+mod email;
+mod load_file;
+mod markdown;
+mod meta;
+#[cfg(feature = "ocr")]
+mod ocr;
+mod pdf_layout;
+pub mod pipeline;
+mod spreadsheet;
+mod sqlite;
+mod subtitles;
+mod xml;
+
+pub use cache::default_cache_dir;
+pub use load_file::load_file_function;
+pub(crate) use load_file::{canonical_dirs, resolve};
+pub use meta::InputMeta;
+pub use pipeline::{InputPipeline, Stage};
+
+use crate::environment::HostFunction;
+use base64::prelude::{BASE64_STANDARD, Engine as _};
 use lopdf::Document;
+use scraper::{ElementRef, Html, Selector};
+use serde_json::Value;
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Bytes inspected when deciding whether a file is binary
+const BINARY_SNIFF_BYTES: usize = 8192;
+
+/// Elements whose content is never readable text
+const HTML_SKIPPED: &[&str] = &[
+    "head", "script", "style", "noscript", "template", "svg", "iframe",
+];
+
+/// Elements rendered on their own line
+const HTML_LINES: &[&str] = &["dd", "dt", "li", "tr"];
+
+/// Elements separated from their siblings by a blank line
+const HTML_BLOCKS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "body",
+    "div",
+    "dl",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "main",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "ul",
+];
 
 #[derive(Debug)]
 pub enum InputError {
     FileNotFound(String),
     ReadError(String),
     PdfError(String),
+    OfficeError(String),
+    CsvError(String),
+    JsonError(String),
+    OcrError(String),
+    SqliteError(String),
+    EmailError(String),
     UnsupportedFormat(String),
 }
 
@@ -16,6 +90,12 @@ impl std::fmt::Display for InputError {
             InputError::FileNotFound(path) => write!(f, "File not found: {path}"),
             InputError::ReadError(msg) => write!(f, "Error reading file: {msg}"),
             InputError::PdfError(msg) => write!(f, "Error processing PDF: {msg}"),
+            InputError::OfficeError(msg) => write!(f, "Error processing office document: {msg}"),
+            InputError::CsvError(msg) => write!(f, "Error parsing CSV: {msg}"),
+            InputError::JsonError(msg) => write!(f, "Error parsing JSON: {msg}"),
+            InputError::OcrError(msg) => write!(f, "Error running OCR: {msg}"),
+            InputError::SqliteError(msg) => write!(f, "Error reading SQLite database: {msg}"),
+            InputError::EmailError(msg) => write!(f, "Error parsing email: {msg}"),
             InputError::UnsupportedFormat(msg) => write!(f, "Unsupported format: {msg}"),
         }
     }
@@ -23,61 +103,697 @@ impl std::fmt::Display for InputError {
 
 impl std::error::Error for InputError {}
 
+/// Options controlling how files are loaded
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// Insert a `--- page N ---` line before each PDF page
+    pub pdf_page_markers: bool,
+    /// Fail on text that is malformed in its detected encoding instead of
+    /// replacing the bad bytes with U+FFFD
+    pub strict_encoding: bool,
+    /// Reconstruct PDF tables as `| a | b |` rows from the position of each
+    /// piece of text, instead of extracting text in content-stream order
+    pub pdf_tables: bool,
+    /// Load binary files as the base64 encoding of this many leading bytes
+    /// instead of rejecting them
+    pub binary_prefix_bytes: Option<usize>,
+    /// Cache extracted text of slow formats (PDFs, images, office documents)
+    /// in this directory, keyed by a hash of the file's contents
+    pub cache_dir: Option<PathBuf>,
+}
+
 #[derive(Debug)]
 pub struct Input {
     content: String,
+    description: Option<String>,
+    globals: BTreeMap<String, Value>,
+    functions: Vec<HostFunction>,
+    meta: Option<InputMeta>,
 }
 
 impl Input {
-    /// Load content from a file. Supports text files and PDFs.
+    /// Load content from a file. Supports text files, PDFs, HTML, DOCX, ODT,
+    /// spreadsheets, CSV/TSV, JSON, Markdown, Jupyter notebooks, emails and mailboxes and
+    /// SQLite databases, and images with the `ocr` feature.
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, InputError> {
+        Self::from_file_with(path, &LoadOptions::default())
+    }
+
+    /// Load content from a file with explicit [`LoadOptions`]
+    pub fn from_file_with<P: AsRef<Path>>(
+        path: P,
+        options: &LoadOptions,
+    ) -> Result<Self, InputError> {
         let path = path.as_ref();
 
         if !path.exists() {
             return Err(InputError::FileNotFound(path.display().to_string()));
         }
 
-        // Check if it's a PDF by extension
-        if let Some(ext) = path.extension() {
-            if ext.eq_ignore_ascii_case("pdf") {
-                return Self::load_pdf(path);
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        // Slow formats are read from the cache when possible
+        let cache = match &options.cache_dir {
+            Some(dir) if cache::cacheable(&ext) => Some((dir, cache::key(path, &ext, options)?)),
+            _ => None,
+        };
+        let cached = cache.as_ref().and_then(|(dir, key)| cache::load(dir, key));
+        let mut input = match cached {
+            Some(input) => {
+                tracing::debug!("Loaded {} from cache", path.display());
+                input
+            }
+            None => {
+                let input = Self::load_extension(path, &ext, options)?;
+                if let Some((dir, key)) = &cache {
+                    cache::store(dir, key, &input);
+                }
+                input
+            }
+        };
+
+        let mut meta = InputMeta::for_path(path)?;
+        meta.pages = input
+            .globals
+            .get("pages")
+            .and_then(Value::as_array)
+            .map(Vec::len);
+        input.meta = Some(meta);
+        Ok(input)
+    }
+
+    /// Load a file with the loader for its (lowercased) extension
+    fn load_extension(path: &Path, ext: &str, options: &LoadOptions) -> Result<Self, InputError> {
+        match ext {
+            // OCR and SQLite work on the file itself
+            "png" | "jpg" | "jpeg" | "tif" | "tiff" => Self::load_image(path),
+            "db" | "sqlite" | "sqlite3" => Self::load_sqlite(path),
+            _ => {
+                let bytes = fs::read(path).map_err(|e| InputError::ReadError(e.to_string()))?;
+                let name = path.display().to_string();
+                if ext == "pdf" {
+                    Self::load_pdf(&bytes, Some(path), options)
+                } else {
+                    Self::load_bytes(&bytes, &name, ext, options)
+                }
             }
         }
+    }
+
+    /// Load in-memory data, e.g. an upload, with the same format dispatch as
+    /// [`Input::from_file`].
+    ///
+    /// The `hint` names the format as a MIME type (`application/pdf`), an
+    /// extension (`pdf`), or a filename (`report.pdf`). Unknown formats are
+    /// read as text. Images and SQLite databases can only be loaded from files.
+    pub fn from_bytes(bytes: &[u8], hint: &str) -> Result<Self, InputError> {
+        Self::from_bytes_with(bytes, hint, &LoadOptions::default())
+    }
+
+    /// Load in-memory data with explicit [`LoadOptions`]
+    pub fn from_bytes_with(
+        bytes: &[u8],
+        hint: &str,
+        options: &LoadOptions,
+    ) -> Result<Self, InputError> {
+        let ext = extension_for_hint(hint);
+        let name = if hint.contains('.') { hint } else { "data" };
+        Self::load_bytes(bytes, name, &ext, options)
+    }
+
+    /// Load the contents of a file; `name` identifies it in errors
+    fn load_bytes(
+        bytes: &[u8],
+        name: &str,
+        ext: &str,
+        options: &LoadOptions,
+    ) -> Result<Self, InputError> {
+        let input = match ext {
+            "pdf" => Self::load_pdf(bytes, None, options)?,
+            "png" | "jpg" | "jpeg" | "tif" | "tiff" | "db" | "sqlite" | "sqlite3" => {
+                return Err(InputError::UnsupportedFormat(format!(
+                    "{name}: .{ext} files can only be loaded from the filesystem"
+                )));
+            }
+            "html" | "htm" => Self::load_html(bytes, name, options)?,
+            "docx" => Self::load_docx(bytes)?,
+            "odt" => Self::load_odt(bytes)?,
+            "xlsx" | "xlsm" | "xls" | "ods" => Self::load_spreadsheet(bytes)?,
+            "csv" => Self::load_csv(bytes, name, b',', options)?,
+            "tsv" => Self::load_csv(bytes, name, b'\t', options)?,
+            "ipynb" => Self::load_notebook(bytes, name, options)?,
+            "eml" | "mbox" => Self::load_email(bytes, ext)?,
+            "md" | "markdown" => Self::load_markdown(bytes, name, options)?,
+            "xml" => Self::load_xml(bytes, name, options)?,
+            "srt" | "vtt" => Self::load_subtitles(bytes, name, options)?,
+            "json" => {
+                // Malformed JSON is still useful as text
+                let input = Self::load_text(bytes, name, options)?;
+                let text = input.content.clone();
+                input.parse_json().unwrap_or_else(|e| {
+                    tracing::warn!("{name}: {e}, loading as text");
+                    Input::from_string(text)
+                })
+            }
+            // Otherwise try to read as text
+            _ => Self::load_text(bytes, name, options)?,
+        };
+        Ok(input)
+    }
 
-        // Otherwise try to read as text
-        Self::load_text(path)
+    /// Load every supported file under a directory, recursively.
+    ///
+    /// Files are visited in path order and concatenated, each preceded by a
+    /// `=== relative/path ===` header line. Hidden files and directories are
+    /// skipped, as are files that fail to load (e.g. binary files).
+    pub fn from_dir<P: AsRef<Path>>(path: P) -> Result<Self, InputError> {
+        Self::from_dir_with(path, &LoadOptions::default())
+    }
+
+    /// Load a directory with explicit [`LoadOptions`] applied to every file
+    pub fn from_dir_with<P: AsRef<Path>>(
+        path: P,
+        options: &LoadOptions,
+    ) -> Result<Self, InputError> {
+        let root = path.as_ref();
+        if !root.is_dir() {
+            return Err(InputError::FileNotFound(root.display().to_string()));
+        }
+
+        let mut files = Vec::new();
+        collect_files(root, &mut files)?;
+
+        let named: Vec<(String, Input)> = files
+            .into_iter()
+            .filter_map(|file| match Self::from_file_with(&file, options) {
+                Ok(input) => {
+                    let relative = file.strip_prefix(root).unwrap_or(&file);
+                    Some((relative.display().to_string(), input))
+                }
+                Err(e) => {
+                    tracing::warn!("Skipping {}: {e}", file.display());
+                    None
+                }
+            })
+            .collect();
+
+        let mut input = Self::concat(&named);
+        let mut meta = InputMeta::for_path(root)?;
+        meta.size = named
+            .iter()
+            .filter_map(|(_, input)| input.meta.as_ref())
+            .map(|meta| meta.size)
+            .sum();
+        meta.files = Some(named.len());
+        input.meta = Some(meta);
+        Ok(input)
+    }
+
+    /// Concatenate named inputs, each preceded by a `=== name ===` header line
+    pub fn concat(inputs: &[(String, Input)]) -> Self {
+        let mut content = String::new();
+        let mut functions: Vec<HostFunction> = Vec::new();
+        for (name, input) in inputs {
+            for function in &input.functions {
+                if functions.iter().any(|f| f.name == function.name) {
+                    tracing::warn!("Dropping duplicate `{}` from {name}", function.name);
+                } else {
+                    functions.push(function.clone());
+                }
+            }
+            content.push_str(&format!("=== {name} ===\n"));
+            content.push_str(input.content());
+            if !content.ends_with('\n') {
+                content.push('\n');
+            }
+            content.push('\n');
+        }
+
+        Input {
+            content,
+            description: Some(format!(
+                "{} files concatenated, each starting with a header line `=== path ===`",
+                inputs.len()
+            )),
+            globals: BTreeMap::new(),
+            functions,
+            meta: None,
+        }
+    }
+
+    /// Compare two inputs as a unified diff of their text, e.g. two versions
+    /// of a contract.
+    ///
+    /// The diff becomes the content and the full texts are exposed as the
+    /// `context_old` and `context_new` globals. Inputs are labelled with the
+    /// path they were loaded from, or "old" and "new".
+    pub fn diff(old: &Input, new: &Input) -> Self {
+        let label = |input: &Input, default: &str| {
+            input
+                .meta
+                .as_ref()
+                .map_or_else(|| default.to_string(), |meta| meta.path.clone())
+        };
+        let (old_name, new_name) = (label(old, "old"), label(new, "new"));
+
+        let diff = similar::TextDiff::from_lines(old.content(), new.content());
+        let (mut added, mut removed) = (0, 0);
+        for change in diff.iter_all_changes() {
+            match change.tag() {
+                similar::ChangeTag::Insert => added += 1,
+                similar::ChangeTag::Delete => removed += 1,
+                similar::ChangeTag::Equal => {}
+            }
+        }
+        let hunks = diff.grouped_ops(3).len();
+
+        let mut input = if hunks == 0 {
+            Input::from_string(format!(
+                "No differences between {old_name} and {new_name}\n"
+            ))
+        } else {
+            Input::from_string(
+                diff.unified_diff()
+                    .context_radius(3)
+                    .header(&old_name, &new_name)
+                    .to_string(),
+            )
+        };
+        input.description = Some(format!(
+            "unified diff of {old_name} (---) and {new_name} (+++) with {hunks} hunks, {added} lines added and {removed} removed; the full texts are in `context_old` and `context_new`"
+        ));
+        input
+            .globals
+            .insert("context_old".to_string(), old.content().into());
+        input
+            .globals
+            .insert("context_new".to_string(), new.content().into());
+        input
     }
 
     /// Load a text file
-    fn load_text<P: AsRef<Path>>(path: P) -> Result<Self, InputError> {
-        let content =
-            fs::read_to_string(path.as_ref()).map_err(|e| InputError::ReadError(e.to_string()))?;
+    fn load_text(bytes: &[u8], name: &str, options: &LoadOptions) -> Result<Self, InputError> {
+        match (read_text(bytes, name, options), options.binary_prefix_bytes) {
+            (Err(InputError::UnsupportedFormat(_)), Some(limit)) => {
+                Ok(Self::load_binary_prefix(bytes, limit))
+            }
+            (content, _) => content.map(Input::from_string),
+        }
+    }
+
+    /// Load the first `limit` bytes of a binary file, base64-encoded
+    fn load_binary_prefix(bytes: &[u8], limit: usize) -> Self {
+        let prefix = &bytes[..bytes.len().min(limit)];
+        let size = bytes.len();
+        let mime_type = sniff_binary(prefix).unwrap_or("application/octet-stream");
 
-        Ok(Input { content })
+        let mut input = Input::from_string(BASE64_STANDARD.encode(prefix));
+        input.description = Some(format!(
+            "binary file ({mime_type}, {size} bytes); `context` is the base64 encoding of the first {} bytes",
+            prefix.len()
+        ));
+        input
     }
 
-    /// Load a PDF file and extract text
-    fn load_pdf<P: AsRef<Path>>(path: P) -> Result<Self, InputError> {
-        let doc = Document::load(path.as_ref())
+    /// Load a PDF file and extract text.
+    ///
+    /// The text of each page is also exposed as the `pages` global, indexed by
+    /// page number, so the model can cite pages. Scanned PDFs are recognized
+    /// with OCR when loaded from a file (`path`).
+    fn load_pdf(
+        bytes: &[u8],
+        #[allow(unused_variables)] path: Option<&Path>,
+        options: &LoadOptions,
+    ) -> Result<Self, InputError> {
+        let doc = Document::load_mem(bytes)
             .map_err(|e| InputError::PdfError(format!("Failed to load PDF: {e}")))?;
 
-        let mut content = String::new();
+        // Extract text from all pages, keeping unreadable pages as empty
+        // entries so indices match page numbers
+        #[allow(unused_mut)]
+        let mut pages: Vec<String> = doc
+            .get_pages()
+            .into_iter()
+            .map(|(page_num, page_id)| {
+                let text = if options.pdf_tables {
+                    pdf_layout::page_text(&doc, page_id)
+                } else {
+                    doc.extract_text(&[page_num])
+                        .map_err(|e| InputError::PdfError(e.to_string()))
+                };
+                text.map(|text| normalize_pdf_text(&text))
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        // Scanned documents have no text layer
+        #[cfg(feature = "ocr")]
+        if let Some(path) = path
+            && pages.iter().all(String::is_empty)
+        {
+            pages = ocr::pdf_pages(path)?;
+        }
 
-        // Extract text from all pages
-        for page_num in 1..=doc.get_pages().len() {
-            if let Ok(page_content) = doc.extract_text(&[page_num as u32]) {
-                content.push_str(&page_content);
+        if pages.iter().all(String::is_empty) {
+            return Err(InputError::PdfError(if cfg!(feature = "ocr") {
+                "No text could be extracted from PDF, even with OCR".to_string()
+            } else {
+                "No text could be extracted from PDF (scanned documents need the `ocr` feature)"
+                    .to_string()
+            }));
+        }
+
+        let mut content = String::new();
+        for (i, page) in pages.iter().enumerate() {
+            if options.pdf_page_markers {
+                content.push_str(&format!("--- page {} ---\n", i + 1));
+            }
+            if !page.is_empty() {
+                content.push_str(page);
                 content.push('\n');
             }
         }
 
-        if content.is_empty() {
-            return Err(InputError::PdfError(
-                "No text could be extracted from PDF".to_string(),
-            ));
+        let mut input = Input::from_string(content);
+        input.description = Some(format!(
+            "PDF with {} pages{}; the text of each page is also available as `pages[n]`",
+            pages.len(),
+            if options.pdf_page_markers {
+                ", each starting with a `--- page N ---` line"
+            } else {
+                ""
+            }
+        ));
+        input.globals.insert(
+            "pages".to_string(),
+            pages.into_iter().map(Value::from).collect(),
+        );
+        Ok(input)
+    }
+
+    /// Load an image by running OCR on it (requires the `ocr` feature)
+    fn load_image<P: AsRef<Path>>(path: P) -> Result<Self, InputError> {
+        #[cfg(feature = "ocr")]
+        {
+            let text = ocr::image_text(path.as_ref())?;
+            if text.is_empty() {
+                return Err(InputError::OcrError(
+                    "No text could be recognized in image".to_string(),
+                ));
+            }
+            Ok(Input::from_string(text))
+        }
+
+        #[cfg(not(feature = "ocr"))]
+        Err(InputError::UnsupportedFormat(format!(
+            "{} is an image; loading images requires the `ocr` feature",
+            path.as_ref().display()
+        )))
+    }
+
+    /// Load an HTML file and extract its readable text
+    fn load_html(bytes: &[u8], name: &str, options: &LoadOptions) -> Result<Self, InputError> {
+        let html = read_text(bytes, name, options)?;
+
+        Ok(Input::from_string(html_to_text(&html)))
+    }
+
+    /// Load a Word document and extract the text of its paragraphs
+    fn load_docx(bytes: &[u8]) -> Result<Self, InputError> {
+        let xml = read_zip_entry(bytes, "word/document.xml")?;
+        let doc = roxmltree::Document::parse(&xml)
+            .map_err(|e| InputError::OfficeError(format!("Invalid document XML: {e}")))?;
+
+        let mut content = String::new();
+        append_docx_text(doc.root(), &mut content);
+
+        Ok(Input::from_string(content.trim_end().to_string()))
+    }
+
+    /// Load an OpenDocument text file and extract the text of its paragraphs
+    fn load_odt(bytes: &[u8]) -> Result<Self, InputError> {
+        let xml = read_zip_entry(bytes, "content.xml")?;
+        let doc = roxmltree::Document::parse(&xml)
+            .map_err(|e| InputError::OfficeError(format!("Invalid document XML: {e}")))?;
+
+        let mut content = String::new();
+        if let Some(body) = doc.descendants().find(|n| n.tag_name().name() == "body") {
+            append_odt_text(body, &mut content, false);
+        }
+
+        Ok(Input::from_string(content.trim_end().to_string()))
+    }
+
+    /// Load a Jupyter notebook as its cells in order.
+    ///
+    /// Each cell starts with a `--- cell N (type) ---` line and its outputs
+    /// follow a `--- output ---` line; rich outputs without a text form are
+    /// replaced by a placeholder.
+    fn load_notebook(bytes: &[u8], name: &str, options: &LoadOptions) -> Result<Self, InputError> {
+        let notebook: Value = serde_json::from_str(&read_text(bytes, name, options)?)
+            .map_err(|e| InputError::JsonError(e.to_string()))?;
+        let cells = notebook["cells"]
+            .as_array()
+            .ok_or_else(|| InputError::JsonError("notebook has no cells".to_string()))?;
+
+        let mut content = String::new();
+        for (i, cell) in cells.iter().enumerate() {
+            let cell_type = cell["cell_type"].as_str().unwrap_or("unknown");
+            content.push_str(&format!("--- cell {} ({cell_type}) ---\n", i + 1));
+            push_line(&mut content, &notebook_text(&cell["source"]));
+
+            let outputs = cell["outputs"].as_array().map(Vec::as_slice).unwrap_or(&[]);
+            if !outputs.is_empty() {
+                content.push_str("--- output ---\n");
+            }
+            for output in outputs {
+                let text = match output["output_type"].as_str() {
+                    Some("stream") => notebook_text(&output["text"]),
+                    Some("error") => format!(
+                        "{}: {}",
+                        output["ename"].as_str().unwrap_or("Error"),
+                        output["evalue"].as_str().unwrap_or_default()
+                    ),
+                    _ => match output["data"].get("text/plain") {
+                        Some(text) => notebook_text(text),
+                        None => {
+                            let kinds: Vec<&str> = output["data"]
+                                .as_object()
+                                .map(|data| data.keys().map(String::as_str).collect())
+                                .unwrap_or_default();
+                            format!("[{} output omitted]", kinds.join(", "))
+                        }
+                    },
+                };
+                push_line(&mut content, &text);
+            }
+            content.push('\n');
+        }
+
+        let mut input = Input::from_string(content);
+        input.description = Some(format!(
+            "Jupyter notebook with {} cells, each starting with a `--- cell N (type) ---` line; cell outputs follow a `--- output ---` line",
+            cells.len()
+        ));
+        Ok(input)
+    }
+
+    /// Load an email (`.eml`) or mailbox (`.mbox`).
+    ///
+    /// Messages are rendered with their From/To/Date/Subject headers and
+    /// plain-text bodies, and exposed as the `messages` global.
+    fn load_email(raw: &[u8], ext: &str) -> Result<Self, InputError> {
+        let messages = if ext == "mbox" {
+            email::parse_mbox(raw)?
+        } else {
+            vec![email::parse_message(raw)?]
+        };
+
+        let mut input = Input::from_string(email::to_text(&messages));
+        input.description = Some(format!(
+            "{} email messages, each starting with a `--- message N ---` line; also in the `messages` table as {{from, to, date, subject, body}}",
+            messages.len()
+        ));
+        input
+            .globals
+            .insert("messages".to_string(), Value::Array(messages));
+        Ok(input)
+    }
+
+    /// Load a spreadsheet as CSV text, one section per sheet.
+    ///
+    /// Each sheet starts with a `--- sheet NAME ---` line, and its cells are
+    /// exposed as the `sheets` global keyed by sheet name.
+    fn load_spreadsheet(bytes: &[u8]) -> Result<Self, InputError> {
+        let sheets = spreadsheet::read(bytes)?;
+        let names: Vec<&str> = sheets.iter().map(|(name, _)| name.as_str()).collect();
+
+        let mut input = Input::from_string(spreadsheet::to_text(&sheets)?);
+        input.description = Some(format!(
+            "Spreadsheet with {} sheets ({}) as CSV, each starting with a `--- sheet NAME ---` line; cells are also in the `sheets` table as sheets[name][row][column]",
+            sheets.len(),
+            names.join(", ")
+        ));
+        input
+            .globals
+            .insert("sheets".to_string(), spreadsheet::to_value(&sheets));
+        Ok(input)
+    }
+
+    /// Load a Markdown file with an index of its sections.
+    ///
+    /// The heading tree is exposed as the `context_sections` global and the
+    /// `get_section(title)` function returns a section's text.
+    fn load_markdown(bytes: &[u8], name: &str, options: &LoadOptions) -> Result<Self, InputError> {
+        let content = read_text(bytes, name, options)?;
+        let sections = markdown::sections(&content);
+        if sections.is_empty() {
+            return Ok(Input::from_string(content));
         }
 
-        Ok(Input { content })
+        let mut input = Input::from_string(content.clone());
+        input.description = Some(format!(
+            "Markdown document with {} sections, listed in the `context_sections` table; fetch one with `get_section(title)`",
+            sections.len()
+        ));
+        input.globals.insert(
+            "context_sections".to_string(),
+            markdown::sections_value(&sections),
+        );
+        input
+            .functions
+            .push(markdown::get_section_function(content, sections));
+        Ok(input)
+    }
+
+    /// Load an XML document, queried with the `xml_find(path)` and
+    /// `xml_children(node)` functions. Malformed XML is loaded as plain text.
+    fn load_xml(bytes: &[u8], name: &str, options: &LoadOptions) -> Result<Self, InputError> {
+        let content = read_text(bytes, name, options)?;
+        let tree = match xml::XmlTree::parse(&content) {
+            Ok(tree) => tree,
+            Err(e) => {
+                tracing::warn!("{name}: invalid XML ({e}), loading as text");
+                return Ok(Input::from_string(content));
+            }
+        };
+
+        let mut input = Input::from_string(content);
+        input.description = Some(format!(
+            "XML document with root element <{}> and {} elements; query it with `xml_find(path)` and `xml_children(node)` instead of string patterns",
+            tree.root_tag(),
+            tree.element_count()
+        ));
+        input.functions.extend(xml::xml_functions(tree));
+        Ok(input)
+    }
+
+    /// Load subtitles or a transcript as clean text.
+    ///
+    /// Timestamps and markup are dropped from the content; the cues are
+    /// exposed as the `segments` global with times in seconds.
+    fn load_subtitles(bytes: &[u8], name: &str, options: &LoadOptions) -> Result<Self, InputError> {
+        let segments = subtitles::parse(&read_text(bytes, name, options)?)?;
+        let duration = segments.last().map_or(0.0, |s| s.end);
+
+        let mut input = Input::from_string(subtitles::transcript(&segments));
+        input.description = Some(format!(
+            "Transcript of {} subtitle segments ({}:{:02} long), one line per segment with speaker names where known; timings are in the `segments` table as {{start, end, text, speaker}} with times in seconds",
+            segments.len(),
+            (duration / 60.0) as u64,
+            (duration % 60.0) as u64
+        ));
+        input
+            .globals
+            .insert("segments".to_string(), subtitles::to_value(&segments));
+        Ok(input)
+    }
+
+    /// Load a SQLite database.
+    ///
+    /// The content is the schema, with a row count per table; the data is
+    /// queried through the `sql_query(sql)` function, which only accepts
+    /// read-only statements.
+    fn load_sqlite(path: &Path) -> Result<Self, InputError> {
+        let conn = sqlite::open(path)?;
+        let mut input = Input::from_string(sqlite::schema(&conn)?);
+        input.description =
+            Some("SQLite database schema; query the data with `sql_query(sql)`".to_string());
+        input.functions.push(sqlite::sql_query_function(path)?);
+        Ok(input)
+    }
+
+    /// Load a CSV or TSV file as text, with its rows as structured data.
+    ///
+    /// The first line is the header. Rows are exposed as the `context_rows`
+    /// global (a list of tables keyed by column name, with string values) and
+    /// the column names, in order, as `context_columns`.
+    fn load_csv(
+        bytes: &[u8],
+        name: &str,
+        delimiter: u8,
+        options: &LoadOptions,
+    ) -> Result<Self, InputError> {
+        let content = read_text(bytes, name, options)?;
+
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .flexible(true)
+            .from_reader(content.as_bytes());
+        let columns: Vec<String> = reader
+            .headers()
+            .map_err(|e| InputError::CsvError(e.to_string()))?
+            .iter()
+            .map(str::to_string)
+            .collect();
+
+        let mut rows = Vec::new();
+        for record in reader.records() {
+            let record = record.map_err(|e| InputError::CsvError(e.to_string()))?;
+            let row: serde_json::Map<String, Value> = columns
+                .iter()
+                .zip(record.iter())
+                .map(|(column, field)| (column.clone(), field.into()))
+                .collect();
+            rows.push(Value::Object(row));
+        }
+
+        let mut input = Input::from_string(content);
+        input.description = Some(format!(
+            "table with {} rows and columns {}; rows are also available in `context_rows` as tables keyed by column name (values are strings), column names in order in `context_columns`",
+            rows.len(),
+            columns.join(", ")
+        ));
+        input
+            .globals
+            .insert("context_rows".to_string(), Value::Array(rows));
+        input.globals.insert(
+            "context_columns".to_string(),
+            columns.into_iter().map(Value::from).collect(),
+        );
+        Ok(input)
+    }
+
+    /// Decode the content as JSON, exposing it as the `context_json` global.
+    ///
+    /// Objects become tables keyed by field and arrays 1-indexed sequences;
+    /// the raw text stays available as the content.
+    pub fn parse_json(mut self) -> Result<Self, InputError> {
+        let value: Value = serde_json::from_str(&self.content)
+            .map_err(|e| InputError::JsonError(e.to_string()))?;
+
+        let shape = match &value {
+            Value::Object(map) => format!("an object with {} keys", map.len()),
+            Value::Array(items) => format!("an array of {} items", items.len()),
+            _ => "a scalar".to_string(),
+        };
+        self.description = Some(format!(
+            "JSON document ({shape}); also decoded into the `context_json` table (objects are tables keyed by field, arrays are 1-indexed, null is nil)"
+        ));
+        self.globals.insert("context_json".to_string(), value);
+        Ok(self)
     }
 
     /// Get the content as a string
@@ -85,9 +801,373 @@ impl Input {
         &self.content
     }
 
+    /// Description of how the content is structured, for the model's prompt
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// Structured data derived from the content, to be set as Lua globals
+    /// (see [`crate::environment::EnvironmentConfig::with_global`])
+    pub fn globals(&self) -> &BTreeMap<String, Value> {
+        &self.globals
+    }
+
+    /// Lua functions giving access to the source, e.g. `sql_query` for a
+    /// database (see [`crate::environment::EnvironmentConfig::with_function`])
+    pub fn functions(&self) -> &[HostFunction] {
+        &self.functions
+    }
+
+    /// File metadata, for inputs loaded from a file or directory
+    pub fn meta(&self) -> Option<&InputMeta> {
+        self.meta.as_ref()
+    }
+
     /// Create an Input from a string directly (for backwards compatibility or testing)
     pub fn from_string(content: String) -> Self {
-        Input { content }
+        Input {
+            content,
+            description: None,
+            globals: BTreeMap::new(),
+            functions: Vec::new(),
+            meta: None,
+        }
+    }
+}
+
+/// Expand a context argument into paths.
+///
+/// Existing paths are returned as is; otherwise glob patterns such as
+/// `logs/2024-*.log` expand to their matches in sorted order. A pattern
+/// without matches is an error.
+pub fn expand_glob(pattern: &str) -> Result<Vec<String>, InputError> {
+    if Path::new(pattern).exists() || !pattern.contains(['*', '?', '[']) {
+        return Ok(vec![pattern.to_string()]);
+    }
+
+    let entries = glob::glob(pattern)
+        .map_err(|e| InputError::ReadError(format!("Invalid glob pattern {pattern}: {e}")))?;
+    let mut paths = entries
+        .map(|entry| {
+            entry
+                .map(|path| path.display().to_string())
+                .map_err(|e| InputError::ReadError(e.to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if paths.is_empty() {
+        return Err(InputError::FileNotFound(format!(
+            "no files match {pattern}"
+        )));
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Decode text in any encoding to UTF-8; `name` identifies the file in errors.
+///
+/// A byte order mark decides the encoding if present; otherwise valid UTF-8 is
+/// used as is, and anything else is detected with `chardetng` (e.g. Latin-1 or
+/// Shift-JIS). Binary files are rejected with their detected MIME type.
+fn read_text(bytes: &[u8], name: &str, options: &LoadOptions) -> Result<String, InputError> {
+    if let Some(mime_type) = sniff_binary(bytes) {
+        return Err(binary_error(name, mime_type));
+    }
+    decode_text(bytes, options).map_err(|msg| InputError::ReadError(format!("{name}: {msg}")))
+}
+
+fn binary_error(name: &str, mime_type: &str) -> InputError {
+    InputError::UnsupportedFormat(format!(
+        "{name} is a binary file ({mime_type}); use a supported format or load a base64 prefix with --binary-prefix-bytes"
+    ))
+}
+
+/// The extension whose loader handles a format hint: a MIME type (parameters
+/// such as `; charset=utf-8` are ignored), an extension, or a filename
+fn extension_for_hint(hint: &str) -> String {
+    let hint = hint.split(';').next().unwrap_or_default().trim();
+    if hint.contains('/') {
+        return meta::extension_for_mime(&hint.to_lowercase())
+            .unwrap_or("txt")
+            .to_string();
+    }
+    hint.rsplit('.').next().unwrap_or_default().to_lowercase()
+}
+
+/// Detect binary content from the first few kilobytes: NUL bytes or many
+/// control characters outside a UTF-16 text. Returns the MIME type guessed
+/// from the magic number, or `application/octet-stream`.
+fn sniff_binary(bytes: &[u8]) -> Option<&'static str> {
+    if encoding_rs::Encoding::for_bom(bytes).is_some() {
+        return None;
+    }
+    let sample = &bytes[..bytes.len().min(BINARY_SNIFF_BYTES)];
+    let controls = sample
+        .iter()
+        .filter(|&&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+        .count();
+    if !sample.contains(&0) && controls * 10 <= sample.len() {
+        return None;
+    }
+
+    const MAGIC: &[(&[u8], &str)] = &[
+        (b"%PDF", "application/pdf"),
+        (b"\x89PNG", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF8", "image/gif"),
+        (b"II*\0", "image/tiff"),
+        (b"MM\0*", "image/tiff"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"BZh", "application/x-bzip2"),
+        (b"\xfd7zXZ\0", "application/x-xz"),
+        (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+        (b"Rar!", "application/vnd.rar"),
+        (b"SQLite format 3\0", "application/vnd.sqlite3"),
+        (b"\x7fELF", "application/x-executable"),
+        (b"MZ", "application/vnd.microsoft.portable-executable"),
+        (b"\xca\xfe\xba\xbe", "application/java-vm"),
+        (b"\0asm", "application/wasm"),
+        (b"ID3", "audio/mpeg"),
+        (b"OggS", "audio/ogg"),
+        (b"RIFF", "audio/wav"),
+    ];
+    let mime_type = MAGIC
+        .iter()
+        .find(|(magic, _)| bytes.starts_with(magic))
+        .map(|(_, mime_type)| *mime_type);
+    Some(match mime_type {
+        Some(mime_type) => mime_type,
+        None if bytes.get(4..8) == Some(b"ftyp") => "video/mp4",
+        None => "application/octet-stream",
+    })
+}
+
+fn decode_text(bytes: &[u8], options: &LoadOptions) -> Result<String, String> {
+    let encoding = match encoding_rs::Encoding::for_bom(bytes) {
+        Some((encoding, _)) => encoding,
+        None => {
+            if let Ok(text) = std::str::from_utf8(bytes) {
+                return Ok(text.to_string());
+            }
+            let mut detector = chardetng::EncodingDetector::new();
+            detector.feed(bytes, true);
+            detector.guess(None, true)
+        }
+    };
+
+    // decode() strips the BOM and replaces malformed sequences
+    let (text, actual, malformed) = encoding.decode(bytes);
+    if malformed && options.strict_encoding {
+        return Err(format!("invalid {} text", actual.name()));
+    }
+    if actual != encoding_rs::UTF_8 {
+        tracing::info!("Transcoded text from {}", actual.name());
+    }
+    Ok(text.into_owned())
+}
+
+/// Join notebook text, which is stored either as a string or a list of lines
+fn notebook_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Array(lines) => lines.iter().filter_map(Value::as_str).collect(),
+        _ => String::new(),
+    }
+}
+
+/// Append text, ending it with a newline
+fn push_line(out: &mut String, text: &str) {
+    out.push_str(text);
+    if !text.is_empty() && !text.ends_with('\n') {
+        out.push('\n');
+    }
+}
+
+/// Tidy extracted page text: normalize line endings, strip trailing spaces
+/// and collapse runs of blank lines, keeping line breaks intact
+fn normalize_pdf_text(text: &str) -> String {
+    let mut normalized = String::with_capacity(text.len());
+    let mut blank_lines = 0;
+    for line in text.replace("\r\n", "\n").replace('\r', "\n").lines() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            blank_lines += 1;
+            if blank_lines > 1 {
+                continue;
+            }
+        } else {
+            blank_lines = 0;
+        }
+        normalized.push_str(line);
+        normalized.push('\n');
+    }
+    normalized.trim_matches('\n').to_string()
+}
+
+/// Recursively collect non-hidden files under `dir`, sorted by path
+fn collect_files(dir: &Path, files: &mut Vec<std::path::PathBuf>) -> Result<(), InputError> {
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .map_err(|e| InputError::ReadError(format!("{}: {e}", dir.display())))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            !path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        })
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Read a file from a zip archive (office documents are zipped XML)
+fn read_zip_entry(bytes: &[u8], name: &str) -> Result<String, InputError> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes))
+        .map_err(|e| InputError::OfficeError(format!("Not a valid archive: {e}")))?;
+    let mut entry = archive
+        .by_name(name)
+        .map_err(|e| InputError::OfficeError(format!("Missing {name}: {e}")))?;
+
+    let mut xml = String::new();
+    entry
+        .read_to_string(&mut xml)
+        .map_err(|e| InputError::OfficeError(format!("Failed to read {name}: {e}")))?;
+    Ok(xml)
+}
+
+/// Collect DOCX text: runs (`w:t`), tabs and breaks, one line per paragraph
+fn append_docx_text(node: roxmltree::Node, out: &mut String) {
+    for child in node.children().filter(|c| c.is_element()) {
+        match child.tag_name().name() {
+            "t" => out.push_str(child.text().unwrap_or_default()),
+            "tab" => out.push('\t'),
+            "br" | "cr" => out.push('\n'),
+            // Deleted revisions and field instructions are not visible text
+            "del" | "instrText" => {}
+            name => {
+                append_docx_text(child, out);
+                if name == "p" {
+                    out.push('\n');
+                }
+            }
+        }
+    }
+}
+
+/// Collect ODT text, one line per paragraph or heading. Text outside
+/// paragraphs is only formatting whitespace.
+fn append_odt_text(node: roxmltree::Node, out: &mut String, in_paragraph: bool) {
+    for child in node.children() {
+        if child.is_text() {
+            if in_paragraph {
+                out.push_str(child.text().unwrap_or_default());
+            }
+            continue;
+        }
+        if !child.is_element() {
+            continue;
+        }
+        match child.tag_name().name() {
+            // Runs of spaces are stored as <text:s text:c="N"/>
+            "s" => {
+                let count = child
+                    .attributes()
+                    .find(|a| a.name() == "c")
+                    .and_then(|a| a.value().parse().ok())
+                    .unwrap_or(1);
+                out.push_str(&" ".repeat(count));
+            }
+            "tab" => out.push('\t'),
+            "line-break" => out.push('\n'),
+            // Annotations and change tracking are not part of the body text
+            "annotation" | "tracked-changes" => {}
+            name => {
+                let paragraph = name == "p" || name == "h";
+                append_odt_text(child, out, in_paragraph || paragraph);
+                if paragraph {
+                    out.push('\n');
+                }
+            }
+        }
+    }
+}
+
+/// Extract readable text from an HTML document.
+///
+/// Scripts, styles and other non-content elements are dropped, block elements
+/// start new lines, and whitespace is collapsed. The page title, if any, comes
+/// first.
+pub fn html_to_text(html: &str) -> String {
+    let document = Html::parse_document(html);
+    let mut raw = String::new();
+
+    let title = Selector::parse("title").unwrap();
+    if let Some(title) = document.select(&title).next() {
+        raw.push_str(&title.text().collect::<String>());
+        raw.push_str("\n\n");
+    }
+    append_html_text(document.root_element(), &mut raw);
+
+    // Trim lines and collapse runs of blank lines
+    let mut text = String::new();
+    let mut blank = true;
+    for line in raw.lines().map(str::trim) {
+        if line.is_empty() {
+            if !blank {
+                text.push('\n');
+            }
+            blank = true;
+        } else {
+            text.push_str(line);
+            text.push('\n');
+            blank = false;
+        }
+    }
+    text.trim_end().to_string()
+}
+
+fn append_html_text(element: ElementRef, out: &mut String) {
+    for child in element.children() {
+        if let Some(text) = child.value().as_text() {
+            // Collapse whitespace, keeping a single space at the edges
+            if text.starts_with(char::is_whitespace) && !out.ends_with([' ', '\n']) {
+                out.push(' ');
+            }
+            out.push_str(&text.split_whitespace().collect::<Vec<_>>().join(" "));
+            if text.ends_with(char::is_whitespace) && !text.trim().is_empty() {
+                out.push(' ');
+            }
+        } else if let Some(child) = ElementRef::wrap(child) {
+            let name = child.value().name();
+            if HTML_SKIPPED.contains(&name) {
+                continue;
+            }
+            if name == "br" {
+                out.push('\n');
+                continue;
+            }
+            let block = HTML_BLOCKS.contains(&name);
+            let line = HTML_LINES.contains(&name);
+            if block || (line && !out.ends_with('\n')) {
+                out.push('\n');
+            }
+            if name == "li" {
+                out.push_str("- ");
+            } else if matches!(name, "td" | "th") {
+                out.push('\t');
+            }
+            append_html_text(child, out);
+            if block || (line && !out.ends_with('\n')) {
+                out.push('\n');
+            }
+        }
     }
 }
 
@@ -120,4 +1200,882 @@ mod tests {
         let input = Input::from_string("Direct content".to_string());
         assert_eq!(input.content(), "Direct content");
     }
+
+    #[test]
+    fn test_load_html_file() {
+        let mut file = tempfile::Builder::new().suffix(".html").tempfile().unwrap();
+        write!(
+            file,
+            r#"<!DOCTYPE html>
+<html>
+<head><title>Report</title><style>p {{ color: red; }}</style></head>
+<body>
+  <script>var secret = 1;</script>
+  <h1>Summary</h1>
+  <p>The   quick <b>brown</b>
+     fox.</p>
+  <ul><li>One</li><li>Two</li></ul>
+</body>
+</html>"#
+        )
+        .unwrap();
+
+        let input = Input::from_file(file.path()).unwrap();
+        assert_eq!(
+            input.content(),
+            "Report\n\nSummary\n\nThe quick brown fox.\n\n- One\n- Two"
+        );
+    }
+
+    #[test]
+    fn test_html_to_text_drops_non_content() {
+        let text = html_to_text("<p>Hi<br>there</p><noscript>enable js</noscript>");
+        assert_eq!(text, "Hi\nthere");
+    }
+
+    /// Write a zip archive with the given entries to a temp file with the given suffix
+    fn zip_file(suffix: &str, entries: &[(&str, &str)]) -> NamedTempFile {
+        let file = tempfile::Builder::new().suffix(suffix).tempfile().unwrap();
+        let mut zip = zip::ZipWriter::new(file.reopen().unwrap());
+        for (name, content) in entries {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        file
+    }
+
+    #[test]
+    fn test_load_docx_file() {
+        let file = zip_file(
+            ".docx",
+            &[(
+                "word/document.xml",
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<w:document xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main">
+  <w:body>
+    <w:p><w:r><w:t>Hello</w:t></w:r><w:r><w:t xml:space="preserve"> world</w:t></w:r></w:p>
+    <w:p><w:r><w:t>A</w:t><w:tab/><w:t>B</w:t></w:r><w:del><w:r><w:t>gone</w:t></w:r></w:del></w:p>
+  </w:body>
+</w:document>"#,
+            )],
+        );
+
+        let input = Input::from_file(file.path()).unwrap();
+        assert_eq!(input.content(), "Hello world\nA\tB");
+    }
+
+    #[test]
+    fn test_load_xlsx_file() {
+        let file = zip_file(
+            ".xlsx",
+            &[
+                (
+                    "[Content_Types].xml",
+                    r#"<?xml version="1.0" encoding="UTF-8"?>
+<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types">
+  <Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/>
+  <Default Extension="xml" ContentType="application/xml"/>
+  <Override PartName="/xl/workbook.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml"/>
+  <Override PartName="/xl/worksheets/sheet1.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml"/>
+</Types>"#,
+                ),
+                (
+                    "_rels/.rels",
+                    r#"<?xml version="1.0" encoding="UTF-8"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+  <Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="xl/workbook.xml"/>
+</Relationships>"#,
+                ),
+                (
+                    "xl/workbook.xml",
+                    r#"<?xml version="1.0" encoding="UTF-8"?>
+<workbook xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main" xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships">
+  <sheets><sheet name="Sales" sheetId="1" r:id="rId1"/></sheets>
+</workbook>"#,
+                ),
+                (
+                    "xl/_rels/workbook.xml.rels",
+                    r#"<?xml version="1.0" encoding="UTF-8"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+  <Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="worksheets/sheet1.xml"/>
+</Relationships>"#,
+                ),
+                (
+                    "xl/worksheets/sheet1.xml",
+                    r#"<?xml version="1.0" encoding="UTF-8"?>
+<worksheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main">
+  <sheetData>
+    <row r="1"><c r="A1" t="inlineStr"><is><t>region</t></is></c><c r="B1" t="inlineStr"><is><t>total</t></is></c></row>
+    <row r="2"><c r="A2" t="inlineStr"><is><t>North, East</t></is></c><c r="B2"><v>42.5</v></c></row>
+  </sheetData>
+</worksheet>"#,
+                ),
+            ],
+        );
+
+        let input = Input::from_file(file.path()).unwrap();
+        assert_eq!(
+            input.content(),
+            "--- sheet Sales ---\nregion,total\n\"North, East\",42.5\n\n"
+        );
+        assert_eq!(input.globals()["sheets"]["Sales"][1][1], 42.5);
+        assert!(input.description().unwrap().contains("1 sheets (Sales)"));
+    }
+
+    #[test]
+    fn test_load_odt_file() {
+        let file = zip_file(
+            ".odt",
+            &[(
+                "content.xml",
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<office:document-content xmlns:office="urn:oasis:names:tc:opendocument:xmlns:office:1.0" xmlns:text="urn:oasis:names:tc:opendocument:xmlns:text:1.0">
+  <office:body>
+    <office:text>
+      <text:h>Title</text:h>
+      <text:p>One<text:s text:c="3"/>two <text:span>three</text:span></text:p>
+    </office:text>
+  </office:body>
+</office:document-content>"#,
+            )],
+        );
+
+        let input = Input::from_file(file.path()).unwrap();
+        assert_eq!(input.content(), "Title\nOne   two three");
+    }
+
+    #[test]
+    fn test_load_invalid_docx() {
+        let mut file = tempfile::Builder::new().suffix(".docx").tempfile().unwrap();
+        write!(file, "not a zip").unwrap();
+        let result = Input::from_file(file.path());
+        assert!(matches!(result.unwrap_err(), InputError::OfficeError(_)));
+    }
+
+    #[test]
+    fn test_load_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("b.txt"), "second").unwrap();
+        fs::write(dir.path().join("a.txt"), "first\n").unwrap();
+        fs::write(dir.path().join("sub").join("c.log"), "third").unwrap();
+        fs::write(dir.path().join(".hidden"), "secret").unwrap();
+        fs::write(dir.path().join("binary.bin"), [0x00, 0x01, 0x02, 0xff]).unwrap();
+
+        let input = Input::from_dir(dir.path()).unwrap();
+        let sub = Path::new("sub").join("c.log");
+        assert_eq!(
+            input.content(),
+            format!(
+                "=== a.txt ===\nfirst\n\n=== b.txt ===\nsecond\n\n=== {} ===\nthird\n\n",
+                sub.display()
+            )
+        );
+        assert!(input.description().unwrap().starts_with("3 files"));
+    }
+
+    #[test]
+    fn test_load_csv_file() {
+        let mut file = tempfile::Builder::new().suffix(".csv").tempfile().unwrap();
+        write!(file, "name,age\nAda,36\n\"Lovelace, A\",\n").unwrap();
+
+        let input = Input::from_file(file.path()).unwrap();
+        assert_eq!(input.content(), "name,age\nAda,36\n\"Lovelace, A\",\n");
+        assert_eq!(
+            input.globals()["context_rows"],
+            serde_json::json!([
+                {"name": "Ada", "age": "36"},
+                {"name": "Lovelace, A", "age": ""}
+            ])
+        );
+        assert_eq!(
+            input.globals()["context_columns"],
+            serde_json::json!(["name", "age"])
+        );
+        assert!(
+            input
+                .description()
+                .unwrap()
+                .starts_with("table with 2 rows")
+        );
+    }
+
+    #[test]
+    fn test_load_tsv_file() {
+        let mut file = tempfile::Builder::new().suffix(".tsv").tempfile().unwrap();
+        write!(file, "a\tb\n1\t2\n").unwrap();
+
+        let input = Input::from_file(file.path()).unwrap();
+        assert_eq!(
+            input.globals()["context_rows"],
+            serde_json::json!([{"a": "1", "b": "2"}])
+        );
+    }
+
+    #[test]
+    fn test_load_json_file() {
+        let mut file = tempfile::Builder::new().suffix(".json").tempfile().unwrap();
+        write!(file, r#"{{"users": [{{"name": "Ada"}}]}}"#).unwrap();
+
+        let input = Input::from_file(file.path()).unwrap();
+        assert_eq!(input.content(), r#"{"users": [{"name": "Ada"}]}"#);
+        assert_eq!(
+            input.globals()["context_json"],
+            serde_json::json!({"users": [{"name": "Ada"}]})
+        );
+        assert!(
+            input
+                .description()
+                .unwrap()
+                .contains("an object with 1 keys")
+        );
+    }
+
+    #[test]
+    fn test_load_malformed_json_as_text() {
+        let mut file = tempfile::Builder::new().suffix(".json").tempfile().unwrap();
+        write!(file, "{{not json").unwrap();
+
+        let input = Input::from_file(file.path()).unwrap();
+        assert_eq!(input.content(), "{not json");
+        assert!(input.globals().is_empty());
+    }
+
+    #[test]
+    fn test_parse_json_explicitly() {
+        let input = Input::from_string("[1, 2]".to_string())
+            .parse_json()
+            .unwrap();
+        assert_eq!(input.globals()["context_json"], serde_json::json!([1, 2]));
+        assert!(matches!(
+            Input::from_string("nope".to_string()).parse_json(),
+            Err(InputError::JsonError(_))
+        ));
+    }
+
+    /// Write a PDF with one line of text per page
+    fn pdf_file(pages: &[&str]) -> NamedTempFile {
+        use lopdf::Object;
+        use lopdf::content::Operation;
+
+        pdf_file_with(
+            pages
+                .iter()
+                .map(|text| {
+                    vec![
+                        Operation::new("BT", vec![]),
+                        Operation::new("Tf", vec!["F1".into(), 12.into()]),
+                        Operation::new("Td", vec![100.into(), 600.into()]),
+                        Operation::new("Tj", vec![Object::string_literal(*text)]),
+                        Operation::new("ET", vec![]),
+                    ]
+                })
+                .collect(),
+        )
+    }
+
+    fn pdf_file_with(pages: Vec<Vec<lopdf::content::Operation>>) -> NamedTempFile {
+        use lopdf::content::Content;
+        use lopdf::{Object, Stream, dictionary};
+
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Courier",
+        });
+        let resources_id = doc.add_object(dictionary! {
+            "Font" => dictionary! { "F1" => font_id },
+        });
+
+        let mut kids = Vec::new();
+        for operations in pages {
+            let content = Content { operations };
+            let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+            let page_id = doc.add_object(dictionary! {
+                "Type" => "Page",
+                "Parent" => pages_id,
+                "Contents" => content_id,
+            });
+            kids.push(page_id.into());
+        }
+
+        let count = kids.len() as i64;
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => kids,
+                "Count" => count,
+                "Resources" => resources_id,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+
+        let mut file = tempfile::Builder::new().suffix(".pdf").tempfile().unwrap();
+        doc.save_to(&mut file).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_load_pdf_pages() {
+        let file = pdf_file(&["First page", "Second page"]);
+
+        let input = Input::from_file(file.path()).unwrap();
+        assert!(input.content().contains("First page"));
+        assert!(!input.content().contains("--- page"));
+        let pages = input.globals()["pages"].as_array().unwrap();
+        assert_eq!(pages.len(), 2);
+        assert!(pages[1].as_str().unwrap().contains("Second page"));
+        let meta = input.meta().unwrap();
+        assert_eq!((meta.mime_type, meta.pages), ("application/pdf", Some(2)));
+    }
+
+    #[test]
+    fn test_load_pdf_page_markers() {
+        let file = pdf_file(&["First page", "Second page"]);
+        let options = LoadOptions {
+            pdf_page_markers: true,
+            ..Default::default()
+        };
+
+        let input = Input::from_file_with(file.path(), &options).unwrap();
+        let content = input.content();
+        let first = content.find("--- page 1 ---").unwrap();
+        let second = content.find("--- page 2 ---").unwrap();
+        assert!(first < content.find("First page").unwrap());
+        assert!(second < content.find("Second page").unwrap());
+        assert!(second > content.find("First page").unwrap());
+    }
+
+    #[test]
+    fn test_normalize_pdf_text() {
+        assert_eq!(normalize_pdf_text("a  \r\n\n\n\nb\rc\n"), "a\n\nb\nc");
+    }
+
+    #[cfg(not(feature = "ocr"))]
+    #[test]
+    fn test_images_require_ocr_feature() {
+        let file = tempfile::Builder::new().suffix(".png").tempfile().unwrap();
+        let result = Input::from_file(file.path());
+        assert!(matches!(
+            result.unwrap_err(),
+            InputError::UnsupportedFormat(_)
+        ));
+    }
+
+    #[test]
+    fn test_load_latin1_file() {
+        let mut file = NamedTempFile::new().unwrap();
+        // "café résumé" in ISO-8859-1
+        file.write_all(b"caf\xe9 r\xe9sum\xe9\n").unwrap();
+
+        let input = Input::from_file(file.path()).unwrap();
+        assert_eq!(input.content(), "café résumé\n");
+    }
+
+    #[test]
+    fn test_load_utf16_file() {
+        let mut file = NamedTempFile::new().unwrap();
+        let mut bytes = vec![0xff, 0xfe];
+        for unit in "héllo\r\n".encode_utf16() {
+            bytes.extend_from_slice(&unit.to_le_bytes());
+        }
+        file.write_all(&bytes).unwrap();
+
+        let input = Input::from_file(file.path()).unwrap();
+        assert_eq!(input.content(), "héllo\r\n");
+    }
+
+    #[test]
+    fn test_decode_strict_and_lossy() {
+        // Truncated UTF-16 code unit
+        let bytes = [0xff, 0xfe, b'a', 0x00, b'b'];
+        let lossy = decode_text(&bytes, &LoadOptions::default()).unwrap();
+        assert_eq!(lossy, "a\u{fffd}");
+
+        let strict = LoadOptions {
+            strict_encoding: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            decode_text(&bytes, &strict).unwrap_err(),
+            "invalid UTF-16LE text"
+        );
+    }
+
+    #[test]
+    fn test_sniff_binary() {
+        assert_eq!(sniff_binary(b"plain text\n\ttabbed"), None);
+        assert_eq!(sniff_binary(b"caf\xe9"), None);
+        assert_eq!(sniff_binary(&[0xff, 0xfe, b'a', 0x00]), None);
+        assert_eq!(
+            sniff_binary(b"\x7fELF\x02\x01\x01\0"),
+            Some("application/x-executable")
+        );
+        assert_eq!(sniff_binary(b"\0\0\0\x18ftypmp42"), Some("video/mp4"));
+        assert_eq!(
+            sniff_binary(&[0x00, 0x01, 0x02, 0xff]),
+            Some("application/octet-stream")
+        );
+    }
+
+    #[test]
+    fn test_load_binary_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.dat");
+        fs::write(&path, b"\x1f\x8b\x08\0\0\0\0\0binary payload").unwrap();
+
+        let err = Input::from_file(&path).unwrap_err();
+        assert!(matches!(err, InputError::UnsupportedFormat(_)));
+        assert!(err.to_string().contains("binary file (application/gzip)"));
+
+        let options = LoadOptions {
+            binary_prefix_bytes: Some(4),
+            ..Default::default()
+        };
+        let input = Input::from_file_with(&path, &options).unwrap();
+        assert_eq!(input.content(), "H4sIAA==");
+        assert!(input.description().unwrap().contains("application/gzip"));
+    }
+
+    fn sqlite_file() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = rusqlite::Connection::open(dir.path().join("app.db")).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, score REAL);
+             INSERT INTO users VALUES (1, 'Ada', 9.5), (2, 'Linus', NULL);",
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_load_sqlite() {
+        let dir = sqlite_file();
+        let input = Input::from_file(dir.path().join("app.db")).unwrap();
+
+        assert!(
+            input
+                .content()
+                .contains("-- users: 2 rows\nCREATE TABLE users")
+        );
+        assert!(input.description().unwrap().contains("sql_query"));
+        assert_eq!(input.functions().len(), 1);
+        assert_eq!(input.functions()[0].name, "sql_query");
+    }
+
+    #[test]
+    fn test_sql_query() {
+        let dir = sqlite_file();
+        let input = Input::from_file(dir.path().join("app.db")).unwrap();
+        let mut config = crate::environment::EnvironmentConfig::default();
+        config.functions.extend(input.functions().iter().cloned());
+        let env = crate::environment::Environment::with_config(
+            input.content(),
+            crate::environment::LlmClient::Ollama("qwen3:30b".to_string()),
+            config,
+        )
+        .unwrap();
+
+        let result = env
+            .eval(
+                "local rows = sql_query('SELECT * FROM users ORDER BY id')
+                 print(#rows, rows[1].name, rows[1].score, rows[2].score)",
+            )
+            .unwrap();
+        assert_eq!(result, Some("2\tAda\t9.5\tnil".to_string()));
+
+        let err = env.eval("sql_query('DELETE FROM users')").unwrap_err();
+        assert!(err.to_string().contains("read-only"));
+
+        let other = dir.path().join("other.db");
+        rusqlite::Connection::open(&other)
+            .unwrap()
+            .execute_batch("CREATE TABLE secrets (key TEXT); INSERT INTO secrets VALUES ('x');")
+            .unwrap();
+        let err = env
+            .eval(&format!(
+                "sql_query(\"ATTACH DATABASE '{}' AS other\")",
+                other.display()
+            ))
+            .unwrap_err();
+        assert!(err.to_string().contains("SQL error"), "{err}");
+    }
+
+    #[test]
+    fn test_sql_query_limits() {
+        let dir = sqlite_file();
+        let input = Input::from_file(dir.path().join("app.db")).unwrap();
+        let endless = "sql_query('WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n) SELECT count(*) FROM n')";
+
+        let mut config = crate::environment::EnvironmentConfig::default()
+            .with_cell_timeout(Some(std::time::Duration::from_millis(200)));
+        config.functions.extend(input.functions().iter().cloned());
+        let env = crate::environment::Environment::with_config(
+            input.content(),
+            crate::environment::LlmClient::Ollama("qwen3:30b".to_string()),
+            config,
+        )
+        .unwrap();
+        let err = env.eval(endless).unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+
+        let mut config = crate::environment::EnvironmentConfig::default()
+            .with_max_cell_instructions(Some(1_000_000));
+        config.functions.extend(input.functions().iter().cloned());
+        let env = crate::environment::Environment::with_config(
+            input.content(),
+            crate::environment::LlmClient::Ollama("qwen3:30b".to_string()),
+            config,
+        )
+        .unwrap();
+        let err = env.eval(endless).unwrap_err();
+        assert!(err.to_string().contains("instructions"), "{err}");
+        // The connection works again for the next query
+        let result = env
+            .eval("print(#sql_query('SELECT * FROM users'))")
+            .unwrap();
+        assert_eq!(result.as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn test_load_markdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("guide.md");
+        fs::write(&path, "# Guide\nIntro\n## Install\nRun it\n").unwrap();
+
+        let input = Input::from_file(&path).unwrap();
+        assert!(input.description().unwrap().contains("2 sections"));
+        assert_eq!(
+            input.globals()["context_sections"][1],
+            serde_json::json!({"title": "Install", "level": 2, "start": 15, "stop": 32, "parent": 1})
+        );
+
+        let mut config = crate::environment::EnvironmentConfig::default();
+        config.functions.extend(input.functions().iter().cloned());
+        for (name, value) in input.globals() {
+            config = config.with_global(name.clone(), value.clone());
+        }
+        let env = crate::environment::Environment::with_config(
+            input.content(),
+            crate::environment::LlmClient::Ollama("qwen3:30b".to_string()),
+            config,
+        )
+        .unwrap();
+        let result = env
+            .eval(
+                "local s = context_sections[2]
+                 print(get_section('install') == context:sub(s.start, s.stop), context_sections[s.parent].title)",
+            )
+            .unwrap();
+        assert_eq!(result, Some("true\tGuide".to_string()));
+    }
+
+    #[test]
+    fn test_load_notebook() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("analysis.ipynb");
+        let notebook = serde_json::json!({
+            "cells": [
+                {"cell_type": "markdown", "source": ["# Analysis\n", "Load the data"]},
+                {
+                    "cell_type": "code",
+                    "source": "x = 1 + 1\nx",
+                    "outputs": [
+                        {"output_type": "stream", "text": ["computing\n"]},
+                        {"output_type": "execute_result", "data": {"text/plain": ["2"]}},
+                        {"output_type": "display_data", "data": {"image/png": "iVBOR"}},
+                        {"output_type": "error", "ename": "ValueError", "evalue": "bad"}
+                    ]
+                }
+            ],
+            "metadata": {},
+            "nbformat": 4
+        });
+        fs::write(&path, notebook.to_string()).unwrap();
+
+        let input = Input::from_file(&path).unwrap();
+        assert_eq!(
+            input.content(),
+            "--- cell 1 (markdown) ---\n# Analysis\nLoad the data\n\n\
+             --- cell 2 (code) ---\nx = 1 + 1\nx\n--- output ---\ncomputing\n2\n\
+             [image/png output omitted]\nValueError: bad\n\n"
+        );
+        assert!(input.description().unwrap().contains("2 cells"));
+    }
+
+    #[test]
+    fn test_load_eml() {
+        let mut file = tempfile::Builder::new().suffix(".eml").tempfile().unwrap();
+        file.write_all(
+            b"From: Ada Lovelace <ada@example.com>\r\n\
+              To: charles@example.com\r\n\
+              Date: Mon, 1 Jan 2024 10:00:00 +0000\r\n\
+              Subject: =?UTF-8?Q?Engine_r=C3=A9sum=C3=A9?=\r\n\
+              Content-Type: text/plain; charset=utf-8\r\n\
+              \r\n\
+              The engine works.\r\n",
+        )
+        .unwrap();
+
+        let input = Input::from_file(file.path()).unwrap();
+        assert_eq!(
+            input.content(),
+            "--- message 1 ---\n\
+             From: Ada Lovelace <ada@example.com>\n\
+             To: charles@example.com\n\
+             Date: 2024-01-01T10:00:00Z\n\
+             Subject: Engine résumé\n\n\
+             The engine works.\n\n"
+        );
+        assert_eq!(input.globals()["messages"][0]["subject"], "Engine résumé");
+    }
+
+    #[test]
+    fn test_load_mbox() {
+        let mut file = tempfile::Builder::new().suffix(".mbox").tempfile().unwrap();
+        file.write_all(
+            b"From ada@example.com Mon Jan  1 10:00:00 2024\n\
+              From: ada@example.com\n\
+              Subject: First\n\
+              \n\
+              Hello\n\
+              \n\
+              From bob@example.com Tue Jan  2 10:00:00 2024\n\
+              From: bob@example.com\n\
+              Subject: Second\n\
+              Content-Type: text/html\n\
+              \n\
+              <p>Hi <b>there</b></p>\n",
+        )
+        .unwrap();
+
+        let input = Input::from_file(file.path()).unwrap();
+        let messages = &input.globals()["messages"];
+        assert_eq!(messages.as_array().unwrap().len(), 2);
+        assert_eq!(messages[0]["body"], "Hello");
+        assert_eq!(messages[1]["from"], "bob@example.com");
+        assert_eq!(messages[1]["body"], "Hi there");
+        assert!(input.description().unwrap().starts_with("2 email messages"));
+    }
+
+    #[test]
+    fn test_input_meta() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("notes.txt"), "hello").unwrap();
+        fs::write(dir.path().join("data.csv"), "a,b\n1,2\n").unwrap();
+
+        let input = Input::from_file(dir.path().join("data.csv")).unwrap();
+        let meta = input.meta().unwrap();
+        assert_eq!(meta.filename, "data.csv");
+        assert_eq!(meta.size, 8);
+        assert_eq!(meta.mime_type, "text/csv");
+        assert!(meta.modified.as_ref().unwrap().ends_with('Z'));
+        assert_eq!(meta.pages, None);
+
+        let input = Input::from_dir(dir.path()).unwrap();
+        let meta = input.meta().unwrap();
+        assert_eq!(meta.mime_type, "inode/directory");
+        assert_eq!((meta.size, meta.files), (13, Some(2)));
+
+        assert!(Input::from_string("x".to_string()).meta().is_none());
+    }
+
+    #[test]
+    fn test_expand_glob() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["2024-02.log", "2024-01.log", "2023-12.log", "notes.txt"] {
+            fs::write(dir.path().join(name), "x").unwrap();
+        }
+        let root = dir.path().display().to_string();
+
+        let paths = expand_glob(&format!("{root}/2024-*.log")).unwrap();
+        assert_eq!(
+            paths,
+            vec![format!("{root}/2024-01.log"), format!("{root}/2024-02.log")]
+        );
+        assert_eq!(
+            expand_glob(&format!("{root}/notes.txt")).unwrap(),
+            vec![format!("{root}/notes.txt")]
+        );
+        assert!(matches!(
+            expand_glob(&format!("{root}/*.csv")),
+            Err(InputError::FileNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_pdf_tables() {
+        use lopdf::Object;
+        use lopdf::content::Operation;
+
+        let text_at = |x: i64, y: i64, text: &str| {
+            vec![
+                Operation::new("BT", vec![]),
+                Operation::new("Tf", vec!["F1".into(), 10.into()]),
+                Operation::new("Td", vec![x.into(), y.into()]),
+                Operation::new("Tj", vec![Object::string_literal(text)]),
+                Operation::new("ET", vec![]),
+            ]
+        };
+        // Cells written column by column, as some producers do
+        let mut operations = text_at(72, 700, "Quarterly results");
+        for (x, column) in [
+            (72, ["Item", "Revenue", "Costs"]),
+            (200, ["Q1", "1,200", "  800"]),
+            (300, ["Q2", "1,350", "900"]),
+        ] {
+            for (y, cell) in [680, 665, 650].into_iter().zip(column) {
+                operations.extend(text_at(x, y, cell));
+            }
+        }
+        operations.extend(text_at(72, 620, "Figures in thousands"));
+        let file = pdf_file_with(vec![operations]);
+
+        let options = LoadOptions {
+            pdf_tables: true,
+            ..Default::default()
+        };
+        let input = Input::from_file_with(file.path(), &options).unwrap();
+        assert_eq!(
+            input.content(),
+            "Quarterly results\n\
+             | Item | Q1 | Q2 |\n\
+             | Revenue | 1,200 | 1,350 |\n\
+             | Costs | 800 | 900 |\n\
+             Figures in thousands\n"
+        );
+    }
+
+    #[test]
+    fn test_load_cached() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let file = pdf_file(&["Original text"]);
+        let options = LoadOptions {
+            cache_dir: Some(cache_dir.path().to_path_buf()),
+            ..Default::default()
+        };
+
+        let input = Input::from_file_with(file.path(), &options).unwrap();
+        assert!(input.content().contains("Original text"));
+        let entries: Vec<_> = fs::read_dir(cache_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(entries.len(), 1);
+
+        // A second load reads the entry instead of the PDF
+        let entry = fs::read_to_string(&entries[0]).unwrap();
+        fs::write(&entries[0], entry.replace("Original", "Cached")).unwrap();
+        let input = Input::from_file_with(file.path(), &options).unwrap();
+        assert!(input.content().contains("Cached text"));
+        assert_eq!(input.meta().unwrap().pages, Some(1));
+
+        // Options that change extraction use another entry
+        let marked = LoadOptions {
+            pdf_page_markers: true,
+            ..options.clone()
+        };
+        let input = Input::from_file_with(file.path(), &marked).unwrap();
+        assert!(input.content().contains("Original text"));
+
+        // Without a cache directory nothing is read from the cache
+        let input = Input::from_file(file.path()).unwrap();
+        assert!(input.content().contains("Original text"));
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let input = Input::from_bytes(b"name,qty\nbolt,3\n", "text/csv; charset=utf-8").unwrap();
+        assert_eq!(input.globals()["context_rows"][0]["qty"], "3");
+        assert!(input.meta().is_none());
+
+        let input = Input::from_bytes(b"<p>Hello <b>upload</b></p>", "page.HTML").unwrap();
+        assert_eq!(input.content(), "Hello upload");
+
+        let pdf = fs::read(pdf_file(&["First page"]).path()).unwrap();
+        let input = Input::from_bytes(&pdf, "application/pdf").unwrap();
+        assert_eq!(input.globals()["pages"][0], "First page");
+
+        // Unknown formats are read as text, and binary data is still rejected
+        let input = Input::from_bytes(b"plain", "application/x-unknown").unwrap();
+        assert_eq!(input.content(), "plain");
+        let err = Input::from_bytes(b"\x00\x01\x02", "txt").unwrap_err();
+        assert!(err.to_string().contains("binary file"));
+
+        let err = Input::from_bytes(b"SQLite format 3\0", "db").unwrap_err();
+        assert!(matches!(err, InputError::UnsupportedFormat(_)));
+    }
+
+    #[test]
+    fn test_load_xml() {
+        let mut file = NamedTempFile::with_suffix(".xml").unwrap();
+        write!(
+            file,
+            "<orders><order id=\"7\"><total>12</total></order></orders>"
+        )
+        .unwrap();
+        let input = Input::from_file(file.path()).unwrap();
+        assert!(
+            input
+                .description()
+                .unwrap()
+                .contains("root element <orders> and 3 elements")
+        );
+        let names: Vec<&str> = input.functions().iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["xml_find", "xml_children"]);
+
+        let input = Input::from_bytes(b"<unclosed>", "xml").unwrap();
+        assert_eq!(input.content(), "<unclosed>");
+        assert!(input.functions().is_empty());
+    }
+
+    #[test]
+    fn test_load_subtitles() {
+        let mut file = NamedTempFile::with_suffix(".srt").unwrap();
+        write!(
+            file,
+            "1\n00:00:01,000 --> 00:00:02,000\nHello.\n\n2\n00:01:30,000 --> 00:01:32,500\nBye.\n"
+        )
+        .unwrap();
+        let input = Input::from_file(file.path()).unwrap();
+        assert_eq!(input.content(), "Hello.\nBye.\n");
+        assert!(
+            input
+                .description()
+                .unwrap()
+                .contains("2 subtitle segments (1:32 long)")
+        );
+        assert_eq!(input.globals()["segments"][1]["start"], 90.0);
+        assert_eq!(input.globals()["segments"][1]["text"], "Bye.");
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = Input::from_string("Term: 12 months\nFee: $100\nLaw: NY\n".to_string());
+        let new = Input::from_string("Term: 24 months\nFee: $100\nLaw: NY\n".to_string());
+        let diff = Input::diff(&old, &new);
+        assert_eq!(
+            diff.content(),
+            "--- old\n+++ new\n@@ -1,3 +1,3 @@\n-Term: 12 months\n+Term: 24 months\n Fee: $100\n Law: NY\n"
+        );
+        assert!(
+            diff.description()
+                .unwrap()
+                .contains("1 hunks, 1 lines added and 1 removed")
+        );
+        assert_eq!(diff.globals()["context_new"], new.content());
+
+        let same = Input::diff(&old, &old);
+        assert_eq!(same.content(), "No differences between old and new\n");
+    }
 }
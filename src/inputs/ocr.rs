@@ -0,0 +1,64 @@
+//! OCR for scanned PDFs and images, using the `tesseract` and `pdftoppm`
+//! (poppler) command line tools, which must be on the `PATH`.
+
+use super::{InputError, normalize_pdf_text};
+use std::path::Path;
+use std::process::Command;
+
+/// Resolution PDF pages are rendered at before OCR
+const RENDER_DPI: &str = "300";
+
+/// Recognize the text in an image
+pub fn image_text(path: &Path) -> Result<String, InputError> {
+    let output = Command::new("tesseract")
+        .arg(path)
+        .arg("stdout")
+        .output()
+        .map_err(|e| InputError::OcrError(format!("Failed to run tesseract: {e}")))?;
+
+    if !output.status.success() {
+        return Err(InputError::OcrError(format!(
+            "tesseract failed on {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(normalize_pdf_text(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Render every page of a PDF and recognize its text, one entry per page
+pub fn pdf_pages(path: &Path) -> Result<Vec<String>, InputError> {
+    let dir = std::env::temp_dir().join(format!("moonraker-ocr-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).map_err(|e| InputError::OcrError(e.to_string()))?;
+    let result = render_and_recognize(path, &dir);
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+fn render_and_recognize(path: &Path, dir: &Path) -> Result<Vec<String>, InputError> {
+    let output = Command::new("pdftoppm")
+        .args(["-r", RENDER_DPI, "-png"])
+        .arg(path)
+        .arg(dir.join("page"))
+        .output()
+        .map_err(|e| InputError::OcrError(format!("Failed to run pdftoppm: {e}")))?;
+
+    if !output.status.success() {
+        return Err(InputError::OcrError(format!(
+            "pdftoppm failed on {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    // pdftoppm names pages page-1.png, page-01.png, ... depending on the page count
+    let mut images: Vec<_> = std::fs::read_dir(dir)
+        .map_err(|e| InputError::OcrError(e.to_string()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "png"))
+        .collect();
+    images.sort();
+
+    images.iter().map(|image| image_text(image)).collect()
+}
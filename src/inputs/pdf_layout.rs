@@ -0,0 +1,256 @@
+//! Layout-aware PDF text extraction that keeps tables readable.
+//!
+//! Plain extraction emits text in content-stream order, which interleaves the
+//! cells of a table. Here every text run is placed at its position on the
+//! page, runs are grouped into lines by baseline, and consecutive lines made of
+//! several separated cells are rendered as pipe-delimited rows with cells
+//! aligned to shared columns.
+
+use super::InputError;
+use lopdf::content::Content;
+use lopdf::{Document, Encoding, Object, ObjectId};
+use std::collections::BTreeMap;
+
+/// Approximate glyph width as a fraction of the font size, since font
+/// metrics are not read
+const CHAR_WIDTH: f32 = 0.5;
+
+/// Gap between runs, in font sizes, that separates table cells
+const CELL_GAP: f32 = 1.0;
+
+/// A piece of text shown at a position on the page
+#[derive(Debug, Clone)]
+struct Run {
+    x: f32,
+    y: f32,
+    size: f32,
+    text: String,
+}
+
+impl Run {
+    fn end(&self) -> f32 {
+        self.x + self.text.chars().count() as f32 * self.size * CHAR_WIDTH
+    }
+}
+
+/// Extract the text of a page, rendering tables as `| a | b |` rows
+pub fn page_text(doc: &Document, page_id: ObjectId) -> Result<String, InputError> {
+    let err = |e: lopdf::Error| InputError::PdfError(e.to_string());
+    let encodings: BTreeMap<Vec<u8>, Encoding> = doc
+        .get_page_fonts(page_id)
+        .map_err(err)?
+        .into_iter()
+        .filter_map(|(name, font)| font.get_font_encoding(doc).ok().map(|e| (name, e)))
+        .collect();
+    let content = Content::decode(&doc.get_page_content(page_id).map_err(err)?).map_err(err)?;
+
+    let runs = positioned_runs(&content, &encodings);
+    Ok(render(&runs))
+}
+
+/// Place the text shown by the content stream, following the text matrix
+fn positioned_runs(content: &Content, encodings: &BTreeMap<Vec<u8>, Encoding>) -> Vec<Run> {
+    let mut runs = Vec::new();
+    let mut open: Option<Run> = None;
+    let mut encoding = None;
+    let mut font_size = 12.0;
+    let mut leading = 0.0;
+    let mut line_matrix = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+
+    for op in &content.operations {
+        let num = |i: usize| op.operands.get(i).and_then(|o| o.as_float().ok());
+        let mut shown: Vec<&Object> = Vec::new();
+        match op.operator.as_str() {
+            "BT" => line_matrix = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0],
+            "Tf" => {
+                encoding = op
+                    .operands
+                    .first()
+                    .and_then(|o| o.as_name().ok())
+                    .and_then(|name| encodings.get(name));
+                font_size = num(1).unwrap_or(font_size);
+                continue;
+            }
+            "TL" => {
+                leading = num(0).unwrap_or(leading);
+                continue;
+            }
+            "Tm" => {
+                if let [a, b, c, d, e, f] = (0..6).map(num).collect::<Vec<_>>()[..] {
+                    line_matrix = [
+                        a.unwrap_or(1.0),
+                        b.unwrap_or(0.0),
+                        c.unwrap_or(0.0),
+                        d.unwrap_or(1.0),
+                        e.unwrap_or(0.0),
+                        f.unwrap_or(0.0),
+                    ];
+                }
+            }
+            "Td" | "TD" => {
+                let (tx, ty) = (num(0).unwrap_or(0.0), num(1).unwrap_or(0.0));
+                if op.operator == "TD" {
+                    leading = -ty;
+                }
+                translate(&mut line_matrix, tx, ty);
+            }
+            "T*" => translate(&mut line_matrix, 0.0, -leading),
+            "Tj" | "TJ" => shown.extend(op.operands.first()),
+            "'" | "\"" => {
+                runs.extend(open.take());
+                translate(&mut line_matrix, 0.0, -leading);
+                let text = if op.operator == "'" { 0 } else { 2 };
+                shown.extend(op.operands.get(text));
+            }
+            _ => continue,
+        }
+
+        // Positioning ends the open run; showing text extends it
+        if shown.is_empty() {
+            runs.extend(open.take());
+            continue;
+        }
+        let size = font_size * line_matrix[0].hypot(line_matrix[1]).max(f32::EPSILON);
+        let run = open.get_or_insert_with(|| Run {
+            x: line_matrix[4],
+            y: line_matrix[5],
+            size,
+            text: String::new(),
+        });
+        for object in shown {
+            show(object, encoding, run, &mut runs);
+        }
+    }
+    runs.extend(open);
+    runs.retain(|run| !run.text.trim().is_empty());
+    runs
+}
+
+/// Append shown text to a run. Large negative adjustments in a `TJ` array
+/// move far enough to start a new run, as some producers space cells that way.
+fn show(object: &Object, encoding: Option<&Encoding>, run: &mut Run, runs: &mut Vec<Run>) {
+    match object {
+        Object::String(bytes, _) => {
+            let text = encoding
+                .and_then(|e| Document::decode_text(e, bytes).ok())
+                .unwrap_or_else(|| String::from_utf8_lossy(bytes).to_string());
+            run.text.push_str(&text);
+        }
+        Object::Array(items) => {
+            for item in items {
+                match item.as_float() {
+                    Ok(adjust) if -adjust / 1000.0 > CELL_GAP => {
+                        let x = run.end() - adjust / 1000.0 * run.size;
+                        let next = Run {
+                            x,
+                            y: run.y,
+                            size: run.size,
+                            text: String::new(),
+                        };
+                        runs.push(std::mem::replace(run, next));
+                    }
+                    Ok(adjust) if adjust < -100.0 => run.text.push(' '),
+                    Ok(_) => {}
+                    Err(_) => show(item, encoding, run, runs),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn translate(matrix: &mut [f32; 6], tx: f32, ty: f32) {
+    matrix[4] += tx * matrix[0] + ty * matrix[2];
+    matrix[5] += tx * matrix[1] + ty * matrix[3];
+}
+
+/// Group runs into lines top to bottom and lay out tables
+fn render(runs: &[Run]) -> String {
+    let mut sorted: Vec<&Run> = runs.iter().collect();
+    sorted.sort_by(|a, b| b.y.total_cmp(&a.y).then(a.x.total_cmp(&b.x)));
+
+    // Runs on (nearly) the same baseline form a line
+    let mut lines: Vec<Vec<&Run>> = Vec::new();
+    for run in sorted {
+        match lines.last_mut() {
+            Some(line) if (line[0].y - run.y).abs() <= (run.size * 0.3).max(2.0) => line.push(run),
+            _ => lines.push(vec![run]),
+        }
+    }
+    let lines: Vec<Vec<Run>> = lines.into_iter().map(cells).collect();
+
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        // A table is two or more consecutive lines with several cells
+        let end = (i..lines.len())
+            .find(|&j| lines[j].len() < 2)
+            .unwrap_or(lines.len());
+        if end - i >= 2 {
+            out.extend(table_rows(&lines[i..end]));
+            i = end;
+        } else {
+            let words: Vec<&str> = lines[i].iter().map(|cell| cell.text.as_str()).collect();
+            out.push(words.join(" "));
+            i += 1;
+        }
+    }
+    out.join("\n")
+}
+
+/// Merge the runs of a line into cells, split where the gap is wide
+fn cells(mut line: Vec<&Run>) -> Vec<Run> {
+    line.sort_by(|a, b| a.x.total_cmp(&b.x));
+    let mut cells: Vec<Run> = Vec::new();
+    for run in line {
+        match cells.last_mut() {
+            Some(cell) if run.x - cell.end() < run.size * CELL_GAP => {
+                if run.x - cell.end() > run.size * 0.15 {
+                    cell.text.push(' ');
+                }
+                cell.text.push_str(&run.text);
+            }
+            _ => cells.push(run.clone()),
+        }
+    }
+    for cell in &mut cells {
+        cell.text = cell.text.trim().to_string();
+    }
+    cells
+}
+
+/// Render table lines with cells aligned to columns shared by all rows
+fn table_rows(lines: &[Vec<Run>]) -> Vec<String> {
+    // Columns are the merged horizontal extents of the cells
+    let mut extents: Vec<(f32, f32)> = lines
+        .iter()
+        .flatten()
+        .map(|cell| (cell.x, cell.end()))
+        .collect();
+    extents.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut columns: Vec<(f32, f32)> = Vec::new();
+    for (start, end) in extents {
+        match columns.last_mut() {
+            Some(column) if start <= column.1 => column.1 = column.1.max(end),
+            _ => columns.push((start, end)),
+        }
+    }
+
+    lines
+        .iter()
+        .map(|line| {
+            let mut row = vec![String::new(); columns.len()];
+            for cell in line {
+                let column = columns
+                    .iter()
+                    .rposition(|(start, _)| *start <= cell.x)
+                    .unwrap_or(0);
+                if !row[column].is_empty() {
+                    row[column].push(' ');
+                }
+                row[column].push_str(&cell.text);
+            }
+            format!("| {} |", row.join(" | "))
+        })
+        .collect()
+}
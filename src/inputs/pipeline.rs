@@ -0,0 +1,402 @@
+//! Preprocessing of loaded content before it becomes `context`.
+//!
+//! An [`InputPipeline`] runs a chain of [`Stage`]s over the text of an
+//! [`Input`]. A few built-in stages cover common cleanups; implement [`Stage`]
+//! to add your own.
+
+use super::Input;
+use mlua::{Lua, Value as LuaValue};
+use regex::{Captures, Regex};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// How deep nested tables returned by context functions are rewritten
+const MAX_DATA_DEPTH: usize = 16;
+
+/// A transformation of the content text
+pub trait Stage: Send + Sync {
+    /// Short name used in logs
+    fn name(&self) -> &str;
+
+    fn apply(&self, content: String) -> String;
+
+    /// Whether the stage also rewrites every string in the input's structured
+    /// data and in values returned by its functions (e.g. `sql_query` rows),
+    /// as redaction must
+    fn transforms_data(&self) -> bool {
+        false
+    }
+}
+
+/// An ordered chain of [`Stage`]s
+#[derive(Default, Clone)]
+pub struct InputPipeline {
+    stages: Vec<Arc<dyn Stage>>,
+}
+
+impl InputPipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a stage; stages run in the order they were added
+    pub fn with_stage(mut self, stage: impl Stage + 'static) -> Self {
+        self.stages.push(Arc::new(stage));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Run every stage over a text
+    pub fn apply(&self, mut content: String) -> String {
+        for stage in &self.stages {
+            let before = content.len();
+            content = stage.apply(content);
+            tracing::debug!(
+                "Stage {}: {} -> {} bytes",
+                stage.name(),
+                before,
+                content.len()
+            );
+        }
+        content
+    }
+
+    /// Run every stage over the content of an input. Stages that transform
+    /// data also rewrite its structured data and function results.
+    pub fn run(&self, mut input: Input) -> Input {
+        input.content = self.apply(input.content);
+
+        let data_stages = InputPipeline {
+            stages: self
+                .stages
+                .iter()
+                .filter(|s| s.transforms_data())
+                .cloned()
+                .collect(),
+        };
+        if data_stages.is_empty() {
+            return input;
+        }
+
+        for value in input.globals.values_mut() {
+            data_stages.apply_json(value);
+        }
+        input.functions = std::mem::take(&mut input.functions)
+            .into_iter()
+            .map(|function| {
+                let stages = data_stages.clone();
+                function.map_output(move |lua, values| {
+                    values
+                        .into_iter()
+                        .map(|value| stages.apply_lua(lua, value, 0))
+                        .collect()
+                })
+            })
+            .collect();
+        input
+    }
+
+    fn apply_json(&self, value: &mut Value) {
+        match value {
+            Value::String(text) => *text = self.apply(std::mem::take(text)),
+            Value::Array(items) => items.iter_mut().for_each(|v| self.apply_json(v)),
+            Value::Object(map) => map.values_mut().for_each(|v| self.apply_json(v)),
+            _ => {}
+        }
+    }
+
+    fn apply_lua(&self, lua: &Lua, value: LuaValue, depth: usize) -> mlua::Result<LuaValue> {
+        match value {
+            LuaValue::String(text) => {
+                let text = self.apply(text.to_string_lossy());
+                Ok(LuaValue::String(lua.create_string(text)?))
+            }
+            LuaValue::Table(table) if depth < MAX_DATA_DEPTH => {
+                let pairs: Vec<(LuaValue, LuaValue)> =
+                    table.pairs().collect::<mlua::Result<_>>()?;
+                for (key, value) in pairs {
+                    table.raw_set(key, self.apply_lua(lua, value, depth + 1)?)?;
+                }
+                Ok(LuaValue::Table(table))
+            }
+            other => Ok(other),
+        }
+    }
+}
+
+/// Removes repeated boilerplate lines such as page headers and footers.
+///
+/// Lines are compared with digits ignored, so "Page 3 of 10" and
+/// "Page 4 of 10" count as the same line. Lines without letters are kept.
+#[derive(Debug, Clone)]
+pub struct StripBoilerplate {
+    /// Minimum number of occurrences for a line to count as boilerplate
+    pub min_repeats: usize,
+}
+
+impl Default for StripBoilerplate {
+    fn default() -> Self {
+        Self { min_repeats: 3 }
+    }
+}
+
+impl StripBoilerplate {
+    fn key(line: &str) -> Option<String> {
+        let line = line.trim();
+        if !line.chars().any(char::is_alphabetic) {
+            return None;
+        }
+        Some(line.chars().filter(|c| !c.is_ascii_digit()).collect())
+    }
+}
+
+impl Stage for StripBoilerplate {
+    fn name(&self) -> &str {
+        "strip-boilerplate"
+    }
+
+    fn apply(&self, content: String) -> String {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for line in content.lines() {
+            if let Some(key) = Self::key(line) {
+                *counts.entry(key).or_default() += 1;
+            }
+        }
+        content
+            .split_inclusive('\n')
+            .filter(|line| Self::key(line).is_none_or(|key| counts[&key] < self.min_repeats))
+            .collect()
+    }
+}
+
+/// Drops lines identical to an earlier line, keeping blank lines
+#[derive(Debug, Clone, Default)]
+pub struct DedupeLines;
+
+impl Stage for DedupeLines {
+    fn name(&self) -> &str {
+        "dedupe-lines"
+    }
+
+    fn apply(&self, content: String) -> String {
+        let mut seen = HashSet::new();
+        content
+            .split_inclusive('\n')
+            .filter(|line| {
+                let line = line.trim_end_matches(['\r', '\n']);
+                line.trim().is_empty() || seen.insert(line.to_string())
+            })
+            .collect()
+    }
+}
+
+/// Collapses runs of spaces and tabs, trims line ends, converts CRLF to LF,
+/// and keeps at most one blank line in a row
+#[derive(Debug, Clone, Default)]
+pub struct NormalizeWhitespace;
+
+impl Stage for NormalizeWhitespace {
+    fn name(&self) -> &str {
+        "normalize-whitespace"
+    }
+
+    fn apply(&self, content: String) -> String {
+        let mut out = String::with_capacity(content.len());
+        let mut blank = false;
+        for line in content.lines() {
+            let line = line.split([' ', '\t']).filter(|w| !w.is_empty());
+            let line = line.collect::<Vec<_>>().join(" ");
+            if line.is_empty() {
+                if blank {
+                    continue;
+                }
+                blank = true;
+            } else {
+                blank = false;
+            }
+            out.push_str(&line);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Masks personal data: email addresses, credit card numbers (checked with
+/// the Luhn algorithm), US Social Security numbers, phone numbers, and any
+/// extra patterns added with [`RedactPii::with_pattern`].
+///
+/// Redaction also applies to the input's structured data and to the values
+/// returned by its functions.
+#[derive(Debug, Clone)]
+pub struct RedactPii {
+    patterns: Vec<(Regex, &'static str)>,
+}
+
+impl Default for RedactPii {
+    fn default() -> Self {
+        // Card numbers first, so their digit groups are not taken for phone numbers
+        let patterns = [
+            (r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}", "[EMAIL]"),
+            (r"\b(?:\d[ -]?){12,18}\d\b", "[CARD]"),
+            (r"\b\d{3}-\d{2}-\d{4}\b", "[SSN]"),
+            (
+                r"(?:\+\d{1,3}[ .-]?)?(?:\(\d{3}\)|\b\d{3})[ .-]?\d{3}[ .-]?\d{4}\b",
+                "[PHONE]",
+            ),
+        ];
+        Self {
+            patterns: patterns
+                .into_iter()
+                .map(|(pattern, label)| (Regex::new(pattern).unwrap(), label))
+                .collect(),
+        }
+    }
+}
+
+impl RedactPii {
+    /// Also mask matches of a custom pattern, replaced with `[REDACTED]`
+    pub fn with_pattern(mut self, pattern: Regex) -> Self {
+        self.patterns.push((pattern, "[REDACTED]"));
+        self
+    }
+}
+
+/// Whether a digit string passes the Luhn checksum used by card numbers
+fn luhn_valid(number: &str) -> bool {
+    let digits: Vec<u32> = number.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| match (i % 2, d * 2) {
+            (0, _) => d,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+impl Stage for RedactPii {
+    fn name(&self) -> &str {
+        "redact-pii"
+    }
+
+    fn apply(&self, mut content: String) -> String {
+        for (pattern, label) in &self.patterns {
+            content = pattern
+                .replace_all(&content, |caps: &Captures| {
+                    if *label == "[CARD]" && !luhn_valid(&caps[0]) {
+                        caps[0].to_string()
+                    } else {
+                        label.to_string()
+                    }
+                })
+                .into_owned();
+        }
+        content
+    }
+
+    fn transforms_data(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_boilerplate() {
+        let text = "ACME Report - Page 1\nalpha\n---\nACME Report - Page 2\nbeta\n---\nACME Report - Page 3\ngamma\n---\n";
+        assert_eq!(
+            StripBoilerplate::default().apply(text.to_string()),
+            "alpha\n---\nbeta\n---\ngamma\n---\n"
+        );
+    }
+
+    #[test]
+    fn test_dedupe_and_normalize() {
+        let text = "a  b\t c \r\n\r\n\r\n\r\na  b\t c\nd\n";
+        let pipeline = InputPipeline::new()
+            .with_stage(NormalizeWhitespace)
+            .with_stage(DedupeLines);
+        assert_eq!(pipeline.apply(text.to_string()), "a b c\n\nd\n");
+    }
+
+    #[test]
+    fn test_redact_pii() {
+        let text = "Contact jane.doe@example.com, SSN 123-45-6789, order 1234-56-7890.";
+        assert_eq!(
+            RedactPii::default().apply(text.to_string()),
+            "Contact [EMAIL], SSN [SSN], order 1234-56-7890."
+        );
+
+        let text = "Card 4111 1111 1111 1111, ref 4111 1111 1111 1112, call (555) 123-4567 or +1 555.987.6543, id EMP-0042";
+        let redact = RedactPii::default().with_pattern(Regex::new(r"EMP-\d+").unwrap());
+        assert_eq!(
+            redact.apply(text.to_string()),
+            "Card [CARD], ref 4111 1111 1111 1112, call [PHONE] or [PHONE], id [REDACTED]"
+        );
+    }
+
+    #[test]
+    fn test_redact_structured_data() {
+        let mut input = Input::from_string("mail bob@example.com".to_string());
+        input.globals.insert(
+            "messages".to_string(),
+            serde_json::json!([{"from": "bob@example.com", "size": 3}]),
+        );
+        input.functions.push(crate::environment::HostFunction::new(
+            "lookup",
+            "Returns a contact.",
+            |lua, ()| {
+                let row = lua.create_table()?;
+                row.set("email", "ann@example.com")?;
+                Ok(vec![row])
+            },
+        ));
+
+        let input = InputPipeline::new()
+            .with_stage(NormalizeWhitespace)
+            .with_stage(RedactPii::default())
+            .run(input);
+        assert_eq!(input.content(), "mail [EMAIL]\n");
+        assert_eq!(
+            input.globals()["messages"],
+            serde_json::json!([{"from": "[EMAIL]", "size": 3}])
+        );
+
+        let mut config = crate::environment::EnvironmentConfig::default();
+        config.functions.extend(input.functions().iter().cloned());
+        let env = crate::environment::Environment::with_config(
+            "",
+            crate::environment::LlmClient::Ollama("qwen3:30b".to_string()),
+            config,
+        )
+        .unwrap();
+        let result = env.eval("print(lookup()[1].email)").unwrap();
+        assert_eq!(result, Some("[EMAIL]".to_string()));
+    }
+
+    #[test]
+    fn test_custom_stage() {
+        struct Upper;
+        impl Stage for Upper {
+            fn name(&self) -> &str {
+                "upper"
+            }
+            fn apply(&self, content: String) -> String {
+                content.to_uppercase()
+            }
+        }
+
+        let input = Input::from_string("hello".to_string());
+        let input = InputPipeline::new().with_stage(Upper).run(input);
+        assert_eq!(input.content(), "HELLO");
+    }
+}
@@ -0,0 +1,86 @@
+//! Spreadsheets (`.xlsx`, `.xls`, `.ods`): each sheet as CSV text under a
+//! sheet header, and as rows of cells in the `sheets` Lua table.
+
+use super::InputError;
+use calamine::{Data, Reader, open_workbook_auto_from_rs};
+use serde_json::{Map, Value};
+use std::io::Cursor;
+
+/// A sheet's name and rows of cells
+pub type Sheet = (String, Vec<Vec<Data>>);
+
+/// Read every sheet of a workbook, in workbook order
+pub fn read(bytes: &[u8]) -> Result<Vec<Sheet>, InputError> {
+    let mut workbook = open_workbook_auto_from_rs(Cursor::new(bytes))
+        .map_err(|e| InputError::OfficeError(e.to_string()))?;
+
+    let mut sheets = Vec::new();
+    for name in workbook.sheet_names() {
+        let range = workbook
+            .worksheet_range(&name)
+            .map_err(|e| InputError::OfficeError(format!("Failed to read sheet {name}: {e}")))?;
+        sheets.push((name, range.rows().map(<[Data]>::to_vec).collect()));
+    }
+    Ok(sheets)
+}
+
+/// Render sheets as CSV, each under a `--- sheet NAME ---` line
+pub fn to_text(sheets: &[Sheet]) -> Result<String, InputError> {
+    let mut out = String::new();
+    for (name, rows) in sheets {
+        let mut writer = csv::WriterBuilder::new()
+            .flexible(true)
+            .from_writer(Vec::new());
+        for row in rows {
+            writer
+                .write_record(row.iter().map(cell_text))
+                .map_err(|e| InputError::CsvError(e.to_string()))?;
+        }
+        let csv = writer
+            .into_inner()
+            .map_err(|e| InputError::CsvError(e.to_string()))?;
+
+        out.push_str(&format!("--- sheet {name} ---\n"));
+        out.push_str(&String::from_utf8_lossy(&csv));
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// The `sheets` table: sheet name to a list of rows, each a list of cells.
+/// Numbers and booleans keep their type, empty cells are empty strings.
+pub fn to_value(sheets: &[Sheet]) -> Value {
+    let mut map = Map::new();
+    for (name, rows) in sheets {
+        let rows = rows
+            .iter()
+            .map(|row| row.iter().map(cell_value).collect())
+            .collect();
+        map.insert(name.clone(), Value::Array(rows));
+    }
+    Value::Object(map)
+}
+
+/// Cell text, with dates formatted as ISO 8601
+fn cell_text(cell: &Data) -> String {
+    match cell {
+        Data::DateTime(dt) if dt.is_datetime() => {
+            let (y, mo, d, h, mi, s, _) = dt.to_ymd_hms_milli();
+            if (h, mi, s) == (0, 0, 0) {
+                format!("{y:04}-{mo:02}-{d:02}")
+            } else {
+                format!("{y:04}-{mo:02}-{d:02} {h:02}:{mi:02}:{s:02}")
+            }
+        }
+        _ => cell.to_string(),
+    }
+}
+
+fn cell_value(cell: &Data) -> Value {
+    match cell {
+        Data::Int(n) => (*n).into(),
+        Data::Float(x) => (*x).into(),
+        Data::Bool(b) => (*b).into(),
+        _ => cell_text(cell).into(),
+    }
+}
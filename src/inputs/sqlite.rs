@@ -0,0 +1,135 @@
+//! SQLite databases as context: the schema becomes the `context` string and
+//! the data is reachable through a read-only `sql_query` Lua function.
+
+use super::InputError;
+use crate::environment::{CellLimits, HOOK_INTERVAL, HostFunction};
+use mlua::{Lua, Table};
+use rusqlite::limits::Limit;
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Usage description listed under Available Functions in the system prompt
+const SQL_QUERY_DESCRIPTION: &str = "Runs a read-only SQL query against the context database with `sql_query(sql)` and returns a list of rows, each a table keyed by column name (NULL columns are nil). Write statements fail. Use it instead of parsing the schema in `context`.";
+
+/// Open a database read-only. Other databases can't be attached to it,
+/// since `ATTACH` statements count as read-only and would otherwise let
+/// queries read any SQLite file on disk.
+pub fn open(path: &Path) -> Result<Connection, InputError> {
+    let conn = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(|e| InputError::SqliteError(e.to_string()))?;
+    conn.pragma_update(None, "query_only", true)
+        .map_err(|e| InputError::SqliteError(e.to_string()))?;
+    conn.set_limit(Limit::SQLITE_LIMIT_ATTACHED, 0)
+        .map_err(|e| InputError::SqliteError(e.to_string()))?;
+    Ok(conn)
+}
+
+/// Dump the schema as SQL, annotating each table with its row count
+pub fn schema(conn: &Connection) -> Result<String, InputError> {
+    let err = |e: rusqlite::Error| InputError::SqliteError(e.to_string());
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT type, name, sql FROM sqlite_master \
+             WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%' \
+             ORDER BY CASE type WHEN 'table' THEN 0 WHEN 'view' THEN 1 ELSE 2 END, name",
+        )
+        .map_err(err)?;
+    let entries = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })
+        .map_err(err)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(err)?;
+
+    let mut out = String::new();
+    for (kind, name, sql) in entries {
+        if kind == "table" {
+            let quoted = name.replace('"', "\"\"");
+            let rows: i64 = conn
+                .query_row(&format!("SELECT COUNT(*) FROM \"{quoted}\""), [], |row| {
+                    row.get(0)
+                })
+                .map_err(err)?;
+            out.push_str(&format!("-- {name}: {rows} rows\n"));
+        }
+        out.push_str(&sql);
+        out.push_str(";\n\n");
+    }
+    Ok(out)
+}
+
+/// Create the `sql_query(sql)` function over a database. Queries stop at
+/// the running cell's deadline and count SQLite's steps against its
+/// instruction budget (see [`CellLimits`]), so a runaway recursive query or
+/// join fails like a runaway loop.
+pub fn sql_query_function(path: &Path) -> Result<HostFunction, InputError> {
+    let conn = Mutex::new(open(path)?);
+    Ok(HostFunction::new(
+        "sql_query",
+        SQL_QUERY_DESCRIPTION,
+        move |lua, sql: String| {
+            let conn = conn.lock().unwrap();
+            // The limit that interrupted the query, raised instead of SQLite's error
+            let stopped = Arc::new(Mutex::new(None));
+            if let Some(limits) = CellLimits::of(lua) {
+                let stopped = stopped.clone();
+                conn.progress_handler(
+                    HOOK_INTERVAL as i32,
+                    Some(move || match limits.charge(HOOK_INTERVAL) {
+                        Ok(()) => false,
+                        Err(e) => {
+                            *stopped.lock().unwrap() = Some(e);
+                            true
+                        }
+                    }),
+                );
+            }
+            let result = query(lua, &conn, &sql);
+            conn.progress_handler(0, None::<fn() -> bool>);
+            result.map_err(|e| {
+                stopped
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .unwrap_or_else(|| mlua::Error::RuntimeError(format!("SQL error: {e}")))
+            })
+        },
+    ))
+}
+
+/// Run a query and convert its rows to Lua tables
+fn query(lua: &Lua, conn: &Connection, sql: &str) -> Result<Table, Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(sql)?;
+    if !stmt.readonly() {
+        return Err("only read-only statements are allowed".into());
+    }
+    let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+
+    let result = lua.create_table()?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let entry = lua.create_table()?;
+        for (i, column) in columns.iter().enumerate() {
+            match row.get_ref(i)? {
+                ValueRef::Null => {}
+                ValueRef::Integer(n) => entry.set(column.as_str(), n)?,
+                ValueRef::Real(x) => entry.set(column.as_str(), x)?,
+                ValueRef::Text(text) => entry.set(column.as_str(), lua.create_string(text)?)?,
+                ValueRef::Blob(blob) => entry.set(column.as_str(), lua.create_string(blob)?)?,
+            }
+        }
+        result.push(entry)?;
+    }
+    Ok(result)
+}
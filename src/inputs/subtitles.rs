@@ -0,0 +1,164 @@
+//! Subtitles and transcripts (`.srt`, `.vtt`): cues become the `segments` Lua
+//! table and the text, without timestamps and markup, becomes the transcript.
+
+use super::InputError;
+use regex::Regex;
+use serde_json::{Value, json};
+use std::sync::LazyLock;
+
+/// Markup inside cue text: HTML-like tags (including WebVTT timestamps) and
+/// ASS override blocks such as `{\an8}`
+static MARKUP: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>|\{\\[^}]*\}").unwrap());
+
+/// A WebVTT voice tag: `<v Speaker>` or `<v.class Speaker>`
+static VOICE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<v(?:\.[^ >]*)? ([^>]+)>").unwrap());
+
+/// A timed piece of text
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// Start time in seconds
+    pub start: f64,
+    /// End time in seconds
+    pub end: f64,
+    pub text: String,
+    pub speaker: Option<String>,
+}
+
+/// Parse the cues of an SRT or WebVTT file
+pub fn parse(text: &str) -> Result<Vec<Segment>, InputError> {
+    let text = text.replace("\r\n", "\n").replace('\r', "\n");
+    let mut segments = Vec::new();
+
+    for block in text.split("\n\n") {
+        let mut lines = block.lines().skip_while(|line| !line.contains("-->"));
+        let Some(timing) = lines.next() else {
+            // Headers, NOTE, STYLE and REGION blocks have no timing line
+            continue;
+        };
+        let (start, end) = timing
+            .split_once("-->")
+            .and_then(|(start, rest)| {
+                // WebVTT cue settings follow the end time
+                let end = rest.split_whitespace().next()?;
+                Some((parse_time(start.trim())?, parse_time(end)?))
+            })
+            .ok_or_else(|| InputError::ReadError(format!("Invalid cue timing: {timing}")))?;
+
+        let raw = lines.collect::<Vec<_>>().join(" ");
+        let speaker = VOICE.captures(&raw).map(|caps| caps[1].trim().to_string());
+        let text = decode_entities(&MARKUP.replace_all(&raw, ""));
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !text.is_empty() {
+            segments.push(Segment {
+                start,
+                end,
+                text,
+                speaker,
+            });
+        }
+    }
+
+    if segments.is_empty() {
+        return Err(InputError::ReadError("No subtitle cues found".to_string()));
+    }
+    Ok(segments)
+}
+
+/// Parse `HH:MM:SS,mmm` (SRT), `HH:MM:SS.mmm` or `MM:SS.mmm` (WebVTT)
+fn parse_time(time: &str) -> Option<f64> {
+    let time = time.replace(',', ".");
+    let mut seconds = 0.0;
+    for part in time.split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(seconds)
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// The transcript: one line per cue, prefixed with the speaker when it
+/// changes. Cues repeating the previous cue (common in auto-generated
+/// captions) are skipped.
+pub fn transcript(segments: &[Segment]) -> String {
+    let mut out = String::new();
+    let mut previous: Option<&Segment> = None;
+    for segment in segments {
+        if previous.is_some_and(|p| p.text == segment.text) {
+            continue;
+        }
+        match &segment.speaker {
+            Some(speaker) if previous.is_none_or(|p| p.speaker != segment.speaker) => {
+                out.push_str(&format!("{speaker}: {}\n", segment.text));
+            }
+            _ => {
+                out.push_str(&segment.text);
+                out.push('\n');
+            }
+        }
+        previous = Some(segment);
+    }
+    out
+}
+
+/// The `segments` table: a list of {start, end, text, speaker}
+pub fn to_value(segments: &[Segment]) -> Value {
+    segments
+        .iter()
+        .map(|s| json!({"start": s.start, "end": s.end, "text": s.text, "speaker": s.speaker}))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_srt() {
+        let srt = "1\r\n00:00:01,000 --> 00:00:03,500\r\n{\\an8}Hello <i>there</i>,\r\nfriends.\r\n\r\n2\r\n01:02:03,250 --> 01:02:05,000\r\nFish &amp; chips\r\n";
+        let segments = parse(srt).unwrap();
+        assert_eq!(
+            segments,
+            [
+                Segment {
+                    start: 1.0,
+                    end: 3.5,
+                    text: "Hello there, friends.".to_string(),
+                    speaker: None,
+                },
+                Segment {
+                    start: 3723.25,
+                    end: 3725.0,
+                    text: "Fish & chips".to_string(),
+                    speaker: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_vtt() {
+        let vtt = "WEBVTT - Standup\n\nNOTE recorded remotely\n\nintro\n00:01.000 --> 00:04.000 align:start\n<v Ana>Let's <00:02.000>begin.\n\n00:04.000 --> 00:06.000\n<v Ana>Ready?\n\n00:06.000 --> 00:08.000\n<v.loud Ben>Yes!\n\n00:08.000 --> 00:09.000\n<v.loud Ben>Yes!\n";
+        let segments = parse(vtt).unwrap();
+        assert_eq!(segments.len(), 4);
+        assert_eq!(segments[0].start, 1.0);
+        assert_eq!(segments[0].text, "Let's begin.");
+        assert_eq!(segments[2].speaker.as_deref(), Some("Ben"));
+        assert_eq!(
+            transcript(&segments),
+            "Ana: Let's begin.\nReady?\nBen: Yes!\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_without_cues() {
+        assert!(parse("WEBVTT\n\nNOTE nothing here\n").is_err());
+        assert!(parse("1\n00:00:xx --> 00:00:02\nText\n").is_err());
+    }
+}
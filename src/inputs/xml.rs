@@ -0,0 +1,365 @@
+//! XML contexts: the document is parsed once into an element tree that Lua
+//! queries with `xml_find(path)` and `xml_children(node)`.
+//!
+//! Paths are a small subset of XPath: `/` separated steps of element names
+//! (local names, namespaces ignored) or `*`, `//` for any depth, `.` and
+//! `..`, and predicates `[@attr]`, `[@attr='value']`, and `[n]` (1-based
+//! position among the matches of a step under the same parent).
+
+use crate::environment::HostFunction;
+use mlua::{Lua, Table, Value as LuaValue};
+use std::sync::Arc;
+
+const XML_FIND_DESCRIPTION: &str = "Find XML elements with `xml_find(path)` or `xml_find(path, node)`; returns a list of nodes {tag, attrs, text, id}, where `text` is all text inside the element with whitespace collapsed. Paths are a subset of XPath: `/catalog/book` from the root, `//book` at any depth, `book/title` relative to `node`, `*` for any element, `..` for the parent, and predicates `[@id]`, `[@id='b7']`, `[2]`.";
+
+const XML_CHILDREN_DESCRIPTION: &str = "Returns the child elements of an XML node from `xml_find`, as a list of nodes, with `xml_children(node)`; `xml_children(node, tag)` keeps only children with that tag.";
+
+/// An element in document order; index 0 is the document itself
+#[derive(Debug, Clone)]
+struct Element {
+    tag: String,
+    attrs: Vec<(String, String)>,
+    text: String,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+/// A parsed XML document
+#[derive(Debug, Clone)]
+pub struct XmlTree {
+    elements: Vec<Element>,
+}
+
+impl XmlTree {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let doc = roxmltree::Document::parse(text).map_err(|e| e.to_string())?;
+        let mut tree = XmlTree {
+            elements: vec![Element {
+                tag: String::new(),
+                attrs: Vec::new(),
+                text: String::new(),
+                parent: None,
+                children: Vec::new(),
+            }],
+        };
+        tree.add(doc.root_element(), 0);
+        Ok(tree)
+    }
+
+    fn add(&mut self, node: roxmltree::Node, parent: usize) {
+        let id = self.elements.len();
+        let text: Vec<&str> = node
+            .descendants()
+            .filter(|n| n.is_text())
+            .filter_map(|n| n.text())
+            .collect();
+        self.elements.push(Element {
+            tag: node.tag_name().name().to_string(),
+            attrs: node
+                .attributes()
+                .map(|a| (a.name().to_string(), a.value().to_string()))
+                .collect(),
+            text: text
+                .concat()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" "),
+            parent: Some(parent),
+            children: Vec::new(),
+        });
+        self.elements[parent].children.push(id);
+        for child in node.children().filter(|c| c.is_element()) {
+            self.add(child, id);
+        }
+    }
+
+    /// Number of elements in the document
+    pub fn element_count(&self) -> usize {
+        self.elements.len() - 1
+    }
+
+    /// Tag of the root element
+    pub fn root_tag(&self) -> &str {
+        &self.elements[1].tag
+    }
+
+    /// Ids of the elements matching a path, in document order. Relative
+    /// paths start at `from` (the document if None).
+    pub fn find(&self, path: &str, from: Option<usize>) -> Result<Vec<usize>, String> {
+        let mut rest = path.trim();
+        let mut context = vec![if rest.starts_with('/') {
+            0
+        } else {
+            from.unwrap_or(0)
+        }];
+
+        while !rest.is_empty() {
+            let descendant = rest.starts_with("//");
+            rest = rest.trim_start_matches('/');
+            if rest.is_empty() {
+                break;
+            }
+            let end = step_end(rest);
+            let (step, remainder) = rest.split_at(end);
+            rest = remainder;
+            context = self.step(&context, step, descendant, path)?;
+        }
+        Ok(context.into_iter().filter(|&id| id != 0).collect())
+    }
+
+    fn step(
+        &self,
+        context: &[usize],
+        step: &str,
+        descendant: bool,
+        path: &str,
+    ) -> Result<Vec<usize>, String> {
+        let (name, predicates) = split_predicates(step)
+            .ok_or_else(|| format!("invalid XML path {path:?} at {step:?}"))?;
+
+        let mut matches = Vec::new();
+        for &id in context {
+            let candidates = match name {
+                "." => vec![id],
+                ".." => self.elements[id].parent.into_iter().collect(),
+                _ => {
+                    let mut nodes = Vec::new();
+                    if descendant {
+                        self.descendants(id, &mut nodes);
+                    } else {
+                        nodes.extend(&self.elements[id].children);
+                    }
+                    nodes.retain(|&n| name == "*" || self.elements[n].tag == name);
+                    nodes
+                }
+            };
+            matches.extend(self.filter(candidates, &predicates, path)?);
+        }
+        matches.sort_unstable();
+        matches.dedup();
+        Ok(matches)
+    }
+
+    fn descendants(&self, id: usize, out: &mut Vec<usize>) {
+        for &child in &self.elements[id].children {
+            out.push(child);
+            self.descendants(child, out);
+        }
+    }
+
+    fn filter(
+        &self,
+        mut nodes: Vec<usize>,
+        predicates: &[&str],
+        path: &str,
+    ) -> Result<Vec<usize>, String> {
+        for predicate in predicates {
+            if let Ok(position) = predicate.parse::<usize>() {
+                nodes = nodes
+                    .get(position.wrapping_sub(1))
+                    .copied()
+                    .into_iter()
+                    .collect();
+                continue;
+            }
+            let Some(attr) = predicate.strip_prefix('@') else {
+                return Err(format!(
+                    "unsupported predicate [{predicate}] in XML path {path:?}"
+                ));
+            };
+            let (name, value) = match attr.split_once('=') {
+                Some((name, value)) => {
+                    let value = value.trim();
+                    let unquoted = value
+                        .strip_prefix('\'')
+                        .and_then(|v| v.strip_suffix('\''))
+                        .or_else(|| value.strip_prefix('"').and_then(|v| v.strip_suffix('"')));
+                    (name.trim(), Some(unquoted.unwrap_or(value)))
+                }
+                None => (attr.trim(), None),
+            };
+            nodes.retain(|&n| {
+                self.elements[n]
+                    .attrs
+                    .iter()
+                    .any(|(k, v)| k == name && value.is_none_or(|value| v == value))
+            });
+        }
+        Ok(nodes)
+    }
+
+    fn to_lua(&self, lua: &Lua, id: usize) -> mlua::Result<Table> {
+        let element = &self.elements[id];
+        let node = lua.create_table()?;
+        node.set("id", id)?;
+        node.set("tag", element.tag.as_str())?;
+        node.set("text", element.text.as_str())?;
+        let attrs = lua.create_table()?;
+        for (name, value) in &element.attrs {
+            attrs.set(name.as_str(), value.as_str())?;
+        }
+        node.set("attrs", attrs)?;
+        Ok(node)
+    }
+
+    fn to_lua_list(&self, lua: &Lua, ids: &[usize]) -> mlua::Result<Table> {
+        let list = lua.create_table()?;
+        for &id in ids {
+            list.push(self.to_lua(lua, id)?)?;
+        }
+        Ok(list)
+    }
+
+    /// The element id of a node table from Lua
+    fn node_id(&self, node: &LuaValue) -> mlua::Result<usize> {
+        let id = match node {
+            LuaValue::Table(table) => table.get::<Option<usize>>("id")?,
+            LuaValue::Integer(id) => usize::try_from(*id).ok(),
+            _ => None,
+        };
+        id.filter(|id| (1..self.elements.len()).contains(id))
+            .ok_or_else(|| {
+                mlua::Error::RuntimeError("expected an XML node from xml_find".to_string())
+            })
+    }
+}
+
+/// Length of the first step of a path: up to the next `/` outside brackets
+fn step_end(path: &str) -> usize {
+    let mut depth = 0;
+    for (i, c) in path.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            '/' if depth == 0 => return i,
+            _ => {}
+        }
+    }
+    path.len()
+}
+
+/// Split `name[p1][p2]` into the name and predicate contents
+fn split_predicates(step: &str) -> Option<(&str, Vec<&str>)> {
+    let (name, mut rest) = match step.find('[') {
+        Some(i) => step.split_at(i),
+        None => (step, ""),
+    };
+    let mut predicates = Vec::new();
+    while !rest.is_empty() {
+        let close = rest.find(']')?;
+        predicates.push(rest.get(1..close)?.trim());
+        rest = &rest[close + 1..];
+    }
+    let name = name.trim();
+    (!name.is_empty()).then_some((name, predicates))
+}
+
+/// Create the `xml_find` and `xml_children` functions over a document
+pub fn xml_functions(tree: XmlTree) -> Vec<HostFunction> {
+    let tree = Arc::new(tree);
+    let find_tree = tree.clone();
+    vec![
+        HostFunction::new(
+            "xml_find",
+            XML_FIND_DESCRIPTION,
+            move |lua, (path, node): (String, Option<LuaValue>)| {
+                let from = node.map(|node| find_tree.node_id(&node)).transpose()?;
+                let ids = find_tree
+                    .find(&path, from)
+                    .map_err(mlua::Error::RuntimeError)?;
+                find_tree.to_lua_list(lua, &ids)
+            },
+        ),
+        HostFunction::new(
+            "xml_children",
+            XML_CHILDREN_DESCRIPTION,
+            move |lua, (node, tag): (LuaValue, Option<String>)| {
+                let id = tree.node_id(&node)?;
+                let children: Vec<usize> = tree.elements[id]
+                    .children
+                    .iter()
+                    .copied()
+                    .filter(|&c| tag.as_ref().is_none_or(|tag| tree.elements[c].tag == *tag))
+                    .collect();
+                tree.to_lua_list(lua, &children)
+            },
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CATALOG: &str = r#"<?xml version="1.0"?>
+<catalog xmlns:x="urn:x">
+  <book id="b1" lang="en">
+    <title>Dune</title>
+    <price>9.99</price>
+  </book>
+  <book id="b2">
+    <title>Solaris</title>
+    <x:price>12.50</x:price>
+  </book>
+  <magazine><title>Analog</title></magazine>
+</catalog>"#;
+
+    fn texts(tree: &XmlTree, ids: &[usize]) -> Vec<String> {
+        ids.iter()
+            .map(|&id| tree.elements[id].text.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_find() {
+        let tree = XmlTree::parse(CATALOG).unwrap();
+        assert_eq!(tree.root_tag(), "catalog");
+
+        let titles = tree.find("/catalog/book/title", None).unwrap();
+        assert_eq!(texts(&tree, &titles), ["Dune", "Solaris"]);
+        let titles = tree.find("//title", None).unwrap();
+        assert_eq!(texts(&tree, &titles), ["Dune", "Solaris", "Analog"]);
+
+        let book = tree.find("//book[@id='b2']", None).unwrap();
+        assert_eq!(texts(&tree, &book), ["Solaris 12.50"]);
+        assert_eq!(tree.find("//book[@lang]", None).unwrap().len(), 1);
+        let second = tree.find("/catalog/*[2]/title", None).unwrap();
+        assert_eq!(texts(&tree, &second), ["Solaris"]);
+
+        // Relative paths, parents, and namespaced tags by local name
+        let price = tree.find("price", Some(book[0])).unwrap();
+        assert_eq!(texts(&tree, &price), ["12.50"]);
+        let parent = tree.find("..", Some(price[0])).unwrap();
+        assert_eq!(parent, book);
+
+        assert!(tree.find("//book[last()]", None).is_err());
+        assert!(tree.find("//missing", None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_xml_functions() {
+        use crate::environment::{Environment, EnvironmentConfig, LlmClient};
+
+        let mut config = EnvironmentConfig::default();
+        config
+            .functions
+            .extend(xml_functions(XmlTree::parse(CATALOG).unwrap()));
+        let env = Environment::with_config("", LlmClient::Ollama("qwen3:30b".to_string()), config)
+            .unwrap();
+
+        let result = env
+            .eval(
+                r#"
+                book = xml_find("//book")[1]
+                tags = {}
+                for _, child in ipairs(xml_children(book)) do
+                    table.insert(tags, child.tag .. "=" .. child.text)
+                end
+                print(book.attrs.id, table.concat(tags, ","), #xml_children(book, "title"))
+                "#,
+            )
+            .unwrap();
+        assert_eq!(result, Some("b1\ttitle=Dune,price=9.99\t1".to_string()));
+        assert!(env.eval(r#"xml_children("book")"#).is_err());
+    }
+}
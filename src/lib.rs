@@ -1,6 +1,35 @@
+pub mod chunking;
+pub mod context_info;
+pub mod datetime;
+pub mod diff;
+pub mod direct;
+pub mod documents;
+pub mod embeddings;
+pub mod encoding;
 pub mod environment;
+pub mod fuzzy;
+pub mod http;
 pub mod inputs;
+pub mod markup;
+pub mod notes;
+pub mod parse;
+pub mod patterns;
+pub mod policy;
+pub mod prefilter;
+pub mod prompt;
 pub mod registry;
 pub mod repl;
+pub mod report;
 pub mod rlm;
+pub mod sample;
+pub mod stats;
+pub mod stdlib;
+pub mod store;
+pub mod summarize;
+pub mod tasks;
+pub mod tokens;
 pub mod tools;
+pub mod trace;
+pub mod transcript;
+pub mod usage;
+pub mod window;
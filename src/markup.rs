@@ -0,0 +1,216 @@
+//! HTML and XML parsing for Lua code.
+//!
+//! Pulling fields out of HTML or XML with patterns breaks on attributes in
+//! a different order, nested tags, and entities. `html_text(s)` returns the
+//! readable text of a page, `html_select(s, selector)` finds elements by
+//! CSS selector, and `xml_parse(s)` turns an XML document into nested
+//! tables. Unlike `xml_find`, which is available when the context itself is
+//! an XML file, these work on any string, such as a fetched page or a
+//! fragment embedded in the context.
+
+use crate::inputs::html_to_text;
+use mlua::Lua;
+use scraper::{ElementRef, Html, Selector};
+
+/// Tags the HTML parser drops outside a table, so a fragment starting with
+/// one, such as the `html` of a row from `html_select`, is parsed inside one
+const TABLE_PARTS: &[&str] = &[
+    "tr", "td", "th", "tbody", "thead", "tfoot", "caption", "col",
+];
+
+/// Parse a document or a fragment of one
+fn parse_html(html: &str) -> Html {
+    let start = html.trim_start();
+    let in_table = start.strip_prefix('<').is_some_and(|rest| {
+        TABLE_PARTS.iter().any(|tag| {
+            rest.get(..tag.len())
+                .is_some_and(|name| name.eq_ignore_ascii_case(tag))
+                && rest[tag.len()..].starts_with(|c: char| c == '>' || c.is_whitespace())
+        })
+    });
+    if in_table {
+        Html::parse_document(&format!("<table>{html}</table>"))
+    } else {
+        Html::parse_document(html)
+    }
+}
+
+/// Text inside a node with runs of whitespace collapsed to single spaces
+fn collapsed<'a>(text: impl Iterator<Item = &'a str>) -> String {
+    text.collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Creates the `html_text(s)` Lua function, which returns the readable text
+/// of an HTML document or fragment: scripts and styles are dropped, block
+/// elements start new lines, and whitespace is collapsed.
+pub fn create_html_text_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(|_, html: String| Ok(html_to_text(&html)))
+}
+
+/// Creates the `html_select(s, selector)` Lua function.
+///
+/// Returns the elements of the HTML `s` matching the CSS `selector`, in
+/// document order, as `{tag, attrs, text, html}` tables: `attrs` maps
+/// attribute names to values, `text` is the text inside the element with
+/// whitespace collapsed, and `html` is the element's own markup, which can
+/// be passed to `html_select` again. Fails on an invalid selector.
+pub fn create_html_select_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(|lua, (html, selector): (String, String)| {
+        let parsed = Selector::parse(&selector).map_err(|e| {
+            mlua::Error::RuntimeError(format!("html_select: invalid selector {selector:?}: {e}"))
+        })?;
+        let document = parse_html(&html);
+        let results = lua.create_table()?;
+        for element in document.select(&parsed) {
+            results.push(html_element_to_lua(lua, element)?)?;
+        }
+        Ok(results)
+    })
+}
+
+fn html_element_to_lua(lua: &Lua, element: ElementRef) -> mlua::Result<mlua::Table> {
+    let node = lua.create_table()?;
+    node.set("tag", element.value().name())?;
+    let attrs = lua.create_table()?;
+    for (name, value) in element.value().attrs() {
+        attrs.set(name, value)?;
+    }
+    node.set("attrs", attrs)?;
+    node.set("text", collapsed(element.text()))?;
+    node.set("html", element.html())?;
+    Ok(node)
+}
+
+/// Creates the `xml_parse(s)` Lua function.
+///
+/// Returns the root element of the XML document `s` as a
+/// `{tag, attrs, text, children}` table, where `tag` is the local name
+/// (namespaces are dropped), `text` is all text inside the element with
+/// whitespace collapsed, and `children` lists the child elements as tables
+/// of the same shape. Returns nil and an error message if `s` is not
+/// well-formed XML.
+pub fn create_xml_parse_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(|lua, xml: String| {
+        Ok(match roxmltree::Document::parse(&xml) {
+            Ok(document) => (
+                mlua::Value::Table(xml_element_to_lua(lua, document.root_element())?),
+                None,
+            ),
+            Err(e) => (mlua::Value::Nil, Some(format!("invalid XML: {e}"))),
+        })
+    })
+}
+
+fn xml_element_to_lua(lua: &Lua, element: roxmltree::Node) -> mlua::Result<mlua::Table> {
+    let node = lua.create_table()?;
+    node.set("tag", element.tag_name().name())?;
+    let attrs = lua.create_table()?;
+    for attribute in element.attributes() {
+        attrs.set(attribute.name(), attribute.value())?;
+    }
+    node.set("attrs", attrs)?;
+    node.set(
+        "text",
+        collapsed(
+            element
+                .descendants()
+                .filter(|n| n.is_text())
+                .filter_map(|n| n.text()),
+        ),
+    )?;
+    let children = lua.create_table()?;
+    for child in element.children().filter(|c| c.is_element()) {
+        children.push(xml_element_to_lua(lua, child)?)?;
+    }
+    node.set("children", children)?;
+    Ok(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lua() -> Lua {
+        let lua = Lua::new();
+        let globals = lua.globals();
+        globals
+            .set("html_text", create_html_text_function(&lua).unwrap())
+            .unwrap();
+        globals
+            .set("html_select", create_html_select_function(&lua).unwrap())
+            .unwrap();
+        globals
+            .set("xml_parse", create_xml_parse_function(&lua).unwrap())
+            .unwrap();
+        lua
+    }
+
+    #[test]
+    fn test_html_functions() {
+        let lua = lua();
+        lua.globals()
+            .set(
+                "page",
+                r#"<html><head><script>var x = 1;</script></head><body>
+                <h1>Prices</h1>
+                <table><tr class="item" data-sku="a1"><td>Tea</td><td>  3.50 </td></tr>
+                <tr class="item" data-sku="b2"><td>Coffee &amp; cake</td><td>4.20</td></tr></table>
+                </body></html>"#,
+            )
+            .unwrap();
+        let text: String = lua.load("return html_text(page)").eval().unwrap();
+        assert!(text.starts_with("Prices\n"), "{text}");
+        assert!(!text.contains("var x"), "{text}");
+
+        let rows: Vec<String> = lua
+            .load(
+                r#"
+                local rows = {}
+                for _, row in ipairs(html_select(page, "tr.item")) do
+                    local cells = html_select(row.html, "td")
+                    table.insert(rows, row.attrs["data-sku"] .. "=" .. cells[1].text .. ":" .. cells[2].text)
+                end
+                return rows
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(rows, ["a1=Tea:3.50", "b2=Coffee & cake:4.20"]);
+
+        let err = lua.load(r#"html_select(page, "tr[")"#).exec().unwrap_err();
+        assert!(err.to_string().contains("invalid selector"), "{err}");
+    }
+
+    #[test]
+    fn test_xml_parse() {
+        let lua = lua();
+        let (tag, id, titles, text): (String, String, String, String) = lua
+            .load(
+                r#"
+                local root = xml_parse([[<catalog xmlns:x="urn:x">
+                  <book id="b1"><title>Dune</title></book>
+                  <x:book id="b2"><title>Solaris</title></x:book>
+                </catalog>]])
+                local titles = {}
+                for _, book in ipairs(root.children) do
+                    table.insert(titles, book.tag .. ":" .. book.children[1].text)
+                end
+                return root.tag, root.children[2].attrs.id, table.concat(titles, ","), root.text
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(tag, "catalog");
+        assert_eq!(id, "b2");
+        assert_eq!(titles, "book:Dune,book:Solaris");
+        assert_eq!(text, "Dune Solaris");
+
+        let (root, err): (mlua::Value, String) =
+            lua.load("return xml_parse('<a><b></a>')").eval().unwrap();
+        assert!(root.is_nil());
+        assert!(err.starts_with("invalid XML"), "{err}");
+    }
+}
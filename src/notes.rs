@@ -0,0 +1,220 @@
+//! Running notes kept by the host.
+//!
+//! Notes used to be a `notes` table in Lua, which the model had to print
+//! again to see and which was gone from view once the cells that printed it
+//! were evicted from the context window. `note_add(text, tags)` records a
+//! note instead, `notes_list(tag)` returns them, and
+//! `notes_summarize(max_tokens)` renders them within a token budget, e.g. to
+//! pass to `llm_query`. The notes are shown at the top of every prompt (see
+//! [`crate::repl::Repl::notes`]), so they stay in view however long the
+//! transcript gets.
+
+use crate::tokens::Tokenizer;
+use mlua::Lua;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+/// A note recorded from Lua with `note_add`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Note {
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+impl Note {
+    /// The note as a list item, with its tags in brackets after the text
+    pub fn line(&self) -> String {
+        if self.tags.is_empty() {
+            format!("- {}", self.text)
+        } else {
+            format!("- {} [{}]", self.text, self.tags.join(", "))
+        }
+    }
+}
+
+/// The notes of an environment, in the order they were added
+pub type Notes = Arc<Mutex<Vec<Note>>>;
+
+/// The notes as list items, one per line, keeping the most recent notes
+/// that fit in `max_tokens` tokens (all of them if None). Omitted notes are
+/// counted in a first line.
+pub fn summarize(notes: &[Note], max_tokens: Option<usize>, tokenizer: Tokenizer) -> String {
+    let Some(max_tokens) = max_tokens else {
+        return notes.iter().map(Note::line).collect::<Vec<_>>().join("\n");
+    };
+    let mut kept: Vec<(String, usize)> = Vec::new();
+    let mut tokens = 0;
+    for note in notes.iter().rev() {
+        let line = note.line();
+        let cost = tokenizer.count(&line) + 1;
+        if tokens + cost > max_tokens {
+            break;
+        }
+        tokens += cost;
+        kept.push((line, cost));
+    }
+    // Make room for the line counting the omitted notes
+    while kept.len() < notes.len() {
+        let omitted = format!("({} earlier notes omitted)", notes.len() - kept.len());
+        if tokens + tokenizer.count(&omitted) <= max_tokens || kept.is_empty() {
+            kept.push((omitted, 0));
+            break;
+        }
+        let (_, cost) = kept.pop().unwrap();
+        tokens -= cost;
+    }
+    let mut kept: Vec<String> = kept.into_iter().map(|(line, _)| line).collect();
+    kept.reverse();
+    kept.join("\n")
+}
+
+/// Tags given as a list of strings, a single string, or nil
+fn tags(value: mlua::Value) -> mlua::Result<Vec<String>> {
+    match value {
+        mlua::Value::Nil => Ok(Vec::new()),
+        mlua::Value::String(tag) => Ok(vec![tag.to_str()?.to_string()]),
+        mlua::Value::Table(tags) => tags.sequence_values::<String>().collect(),
+        other => Err(mlua::Error::RuntimeError(format!(
+            "note_add: tags must be a string or a list of strings, not {}",
+            other.type_name()
+        ))),
+    }
+}
+
+/// Creates the `note_add(text, tags)` Lua function, which records a note
+/// with optional tags, a string or a list of strings
+pub fn create_note_add_function(lua: &Lua, notes: Notes) -> mlua::Result<mlua::Function> {
+    lua.create_function(move |_, (text, value): (String, mlua::Value)| {
+        let tags = tags(value)?;
+        notes.lock().unwrap().push(Note { text, tags });
+        Ok(())
+    })
+}
+
+/// Creates the `notes_list(tag)` Lua function, which returns the notes with
+/// the tag, or all notes without one, as `{text, tags}` tables in the order
+/// they were added
+pub fn create_notes_list_function(lua: &Lua, notes: Notes) -> mlua::Result<mlua::Function> {
+    lua.create_function(move |lua, tag: Option<String>| {
+        let results = lua.create_table()?;
+        for note in notes.lock().unwrap().iter() {
+            if tag.as_ref().is_some_and(|tag| !note.tags.contains(tag)) {
+                continue;
+            }
+            let item = lua.create_table()?;
+            item.set("text", note.text.as_str())?;
+            item.set(
+                "tags",
+                lua.create_sequence_from(note.tags.iter().map(String::as_str))?,
+            )?;
+            results.push(item)?;
+        }
+        Ok(results)
+    })
+}
+
+/// Creates the `notes_summarize(max_tokens)` Lua function, which returns
+/// the notes as a list within `max_tokens` tokens, or all of them without a
+/// limit (see [`summarize`])
+pub fn create_notes_summarize_function(
+    lua: &Lua,
+    notes: Notes,
+    tokenizer: Tokenizer,
+) -> mlua::Result<mlua::Function> {
+    lua.create_function(move |_, max_tokens: Option<usize>| {
+        Ok(summarize(&notes.lock().unwrap(), max_tokens, tokenizer))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(text: &str, tags: &[&str]) -> Note {
+        Note {
+            text: text.to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        let notes = [
+            note("first note about the layout of the logs", &[]),
+            note("second note about timeouts", &["errors"]),
+            note("third note", &["errors", "todo"]),
+        ];
+        let tokenizer = Tokenizer::default();
+        let all = summarize(&notes, None, tokenizer);
+        assert_eq!(
+            all,
+            "- first note about the layout of the logs\n\
+             - second note about timeouts [errors]\n\
+             - third note [errors, todo]"
+        );
+        assert_eq!(summarize(&notes, Some(10_000), tokenizer), all);
+
+        let recent = summarize(&notes, Some(28), tokenizer);
+        assert!(
+            recent.starts_with("(1 earlier notes omitted)\n- second note"),
+            "{recent}"
+        );
+        assert!(tokenizer.count(&recent) <= 28, "{recent}");
+        assert_eq!(
+            summarize(&notes, Some(0), tokenizer),
+            "(3 earlier notes omitted)"
+        );
+    }
+
+    #[test]
+    fn test_lua_functions() {
+        let lua = Lua::new();
+        let notes = Notes::default();
+        let globals = lua.globals();
+        globals
+            .set(
+                "note_add",
+                create_note_add_function(&lua, notes.clone()).unwrap(),
+            )
+            .unwrap();
+        globals
+            .set(
+                "notes_list",
+                create_notes_list_function(&lua, notes.clone()).unwrap(),
+            )
+            .unwrap();
+        globals
+            .set(
+                "notes_summarize",
+                create_notes_summarize_function(&lua, notes.clone(), Tokenizer::default()).unwrap(),
+            )
+            .unwrap();
+
+        let (count, tagged, summary): (usize, String, String) = lua
+            .load(
+                r#"
+                note_add("3 categories: A, B, C")
+                note_add("B items all say 'urgent'", {"pattern", "b"})
+                note_add("A is the largest", "a")
+                local tagged = {}
+                for _, n in ipairs(notes_list("b")) do
+                    table.insert(tagged, n.text .. "/" .. table.concat(n.tags, ","))
+                end
+                return #notes_list(), table.concat(tagged, ";"), notes_summarize()
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(tagged, "B items all say 'urgent'/pattern,b");
+        assert!(summary.ends_with("\n- A is the largest [a]"), "{summary}");
+        assert_eq!(
+            notes.lock().unwrap()[1],
+            note("B items all say 'urgent'", &["pattern", "b"])
+        );
+
+        let err = lua.load("note_add('x', 1)").exec().unwrap_err();
+        assert!(err.to_string().contains("tags must be"), "{err}");
+    }
+}
@@ -0,0 +1,442 @@
+//! Parsing of LM responses into [`Cell`]s.
+//!
+//! Responses are expected to use `<comment>`, `<code>`, and `<final>` tags (see
+//! README.md "Testing" section for why we don't use structured output). Reasoning
+//! models often prefix their answer with `<think>…</think>` blocks, and Lua code
+//! may itself contain tag-like text, so parsing is done with explicit searches
+//! rather than a single regex.
+
+use crate::repl::Cell;
+use crate::rlm::OutputParser;
+use std::error::Error;
+
+/// Tags whose contents are model reasoning rather than part of the answer
+const REASONING_TAGS: &[&str] = &["think", "thinking", "reasoning"];
+
+impl OutputParser for Cell {
+    fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        parse_cell(text)
+    }
+}
+
+/// Parse a response into a partial Cell (output set to None).
+pub fn parse_cell(text: &str) -> Result<Cell, Box<dyn Error>> {
+    let text = strip_reasoning_outside(text, &["comment", "code"]);
+
+    // Try to parse as JSON first for backward compatibility
+    if let Ok(cell) = serde_json::from_str::<Cell>(text.trim()) {
+        return Ok(cell);
+    }
+
+    // Extract comment
+    let (comment, comment_end) = find_tag(&text, "comment", 0, false)
+        .ok_or("Failed to parse <comment> tag from response")?;
+
+    // Extract code. The code body is searched after the comment and closed by the
+    // last </code>, so tag-like text inside Lua strings stays in the body.
+    let (code, code_end) = find_tag(&text, "code", comment_end, true)
+        .or_else(|| find_tag(&text, "code", 0, true))
+        .ok_or("Failed to parse <code> tag from response")?;
+
+    // Extract final flag (optional)
+    let final_flag = find_tag(&text, "final", code_end, false)
+        .or_else(|| find_tag(&text, "final", 0, false))
+        .map(|(value, _)| {
+            let value = value.trim().to_lowercase();
+            value == "true" || value == "yes"
+        })
+        .unwrap_or(false);
+
+    let comment = comment.trim().to_string();
+    let code = clean_code(code);
+
+    // Validate that we got comment and code
+    if comment.is_empty() {
+        return Err("Comment tag is empty".into());
+    }
+    if code.is_empty() {
+        return Err("Code tag is empty".into());
+    }
+
+    Ok(Cell {
+        comment,
+        code,
+        output: None,
+        r#final: final_flag,
+        retries: 0,
+        metadata: None,
+        pinned: false,
+        evicted: false,
+        error: None,
+        repeat_of: None,
+    })
+}
+
+/// Remove reasoning blocks (`<think>…</think>` and similar) from a response.
+///
+/// A closing tag without an opening tag (some models omit the opener) drops
+/// everything before it. An unterminated opening tag drops everything up to the
+/// next `<comment>` tag, or the rest of the text if there is none.
+pub fn strip_reasoning(text: &str) -> String {
+    strip_reasoning_outside(text, &[])
+}
+
+/// Remove reasoning blocks as [`strip_reasoning`] does, but keep the
+/// contents of the `protected` tags as they are, so Lua code that handles
+/// `<think>` tags itself, e.g. to clean `llm_query` responses, is not cut.
+/// A protected tag ends at its first closing tag, except `code`, which ends
+/// at the last `</code>` as in [`parse_cell`].
+fn strip_reasoning_outside(text: &str, protected: &[&str]) -> String {
+    let mut stripped = String::new();
+    let mut rest = text;
+    loop {
+        // The earliest reasoning tag, or opening protected tag
+        let next = REASONING_TAGS
+            .iter()
+            .flat_map(|tag| [(*tag, true), (*tag, false)])
+            .chain(protected.iter().map(|tag| (*tag, true)))
+            .filter_map(|(tag, open)| {
+                let marker = if open {
+                    format!("<{tag}>")
+                } else {
+                    format!("</{tag}>")
+                };
+                rest.find(&marker).map(|pos| (pos, tag, open, marker))
+            })
+            .min_by_key(|(pos, ..)| *pos);
+        let Some((pos, tag, open, marker)) = next else {
+            stripped.push_str(rest);
+            return stripped;
+        };
+        let after = pos + marker.len();
+
+        if protected.contains(&tag) {
+            let close = format!("</{tag}>");
+            let end = if tag == "code" {
+                rest.rfind(&close).filter(|&end| end >= after)
+            } else {
+                rest[after..].find(&close).map(|end| after + end)
+            };
+            let end = end.map_or(rest.len(), |end| end + close.len());
+            stripped.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if open {
+            stripped.push_str(&rest[..pos]);
+            let close = format!("</{tag}>");
+            rest = match rest[after..].find(&close) {
+                Some(end) => &rest[after + end + close.len()..],
+                None => rest[after..]
+                    .find("<comment>")
+                    .map_or("", |resume| &rest[after + resume..]),
+            };
+        } else {
+            stripped.clear();
+            rest = &rest[after..];
+        }
+    }
+}
+
+/// Parse a JSON value from a response, tolerating the usual ways models wrap
+/// or damage it: reasoning blocks, markdown fences or prose around the value,
+/// trailing commas, and a value cut off before its closing brackets.
+pub fn parse_json(text: &str) -> Option<serde_json::Value> {
+    let text = strip_reasoning(text);
+    if let Ok(value) = serde_json::from_str(text.trim()) {
+        return Some(value);
+    }
+    // Start at the first bracket, skipping fences and prose before the value
+    let start = text.find(['{', '['])?;
+    serde_json::from_str(&repair_json(&text[start..])).ok()
+}
+
+/// Cut `text` after the object or array it starts with, dropping trailing
+/// commas and closing whatever strings and brackets are left open.
+fn repair_json(text: &str) -> String {
+    let mut repaired = String::with_capacity(text.len());
+    let mut open = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in text.chars() {
+        if in_string {
+            repaired.push(c);
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => open.push('}'),
+            '[' => open.push(']'),
+            '}' | ']' => {
+                trim_trailing_comma(&mut repaired);
+                open.pop();
+            }
+            _ => {}
+        }
+        repaired.push(c);
+        if open.is_empty() {
+            return repaired;
+        }
+    }
+
+    if in_string {
+        repaired.push('"');
+    }
+    trim_trailing_comma(&mut repaired);
+    repaired.extend(open.iter().rev());
+    repaired
+}
+
+fn trim_trailing_comma(text: &mut String) {
+    let trimmed = text.trim_end().len();
+    text.truncate(trimmed);
+    if text.ends_with(',') {
+        text.pop();
+    }
+}
+
+/// Find `<tag>…</tag>` starting the search at `from`.
+///
+/// Returns the (untrimmed) body and the byte offset just past the closing tag.
+/// When `last_close` is set, the body extends to the last closing tag in the
+/// text instead of the first one.
+fn find_tag(text: &str, tag: &str, from: usize, last_close: bool) -> Option<(String, usize)> {
+    let open = format!("<{tag}>");
+    let close = format!("</{tag}>");
+
+    let start = from + text.get(from..)?.find(&open)? + open.len();
+    let end = if last_close {
+        start + text[start..].rfind(&close)?
+    } else {
+        start + text[start..].find(&close)?
+    };
+
+    Some((text[start..end].to_string(), end + close.len()))
+}
+
+/// Normalize a code body: unwrap CDATA sections and markdown fences, and
+/// unescape XML entities when the model escaped the whole body.
+fn clean_code(code: String) -> String {
+    let mut code = code.trim();
+
+    if let Some(inner) = code
+        .strip_prefix("<![CDATA[")
+        .and_then(|c| c.strip_suffix("]]>"))
+    {
+        code = inner.trim();
+    }
+
+    if let Some(inner) = code.strip_prefix("```").and_then(|c| c.strip_suffix("```")) {
+        // Drop the language tag on the opening fence line, if any
+        code = match inner.split_once('\n') {
+            Some((lang, rest)) if !lang.trim().contains(' ') => rest,
+            _ => inner,
+        }
+        .trim();
+    }
+
+    // Only unescape when no raw angle brackets remain, i.e. the body was escaped
+    // as a whole rather than containing a literal entity in a Lua string
+    if code.contains('&') && !code.contains('<') && !code.contains('>') {
+        return code
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&");
+    }
+
+    code.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cell_parser_xml_format() {
+        let text = r#"<comment>
+First, let me check the context to understand what we're working with
+</comment>
+
+<code>
+print(string.sub(context, 1, 100))
+</code>
+
+<final>
+false
+</final>"#;
+
+        let cell = Cell::parse(text).unwrap();
+        assert_eq!(
+            cell.comment,
+            "First, let me check the context to understand what we're working with"
+        );
+        assert_eq!(cell.code, "print(string.sub(context, 1, 100))");
+        assert!(!cell.r#final);
+    }
+
+    #[test]
+    fn test_cell_parser_final_true() {
+        let text = r#"<comment>
+Output the final answer
+</comment>
+
+<code>
+print("The answer is: 42")
+</code>
+
+<final>
+true
+</final>"#;
+
+        let cell = Cell::parse(text).unwrap();
+        assert_eq!(cell.comment, "Output the final answer");
+        assert_eq!(cell.code, r#"print("The answer is: 42")"#);
+        assert!(cell.r#final);
+    }
+
+    #[test]
+    fn test_cell_parser_json_fallback() {
+        let json = r#"{"comment": "Test comment", "code": "print('hello')", "final": false}"#;
+        let cell = Cell::parse(json).unwrap();
+        assert_eq!(cell.comment, "Test comment");
+        assert_eq!(cell.code, "print('hello')");
+        assert!(!cell.r#final);
+    }
+
+    #[test]
+    fn test_strip_think_block() {
+        let text = r#"<think>
+I should write <comment> and <code> tags. Maybe <final>true</final>?
+</think>
+<comment>Count lines</comment>
+<code>print(1)</code>
+<final>false</final>"#;
+
+        let cell = Cell::parse(text).unwrap();
+        assert_eq!(cell.comment, "Count lines");
+        assert_eq!(cell.code, "print(1)");
+        assert!(!cell.r#final);
+    }
+
+    #[test]
+    fn test_strip_orphan_closing_think() {
+        let text = "reasoning without an opener </think><comment>c</comment><code>x = 1</code>";
+        let cell = Cell::parse(text).unwrap();
+        assert_eq!(cell.comment, "c");
+        assert_eq!(cell.code, "x = 1");
+    }
+
+    #[test]
+    fn test_strip_unterminated_think() {
+        assert_eq!(
+            strip_reasoning("<think>still going <comment>c</comment>"),
+            "<comment>c</comment>"
+        );
+        assert_eq!(strip_reasoning("answer <thinking>cut off"), "answer ");
+    }
+
+    #[test]
+    fn test_code_containing_tags() {
+        let text = r#"<comment>Build XML</comment>
+<code>
+xml = "<code>" .. body .. "</code>"
+print(xml)
+</code>
+<final>true</final>"#;
+
+        let cell = Cell::parse(text).unwrap();
+        assert_eq!(
+            cell.code,
+            "xml = \"<code>\" .. body .. \"</code>\"\nprint(xml)"
+        );
+        assert!(cell.r#final);
+    }
+
+    #[test]
+    fn test_code_containing_reasoning_tags() {
+        let text = r#"<think>Strip the tags from the answers</think>
+<comment>Clean answers</comment>
+<code>
+clean = answer:gsub("<think>.-</think>", "")
+rest = answer:match("</think>(.*)")
+</code>"#;
+
+        let cell = Cell::parse(text).unwrap();
+        assert_eq!(cell.comment, "Clean answers");
+        assert_eq!(
+            cell.code,
+            "clean = answer:gsub(\"<think>.-</think>\", \"\")\nrest = answer:match(\"</think>(.*)\")"
+        );
+
+        let text =
+            r#"<comment>Split off reasoning</comment><code>print(s:find("</think>"))</code>"#;
+        let cell = Cell::parse(text).unwrap();
+        assert_eq!(cell.comment, "Split off reasoning");
+        assert_eq!(cell.code, r#"print(s:find("</think>"))"#);
+    }
+
+    #[test]
+    fn test_code_escaped_and_wrapped() {
+        let escaped = "<comment>c</comment><code>if a &lt; b then print(&quot;x&quot;) end</code>";
+        assert_eq!(
+            Cell::parse(escaped).unwrap().code,
+            r#"if a < b then print("x") end"#
+        );
+
+        let cdata = "<comment>c</comment><code><![CDATA[if a < b then print(a) end]]></code>";
+        assert_eq!(
+            Cell::parse(cdata).unwrap().code,
+            "if a < b then print(a) end"
+        );
+
+        let fenced = "<comment>c</comment><code>\n```lua\nprint(1)\n```\n</code>";
+        assert_eq!(Cell::parse(fenced).unwrap().code, "print(1)");
+    }
+
+    #[test]
+    fn test_literal_entity_in_code_kept() {
+        let text = r#"<comment>c</comment><code>print("a &amp; b" .. ("<"))</code>"#;
+        assert_eq!(
+            Cell::parse(text).unwrap().code,
+            r#"print("a &amp; b" .. ("<"))"#
+        );
+    }
+
+    #[test]
+    fn test_missing_tags() {
+        assert!(Cell::parse("<code>print(1)</code>").is_err());
+        assert!(Cell::parse("<comment>c</comment>").is_err());
+        assert!(Cell::parse("<comment> </comment><code>x</code>").is_err());
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let expected = serde_json::json!({"names": ["a", "b"], "count": 2});
+        for text in [
+            r#"{"names": ["a", "b"], "count": 2}"#,
+            "<think>Let me list them</think>\n```json\n{\"names\": [\"a\", \"b\"], \"count\": 2}\n```",
+            r#"Here you go: {"names": ["a", "b",], "count": 2,} Hope this helps!"#,
+            r#"{"names": ["a", "b"], "count": 2"#,
+        ] {
+            assert_eq!(parse_json(text), Some(expected.clone()), "{text}");
+        }
+
+        // Strings are closed, and brackets inside them are left alone
+        assert_eq!(
+            parse_json(r#"[{"note": "uses [brackets] and \"quotes\""}, {"note": "cut off"#),
+            Some(serde_json::json!([
+                {"note": "uses [brackets] and \"quotes\""},
+                {"note": "cut off"}
+            ]))
+        );
+        assert_eq!(parse_json("42"), Some(serde_json::json!(42)));
+        assert_eq!(parse_json("no JSON here"), None);
+    }
+}
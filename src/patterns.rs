@@ -0,0 +1,425 @@
+//! Regular expressions for Lua code, with the syntax of the `regex` crate.
+//!
+//! Lua patterns have no alternation and only single-character quantifiers,
+//! while models write PCRE-style expressions by habit. `re_find`,
+//! `re_findall`, and `re_replace` accept those instead, and `grep` finds the
+//! matching lines of the context with the lines around them. Matching works
+//! on the bytes of a Lua string, so positions line up with `string.sub`.
+
+use mlua::Lua;
+use regex::bytes::{Captures, Regex};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Compiled patterns kept per environment; cleared when full
+const CACHE_SIZE: usize = 64;
+
+/// Compiles patterns, reusing recent ones since cells often match the same
+/// pattern in a loop
+#[derive(Clone, Default)]
+pub struct PatternCache {
+    patterns: Arc<Mutex<HashMap<String, Regex>>>,
+}
+
+impl PatternCache {
+    pub fn get(&self, pattern: &str) -> Result<Regex, regex::Error> {
+        let mut patterns = self.patterns.lock().unwrap();
+        if let Some(regex) = patterns.get(pattern) {
+            return Ok(regex.clone());
+        }
+        let regex = Regex::new(pattern)?;
+        if patterns.len() >= CACHE_SIZE {
+            patterns.clear();
+        }
+        patterns.insert(pattern.to_string(), regex.clone());
+        Ok(regex)
+    }
+
+    fn compile(&self, function: &str, pattern: &str) -> mlua::Result<Regex> {
+        self.get(pattern)
+            .map_err(|e| mlua::Error::RuntimeError(format!("{function}: invalid pattern: {e}")))
+    }
+}
+
+/// Capture groups of a match as a Lua table: numbered groups in order, with
+/// `false` for groups that did not participate, and named groups also by name
+fn groups_table(lua: &Lua, regex: &Regex, captures: &Captures) -> mlua::Result<mlua::Table> {
+    let groups = lua.create_table()?;
+    for i in 1..captures.len() {
+        match captures.get(i) {
+            Some(group) => groups.set(i, lua.create_string(group.as_bytes())?)?,
+            None => groups.set(i, false)?,
+        }
+    }
+    for name in regex.capture_names().flatten() {
+        if let Some(group) = captures.name(name) {
+            groups.set(name, lua.create_string(group.as_bytes())?)?;
+        }
+    }
+    Ok(groups)
+}
+
+/// Creates the `re_find(s, pattern, init)` Lua function.
+///
+/// Returns the first match at or after byte `init` (1 by default) as a table
+/// with `text`, `start` and `stop` (1-based and inclusive, like
+/// `string.find`), and `groups` (see [`groups_table`]), or nil if there is no
+/// match.
+pub fn create_re_find_function(lua: &Lua, cache: PatternCache) -> mlua::Result<mlua::Function> {
+    lua.create_function(
+        move |lua, (s, pattern, init): (mlua::String, String, Option<usize>)| {
+            let regex = cache.compile("re_find", &pattern)?;
+            let bytes = s.as_bytes();
+            let start = init.unwrap_or(1).saturating_sub(1).min(bytes.len());
+            let Some(captures) = regex.captures_at(&bytes, start) else {
+                return Ok(mlua::Value::Nil);
+            };
+            let whole = captures.get(0).expect("group 0 always matches");
+            let result = lua.create_table()?;
+            result.set("text", lua.create_string(whole.as_bytes())?)?;
+            result.set("start", whole.start() + 1)?;
+            result.set("stop", whole.end())?;
+            result.set("groups", groups_table(lua, &regex, &captures)?)?;
+            Ok(mlua::Value::Table(result))
+        },
+    )
+}
+
+/// Creates the `re_findall(s, pattern)` Lua function.
+///
+/// Returns a list of all non-overlapping matches, like Python's
+/// `re.findall`: the matched text if the pattern has no groups, the group if
+/// it has one, and a list of groups (see [`groups_table`]) if it has more.
+pub fn create_re_findall_function(lua: &Lua, cache: PatternCache) -> mlua::Result<mlua::Function> {
+    lua.create_function(move |lua, (s, pattern): (mlua::String, String)| {
+        let regex = cache.compile("re_findall", &pattern)?;
+        let bytes = s.as_bytes();
+        let results = lua.create_table()?;
+        for captures in regex.captures_iter(&bytes) {
+            let value = match captures.len() {
+                1 => mlua::Value::String(lua.create_string(&captures[0])?),
+                2 => match captures.get(1) {
+                    Some(group) => mlua::Value::String(lua.create_string(group.as_bytes())?),
+                    None => mlua::Value::String(lua.create_string("")?),
+                },
+                _ => mlua::Value::Table(groups_table(lua, &regex, &captures)?),
+            };
+            results.push(value)?;
+        }
+        Ok(results)
+    })
+}
+
+/// Creates the `re_replace(s, pattern, replacement, n)` Lua function.
+///
+/// Replaces the first `n` matches (all by default) and returns the new string
+/// and the number of replacements, like `string.gsub`. A string replacement
+/// may refer to groups as `$1` or `${name}` (`$$` for a literal `$`); a
+/// function replacement is called with the matched text and the groups and
+/// returns the replacement text.
+pub fn create_re_replace_function(lua: &Lua, cache: PatternCache) -> mlua::Result<mlua::Function> {
+    lua.create_function(
+        move |lua, args: (mlua::String, String, mlua::Value, Option<usize>)| {
+            let (s, pattern, replacement, n) = args;
+            let regex = cache.compile("re_replace", &pattern)?;
+            let (result, count) = replace(lua, &regex, &s.as_bytes(), &replacement, n)?;
+            Ok((lua.create_string(result)?, count))
+        },
+    )
+}
+
+/// Matching lines `grep` returns when no `max` is given
+pub const DEFAULT_GREP_MAX: usize = 100;
+
+/// Options of `grep`, read from its `opts` table
+#[derive(Debug, Default)]
+struct GrepOptions {
+    text: Option<mlua::String>,
+    around: usize,
+    ignore_case: bool,
+    plain: bool,
+    max: Option<usize>,
+}
+
+impl GrepOptions {
+    fn from_lua(opts: Option<mlua::Value>) -> mlua::Result<Self> {
+        match opts {
+            None | Some(mlua::Value::Nil) => Ok(Self::default()),
+            Some(mlua::Value::String(text)) => Ok(Self {
+                text: Some(text),
+                ..Default::default()
+            }),
+            Some(mlua::Value::Table(opts)) => Ok(Self {
+                text: opts.get("text")?,
+                around: opts.get::<Option<usize>>("around")?.unwrap_or(0),
+                ignore_case: opts.get::<Option<bool>>("ignore_case")?.unwrap_or(false),
+                plain: opts.get::<Option<bool>>("plain")?.unwrap_or(false),
+                max: opts.get("max")?,
+            }),
+            Some(other) => Err(mlua::Error::RuntimeError(format!(
+                "grep: opts must be a table or the string to search, not {}",
+                other.type_name()
+            ))),
+        }
+    }
+}
+
+/// Creates the `grep(pattern, opts)` Lua function.
+///
+/// Searches the lines of `opts.text` (the `context` global by default, or
+/// `opts` itself if it is a string) and returns the matching lines, up to
+/// `opts.max` ([`DEFAULT_GREP_MAX`] by default), as `{line, offset, text,
+/// before, after}` tables: the 1-based line number, the byte position where
+/// the line starts, the line, and lists of up to `opts.around` lines before
+/// and after it. The total number of matching lines is the second return
+/// value. `opts.ignore_case` ignores case, and `opts.plain` matches the
+/// pattern as literal text instead of a regular expression.
+pub fn create_grep_function(lua: &Lua, cache: PatternCache) -> mlua::Result<mlua::Function> {
+    lua.create_function(move |lua, (pattern, opts): (String, Option<mlua::Value>)| {
+        let opts = GrepOptions::from_lua(opts)?;
+        let text = match opts.text {
+            Some(text) => text,
+            None => match lua.globals().get::<mlua::Value>("context")? {
+                mlua::Value::String(context) => context,
+                _ => {
+                    return Err(mlua::Error::RuntimeError(
+                        "grep: `context` is not a string; pass the string to search as opts.text"
+                            .to_string(),
+                    ));
+                }
+            },
+        };
+        let mut pattern = if opts.plain {
+            regex::escape(&pattern)
+        } else {
+            pattern
+        };
+        if opts.ignore_case {
+            pattern = format!("(?i){pattern}");
+        }
+        let regex = cache.compile("grep", &pattern)?;
+
+        let bytes = text.as_bytes();
+        let mut lines = Vec::new();
+        let mut start = 0;
+        for line in bytes.split(|&b| b == b'\n') {
+            lines.push((start, line.strip_suffix(b"\r").unwrap_or(line)));
+            start += line.len() + 1;
+        }
+        // A final newline does not start another line
+        if bytes.ends_with(b"\n") {
+            lines.pop();
+        }
+
+        let max = opts.max.unwrap_or(DEFAULT_GREP_MAX);
+        let results = lua.create_table()?;
+        let mut total = 0;
+        for (i, &(offset, line)) in lines.iter().enumerate() {
+            if !regex.is_match(line) {
+                continue;
+            }
+            total += 1;
+            if total > max {
+                continue;
+            }
+            let context = |range: std::ops::Range<usize>| {
+                lua.create_sequence_from(
+                    lines[range]
+                        .iter()
+                        .map(|(_, line)| lua.create_string(line))
+                        .collect::<mlua::Result<Vec<_>>>()?,
+                )
+            };
+            let found = lua.create_table()?;
+            found.set("line", i + 1)?;
+            found.set("offset", offset + 1)?;
+            found.set("text", lua.create_string(line)?)?;
+            found.set("before", context(i.saturating_sub(opts.around)..i)?)?;
+            found.set(
+                "after",
+                context(i + 1..(i + 1 + opts.around).min(lines.len()))?,
+            )?;
+            results.push(found)?;
+        }
+        Ok((results, total))
+    })
+}
+
+fn replace(
+    lua: &Lua,
+    regex: &Regex,
+    bytes: &[u8],
+    replacement: &mlua::Value,
+    limit: Option<usize>,
+) -> mlua::Result<(Vec<u8>, usize)> {
+    let mut count = 0;
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut last = 0;
+    for captures in regex.captures_iter(bytes).take(limit.unwrap_or(usize::MAX)) {
+        let whole = captures.get(0).expect("group 0 always matches");
+        result.extend_from_slice(&bytes[last..whole.start()]);
+        match replacement {
+            mlua::Value::String(template) => captures.expand(&template.as_bytes(), &mut result),
+            mlua::Value::Function(f) => {
+                let mut args = vec![mlua::Value::String(lua.create_string(whole.as_bytes())?)];
+                for group in captures.iter().skip(1) {
+                    args.push(match group {
+                        Some(group) => mlua::Value::String(lua.create_string(group.as_bytes())?),
+                        None => mlua::Value::Boolean(false),
+                    });
+                }
+                let text: mlua::String = f.call(mlua::MultiValue::from_vec(args))?;
+                result.extend_from_slice(&text.as_bytes());
+            }
+            other => {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "re_replace: replacement must be a string or a function, not {}",
+                    other.type_name()
+                )));
+            }
+        }
+        last = whole.end();
+        count += 1;
+    }
+    result.extend_from_slice(&bytes[last..]);
+    Ok((result, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lua() -> Lua {
+        let lua = Lua::new();
+        let cache = PatternCache::default();
+        let globals = lua.globals();
+        globals
+            .set(
+                "re_find",
+                create_re_find_function(&lua, cache.clone()).unwrap(),
+            )
+            .unwrap();
+        globals
+            .set(
+                "re_findall",
+                create_re_findall_function(&lua, cache.clone()).unwrap(),
+            )
+            .unwrap();
+        globals
+            .set(
+                "re_replace",
+                create_re_replace_function(&lua, cache.clone()).unwrap(),
+            )
+            .unwrap();
+        globals
+            .set("grep", create_grep_function(&lua, cache).unwrap())
+            .unwrap();
+        lua
+    }
+
+    #[test]
+    fn test_re_find() {
+        let lua = lua();
+        let (text, start, stop, year, month): (String, usize, usize, String, String) = lua
+            .load(
+                r#"
+                local m = re_find("due 2024-03-15 or later", [[(?P<year>\d{4})-(\d{2})-\d{2}]])
+                return m.text, m.start, m.stop, m.groups.year, m.groups[2]
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(text, "2024-03-15");
+        assert_eq!((start, stop), (5, 14));
+        assert_eq!((year.as_str(), month.as_str()), ("2024", "03"));
+
+        let none: mlua::Value = lua.load(r#"return re_find("abc", "x|y")"#).eval().unwrap();
+        assert!(none.is_nil());
+        let start: usize = lua
+            .load(r#"return re_find("cat hat cat", "cat|dog", 2).start"#)
+            .eval()
+            .unwrap();
+        assert_eq!(start, 9);
+
+        let err = lua.load(r#"re_find("abc", "(")"#).exec().unwrap_err();
+        assert!(err.to_string().contains("re_find: invalid pattern"));
+    }
+
+    #[test]
+    fn test_re_findall() {
+        let lua = lua();
+        let all: Vec<String> = lua
+            .load(r#"return re_findall("a1 b22 c333", [[\d+]])"#)
+            .eval()
+            .unwrap();
+        assert_eq!(all, ["1", "22", "333"]);
+        let keys: Vec<String> = lua
+            .load(r#"return re_findall("x=1, y=2", [[(\w)=\d]])"#)
+            .eval()
+            .unwrap();
+        assert_eq!(keys, ["x", "y"]);
+        let pair: (String, String) = lua
+            .load(r#"local m = re_findall("x=1, y=2", [[(\w)=(\d)]]) return m[2][1], m[2][2]"#)
+            .eval()
+            .unwrap();
+        assert_eq!(pair, ("y".to_string(), "2".to_string()));
+    }
+
+    #[test]
+    fn test_re_replace() {
+        let lua = lua();
+        let (text, count): (String, usize) = lua
+            .load(r#"return re_replace("2024-03-15", [[(\d+)-(\d+)-(\d+)]], "$3/$2/$1")"#)
+            .eval()
+            .unwrap();
+        assert_eq!((text.as_str(), count), ("15/03/2024", 1));
+        let (text, count): (String, usize) = lua
+            .load(r#"return re_replace("a b c", "[a-z]", string.upper, 2)"#)
+            .eval()
+            .unwrap();
+        assert_eq!((text.as_str(), count), ("A B c", 2));
+        let err = lua.load(r#"re_replace("abc", "b", 1)"#).exec().unwrap_err();
+        assert!(err.to_string().contains("must be a string or a function"));
+    }
+
+    #[test]
+    fn test_grep() {
+        let lua = lua();
+        lua.globals()
+            .set(
+                "context",
+                "boot ok\r\nERROR disk full\nretrying\nerror: timeout\nshutdown\n",
+            )
+            .unwrap();
+        let (count, line, offset, before, after, total): (
+            usize,
+            usize,
+            usize,
+            String,
+            String,
+            usize,
+        ) = lua
+            .load(
+                r#"
+                local matches, total = grep("error", {ignore_case=true, around=1})
+                local m = matches[1]
+                return #matches, m.line, m.offset, m.before[1], table.concat(m.after, "|"), total
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!((count, line, offset, total), (2, 2, 10, 2));
+        assert_eq!((before.as_str(), after.as_str()), ("boot ok", "retrying"));
+
+        // Other strings, literal patterns, and the cap on results
+        let (text, count, total): (String, usize, usize) = lua
+            .load(r#"local m, total = grep("a.b", {text="a.b\naxb\na.b!", plain=true, max=1}) return m[1].text, #m, total"#)
+            .eval()
+            .unwrap();
+        assert_eq!((text.as_str(), count, total), ("a.b", 1, 2));
+        let last: String = lua.load(r#"return grep("down$")[1].text"#).eval().unwrap();
+        assert_eq!(last, "shutdown");
+        let err = lua.load("grep('x', 42)").exec().unwrap_err();
+        assert!(err.to_string().contains("opts must be a table"), "{err}");
+    }
+}
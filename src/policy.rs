@@ -0,0 +1,243 @@
+//! Static policy checking of generated Lua before execution.
+//!
+//! The sandbox already withholds dangerous libraries; this is defense in depth.
+//! Code is lexed (skipping strings and comments) into dotted identifier chains
+//! such as `string.dump`, and any chain matching a denied name is rejected before
+//! it runs, with a message the model can act on.
+
+/// Identifiers rejected by [`PolicyChecker::default`]
+const DEFAULT_DENIED: &[&str] = &[
+    "load",
+    "loadstring",
+    "loadfile",
+    "dofile",
+    "require",
+    "collectgarbage",
+    "string.dump",
+    "rawget",
+    "rawset",
+    "rawequal",
+    "_G",
+    "_ENV",
+    "debug",
+    "os",
+    "io",
+    "package",
+];
+
+/// Rejects code that references denied identifiers
+#[derive(Debug, Clone)]
+pub struct PolicyChecker {
+    denied: Vec<String>,
+}
+
+impl Default for PolicyChecker {
+    fn default() -> Self {
+        Self::new(DEFAULT_DENIED.iter().map(|s| s.to_string()))
+    }
+}
+
+impl PolicyChecker {
+    /// Create a checker denying the given identifiers.
+    ///
+    /// An entry denies the identifier itself and anything accessed through it,
+    /// e.g. `os` denies `os.time`, while `string.dump` denies only that field.
+    pub fn new(denied: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            denied: denied.into_iter().collect(),
+        }
+    }
+
+    /// Check code, returning a description of every violation found
+    pub fn check(&self, code: &str) -> Result<(), Vec<String>> {
+        let mut violations: Vec<String> = Vec::new();
+        for chain in identifier_chains(code) {
+            for denied in &self.denied {
+                let matches = chain == *denied
+                    || (chain.starts_with(denied.as_str())
+                        && chain[denied.len()..].starts_with('.'));
+                let message = format!("use of `{denied}` is not allowed");
+                if matches && !violations.contains(&message) {
+                    violations.push(message);
+                }
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+}
+
+/// Extract dotted identifier chains (`a`, `a.b.c`, method calls as `a.b`) from
+/// Lua code, skipping strings and comments.
+fn identifier_chains(code: &str) -> Vec<String> {
+    let chars: Vec<char> = code.chars().collect();
+    let mut chains = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        // Comments
+        if c == '-' && chars.get(i + 1) == Some(&'-') {
+            i += 2;
+            if let Some(end) = long_bracket_end(&chars, i) {
+                i = end;
+            } else {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            continue;
+        }
+
+        // Long strings
+        if c == '['
+            && let Some(end) = long_bracket_end(&chars, i)
+        {
+            i = end;
+            continue;
+        }
+
+        // Quoted strings
+        if c == '"' || c == '\'' {
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                if chars[i] == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            i += 1;
+            continue;
+        }
+
+        // Numbers (so `1e5` isn't read as an identifier)
+        if c.is_ascii_digit() {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            continue;
+        }
+
+        // Identifier chains; a preceding '.' or ':' means this is a field of
+        // an expression we can't resolve, e.g. `("x"):rep`
+        if c.is_alphabetic() || c == '_' {
+            let mut chain = String::new();
+            loop {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                chain.extend(&chars[start..i]);
+                let mut j = i;
+                while j < chars.len() && chars[j].is_whitespace() {
+                    j += 1;
+                }
+                let is_field =
+                    matches!(chars.get(j), Some('.') | Some(':')) && chars.get(j + 1) != Some(&'.');
+                let mut k = j + 1;
+                while is_field && k < chars.len() && chars[k].is_whitespace() {
+                    k += 1;
+                }
+                if is_field
+                    && chars
+                        .get(k)
+                        .is_some_and(|ch| ch.is_alphabetic() || *ch == '_')
+                {
+                    chain.push('.');
+                    i = k;
+                } else {
+                    break;
+                }
+            }
+            chains.push(chain);
+            continue;
+        }
+
+        i += 1;
+    }
+
+    chains
+}
+
+/// If a long bracket (`[[`, `[==[`) opens at `i`, return the index after its close
+fn long_bracket_end(chars: &[char], i: usize) -> Option<usize> {
+    if chars.get(i) != Some(&'[') {
+        return None;
+    }
+    let mut level = 0;
+    let mut j = i + 1;
+    while chars.get(j) == Some(&'=') {
+        level += 1;
+        j += 1;
+    }
+    if chars.get(j) != Some(&'[') {
+        return None;
+    }
+    let close: Vec<char> = std::iter::once(']')
+        .chain(std::iter::repeat_n('=', level))
+        .chain(std::iter::once(']'))
+        .collect();
+    let end = (j + 1..chars.len())
+        .find(|&k| chars[k..].starts_with(&close))
+        .map_or(chars.len(), |k| k + close.len());
+    Some(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identifier_chains() {
+        let chains = identifier_chains(
+            "x = string.sub(context, 1, 10) -- load()\ny = \"os.exit\" .. [[io.read]] obj:method()",
+        );
+        assert_eq!(
+            chains,
+            vec!["x", "string.sub", "context", "y", "obj.method"]
+        );
+    }
+
+    #[test]
+    fn test_allows_normal_code() {
+        let checker = PolicyChecker::default();
+        let code = r#"
+            -- load the data first
+            loaded = {}
+            for line in string.gmatch(context, "[^\n]+") do
+              table.insert(loaded, line)
+            end
+            print("os and io are " .. #loaded)
+        "#;
+        assert!(checker.check(code).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_denied_identifiers() {
+        let checker = PolicyChecker::default();
+        let err = checker
+            .check("f = load(code)\nprint(os.time())\ncollectgarbage('count')")
+            .unwrap_err();
+        assert_eq!(
+            err,
+            vec![
+                "use of `load` is not allowed",
+                "use of `os` is not allowed",
+                "use of `collectgarbage` is not allowed"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rejects_dotted_identifiers() {
+        let checker = PolicyChecker::default();
+        assert!(checker.check("b = string.dump(f)").is_err());
+        assert!(checker.check("b = string . dump(f)").is_err());
+        assert!(checker.check("s = string.format('%d', 1)").is_ok());
+    }
+}
@@ -0,0 +1,269 @@
+//! Relevance pre-filtering of enormous contexts.
+//!
+//! Before a run, the context is split into chunks at line boundaries and each
+//! chunk is scored against the prompt by a [`ChunkScorer`]. Chunks scoring below
+//! the threshold are replaced with a short marker so the model knows something
+//! was removed, and a [`PrefilterReport`] records what was excluded.
+
+use std::collections::HashSet;
+
+const STOPWORDS: &[&str] = &[
+    "the", "and", "for", "are", "but", "not", "you", "all", "any", "can", "had", "her", "was",
+    "one", "our", "out", "has", "have", "what", "when", "where", "which", "who", "why", "how",
+    "this", "that", "these", "those", "with", "from", "into", "about", "does", "did", "there",
+    "their", "they", "them", "then", "than", "find", "list", "tell", "give", "show", "context",
+];
+
+/// Scores how relevant a chunk of context is to a query.
+///
+/// Higher is more relevant. Implementations may use keywords, embeddings, or
+/// anything else; scores are only compared against [`PrefilterConfig::min_score`].
+pub trait ChunkScorer {
+    /// What is derived from the query once and reused for every chunk,
+    /// e.g. its keywords
+    type Query;
+
+    fn prepare(&self, query: &str) -> Self::Query;
+
+    fn score(&self, query: &Self::Query, chunk: &str) -> f64;
+}
+
+/// Scores a chunk by the fraction of distinct query keywords it contains.
+#[derive(Debug, Clone, Default)]
+pub struct KeywordScorer;
+
+impl KeywordScorer {
+    /// Lowercased query terms with stopwords and short words removed
+    pub fn keywords(query: &str) -> Vec<String> {
+        let mut seen = HashSet::new();
+        query
+            .split(|c: char| !c.is_alphanumeric())
+            .map(|w| w.to_lowercase())
+            .filter(|w| w.chars().count() > 2 && !STOPWORDS.contains(&w.as_str()))
+            .filter(|w| seen.insert(w.clone()))
+            .collect()
+    }
+}
+
+impl ChunkScorer for KeywordScorer {
+    type Query = Vec<String>;
+
+    fn prepare(&self, query: &str) -> Self::Query {
+        Self::keywords(query)
+    }
+
+    fn score(&self, keywords: &Self::Query, chunk: &str) -> f64 {
+        if keywords.is_empty() {
+            return 1.0;
+        }
+        let chunk = chunk.to_lowercase();
+        let found = keywords
+            .iter()
+            .filter(|k| chunk.contains(k.as_str()))
+            .count();
+        found as f64 / keywords.len() as f64
+    }
+}
+
+/// Configuration for [`Prefilter`]
+#[derive(Debug, Clone)]
+pub struct PrefilterConfig {
+    /// Contexts smaller than this many bytes are never filtered
+    pub min_context_bytes: usize,
+    /// Target chunk size in bytes (chunks end at the next line break)
+    pub chunk_size: usize,
+    /// Chunks scoring below this are excluded
+    pub min_score: f64,
+}
+
+impl Default for PrefilterConfig {
+    fn default() -> Self {
+        Self {
+            min_context_bytes: 1_000_000,
+            chunk_size: 8_000,
+            min_score: f64::MIN_POSITIVE,
+        }
+    }
+}
+
+/// A contiguous byte range of the original context that was excluded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExcludedRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Summary of what the pre-filter removed
+#[derive(Debug, Clone, Default)]
+pub struct PrefilterReport {
+    pub total_chunks: usize,
+    pub kept_chunks: usize,
+    pub excluded: Vec<ExcludedRange>,
+}
+
+impl PrefilterReport {
+    /// Total number of bytes excluded from the context
+    pub fn excluded_bytes(&self) -> usize {
+        self.excluded.iter().map(|r| r.end - r.start).sum()
+    }
+}
+
+/// Drops chunks of the context that are irrelevant to the prompt
+pub struct Prefilter<S: ChunkScorer> {
+    scorer: S,
+    config: PrefilterConfig,
+}
+
+impl<S: ChunkScorer> Prefilter<S> {
+    pub fn new(scorer: S, config: PrefilterConfig) -> Self {
+        Self { scorer, config }
+    }
+
+    /// Filter the context, returning the reduced context and a report.
+    ///
+    /// If the context is below the size threshold, or no chunk scores above the
+    /// threshold (so relevance can't be judged), the context is returned unchanged.
+    pub fn apply(&self, query: &str, context: &str) -> (String, PrefilterReport) {
+        if context.len() < self.config.min_context_bytes {
+            return (context.to_string(), PrefilterReport::default());
+        }
+
+        let chunks = split_chunks(context, self.config.chunk_size);
+        let query = self.scorer.prepare(query);
+        let keep: Vec<bool> = chunks
+            .iter()
+            .map(|(start, end)| {
+                self.scorer.score(&query, &context[*start..*end]) >= self.config.min_score
+            })
+            .collect();
+
+        let mut report = PrefilterReport {
+            total_chunks: chunks.len(),
+            kept_chunks: keep.iter().filter(|k| **k).count(),
+            excluded: Vec::new(),
+        };
+        if report.kept_chunks == 0 {
+            report.kept_chunks = report.total_chunks;
+            return (context.to_string(), report);
+        }
+
+        // Merge adjacent excluded chunks into single ranges
+        for ((start, end), keep) in chunks.into_iter().zip(keep) {
+            if keep {
+                continue;
+            }
+            match report.excluded.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => report.excluded.push(ExcludedRange { start, end }),
+            }
+        }
+
+        // Replace each excluded range with a marker
+        let mut filtered = String::new();
+        let mut pos = 0;
+        for range in &report.excluded {
+            filtered.push_str(&context[pos..range.start]);
+            filtered.push_str(&format!(
+                "[... {} bytes excluded as irrelevant (offsets {}-{}) ...]\n",
+                range.end - range.start,
+                range.start,
+                range.end
+            ));
+            pos = range.end;
+        }
+        filtered.push_str(&context[pos..]);
+
+        (filtered, report)
+    }
+}
+
+/// Split text into byte ranges of roughly `size` bytes, ending at line breaks
+pub(crate) fn split_chunks(text: &str, size: usize) -> Vec<(usize, usize)> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < text.len() {
+        let mut end = (start + size.max(1)).min(text.len());
+        while !text.is_char_boundary(end) {
+            end += 1;
+        }
+        if let Some(newline) = text[end..].find('\n') {
+            end += newline + 1;
+        } else {
+            end = text.len();
+        }
+        chunks.push((start, end));
+        start = end;
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> PrefilterConfig {
+        PrefilterConfig {
+            min_context_bytes: 0,
+            chunk_size: 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_keywords() {
+        assert_eq!(
+            KeywordScorer::keywords("What is the Fox doing? the fox!"),
+            vec!["fox", "doing"]
+        );
+    }
+
+    #[test]
+    fn test_keyword_score() {
+        let scorer = KeywordScorer;
+        let query = scorer.prepare("quick brown fox");
+        assert_eq!(scorer.score(&query, "the quick fox"), 2.0 / 3.0);
+        assert_eq!(scorer.score(&query, "nothing here"), 0.0);
+        assert_eq!(scorer.score(&scorer.prepare("how are they"), "x"), 1.0);
+    }
+
+    #[test]
+    fn test_split_chunks_on_lines() {
+        let text = "line one\nline two\nline three\n";
+        let chunks = split_chunks(text, 5);
+        assert_eq!(chunks, vec![(0, 9), (9, 18), (18, 29)]);
+    }
+
+    #[test]
+    fn test_prefilter_excludes_irrelevant_chunks() {
+        let context = "apples are red\nnothing useful\nmore filler\nbananas are yellow\n";
+        let prefilter = Prefilter::new(KeywordScorer, config());
+        let (filtered, report) = prefilter.apply("tell me about apples and bananas", context);
+
+        assert!(filtered.contains("apples are red"));
+        assert!(filtered.contains("bananas are yellow"));
+        assert!(!filtered.contains("nothing useful"));
+        assert!(filtered.contains("bytes excluded as irrelevant"));
+        assert_eq!(report.total_chunks, 4);
+        assert_eq!(report.kept_chunks, 2);
+        // The two adjacent irrelevant chunks are merged into one range
+        assert_eq!(report.excluded, vec![ExcludedRange { start: 15, end: 42 }]);
+        assert_eq!(report.excluded_bytes(), 27);
+    }
+
+    #[test]
+    fn test_prefilter_keeps_everything_without_matches() {
+        let context = "alpha\nbeta\n";
+        let prefilter = Prefilter::new(KeywordScorer, config());
+        let (filtered, report) = prefilter.apply("zebra", context);
+        assert_eq!(filtered, context);
+        assert!(report.excluded.is_empty());
+    }
+
+    #[test]
+    fn test_prefilter_skips_small_contexts() {
+        let prefilter = Prefilter::new(KeywordScorer, PrefilterConfig::default());
+        let (filtered, report) = prefilter.apply("zebra", "alpha\n");
+        assert_eq!(filtered, "alpha\n");
+        assert_eq!(report.total_chunks, 0);
+    }
+}
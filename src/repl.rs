@@ -1,13 +1,24 @@
-use crate::environment::{Environment, LlmClient};
-use crate::rlm::{LmInput, OutputParser};
+use crate::context_info::ContextInfo;
+use crate::environment::{
+    CellTimeout, Environment, EnvironmentConfig, Finding, GlobalsSnapshot, LimitExceeded,
+    LlmClient, LlmQueryError, PinRequest,
+};
+use crate::notes::Note;
+use crate::rlm::LmInput;
+use crate::tokens::Tokenizer;
+use crate::transcript::{Markdown, SharedFormatter};
+use crate::usage::{UsageBreakdown, UsageTracker};
+use chrono::{DateTime, SecondsFormat, Utc};
 use mlua::Result;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::error::Error;
-use tiktoken_rs::p50k_base;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 
-/// Maximum tokens allowed for cell output in context
-const MAX_OUTPUT_TOKENS: usize = 200;
+/// Default maximum tokens of cell output kept in the context
+pub const DEFAULT_MAX_OUTPUT_TOKENS: usize = 200;
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Cell {
@@ -23,66 +34,192 @@ pub struct Cell {
     /// True if this is the final cell in the computation sequence.
     #[serde(default)]
     pub r#final: bool,
+
+    /// Number of times generation was retried after provider errors before this cell was produced.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub retries: u32,
+
+    /// Execution metadata recorded by [`Repl::eval`]; None for cells that have not run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<CellMetadata>,
+
+    /// Pinned cells are never evicted from the context window (see [`crate::window`]).
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub pinned: bool,
+
+    /// Evicted cells stay in the session but are no longer shown to the model.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub evicted: bool,
+
+    /// What kind of error executing this cell raised, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorKind>,
+
+    /// Index of a recent cell with the same code that failed or was
+    /// truncated, i.e. the model is repeating itself (see [`Repl::find_repeat`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_of: Option<usize>,
 }
 
-impl OutputParser for Cell {
-    fn parse(text: &str) -> std::result::Result<Self, Box<dyn Error>> {
-        use regex::Regex;
+/// Why a cell failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The code did not parse
+    Syntax,
+    /// The code raised an error while running
+    Runtime,
+    /// An `llm_query` call failed or hit a call limit
+    LlmQuery,
+    /// The cell ran out of time
+    Timeout,
+    /// The cell exceeded its instruction or memory limit
+    Limit,
+    /// The code was rejected by the policy check without running
+    Policy,
+}
 
-        // Try to parse as JSON first for backward compatibility
-        if let Ok(cell) = serde_json::from_str::<Cell>(text) {
-            return Ok(cell);
+impl ErrorKind {
+    /// Classify an error returned by [`Environment::eval`]
+    pub fn of(error: &mlua::Error) -> Self {
+        match error {
+            mlua::Error::SyntaxError { .. } => ErrorKind::Syntax,
+            mlua::Error::CallbackError { cause, .. } | mlua::Error::WithContext { cause, .. } => {
+                Self::of(cause)
+            }
+            mlua::Error::ExternalError(e) if e.is::<LlmQueryError>() => ErrorKind::LlmQuery,
+            mlua::Error::ExternalError(e) if e.is::<CellTimeout>() => ErrorKind::Timeout,
+            mlua::Error::ExternalError(e) if e.is::<LimitExceeded>() => ErrorKind::Limit,
+            mlua::Error::MemoryError(_) => ErrorKind::Limit,
+            mlua::Error::RuntimeError(message) if message.starts_with("Policy violation") => {
+                ErrorKind::Policy
+            }
+            _ => ErrorKind::Runtime,
         }
+    }
+}
 
-        // Parse using XML tags with regex (using (?s) for multiline matching)
-        let comment_re = Regex::new(r"(?s)<comment>(.*?)</comment>").unwrap();
-        let code_re = Regex::new(r"(?s)<code>(.*?)</code>").unwrap();
-        let final_re = Regex::new(r"(?s)<final>(.*?)</final>").unwrap();
-
-        // Extract comment
-        let comment = comment_re
-            .captures(text)
-            .and_then(|cap| cap.get(1))
-            .map(|m| m.as_str().trim().to_string())
-            .ok_or("Failed to parse <comment> tag from response")?;
-
-        // Extract code
-        let code = code_re
-            .captures(text)
-            .and_then(|cap| cap.get(1))
-            .map(|m| m.as_str().trim().to_string())
-            .ok_or("Failed to parse <code> tag from response")?;
-
-        // Extract final flag (optional)
-        let final_flag = final_re
-            .captures(text)
-            .and_then(|cap| cap.get(1))
-            .map(|m| {
-                let value = m.as_str().trim().to_lowercase();
-                value == "true" || value == "yes"
-            })
-            .unwrap_or(false);
+/// When a cell ran, how long it took, and how much output it added to the transcript
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CellMetadata {
+    /// Start of execution (RFC 3339, UTC)
+    pub timestamp: String,
+    /// Wall-clock execution time in milliseconds
+    pub duration_ms: u64,
+    /// Tokens of output kept in the transcript, after truncation
+    pub output_tokens: usize,
+    /// Whether the output was cut to fit [`Repl::max_output_tokens`]
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub truncated: bool,
+}
 
-        // Validate that we got comment and code
-        if comment.is_empty() {
-            return Err("Comment tag is empty".into());
-        }
-        if code.is_empty() {
-            return Err("Code tag is empty".into());
-        }
+/// Token counts of a REPL, from [`Repl::token_usage`]
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TokenUsage {
+    /// Tokens of every formatted cell in the session, including evicted cells
+    pub transcript_tokens: usize,
+    /// Tokens of each cell's output, in the order of [`Repl::entries`]
+    pub cell_output_tokens: Vec<usize>,
+    /// Estimated input tokens of the next generation: the prompt, findings,
+    /// notes, visible cells, and hint, excluding the system prompt
+    pub prompt_tokens: usize,
+}
 
-        Ok(Cell {
-            comment,
-            code,
-            output: None,
-            r#final: final_flag,
-        })
+impl Cell {
+    /// Whether executing this cell raised an error
+    pub fn is_error(&self) -> bool {
+        // Sessions saved before error kinds were recorded only have the prefix
+        self.error.is_some()
+            || self
+                .output
+                .as_deref()
+                .is_some_and(|o| o.starts_with("Execution error:"))
     }
 }
 
+/// Receives events while a [`Repl`] evaluates cells, e.g. to drive a UI,
+/// logging, or metrics (see [`Repl::add_observer`])
+pub trait ReplObserver: Send + Sync {
+    /// A cell is about to run
+    fn on_cell_start(&self, _comment: &str, _code: &str) {}
+
+    /// The running cell printed text; called as it is printed
+    fn on_output(&self, _text: &str) {}
+
+    /// The running cell reported progress with `progress(msg)`; called
+    /// right away, and not part of the cell's output
+    fn on_progress(&self, _message: &str) {}
+
+    /// The cell's output was cut to [`Repl::max_output_tokens`] for the transcript
+    fn on_truncation(&self, _full: &str, _kept: &str) {}
+
+    /// The cell finished and was recorded in [`Repl::entries`]
+    fn on_cell_complete(&self, _cell: &Cell) {}
+}
+
+/// Number of recent cells [`Repl::find_repeat`] compares new code against
+pub const REPEAT_WINDOW: usize = 5;
+
+/// Lua code without comments or any whitespace, for spotting repeated cells
+fn normalize_code(code: &str) -> String {
+    code.lines()
+        .map(|line| match line.find("--") {
+            Some(comment) => &line[..comment],
+            None => line,
+        })
+        .flat_map(str::chars)
+        .filter(|c| !c.is_whitespace())
+        .collect()
+}
+
+/// Comment of the cells added by `note_important`
+const NOTE_COMMENT: &str = "Important note";
+
+/// Whether a cell was added by `note_important` rather than run
+fn is_note(cell: &Cell) -> bool {
+    cell.code.is_empty() && cell.pinned && cell.comment == NOTE_COMMENT
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
 pub struct Repl {
     pub prompt: String,
     pub entries: Vec<Cell>,
+    /// Arbitrary key/value metadata attached to the session (e.g. project, ticket)
+    pub tags: BTreeMap<String, String>,
+    /// Structured findings recorded with `emit_finding`
+    pub findings: Vec<Finding>,
+    /// Running notes recorded with `note_add`, shown at the top of every
+    /// prompt so they survive the eviction of the cells that made them
+    pub notes: Vec<Note>,
+    /// How the context is structured (e.g. concatenated files), shown in the prompt
+    pub context_description: Option<String>,
+    /// One-line structural summary of the context shown in the prompt (see [`Repl::warm_start`])
+    pub context_summary: Option<String>,
+    /// Guidance appended to the end of the prompt for the next generation (not serialized)
+    pub hint: Option<String>,
+    /// Tokens of cell output kept in the transcript; longer output is truncated (not serialized)
+    pub max_output_tokens: usize,
+    /// Which part of truncated cell output is kept (not serialized)
+    pub truncation: Truncation,
+    /// Encoding used to count and truncate cell output, from
+    /// [`EnvironmentConfig::tokenizer`] (not serialized)
+    pub tokenizer: Tokenizer,
+    /// Notified as cells run (not serialized)
+    pub observers: Vec<Arc<dyn ReplObserver>>,
+    /// Number of most recent iterations whose code is shown to the model; older
+    /// cells show only their comment and output (all code if None, not serialized)
+    pub hide_code_after: Option<usize>,
+    /// How the transcript is formatted for the model (not serialized)
+    pub formatter: SharedFormatter,
+    /// Answer recorded with `final_answer`, see [`Repl::final_answer`]
+    final_answer: Option<String>,
     environment: Environment,
 }
 
@@ -91,45 +228,105 @@ impl Serialize for Repl {
     where
         S: serde::Serializer,
     {
+        use serde::ser::Error;
         use serde::ser::SerializeStruct;
-        let mut state = serializer.serialize_struct("Repl", 2)?;
+        let globals = self
+            .environment
+            .snapshot_globals()
+            .map_err(S::Error::custom)?;
+        let mut state = serializer.serialize_struct("Repl", 9)?;
         state.serialize_field("prompt", &self.prompt)?;
         state.serialize_field("entries", &self.entries)?;
+        state.serialize_field("tags", &self.tags)?;
+        state.serialize_field("findings", &self.findings)?;
+        state.serialize_field("notes", &self.notes)?;
+        state.serialize_field("final_answer", &self.final_answer)?;
+        state.serialize_field("context_description", &self.context_description)?;
+        state.serialize_field("context_summary", &self.context_summary)?;
+        state.serialize_field("globals", &globals)?;
         state.end()
     }
 }
 
+/// A saved [`Repl`], as written by serializing it; resume it with [`Repl::resume`]
+#[derive(Debug, Clone, Deserialize)]
+pub struct Session {
+    pub prompt: String,
+    pub entries: Vec<Cell>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default)]
+    pub findings: Vec<Finding>,
+    #[serde(default)]
+    pub notes: Vec<Note>,
+    #[serde(default)]
+    pub final_answer: Option<String>,
+    #[serde(default)]
+    pub context_description: Option<String>,
+    #[serde(default)]
+    pub context_summary: Option<String>,
+    /// Globals defined by the session's cells
+    #[serde(default)]
+    pub globals: GlobalsSnapshot,
+}
+
 impl<'de> Deserialize<'de> for Repl {
     fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        #[derive(Deserialize)]
-        struct ReplData {
-            prompt: String,
-            entries: Vec<Cell>,
-        }
-
-        let data = ReplData::deserialize(deserializer)?;
+        let data = Session::deserialize(deserializer)?;
 
-        // Create a new environment with a default context when deserializing
+        // Create a new environment with a default context when deserializing;
+        // use Repl::resume to restore the session's globals
         let environment = Environment::new("", LlmClient::Ollama("qwen3:30b".to_string()))
             .map_err(serde::de::Error::custom)?;
 
         Ok(Repl {
             prompt: data.prompt,
             entries: data.entries,
+            tags: data.tags,
+            findings: data.findings,
+            notes: data.notes,
+            context_description: data.context_description,
+            context_summary: data.context_summary,
+            hint: None,
+            max_output_tokens: DEFAULT_MAX_OUTPUT_TOKENS,
+            truncation: Truncation::default(),
+            tokenizer: Tokenizer::default(),
+            observers: Vec::new(),
+            hide_code_after: None,
+            formatter: Arc::new(Markdown),
+            final_answer: data.final_answer,
             environment,
         })
     }
 }
 
 impl Repl {
+    /// Create a REPL that keeps at most `max_output_tokens` tokens of each cell's output
     pub fn new<T>(
         prompt: String,
         init_context: T,
         _model: String,
         client: LlmClient,
+        max_output_tokens: usize,
+    ) -> Result<Self>
+    where
+        T: mlua::IntoLua,
+    {
+        let mut repl =
+            Self::with_config(prompt, init_context, client, EnvironmentConfig::default())?;
+        repl.max_output_tokens = max_output_tokens;
+        Ok(repl)
+    }
+
+    /// Create a REPL whose environment uses the given configuration
+    pub fn with_config<T>(
+        prompt: String,
+        init_context: T,
+        client: LlmClient,
+        config: EnvironmentConfig,
     ) -> Result<Self>
     where
         T: mlua::IntoLua,
@@ -137,40 +334,361 @@ impl Repl {
         Ok(Repl {
             prompt,
             entries: Vec::new(),
-            environment: Environment::new(init_context, client)?,
+            tags: BTreeMap::new(),
+            findings: Vec::new(),
+            notes: Vec::new(),
+            context_description: None,
+            context_summary: None,
+            hint: None,
+            max_output_tokens: DEFAULT_MAX_OUTPUT_TOKENS,
+            truncation: Truncation::default(),
+            tokenizer: config.tokenizer,
+            observers: Vec::new(),
+            hide_code_after: None,
+            formatter: Arc::new(Markdown),
+            final_answer: None,
+            environment: Environment::with_config(init_context, client, config)?,
         })
     }
 
+    /// Continue a saved session in a new environment with the given context
+    /// and configuration.
+    ///
+    /// Serializable globals are restored from the session. If it also defined
+    /// globals that could not be saved (such as functions), the session's
+    /// cells are first replayed to recreate them; replaying repeats any
+    /// `llm_query` calls they made.
+    pub fn resume<T>(
+        session: Session,
+        init_context: T,
+        client: LlmClient,
+        config: EnvironmentConfig,
+    ) -> Result<Self>
+    where
+        T: mlua::IntoLua,
+    {
+        let mut repl = Self::with_config(session.prompt, init_context, client, config)?;
+        repl.entries = session.entries;
+        if !session.globals.unserializable.is_empty() {
+            tracing::info!(
+                "Replaying {} cells to restore {}",
+                repl.entries.len(),
+                session.globals.unserializable.join(", ")
+            );
+            repl.replay();
+        }
+        repl.environment.restore_globals(&session.globals)?;
+        repl.environment.restore_findings(session.findings.clone());
+        repl.environment.restore_notes(session.notes.clone());
+
+        repl.tags = session.tags;
+        repl.findings = session.findings;
+        repl.notes = session.notes;
+        repl.final_answer = session.final_answer;
+        repl.context_description = session.context_description;
+        repl.context_summary = session.context_summary;
+        Ok(repl)
+    }
+
+    /// Precompute structural facts about the context, exposing them as the
+    /// `context_info` Lua table and summarizing them in the prompt
+    pub fn warm_start(&mut self, context: &str) -> Result<()> {
+        let info = ContextInfo::compute(context);
+        self.context_summary = Some(info.summary());
+        self.environment.set_global("context_info", info)
+    }
+
     pub fn eval(&mut self, comment: &str, code: &str) {
-        let output = match self.environment.eval(code) {
+        let timestamp =
+            DateTime::<Utc>::from(SystemTime::now()).to_rfc3339_opts(SecondsFormat::Millis, true);
+        for observer in &self.observers {
+            observer.on_cell_start(comment, code);
+        }
+        let started = Instant::now();
+        let result = if self.observers.is_empty() {
+            self.environment.eval(code)
+        } else {
+            let observers = self.observers.clone();
+            self.environment
+                .set_progress_sink(Some(Arc::new(move |message: &str| {
+                    for observer in &observers {
+                        observer.on_progress(message);
+                    }
+                })));
+            let observers = self.observers.clone();
+            let sink = Arc::new(move |text: &str| {
+                for observer in &observers {
+                    observer.on_output(text);
+                }
+            });
+            let result = self.environment.eval_with_sink(code, sink);
+            self.environment.set_progress_sink(None);
+            result
+        };
+        let error = result.as_ref().err().map(ErrorKind::of);
+        let mut truncated = false;
+        let output = match result {
             Ok(Some(result)) => {
-                // Truncate output to MAX_OUTPUT_TOKENS
-                if let Ok(bpe) = p50k_base() {
-                    let tokens = bpe.encode_with_special_tokens(&result);
-                    if tokens.len() > MAX_OUTPUT_TOKENS {
-                        let truncated_tokens = &tokens[..MAX_OUTPUT_TOKENS];
-                        if let Ok(decoded) = bpe.decode(truncated_tokens.to_vec()) {
-                            Some(format!("{decoded}\n[truncated]"))
-                        } else {
-                            Some(result)
-                        }
-                    } else {
-                        Some(result)
+                let full = result.clone();
+                let kept = truncate_output(
+                    result,
+                    self.max_output_tokens,
+                    self.truncation,
+                    self.tokenizer,
+                );
+                truncated = full != kept;
+                if truncated {
+                    for observer in &self.observers {
+                        observer.on_truncation(&full, &kept);
                     }
-                } else {
-                    Some(result)
                 }
+                Some(kept)
             }
             Ok(None) => None,
             Err(e) => Some(format!("Execution error: {e}")),
         };
 
+        let duration = started.elapsed();
+
+        self.findings = self.environment.findings();
+        self.notes = self.environment.notes();
+        let answer = self.environment.take_final_answer();
+        let answered = answer.is_some();
+        if answered {
+            self.final_answer = answer;
+        }
+
+        let output_tokens = output.as_deref().map_or(0, |o| self.tokenizer.count(o));
+        let repeat_of = self.find_repeat(code);
+
+        // Pinned notes go before the cell that made them, so it stays last
+        let previous = self.entries.len().checked_sub(1);
+        for request in self.environment.take_pin_requests() {
+            match request {
+                PinRequest::LastCell => {
+                    if let Some(previous) = previous {
+                        self.entries[previous].pinned = true;
+                    }
+                }
+                PinRequest::Note(text) => self.entries.push(Cell {
+                    comment: NOTE_COMMENT.to_string(),
+                    code: String::new(),
+                    output: Some(text),
+                    r#final: false,
+                    retries: 0,
+                    metadata: None,
+                    pinned: true,
+                    evicted: false,
+                    error: None,
+                    repeat_of: None,
+                }),
+            }
+        }
+
         self.entries.push(Cell {
             comment: comment.to_string(),
             code: code.to_string(),
             output,
-            r#final: false,
+            r#final: answered,
+            retries: 0,
+            metadata: Some(CellMetadata {
+                timestamp,
+                duration_ms: duration.as_millis() as u64,
+                output_tokens,
+                truncated,
+            }),
+            pinned: false,
+            evicted: false,
+            error,
+            repeat_of,
         });
+        if let Some(cell) = self.entries.last() {
+            for observer in &self.observers {
+                observer.on_cell_complete(cell);
+            }
+        }
+    }
+
+    /// The answer recorded with `final_answer`, untruncated. Calling it
+    /// marks the cell as final; a later call replaces the answer.
+    pub fn final_answer(&self) -> Option<&str> {
+        self.final_answer.as_deref()
+    }
+
+    /// Notify `observer` of the cells evaluated from now on
+    pub fn add_observer(&mut self, observer: Arc<dyn ReplObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Replace the code of the cell at `index` and run it again (see [`Repl::rerun_from`])
+    pub fn edit_cell(&mut self, index: usize, code: &str) -> Result<()> {
+        let cell = self
+            .entries
+            .get_mut(index)
+            .ok_or_else(|| mlua::Error::RuntimeError(format!("no cell at index {index}")))?;
+        cell.code = code.to_string();
+        self.rerun_from(index)
+    }
+
+    /// Run the cell at `index` again from the state before it: later cells
+    /// are dropped, and a fresh environment replays the earlier cells
+    /// (without recording their output again) before the cell is evaluated.
+    /// Notes the cell added with `note_important` are replaced by the ones
+    /// it adds when it runs again. Replaying sends their sub-queries again
+    /// (see [`Repl::undo`]).
+    pub fn rerun_from(&mut self, index: usize) -> Result<()> {
+        if index >= self.entries.len() {
+            return Err(mlua::Error::RuntimeError(format!(
+                "no cell at index {index}"
+            )));
+        }
+        let mut cells = self.entries.split_off(index);
+        let cell = cells.remove(0);
+        while self.entries.last().is_some_and(is_note) {
+            self.entries.pop();
+        }
+
+        self.environment.reset()?;
+        self.replay();
+        self.eval(&cell.comment, &cell.code);
+        let rerun = self.entries.last_mut().unwrap();
+        rerun.r#final |= cell.r#final;
+        rerun.pinned = cell.pinned;
+        Ok(())
+    }
+
+    /// Remove the last cell and undo its effects on the environment: a fresh
+    /// environment replays the remaining cells. Notes the cell added with
+    /// `note_important` are removed with it. Returns the removed cell.
+    ///
+    /// Replaying sends the `llm_query`, `llm_map`, and `rlm_query` calls of
+    /// the remaining cells to the provider again: they are paid for and
+    /// count against the per-run limit, which starting over does not reset,
+    /// and their answers, and so the restored globals, may differ from the
+    /// first run.
+    pub fn undo(&mut self) -> Result<Option<Cell>> {
+        let Some(cell) = self.entries.pop() else {
+            return Ok(None);
+        };
+        while self.entries.last().is_some_and(is_note) {
+            self.entries.pop();
+        }
+
+        self.environment.reset()?;
+        self.replay();
+        Ok(Some(cell))
+    }
+
+    /// Copy the transcript into a new REPL whose fresh environment replays
+    /// the cells, so both can continue independently from here. LLM usage of
+    /// the fork, including the sub-queries of the replayed cells (see
+    /// [`Repl::undo`]), is counted with this REPL's, and against the same
+    /// per-run `llm_query` limit.
+    pub fn fork(&self) -> Result<Self> {
+        let mut fork = Repl {
+            prompt: self.prompt.clone(),
+            entries: self.entries.clone(),
+            tags: self.tags.clone(),
+            findings: Vec::new(),
+            notes: Vec::new(),
+            context_description: self.context_description.clone(),
+            context_summary: self.context_summary.clone(),
+            hint: self.hint.clone(),
+            max_output_tokens: self.max_output_tokens,
+            truncation: self.truncation,
+            tokenizer: self.tokenizer,
+            observers: self.observers.clone(),
+            hide_code_after: self.hide_code_after,
+            formatter: self.formatter.clone(),
+            final_answer: None,
+            environment: self.environment.fresh()?,
+        };
+        fork.replay();
+        Ok(fork)
+    }
+
+    /// Evaluate the code of every recorded cell in the environment, without
+    /// recording output; summary cells have no code and are skipped. Their
+    /// sub-queries are sent again.
+    fn replay(&mut self) {
+        for cell in self.entries.iter().filter(|cell| !cell.code.is_empty()) {
+            if let Err(e) = self.environment.eval(&cell.code) {
+                tracing::debug!("Replayed cell failed: {e}");
+            }
+        }
+        self.findings = self.environment.findings();
+        self.notes = self.environment.notes();
+        self.final_answer = self.environment.take_final_answer();
+        // The pins and notes of replayed cells are already in the transcript
+        self.environment.take_pin_requests();
+    }
+
+    /// Number of cells at the end of the transcript that failed with an error
+    pub fn consecutive_errors(&self) -> usize {
+        self.entries
+            .iter()
+            .rev()
+            .take_while(|cell| cell.is_error())
+            .count()
+    }
+
+    /// Index of one of the last [`REPEAT_WINDOW`] cells whose code is the
+    /// same as `code`, ignoring whitespace and comments, and which failed or
+    /// had its output truncated. Running it again will most likely go the
+    /// same way.
+    pub fn find_repeat(&self, code: &str) -> Option<usize> {
+        let normalized = normalize_code(code);
+        if normalized.is_empty() {
+            return None;
+        }
+        self.entries
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, cell)| !cell.code.is_empty())
+            .take(REPEAT_WINDOW)
+            .find(|(_, cell)| {
+                let truncated = cell.metadata.as_ref().is_some_and(|m| m.truncated);
+                (cell.is_error() || truncated) && normalize_code(&cell.code) == normalized
+            })
+            .map(|(index, _)| index)
+    }
+
+    /// Set what `budget()` reports to the cells (see [`Environment::set_budget`])
+    pub fn set_budget(&self, budget: crate::environment::Budget) {
+        self.environment.set_budget(budget);
+    }
+
+    /// Usage tracker for LLM calls made from inside the environment (e.g. `llm_query`)
+    pub fn usage(&self) -> UsageTracker {
+        self.environment.usage()
+    }
+
+    /// Count the tokens of the transcript and of the next prompt.
+    /// Output tokens recorded at evaluation are reused when available.
+    pub fn token_usage(&self) -> TokenUsage {
+        let transcript_tokens = self
+            .entries
+            .iter()
+            .filter_map(format_cell)
+            .map(|cell| self.tokenizer.count(&cell))
+            .sum();
+        let cell_output_tokens = self
+            .entries
+            .iter()
+            .map(|cell| match &cell.metadata {
+                Some(metadata) => metadata.output_tokens,
+                None => cell
+                    .output
+                    .as_deref()
+                    .map_or(0, |o| self.tokenizer.count(o)),
+            })
+            .collect();
+        TokenUsage {
+            transcript_tokens,
+            cell_output_tokens,
+            prompt_tokens: self.tokenizer.count(&self.format()),
+        }
     }
 
     /// Create a snapshot of the REPL state (prompt and entries) without the environment
@@ -179,11 +697,65 @@ impl Repl {
         Ok(Repl {
             prompt: self.prompt.clone(),
             entries: self.entries.clone(),
+            tags: self.tags.clone(),
+            findings: self.findings.clone(),
+            notes: self.notes.clone(),
+            context_description: self.context_description.clone(),
+            context_summary: self.context_summary.clone(),
+            hint: self.hint.clone(),
+            max_output_tokens: self.max_output_tokens,
+            truncation: self.truncation,
+            tokenizer: self.tokenizer,
+            observers: Vec::new(),
+            hide_code_after: self.hide_code_after,
+            formatter: self.formatter.clone(),
+            final_answer: self.final_answer.clone(),
             environment: Environment::new("", LlmClient::Ollama("qwen3:30b".to_string()))?,
         })
     }
 
     pub fn to_markdown(&self) -> String {
+        let mut parts = self.header();
+
+        // Format each cell still in the context window
+        for cell in self.visible_cells() {
+            if let Some(formatted) = format_cell(&cell) {
+                parts.push(formatted);
+            }
+        }
+
+        // Add the hint last so it is closest to the next generation
+        if let Some(hint) = &self.hint {
+            parts.push(format!("{hint}\n"));
+        }
+
+        parts.join("\n")
+    }
+
+    /// Cells still in the context window, as shown to the model: cells more
+    /// than [`Repl::hide_code_after`] iterations old have their code removed
+    pub fn visible_cells(&self) -> impl Iterator<Item = Cow<'_, Cell>> {
+        let mut recent = self.hide_code_after.unwrap_or(usize::MAX);
+        let mut shown: Vec<Cow<Cell>> = Vec::new();
+        for cell in self.entries.iter().rev().filter(|cell| !cell.evicted) {
+            if cell.code.is_empty() {
+                shown.push(Cow::Borrowed(cell));
+            } else if recent > 0 {
+                recent -= 1;
+                shown.push(Cow::Borrowed(cell));
+            } else {
+                shown.push(Cow::Owned(Cell {
+                    code: String::new(),
+                    ..cell.clone()
+                }));
+            }
+        }
+        shown.into_iter().rev()
+    }
+
+    /// The prompt, context description and summary, findings, and notes, one
+    /// part each
+    pub(crate) fn header(&self) -> Vec<String> {
         let mut parts = Vec::new();
 
         // Add the prompt if it exists
@@ -191,38 +763,191 @@ impl Repl {
             parts.push(format!("Prompt:\n{}\n", self.prompt));
         }
 
-        // Format each cell
-        for cell in &self.entries {
-            let mut cell_parts = Vec::new();
+        // Describe how the context is structured
+        if let Some(description) = &self.context_description {
+            parts.push(format!("Context: {description}\n"));
+        }
 
-            // Add comment as markdown heading
-            if !cell.comment.is_empty() {
-                cell_parts.push(format!("# {}", cell.comment));
-            }
+        // Add the precomputed context summary
+        if let Some(summary) = &self.context_summary {
+            parts.push(format!("Context info: {summary}\n"));
+        }
 
-            // Add code in triple backticks
-            if !cell.code.is_empty() {
-                cell_parts.push(format!("```\n{}\n```", cell.code));
-            }
+        // Add findings compactly, one per line
+        if !self.findings.is_empty() {
+            let lines: Vec<String> = self
+                .findings
+                .iter()
+                .map(|f| format!("- {}: {}", f.key, f.value))
+                .collect();
+            parts.push(format!("Findings:\n{}\n", lines.join("\n")));
+        }
 
-            // Add output in triple backticks if it exists (already truncated in eval)
-            if let Some(output) = &cell.output {
-                cell_parts.push(format!("Output:\n```\n{output}\n```"));
-            }
+        // Add the running notes, which are never evicted
+        if !self.notes.is_empty() {
+            let lines: Vec<String> = self.notes.iter().map(Note::line).collect();
+            parts.push(format!("Notes:\n{}\n", lines.join("\n")));
+        }
+
+        parts
+    }
+
+    /// Hide the cells at `indices` from the model, keeping them in the session.
+    /// A summary of them, if given, is inserted as a cell after the last one.
+    pub fn evict(&mut self, indices: &[usize], summary: Option<String>) {
+        for &index in indices {
+            self.entries[index].evicted = true;
+        }
+        if let (Some(summary), Some(&last)) = (summary, indices.iter().max()) {
+            self.entries.insert(
+                last + 1,
+                Cell {
+                    comment: format!("Summary of {} earlier cells", indices.len()),
+                    code: String::new(),
+                    output: Some(summary),
+                    r#final: false,
+                    retries: 0,
+                    metadata: None,
+                    pinned: false,
+                    evicted: false,
+                    error: None,
+                    repeat_of: None,
+                },
+            );
+        }
+    }
+
+    /// Render the transcript as a standalone HTML report (see [`crate::report::render_html`])
+    pub fn to_html(&self, usage: &UsageBreakdown) -> String {
+        crate::report::render_html(self, usage)
+    }
+
+    /// Render the transcript as a Jupyter notebook (see [`crate::report::render_notebook`])
+    pub fn to_notebook(&self) -> serde_json::Value {
+        crate::report::render_notebook(self)
+    }
+}
+
+/// Format a cell for the transcript: the comment as a heading, then the code
+/// and output in fenced blocks. None if the cell has nothing to show.
+pub(crate) fn format_cell(cell: &Cell) -> Option<String> {
+    let mut cell_parts = Vec::new();
+
+    // Add comment as markdown heading
+    if !cell.comment.is_empty() {
+        cell_parts.push(format!("# {}", cell.comment));
+    }
+
+    // Add code in triple backticks
+    if !cell.code.is_empty() {
+        cell_parts.push(format!("```\n{}\n```", cell.code));
+    }
+
+    // Add output in triple backticks if it exists (already truncated in eval)
+    if let Some(output) = &cell.output {
+        cell_parts.push(format!("Output:\n```\n{output}\n```"));
+    }
+
+    (!cell_parts.is_empty()).then(|| format!("{}\n", cell_parts.join("\n")))
+}
 
-            // Join cell parts and add to main parts
-            if !cell_parts.is_empty() {
-                parts.push(format!("{}\n", cell_parts.join("\n")));
+/// Which part of a long cell output is kept in the transcript
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Truncation {
+    /// Keep the beginning
+    #[default]
+    Head,
+    /// Keep the end, e.g. the error at the bottom of a log
+    Tail,
+    /// Keep the beginning and the end, cutting out the middle
+    HeadAndTail,
+}
+
+impl Truncation {
+    /// Cut `text` down to `max` of its `count` units, where `offset(n)` is
+    /// the byte offset of the boundary before unit `n`
+    fn cut(
+        self,
+        text: &str,
+        count: usize,
+        max: usize,
+        offset: impl Fn(usize) -> usize,
+        marker: &str,
+    ) -> String {
+        match self {
+            Truncation::Head => {
+                let end = text.floor_char_boundary(offset(max));
+                format!("{}\n{marker}", &text[..end])
+            }
+            Truncation::Tail => {
+                let start = text.ceil_char_boundary(offset(count - max));
+                format!("{marker}\n{}", &text[start..])
+            }
+            Truncation::HeadAndTail => {
+                let end = text.floor_char_boundary(offset(max - max / 2));
+                let start = text.ceil_char_boundary(offset(count - max / 2));
+                format!("{}\n{marker}\n{}", &text[..end], &text[start..])
             }
         }
+    }
+}
 
-        parts.join("\n")
+/// Truncate cell output to `max_tokens` tokens, keeping the part chosen by `truncation`.
+///
+/// If the tokenizer cannot be loaded, falls back to a character-based
+/// approximation (4 characters per token) so output can never bypass the
+/// budget.
+fn truncate_output(
+    result: String,
+    max_tokens: usize,
+    truncation: Truncation,
+    tokenizer: Tokenizer,
+) -> String {
+    if let Some(bpe) = tokenizer.bpe() {
+        let tokens = bpe.encode_with_special_tokens(&result);
+        if tokens.len() <= max_tokens {
+            return result;
+        }
+        // Byte offset of every token boundary
+        let mut offsets = vec![0];
+        for token in bpe._decode_native_and_split(tokens) {
+            offsets.push(offsets[offsets.len() - 1] + token.len());
+        }
+        let count = offsets.len() - 1;
+        return truncation.cut(&result, count, max_tokens, |n| offsets[n], "[truncated]");
     }
+
+    truncate_output_approximate(result, max_tokens, truncation)
+}
+
+/// Character-based truncation used when the tokenizer is unavailable.
+fn truncate_output_approximate(
+    result: String,
+    max_tokens: usize,
+    truncation: Truncation,
+) -> String {
+    let max_chars = max_tokens * 4;
+    let offsets: Vec<usize> = result
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain([result.len()])
+        .collect();
+    let count = offsets.len() - 1;
+    if count <= max_chars {
+        return result;
+    }
+    truncation.cut(
+        &result,
+        count,
+        max_chars,
+        |n| offsets[n],
+        "[truncated (approximate)]",
+    )
 }
 
 impl LmInput for Repl {
     fn format(&self) -> String {
-        self.to_markdown()
+        self.formatter.format(self)
     }
 }
 
@@ -237,6 +962,7 @@ mod tests {
             "test",
             "test-model".to_string(),
             LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
         )
         .unwrap();
         repl.eval("Print hello", r#"print("hello")"#);
@@ -254,6 +980,7 @@ mod tests {
             "test",
             "test-model".to_string(),
             LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
         )
         .unwrap();
         repl.eval("Set variable", "x = 5");
@@ -271,6 +998,7 @@ mod tests {
             "test",
             "test-model".to_string(),
             LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
         )
         .unwrap();
 
@@ -289,6 +1017,7 @@ mod tests {
             "test",
             "test-model".to_string(),
             LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
         )
         .unwrap();
         repl.eval("Invalid code", "this is not valid lua");
@@ -301,6 +1030,50 @@ mod tests {
                 .unwrap()
                 .starts_with("Execution error:")
         );
+        assert_eq!(repl.entries[0].error, Some(ErrorKind::Syntax));
+    }
+
+    #[test]
+    fn test_repl_error_kinds() {
+        let config = EnvironmentConfig::default()
+            .with_max_llm_queries_per_cell(Some(0))
+            .with_policy(Some(crate::policy::PolicyChecker::default()))
+            .with_cell_timeout(Some(std::time::Duration::from_millis(100)))
+            .with_max_cell_memory(Some(1 << 20));
+        let mut repl = Repl::with_config(
+            "test prompt".to_string(),
+            "test",
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            config,
+        )
+        .unwrap();
+        repl.eval("Fail", "error('boom')");
+        repl.eval("Query", "local ok = llm_query('hi')");
+        repl.eval("Load", "load('x = 1')()");
+        repl.eval("Loop", "while true do end");
+        repl.eval("Copy", "big = string.rep('x', 1 << 21)");
+        repl.eval("Succeed", "x = 1");
+
+        let kinds: Vec<_> = repl.entries.iter().map(|cell| cell.error).collect();
+        assert_eq!(
+            kinds,
+            [
+                Some(ErrorKind::Runtime),
+                Some(ErrorKind::LlmQuery),
+                Some(ErrorKind::Policy),
+                Some(ErrorKind::Timeout),
+                Some(ErrorKind::Limit),
+                None
+            ]
+        );
+
+        let json = serde_json::to_string(&repl.entries[1]).unwrap();
+        assert!(json.contains(r#""error":"llm_query""#));
+        assert!(
+            !serde_json::to_string(&repl.entries[5])
+                .unwrap()
+                .contains("error")
+        );
     }
 
     #[test]
@@ -310,18 +1083,420 @@ mod tests {
             "test",
             "test-model".to_string(),
             LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
         )
         .unwrap();
         repl.eval("First cell", r#"print("output1")"#);
         repl.eval("Second cell", "x = 10");
+        repl.tags.insert("ticket".to_string(), "1234".to_string());
 
         let json = serde_json::to_string(&repl).unwrap();
+        assert!(json.contains(r#""tags":{"ticket":"1234"}"#));
         assert!(json.contains("test prompt"));
         assert!(json.contains("First cell"));
         assert!(json.contains("Second cell"));
         assert!(json.contains("output1"));
     }
 
+    #[test]
+    fn test_repl_warm_start() {
+        let context = "# Report\nline two\n";
+        let mut repl = Repl::new(
+            "test prompt".to_string(),
+            context,
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
+        repl.warm_start(context).unwrap();
+
+        assert!(
+            repl.to_markdown()
+                .contains("Context info: 18 bytes, 2 lines, detected format: markdown")
+        );
+        repl.eval(
+            "Use info",
+            "print(context_info.lines, context_info.headings[1])",
+        );
+        assert_eq!(repl.entries[0].output, Some("2\t# Report".to_string()));
+        // The summary survives snapshots given to the model
+        assert!(repl.snapshot().unwrap().context_summary.is_some());
+    }
+
+    #[test]
+    fn test_repl_context_description() {
+        let mut repl = Repl::new(
+            "test prompt".to_string(),
+            "=== a.txt ===\nhello\n",
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
+        repl.context_description = Some("1 files concatenated".to_string());
+
+        let markdown = repl.to_markdown();
+        assert!(markdown.starts_with("Prompt:\ntest prompt\n\nContext: 1 files concatenated\n"));
+    }
+
+    #[test]
+    fn test_repl_cell_metadata() {
+        let mut repl = Repl::new(
+            "test prompt".to_string(),
+            0,
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
+        repl.eval("Print", r#"print("hello world")"#);
+        repl.eval("Set", "x = 1");
+
+        let metadata = repl.entries[0].metadata.as_ref().unwrap();
+        assert_eq!(metadata.output_tokens, 2);
+        assert!(metadata.timestamp.ends_with('Z'));
+        assert_eq!(repl.entries[1].metadata.as_ref().unwrap().output_tokens, 0);
+
+        let json = serde_json::to_string(&repl).unwrap();
+        assert!(json.contains(r#""metadata":{"timestamp":""#));
+        let restored: Repl = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.entries[0].metadata.as_ref(), Some(metadata));
+    }
+
+    #[test]
+    fn test_repl_token_usage() {
+        let mut repl = Repl::new(
+            "test prompt".to_string(),
+            0,
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
+        assert_eq!(repl.token_usage().transcript_tokens, 0);
+
+        repl.eval("Print", r#"print("hello world")"#);
+        repl.eval("Set", "x = 1");
+        let usage = repl.token_usage();
+        assert_eq!(usage.cell_output_tokens, [2, 0]);
+        assert!(usage.prompt_tokens > usage.transcript_tokens);
+
+        // Evicted cells still count toward the transcript, not the prompt
+        repl.evict(&[0], None);
+        let evicted = repl.token_usage();
+        assert_eq!(evicted.transcript_tokens, usage.transcript_tokens);
+        assert!(evicted.prompt_tokens < usage.prompt_tokens);
+    }
+
+    #[test]
+    fn test_repl_observers() {
+        #[derive(Default)]
+        struct Recorder(std::sync::Mutex<Vec<String>>);
+
+        impl ReplObserver for Recorder {
+            fn on_cell_start(&self, comment: &str, _code: &str) {
+                self.0.lock().unwrap().push(format!("start {comment}"));
+            }
+            fn on_output(&self, text: &str) {
+                self.0.lock().unwrap().push(format!("output {text}"));
+            }
+            fn on_progress(&self, message: &str) {
+                self.0.lock().unwrap().push(format!("progress {message}"));
+            }
+            fn on_truncation(&self, full: &str, _kept: &str) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("truncated {}", full.len()));
+            }
+            fn on_cell_complete(&self, cell: &Cell) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("complete {}", cell.comment));
+            }
+        }
+
+        let mut repl = Repl::new(
+            "test prompt".to_string(),
+            0,
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            3,
+        )
+        .unwrap();
+        let recorder = Arc::new(Recorder::default());
+        repl.add_observer(recorder.clone());
+        repl.eval("Short", "print('hi') progress('half done')");
+        repl.eval("Long", "print('one two three four five')");
+
+        // Progress messages are not part of the output
+        assert_eq!(repl.entries[0].output.as_deref(), Some("hi"));
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                "start Short",
+                "output hi",
+                "progress half done",
+                "complete Short",
+                "start Long",
+                "output one two three four five",
+                "truncated 23",
+                "complete Long",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_repl_hide_code_after() {
+        let mut repl = Repl::new(
+            "test prompt".to_string(),
+            0,
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
+        repl.eval("First", "print('one')");
+        repl.eval("Note", "note_important('keep going')");
+        repl.eval("Third", "print('three')");
+        repl.hide_code_after = Some(2);
+
+        let markdown = repl.to_markdown();
+        assert!(markdown.contains("# First\nOutput:\n```\none\n```"));
+        assert!(!markdown.contains("print('one')"));
+        assert!(markdown.contains("note_important('keep going')"));
+        assert!(markdown.contains("print('three')"));
+        assert_eq!(repl.entries[0].code, "print('one')");
+    }
+
+    #[test]
+    fn test_repl_resume() {
+        let mut repl = Repl::new(
+            "test prompt".to_string(),
+            "my context",
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
+        repl.eval(
+            "Count words",
+            "words = 2 function double(n) return n * 2 end",
+        );
+        repl.eval("Record", r#"emit_finding("words", words)"#);
+        let json = serde_json::to_string(&repl).unwrap();
+        assert!(json.contains(r#""globals":{"values":{"words":2},"unserializable":["double"]}"#));
+
+        let session: Session = serde_json::from_str(&json).unwrap();
+        let mut resumed = Repl::resume(
+            session,
+            "my context",
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            EnvironmentConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(resumed.prompt, "test prompt");
+        assert_eq!(resumed.entries.len(), 2);
+
+        // Both the saved value and the replayed function are back
+        resumed.eval(
+            "Continue",
+            r#"emit_finding("more", 1) print(double(words), context)"#,
+        );
+        assert_eq!(resumed.entries[2].output, Some("4\tmy context".to_string()));
+        assert_eq!(resumed.findings.len(), 2);
+    }
+
+    #[test]
+    fn test_repl_edit_and_rerun() {
+        let mut repl = Repl::new(
+            "test prompt".to_string(),
+            0,
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
+        repl.eval("Set x", "x = 1");
+        repl.eval("Broken", "y = x + nil");
+        repl.eval("Print", "print(y)");
+        repl.eval("Mutate", "x = x + 10");
+        assert!(repl.entries[1].is_error());
+
+        repl.edit_cell(1, "y = x + 1").unwrap();
+        assert_eq!(repl.entries.len(), 2);
+        assert_eq!(repl.entries[1].output, None);
+        repl.eval("Print", "print(x, y)");
+        assert_eq!(repl.entries[2].output, Some("1\t2".to_string()));
+
+        // Re-running starts from the state before the cell, not the current one
+        repl.eval("Increment", "x = x + 1 print(x)");
+        repl.rerun_from(3).unwrap();
+        assert_eq!(repl.entries[3].output, Some("2".to_string()));
+        assert!(repl.rerun_from(4).is_err());
+    }
+
+    #[test]
+    fn test_repl_find_repeat() {
+        let mut repl = Repl::new(
+            "prompt".to_string(),
+            "x".repeat(100),
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            10,
+        )
+        .unwrap();
+
+        // Successful cells may be repeated freely
+        repl.eval("Print", "print('hi')");
+        repl.eval("Print again", "print('hi')");
+        assert_eq!(repl.entries[1].repeat_of, None);
+
+        // Repeating a failed cell is flagged, ignoring whitespace and comments
+        repl.eval("Fail", "error('boom')");
+        repl.eval("Fail again", "-- retry\nerror( 'boom' )  -- same\n");
+        assert_eq!(repl.entries[3].repeat_of, Some(2));
+        assert!(repl.entries[3].is_error());
+
+        // So is repeating a cell whose output was truncated
+        repl.eval("Dump", "print(context)");
+        assert!(repl.entries[4].metadata.as_ref().unwrap().truncated);
+        repl.eval("Dump again", "print(context)");
+        assert_eq!(repl.entries[5].repeat_of, Some(4));
+
+        // Only recent cells are compared
+        for i in 0..REPEAT_WINDOW {
+            repl.eval("Other", &format!("x = {i}"));
+        }
+        repl.eval("Fail once more", "error('boom')");
+        assert_eq!(repl.entries.last().unwrap().repeat_of, None);
+    }
+
+    #[test]
+    fn test_repl_final_answer() {
+        let mut repl = Repl::new(
+            "prompt".to_string(),
+            0,
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            10,
+        )
+        .unwrap();
+        repl.eval("Work", "print('intermediate')");
+        assert_eq!(repl.final_answer(), None);
+        assert!(!repl.entries[0].r#final);
+
+        // Stored untruncated, unlike the output of the cell
+        let answer = "word ".repeat(100);
+        repl.eval(
+            "Answer",
+            &format!("print(string.rep('x', 1000)) final_answer('{answer}')"),
+        );
+        assert_eq!(repl.final_answer(), Some(answer.as_str()));
+        assert!(repl.entries[1].r#final);
+
+        // Saved with the session and recomputed when cells are undone
+        let json = serde_json::to_string(&repl).unwrap();
+        let session: Session = serde_json::from_str(&json).unwrap();
+        assert_eq!(session.final_answer.as_deref(), Some(answer.as_str()));
+        repl.undo().unwrap();
+        assert_eq!(repl.final_answer(), None);
+    }
+
+    #[test]
+    fn test_repl_undo() {
+        let mut repl = Repl::new(
+            "test prompt".to_string(),
+            0,
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
+        repl.eval("Set x", "x = 1 emit_finding('x', 1)");
+        repl.eval(
+            "Clobber",
+            "x = nil context = nil note_important('cleared') emit_finding('y', 2)",
+        );
+        assert_eq!(repl.entries.len(), 3);
+
+        let undone = repl.undo().unwrap().unwrap();
+        assert_eq!(undone.comment, "Clobber");
+        assert_eq!(repl.entries.len(), 1);
+        assert_eq!(repl.findings.len(), 1);
+        repl.eval("Print", "print(x, context)");
+        assert_eq!(repl.entries[1].output, Some("1\t0".to_string()));
+
+        repl.undo().unwrap();
+        repl.undo().unwrap();
+        assert!(repl.entries.is_empty());
+        assert!(repl.undo().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_repl_fork() {
+        let mut repl = Repl::new(
+            "test prompt".to_string(),
+            "ctx",
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
+        repl.eval("Define", "n = 1 function inc() n = n + 1 end");
+        repl.eval("Record", r#"emit_finding("start", n)"#);
+
+        let mut fork = repl.fork().unwrap();
+        assert_eq!(fork.entries.len(), 2);
+        assert_eq!(fork.findings, repl.findings);
+
+        // The branches diverge from the common prefix
+        fork.eval("Increment twice", "inc() inc() print(n, context)");
+        repl.eval("Print", "print(n)");
+        assert_eq!(fork.entries[2].output, Some("3\tctx".to_string()));
+        assert_eq!(repl.entries[2].output, Some("1".to_string()));
+    }
+
+    #[test]
+    fn test_repl_pins() {
+        let mut repl = Repl::new(
+            "test prompt".to_string(),
+            0,
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
+        repl.eval("Count", "print(42)");
+        repl.eval(
+            "Keep it",
+            r#"pin_last_cell() note_important("42 rows") print("ok")"#,
+        );
+
+        assert_eq!(repl.entries.len(), 3);
+        assert!(repl.entries[0].pinned);
+        assert_eq!(repl.entries[1].comment, "Important note");
+        assert_eq!(repl.entries[1].output.as_deref(), Some("42 rows"));
+        assert!(repl.entries[1].pinned);
+        assert_eq!(repl.entries[2].comment, "Keep it");
+        assert!(!repl.entries[2].pinned);
+
+        // Notes are not duplicated when the cells are replayed
+        let mut fork = repl.fork().unwrap();
+        assert_eq!(fork.entries.len(), 3);
+        fork.eval("Next", "x = 1");
+        assert_eq!(fork.entries.len(), 4);
+
+        // ... nor when the cell that added them runs again
+        repl.rerun_from(2).unwrap();
+        assert_eq!(repl.entries.len(), 3);
+        assert_eq!(repl.entries[1].output.as_deref(), Some("42 rows"));
+        assert_eq!(repl.entries[2].comment, "Keep it");
+        assert_eq!(repl.entries[2].output.as_deref(), Some("ok"));
+    }
+
     #[test]
     fn test_repl_deserialization() {
         let json = r#"{
@@ -341,6 +1516,20 @@ mod tests {
         assert_eq!(repl.entries[0].comment, "Test comment");
         assert_eq!(repl.entries[0].code, "print('hello')");
         assert_eq!(repl.entries[0].output, Some("hello".to_string()));
+        assert!(repl.tags.is_empty());
+    }
+
+    #[test]
+    fn test_repl_deserialization_with_tags() {
+        let json = r#"{
+            "prompt": "p",
+            "entries": [],
+            "tags": {"project": "alpha", "ticket": "1234"}
+        }"#;
+
+        let repl: Repl = serde_json::from_str(json).unwrap();
+        assert_eq!(repl.tags.get("project"), Some(&"alpha".to_string()));
+        assert_eq!(repl.tags.get("ticket"), Some(&"1234".to_string()));
     }
 
     #[test]
@@ -350,6 +1539,7 @@ mod tests {
             "my context",
             "test-model".to_string(),
             LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
         )
         .unwrap();
         repl.eval("Print context", "print(context)");
@@ -364,6 +1554,7 @@ mod tests {
             0,
             "test-model".to_string(),
             LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
         )
         .unwrap();
 
@@ -383,6 +1574,7 @@ mod tests {
             0,
             "test-model".to_string(),
             LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
         )
         .unwrap();
 
@@ -413,6 +1605,7 @@ mod tests {
             0,
             "test-model".to_string(),
             LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
         )
         .unwrap();
 
@@ -429,55 +1622,89 @@ mod tests {
     }
 
     #[test]
-    fn test_cell_parser_xml_format() {
-        let text = r#"<comment>
-First, let me check the context to understand what we're working with
-</comment>
+    fn test_repl_findings_in_format() {
+        let mut repl = Repl::new(
+            "test prompt".to_string(),
+            0,
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
 
-<code>
-print(string.sub(context, 1, 100))
-</code>
+        repl.eval("Record findings", r#"emit_finding("errors", 3)"#);
+        assert_eq!(repl.findings.len(), 1);
 
-<final>
-false
-</final>"#;
+        let formatted = repl.snapshot().unwrap().format();
+        assert!(formatted.contains("Findings:\n- errors: 3\n"));
 
-        let cell = Cell::parse(text).unwrap();
-        assert_eq!(
-            cell.comment,
-            "First, let me check the context to understand what we're working with"
-        );
-        assert_eq!(cell.code, "print(string.sub(context, 1, 100))");
-        assert!(!cell.r#final);
+        let json = serde_json::to_string(&repl).unwrap();
+        assert!(json.contains(r#""findings":[{"key":"errors","value":"3"}]"#));
     }
 
     #[test]
-    fn test_cell_parser_final_true() {
-        let text = r#"<comment>
-Output the final answer
-</comment>
+    fn test_repl_notes_survive_eviction() {
+        let mut repl = Repl::new(
+            "test prompt".to_string(),
+            0,
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
 
-<code>
-print("The answer is: 42")
-</code>
+        repl.eval(
+            "Take notes",
+            r#"note_add("A is largest", {"A"}) note_add("B is urgent")"#,
+        );
+        repl.eval("Count", "print(#notes_list('A'))");
+        assert_eq!(repl.entries[1].output.as_deref(), Some("1"));
+        repl.evict(&[0, 1], None);
 
-<final>
-true
-</final>"#;
+        let formatted = repl.snapshot().unwrap().format();
+        assert!(!formatted.contains("Take notes"));
+        assert!(formatted.contains("Notes:\n- A is largest [A]\n- B is urgent\n"));
 
-        let cell = Cell::parse(text).unwrap();
-        assert_eq!(cell.comment, "Output the final answer");
-        assert_eq!(cell.code, r#"print("The answer is: 42")"#);
-        assert!(cell.r#final);
+        let json = serde_json::to_string(&repl).unwrap();
+        let session: Session = serde_json::from_str(&json).unwrap();
+        let mut resumed = Repl::resume(
+            session,
+            0,
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            EnvironmentConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(resumed.notes, repl.notes);
+        resumed.eval("Add", r#"note_add("C is empty")"#);
+        assert_eq!(resumed.notes.len(), 3);
+
+        // Undoing replays the remaining cells, which add their notes again
+        resumed.undo().unwrap();
+        assert_eq!(resumed.notes, repl.notes);
     }
 
     #[test]
-    fn test_cell_parser_json_fallback() {
-        let json = r#"{"comment": "Test comment", "code": "print('hello')", "final": false}"#;
-        let cell = Cell::parse(json).unwrap();
-        assert_eq!(cell.comment, "Test comment");
-        assert_eq!(cell.code, "print('hello')");
-        assert!(!cell.r#final);
+    fn test_repl_to_html() {
+        let mut repl = Repl::new(
+            "Find <errors>".to_string(),
+            0,
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
+
+        repl.eval("Set variable", "x = 10");
+        repl.eval("Print answer", "print(x * 2)");
+
+        let html = repl.to_html(&UsageBreakdown::default());
+
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("Find &lt;errors&gt;"));
+        assert_eq!(html.matches("<details>").count(), 2);
+        assert!(html.contains("<summary>2. Print answer</summary>"));
+        assert!(html.contains("<div class=\"answer\">20</div>"));
+        assert!(html.contains("<h2>Usage</h2>"));
     }
 
     #[test]
@@ -487,6 +1714,7 @@ true
             0,
             "test-model".to_string(),
             LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
         )
         .unwrap();
 
@@ -524,6 +1752,7 @@ end"#,
             0,
             "test-model".to_string(),
             LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
         )
         .unwrap();
 
@@ -562,6 +1791,65 @@ end"#,
         );
     }
 
+    #[test]
+    fn test_output_truncation_limit() {
+        let mut repl = Repl::new(
+            "Test limit".to_string(),
+            0,
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            5,
+        )
+        .unwrap();
+
+        repl.eval(
+            "Count",
+            r#"print("one two three four five six seven eight")"#,
+        );
+        assert_eq!(
+            repl.entries[0].output.as_deref(),
+            Some("one two three four five\n[truncated]")
+        );
+        assert_eq!(repl.snapshot().unwrap().max_output_tokens, 5);
+    }
+
+    #[test]
+    fn test_output_truncation_approximate() {
+        let long = "é".repeat(1000);
+        let truncated = truncate_output_approximate(long, 100, Truncation::Head);
+        assert!(truncated.ends_with("\n[truncated (approximate)]"));
+        assert!(truncated.starts_with(&"é".repeat(400)));
+        assert_eq!(truncated.chars().filter(|c| *c == 'é').count(), 400);
+
+        let short = "short output".to_string();
+        assert_eq!(
+            truncate_output_approximate(short.clone(), 100, Truncation::Head),
+            short
+        );
+    }
+
+    #[test]
+    fn test_output_truncation_policies() {
+        let output = "one two three four five six seven eight".to_string();
+        assert_eq!(
+            truncate_output(output.clone(), 4, Truncation::Tail, Tokenizer::P50k),
+            "[truncated]\n five six seven eight"
+        );
+        assert_eq!(
+            truncate_output(output.clone(), 4, Truncation::HeadAndTail, Tokenizer::P50k),
+            "one two\n[truncated]\n seven eight"
+        );
+        assert_eq!(
+            truncate_output_approximate(output, 2, Truncation::HeadAndTail),
+            "one \n[truncated (approximate)]\night"
+        );
+
+        // Multibyte characters split across tokens are never cut in half
+        let emoji = "🦀".repeat(50);
+        let tail = truncate_output(emoji, 10, Truncation::Tail, Tokenizer::P50k);
+        assert!(tail.starts_with("[truncated]\n🦀"));
+    }
+
     #[test]
     fn test_short_output_not_truncated() {
         let mut repl = Repl::new(
@@ -569,6 +1857,7 @@ end"#,
             0,
             "test-model".to_string(),
             LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
         )
         .unwrap();
 
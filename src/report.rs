@@ -0,0 +1,397 @@
+use crate::repl::Repl;
+use crate::usage::UsageBreakdown;
+use serde_json::{Value, json};
+
+const LUA_KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+const STYLE: &str = r#"
+body { font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Helvetica, Arial, sans-serif; max-width: 960px; margin: 2em auto; padding: 0 1em; color: #1f2328; }
+h1 { font-size: 1.6em; }
+h2 { font-size: 1.2em; margin-top: 2em; }
+pre { background: #f6f8fa; padding: 0.8em; border-radius: 6px; overflow-x: auto; white-space: pre-wrap; }
+details { border: 1px solid #d0d7de; border-radius: 6px; margin: 0.6em 0; padding: 0.4em 0.8em; }
+summary { cursor: pointer; font-weight: 600; }
+.prompt, .answer { background: #eef6ff; border-left: 4px solid #0969da; padding: 0.8em; white-space: pre-wrap; }
+.output { background: #fff8e5; }
+.kw { color: #cf222e; font-weight: 600; }
+.str { color: #0a3069; }
+.num { color: #0550ae; }
+.com { color: #6e7781; font-style: italic; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #d0d7de; padding: 0.3em 0.8em; text-align: right; }
+th:first-child, td:first-child { text-align: left; }
+"#;
+
+/// Render a REPL transcript as a standalone HTML report.
+///
+/// The report contains the prompt, one collapsible section per cell with
+/// syntax-highlighted Lua and its output, the final answer (recorded with
+/// `final_answer`, or else the output of the last cell), and a usage table.
+/// No external resources are referenced.
+pub fn render_html(repl: &Repl, usage: &UsageBreakdown) -> String {
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str("<title>Moonraker Report</title>\n");
+    html.push_str(&format!("<style>{STYLE}</style>\n"));
+    html.push_str("</head>\n<body>\n<h1>Moonraker Report</h1>\n");
+
+    if !repl.prompt.is_empty() {
+        html.push_str("<h2>Prompt</h2>\n");
+        html.push_str(&format!(
+            "<div class=\"prompt\">{}</div>\n",
+            escape_html(&repl.prompt)
+        ));
+    }
+
+    if !repl.tags.is_empty() {
+        html.push_str("<h2>Tags</h2>\n<table>\n");
+        for (key, value) in &repl.tags {
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td></tr>\n",
+                escape_html(key),
+                escape_html(value)
+            ));
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str(&format!("<h2>Cells ({})</h2>\n", repl.entries.len()));
+    for (i, cell) in repl.entries.iter().enumerate() {
+        let retries = match cell.retries {
+            0 => String::new(),
+            1 => " (1 retry)".to_string(),
+            n => format!(" ({n} retries)"),
+        };
+        html.push_str(&format!(
+            "<details>\n<summary>{}. {}{retries}</summary>\n",
+            i + 1,
+            escape_html(&cell.comment)
+        ));
+        html.push_str(&format!(
+            "<pre><code>{}</code></pre>\n",
+            highlight_lua(&cell.code)
+        ));
+        match &cell.output {
+            Some(output) => html.push_str(&format!(
+                "<pre class=\"output\">{}</pre>\n",
+                escape_html(output)
+            )),
+            None => html.push_str("<p><em>(no output)</em></p>\n"),
+        }
+        html.push_str("</details>\n");
+    }
+
+    html.push_str("<h2>Final Answer</h2>\n");
+    let answer = repl
+        .final_answer()
+        .or_else(|| repl.entries.last().and_then(|cell| cell.output.as_deref()));
+    match answer {
+        Some(answer) => html.push_str(&format!(
+            "<div class=\"answer\">{}</div>\n",
+            escape_html(answer)
+        )),
+        None => html.push_str("<p><em>No output from final cell</em></p>\n"),
+    }
+
+    if !repl.findings.is_empty() {
+        html.push_str("<h2>Findings</h2>\n<table>\n");
+        for finding in &repl.findings {
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td></tr>\n",
+                escape_html(&finding.key),
+                escape_html(&finding.value)
+            ));
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("<h2>Usage</h2>\n<table>\n");
+    html.push_str(
+        "<tr><th>Category</th><th>Calls</th><th>Input tokens</th><th>Output tokens</th></tr>\n",
+    );
+    for (category, tokens) in usage.iter() {
+        html.push_str(&format!(
+            "<tr><td>{category}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            tokens.calls, tokens.input_tokens, tokens.output_tokens
+        ));
+    }
+    let total = usage.total();
+    html.push_str(&format!(
+        "<tr><th>total</th><th>{}</th><th>{}</th><th>{}</th></tr>\n",
+        total.calls, total.input_tokens, total.output_tokens
+    ));
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    html
+}
+
+/// Render a REPL transcript as a Jupyter notebook (nbformat 4.5).
+///
+/// The prompt, findings, and each cell's comment become markdown cells; the
+/// code becomes a Lua code cell whose output is a `stream` output, or an
+/// `error` output for cells that failed. Evicted cells are included.
+pub fn render_notebook(repl: &Repl) -> Value {
+    let mut cells = Vec::new();
+
+    if !repl.prompt.is_empty() {
+        push_markdown(&mut cells, &format!("## Prompt\n\n{}", repl.prompt));
+    }
+    if !repl.findings.is_empty() {
+        let lines: Vec<String> = repl
+            .findings
+            .iter()
+            .map(|f| format!("- **{}**: {}", f.key, f.value))
+            .collect();
+        push_markdown(&mut cells, &format!("## Findings\n\n{}", lines.join("\n")));
+    }
+
+    let mut execution_count = 0;
+    for cell in &repl.entries {
+        if !cell.comment.is_empty() {
+            push_markdown(&mut cells, &format!("### {}", cell.comment));
+        }
+        if cell.code.is_empty() {
+            // Summary cells only have output
+            if let Some(output) = &cell.output {
+                push_markdown(&mut cells, output);
+            }
+            continue;
+        }
+
+        execution_count += 1;
+        let outputs = match &cell.output {
+            None => Vec::new(),
+            Some(output) if cell.is_error() => {
+                let message = output.trim_start_matches("Execution error:").trim();
+                vec![json!({
+                    "output_type": "error",
+                    "ename": "Execution error",
+                    "evalue": message,
+                    "traceback": [message],
+                })]
+            }
+            Some(output) => vec![json!({
+                "output_type": "stream",
+                "name": "stdout",
+                "text": source_lines(output),
+            })],
+        };
+        cells.push(json!({
+            "cell_type": "code",
+            "id": format!("cell-{}", cells.len()),
+            "execution_count": execution_count,
+            "metadata": {},
+            "source": source_lines(&cell.code),
+            "outputs": outputs,
+        }));
+    }
+
+    json!({
+        "nbformat": 4,
+        "nbformat_minor": 5,
+        "metadata": {
+            "kernelspec": {"name": "lua", "display_name": "Lua", "language": "lua"},
+            "language_info": {"name": "lua", "file_extension": ".lua"},
+            "moonraker": {"tags": repl.tags},
+        },
+        "cells": cells,
+    })
+}
+
+fn push_markdown(cells: &mut Vec<Value>, text: &str) {
+    cells.push(json!({
+        "cell_type": "markdown",
+        "id": format!("cell-{}", cells.len()),
+        "metadata": {},
+        "source": source_lines(text),
+    }));
+}
+
+/// Split text into notebook source lines, each but the last keeping its newline
+fn source_lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+/// Escape text for inclusion in HTML element content.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Highlight Lua source as escaped HTML with `<span>` classes for keywords,
+/// strings, numbers, and comments.
+fn highlight_lua(code: &str) -> String {
+    let chars: Vec<char> = code.chars().collect();
+    let mut html = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        // Comments (line and --[[ block ]])
+        if c == '-' && chars.get(i + 1) == Some(&'-') {
+            let end = if chars.get(i + 2) == Some(&'[') && chars.get(i + 3) == Some(&'[') {
+                find_seq(&chars, i + 4, &[']', ']']).map_or(chars.len(), |e| e + 2)
+            } else {
+                chars[i..]
+                    .iter()
+                    .position(|&ch| ch == '\n')
+                    .map_or(chars.len(), |p| i + p)
+            };
+            push_span(&mut html, "com", &chars[i..end]);
+            i = end;
+            continue;
+        }
+
+        // Quoted strings
+        if c == '"' || c == '\'' {
+            let mut end = i + 1;
+            while end < chars.len() && chars[end] != c && chars[end] != '\n' {
+                if chars[end] == '\\' {
+                    end += 1;
+                }
+                end += 1;
+            }
+            let end = (end + 1).min(chars.len());
+            push_span(&mut html, "str", &chars[i..end]);
+            i = end;
+            continue;
+        }
+
+        // Long strings [[ ... ]]
+        if c == '[' && chars.get(i + 1) == Some(&'[') {
+            let end = find_seq(&chars, i + 2, &[']', ']']).map_or(chars.len(), |e| e + 2);
+            push_span(&mut html, "str", &chars[i..end]);
+            i = end;
+            continue;
+        }
+
+        // Numbers
+        if c.is_ascii_digit() {
+            let mut end = i;
+            while end < chars.len() && (chars[end].is_ascii_alphanumeric() || chars[end] == '.') {
+                end += 1;
+            }
+            push_span(&mut html, "num", &chars[i..end]);
+            i = end;
+            continue;
+        }
+
+        // Identifiers and keywords
+        if c.is_alphabetic() || c == '_' {
+            let mut end = i;
+            while end < chars.len() && (chars[end].is_alphanumeric() || chars[end] == '_') {
+                end += 1;
+            }
+            let word: String = chars[i..end].iter().collect();
+            if LUA_KEYWORDS.contains(&word.as_str()) {
+                push_span(&mut html, "kw", &chars[i..end]);
+            } else {
+                html.push_str(&escape_html(&word));
+            }
+            i = end;
+            continue;
+        }
+
+        html.push_str(&escape_html(&c.to_string()));
+        i += 1;
+    }
+
+    html
+}
+
+fn find_seq(chars: &[char], from: usize, seq: &[char]) -> Option<usize> {
+    (from..chars.len()).find(|&i| chars[i..].starts_with(seq))
+}
+
+fn push_span(html: &mut String, class: &str, chars: &[char]) {
+    let text: String = chars.iter().collect();
+    html.push_str(&format!(
+        "<span class=\"{class}\">{}</span>",
+        escape_html(&text)
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html("<a href=\"x\">&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn test_highlight_lua() {
+        let html = highlight_lua("local x = \"a<b\" -- note\nreturn 42");
+        assert!(html.contains("<span class=\"kw\">local</span>"));
+        assert!(html.contains("<span class=\"str\">&quot;a&lt;b&quot;</span>"));
+        assert!(html.contains("<span class=\"com\">-- note</span>"));
+        assert!(html.contains("<span class=\"kw\">return</span>"));
+        assert!(html.contains("<span class=\"num\">42</span>"));
+    }
+
+    #[test]
+    fn test_highlight_lua_block_comment() {
+        let html = highlight_lua("--[[ plan\nstep ]]\nx = 1");
+        assert!(html.contains("<span class=\"com\">--[[ plan\nstep ]]</span>"));
+    }
+
+    #[test]
+    fn test_render_notebook() {
+        use crate::environment::LlmClient;
+        use crate::repl::DEFAULT_MAX_OUTPUT_TOKENS;
+
+        let mut repl = Repl::new(
+            "Count lines".to_string(),
+            "a\nb",
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
+        repl.eval(
+            "Split",
+            "lines = {}\nfor l in context:gmatch('[^\\n]+') do lines[#lines + 1] = l end",
+        );
+        repl.eval("Print", "print(#lines)\nprint(lines[1])");
+        repl.eval("Fail", "error('boom')");
+
+        let notebook = render_notebook(&repl);
+        assert_eq!(notebook["nbformat"], 4);
+        let cells = notebook["cells"].as_array().unwrap();
+        let types: Vec<&str> = cells
+            .iter()
+            .map(|c| c["cell_type"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                "markdown", "markdown", "code", "markdown", "code", "markdown", "code"
+            ]
+        );
+        assert_eq!(
+            cells[0]["source"],
+            json!(["## Prompt\n", "\n", "Count lines"])
+        );
+        assert_eq!(cells[4]["execution_count"], 2);
+        assert_eq!(cells[4]["outputs"][0]["text"], json!(["2\n", "a"]));
+        assert_eq!(cells[6]["outputs"][0]["output_type"], "error");
+    }
+}
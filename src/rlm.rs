@@ -1,4 +1,9 @@
+use crate::repl::ErrorKind;
+use crate::transcript::{Markdown, SharedFormatter, TranscriptFormatter};
+use crate::usage::{UsageBreakdown, UsageCategory, UsageTracker};
+use crate::window::{WindowConfig, WindowStrategy};
 use async_trait::async_trait;
+use regex::Regex;
 use rig::client::CompletionClient;
 use rig::completion::Prompt;
 use rig::providers::{ollama, openrouter};
@@ -6,6 +11,8 @@ use schemars::JsonSchema;
 use serde::de::DeserializeOwned;
 use serde_json::json;
 use std::error::Error;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
 
 /// Trait for inputs to language models
 pub trait LmInput {
@@ -23,13 +30,29 @@ pub trait OutputParser: Sized {
 
 /// Trait for language model providers that can generate structured outputs
 #[async_trait]
-pub trait LmProvider<I: LmInput + Send + 'static, O: DeserializeOwned + JsonSchema + Send + 'static>
+pub trait LmProvider<I: LmInput + Send + 'static, O: DeserializeOwned + JsonSchema + Send + 'static>:
+    Sync
 {
     /// Set the system prompt for the provider
     fn with_system(self, prompt: String) -> Self;
 
     /// Generate a structured output from the given input
     async fn generate(&self, input: I) -> Result<O, Box<dyn Error>>;
+
+    /// Token usage of the calls made by this provider so far
+    fn usage(&self) -> UsageBreakdown {
+        UsageBreakdown::default()
+    }
+
+    /// Complete a free-form prompt, e.g. to summarize cells evicted from the
+    /// context window. Providers that only generate cells return an error.
+    async fn complete_text(
+        &self,
+        _instructions: &str,
+        _text: &str,
+    ) -> Result<String, Box<dyn Error>> {
+        Err("this provider cannot complete free-form prompts".into())
+    }
 }
 
 /// Provider type enum
@@ -45,6 +68,8 @@ pub struct RigProvider {
     system_prompt: Option<String>,
     /// API key for OpenRouter (if applicable)
     api_key: Option<String>,
+    /// Usage of the generations made by this provider
+    usage: UsageTracker,
 }
 
 impl RigProvider {
@@ -55,6 +80,7 @@ impl RigProvider {
             model,
             system_prompt: Some(system_prompt),
             api_key: None,
+            usage: UsageTracker::new(),
         }
     }
 
@@ -69,9 +95,69 @@ impl RigProvider {
             model,
             system_prompt: Some(system_prompt),
             api_key: Some(api_key),
+            usage: UsageTracker::new(),
+        }
+    }
+
+    /// Create a Rig provider for the same model and provider as an environment's client
+    pub fn from_llm_client(client: &crate::environment::LlmClient, system_prompt: String) -> Self {
+        match client {
+            crate::environment::LlmClient::Ollama(model) => {
+                Self::new_ollama_with_system(model.clone(), system_prompt)
+            }
+            crate::environment::LlmClient::Openrouter(model, api_key) => {
+                Self::new_openrouter_with_system_and_key(
+                    model.clone(),
+                    system_prompt,
+                    api_key.clone(),
+                )
+            }
         }
     }
 
+    /// Token usage of the calls made by this provider so far
+    pub fn usage(&self) -> UsageBreakdown {
+        self.usage.breakdown()
+    }
+
+    /// Run a single completion with an optional preamble, recording usage under `category`
+    pub async fn complete(
+        &self,
+        preamble: Option<&str>,
+        prompt: &str,
+        category: UsageCategory,
+    ) -> Result<String, Box<dyn Error>> {
+        // Build the agent based on the provider type
+        let response = match &self.client {
+            ProviderType::Ollama(client) => {
+                let agent = if let Some(preamble) = preamble {
+                    client
+                        .agent(&self.model)
+                        .preamble(preamble)
+                        .additional_params(json!({"think": false}))
+                        .build()
+                } else {
+                    client
+                        .agent(&self.model)
+                        .additional_params(json!({"think": false}))
+                        .build()
+                };
+                agent.prompt(prompt).extended_details().await?
+            }
+            ProviderType::Openrouter(client) => {
+                let agent = if let Some(preamble) = preamble {
+                    client.agent(&self.model).preamble(preamble).build()
+                } else {
+                    client.agent(&self.model).build()
+                };
+                agent.prompt(prompt).extended_details().await?
+            }
+        };
+        self.usage.record(category, response.total_usage);
+
+        Ok(response.output)
+    }
+
     /// Create an LlmClient for the REPL environment from this provider
     pub fn to_llm_client(&self) -> Result<crate::environment::LlmClient, Box<dyn Error>> {
         match &self.client {
@@ -107,38 +193,174 @@ where
         // Get the formatted prompt from the input
         let user_prompt = input.format();
 
-        // Build the agent based on the provider type
-        let response: String = match &self.client {
-            ProviderType::Ollama(client) => {
-                let agent = if let Some(system_prompt) = &self.system_prompt {
-                    client
-                        .agent(&self.model)
-                        .preamble(system_prompt)
-                        .additional_params(json!({"think": false}))
-                        .build()
-                } else {
-                    client
-                        .agent(&self.model)
-                        .additional_params(json!({"think": false}))
-                        .build()
-                };
-                agent.prompt(&user_prompt).await?
-            }
-            ProviderType::Openrouter(client) => {
-                let agent = if let Some(system_prompt) = &self.system_prompt {
-                    client.agent(&self.model).preamble(system_prompt).build()
-                } else {
-                    client.agent(&self.model).build()
-                };
-                agent.prompt(&user_prompt).await?
-            }
-        };
+        let response = self
+            .complete(
+                self.system_prompt.as_deref(),
+                &user_prompt,
+                UsageCategory::Generation,
+            )
+            .await?;
 
         // Parse the text response using the OutputParser trait
         let parsed: O = O::parse(&response)?;
 
         Ok(parsed)
     }
+
+    fn usage(&self) -> UsageBreakdown {
+        self.usage.breakdown()
+    }
+
+    async fn complete_text(
+        &self,
+        instructions: &str,
+        text: &str,
+    ) -> Result<String, Box<dyn Error>> {
+        self.complete(Some(instructions), text, UsageCategory::Summarization)
+            .await
+            .map(|summary| crate::parse::strip_reasoning(&summary).trim().to_string())
+    }
+}
+
+/// Configuration for an [`Rlm`] run
+#[derive(Debug, Clone)]
+pub struct RlmConfig {
+    /// Configuration of the Lua environment the cells run in
+    pub environment: crate::environment::EnvironmentConfig,
+    /// Key/value metadata attached to the session
+    pub tags: std::collections::BTreeMap<String, String>,
+    /// Number of times a failed generation is retried before the step fails
+    pub max_retries: u32,
+    /// Number of times a cell that raised an error is regenerated, with the
+    /// error pointed out, before the step returns it
+    pub fix_retries: u32,
+    /// Delay before the first retry; doubled for each subsequent retry
+    pub retry_backoff: Duration,
+    /// How the context is structured (e.g. concatenated files), shown in the prompt
+    pub context_description: Option<String>,
+    /// Precompute structural facts about the context (see [`crate::context_info`])
+    pub warm_start: bool,
+    /// Consecutive error cells after which the model is told to fix the cause
+    pub error_warning_threshold: usize,
+    /// Consecutive error cells after which the model is told to simplify its approach
+    pub error_escalation_threshold: usize,
+    /// Tokens of each cell's output kept in the transcript
+    pub max_output_tokens: usize,
+    /// Which part of a long cell output is kept
+    pub truncation: crate::repl::Truncation,
+    /// Token budget of the transcript, with how old cells are evicted (unbounded if None)
+    pub window: Option<WindowConfig>,
+    /// Number of most recent iterations whose code is shown to the model (all if None)
+    pub hide_code_after: Option<usize>,
+    /// How the transcript is formatted for the model
+    pub formatter: SharedFormatter,
+    /// Tokens the run may spend in total; iteration stops once they are
+    /// spent (unlimited if None)
+    pub token_budget: Option<u64>,
+}
+
+impl Default for RlmConfig {
+    fn default() -> Self {
+        Self {
+            environment: Default::default(),
+            tags: Default::default(),
+            max_retries: 3,
+            fix_retries: 0,
+            retry_backoff: Duration::from_secs(1),
+            context_description: None,
+            warm_start: false,
+            error_warning_threshold: 2,
+            error_escalation_threshold: 3,
+            max_output_tokens: crate::repl::DEFAULT_MAX_OUTPUT_TOKENS,
+            truncation: Default::default(),
+            window: None,
+            hide_code_after: None,
+            formatter: Arc::new(Markdown),
+            token_budget: None,
+        }
+    }
+}
+
+impl RlmConfig {
+    /// Set the environment configuration
+    pub fn with_environment(mut self, environment: crate::environment::EnvironmentConfig) -> Self {
+        self.environment = environment;
+        self
+    }
+
+    /// Set the number of retries and the initial backoff for failed generations
+    pub fn with_retries(mut self, max_retries: u32, backoff: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Regenerate a cell that raised an error up to `fix_retries` times within a step
+    pub fn with_fix_retries(mut self, fix_retries: u32) -> Self {
+        self.fix_retries = fix_retries;
+        self
+    }
+
+    /// Describe how the context is structured, e.g. from [`crate::inputs::Input::description`]
+    pub fn with_context_description(mut self, description: Option<impl Into<String>>) -> Self {
+        self.context_description = description.map(Into::into);
+        self
+    }
+
+    /// Enable or disable warm-starting the environment with a context summary
+    pub fn with_warm_start(mut self, warm_start: bool) -> Self {
+        self.warm_start = warm_start;
+        self
+    }
+
+    /// Set how many consecutive error cells trigger a warning and an escalated hint
+    pub fn with_error_thresholds(mut self, warning: usize, escalation: usize) -> Self {
+        self.error_warning_threshold = warning;
+        self.error_escalation_threshold = escalation;
+        self
+    }
+
+    /// Set how many tokens of each cell's output are kept; smaller models need tighter limits
+    pub fn with_max_output_tokens(mut self, max_output_tokens: usize) -> Self {
+        self.max_output_tokens = max_output_tokens;
+        self
+    }
+
+    /// Set which part of a long cell output is kept, e.g. the tail for logs
+    pub fn with_truncation(mut self, truncation: crate::repl::Truncation) -> Self {
+        self.truncation = truncation;
+        self
+    }
+
+    /// Keep the transcript within a token budget (see [`crate::window`])
+    pub fn with_window(mut self, window: Option<WindowConfig>) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Show only the comment and output of cells more than `iterations` old
+    pub fn with_hide_code_after(mut self, iterations: Option<usize>) -> Self {
+        self.hide_code_after = iterations;
+        self
+    }
+
+    /// Set how the transcript is formatted for the model (see [`crate::transcript`])
+    pub fn with_formatter(mut self, formatter: impl TranscriptFormatter + 'static) -> Self {
+        self.formatter = Arc::new(formatter);
+        self
+    }
+
+    /// Stop iterating once the run has spent `tokens` tokens
+    pub fn with_token_budget(mut self, tokens: Option<u64>) -> Self {
+        self.token_budget = tokens;
+        self
+    }
+
+    /// Attach a key/value tag to the session
+    pub fn with_tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.tags.insert(key.into(), value.into());
+        self
+    }
 }
 
 /// Recursive Language Model implementation
@@ -148,6 +370,15 @@ where
 {
     provider: P,
     repl: crate::repl::Repl,
+    max_retries: u32,
+    fix_retries: u32,
+    retry_backoff: Duration,
+    error_warning_threshold: usize,
+    error_escalation_threshold: usize,
+    window: Option<WindowConfig>,
+    token_budget: Option<u64>,
+    /// When the Rlm was created, reported by `budget()` as the start of the run
+    started: std::time::Instant,
 }
 
 impl<P> Rlm<P>
@@ -159,48 +390,297 @@ where
         provider: P,
         prompt: String,
         context: String,
-        model: String,
+        _model: String,
         client: crate::environment::LlmClient,
     ) -> Result<Self, Box<dyn Error>> {
-        let repl = crate::repl::Repl::new(prompt, context.as_str(), model, client)
-            .map_err(|e| format!("Failed to create REPL: {e}"))?;
+        Self::with_config(provider, prompt, context, client, RlmConfig::default())
+    }
 
-        Ok(Self { provider, repl })
+    /// Create a new Rlm with an explicit configuration
+    pub fn with_config(
+        provider: P,
+        prompt: String,
+        context: String,
+        client: crate::environment::LlmClient,
+        mut config: RlmConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let environment = std::mem::take(&mut config.environment);
+        let mut repl =
+            crate::repl::Repl::with_config(prompt, context.as_str(), client, environment)
+                .map_err(|e| format!("Failed to create REPL: {e}"))?;
+        repl.tags = std::mem::take(&mut config.tags);
+        repl.context_description = config.context_description.take();
+        Self::from_repl(provider, repl, &context, config)
     }
 
-    /// Perform a single step: generate a Cell from the LM, execute it, and return the executed Cell
-    pub async fn step(&mut self) -> Result<crate::repl::Cell, Box<dyn Error>> {
-        // Create a snapshot of the REPL for input
-        let repl_snapshot = self
-            .repl
-            .snapshot()
-            .map_err(|e| format!("Failed to create REPL snapshot: {e}"))?;
+    /// Continue a saved session (see [`crate::repl::Repl::resume`]) over the
+    /// same context. Tags and a context description in `config` are added to
+    /// those of the session.
+    pub fn resume(
+        provider: P,
+        session: crate::repl::Session,
+        context: String,
+        client: crate::environment::LlmClient,
+        mut config: RlmConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let environment = std::mem::take(&mut config.environment);
+        let mut repl = crate::repl::Repl::resume(session, context.as_str(), client, environment)
+            .map_err(|e| format!("Failed to resume REPL session: {e}"))?;
+        repl.tags.extend(std::mem::take(&mut config.tags));
+        if let Some(description) = config.context_description.take() {
+            repl.context_description = Some(description);
+        }
+        Self::from_repl(provider, repl, &context, config)
+    }
+
+    /// Apply the rest of the configuration to a new or resumed REPL
+    fn from_repl(
+        provider: P,
+        mut repl: crate::repl::Repl,
+        context: &str,
+        config: RlmConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        repl.max_output_tokens = config.max_output_tokens;
+        repl.truncation = config.truncation;
+        repl.hide_code_after = config.hide_code_after;
+        repl.formatter = config.formatter;
+        if config.warm_start {
+            repl.warm_start(context)
+                .map_err(|e| format!("Failed to compute context info: {e}"))?;
+        }
+
+        Ok(Self {
+            provider,
+            repl,
+            max_retries: config.max_retries,
+            fix_retries: config.fix_retries,
+            retry_backoff: config.retry_backoff,
+            error_warning_threshold: config.error_warning_threshold,
+            error_escalation_threshold: config.error_escalation_threshold,
+            window: config.window,
+            token_budget: config.token_budget,
+            started: std::time::Instant::now(),
+        })
+    }
+
+    /// Hint about the last cell repeating the code of an earlier cell that
+    /// failed or was truncated, which models tend to do in a loop
+    fn repeat_hint(&self) -> Option<String> {
+        let earlier = self.repl.entries.last()?.repeat_of?;
+        let outcome = if self.repl.entries[earlier].is_error() {
+            "failed"
+        } else {
+            "had its output truncated"
+        };
+        Some(format!(
+            "WARNING: the last cell repeats the code of an earlier cell (\"{}\") that {outcome}. Running the same code again will not help: try a different approach.",
+            self.repl.entries[earlier].comment
+        ))
+    }
+
+    /// Hint about a run of consecutive error cells, escalating as the run grows
+    fn error_hint(&self) -> Option<String> {
+        let errors = self.repl.consecutive_errors();
+        if errors >= self.error_escalation_threshold {
+            Some(format!(
+                "WARNING: {errors} consecutive errors. Simplify your code, avoid llm_query, and print less until a cell succeeds."
+            ))
+        } else if errors >= self.error_warning_threshold {
+            let advice = match self.repl.entries.last().and_then(|cell| cell.error) {
+                Some(ErrorKind::Syntax) => {
+                    "The last one did not parse: check for unbalanced `end`s, brackets, and quotes."
+                }
+                Some(ErrorKind::Timeout) => {
+                    "The last one ran out of time: make sure loops terminate, and do less work per cell."
+                }
+                Some(ErrorKind::Limit) => {
+                    "The last one used too much memory or ran too long: work on slices of the context instead of copies of it."
+                }
+                Some(ErrorKind::LlmQuery) => {
+                    "The last one failed in llm_query: continue with string processing instead of sub-queries."
+                }
+                _ => "Read the error messages carefully and fix the cause before continuing.",
+            };
+            Some(format!("Note: the last {errors} cells failed. {advice}"))
+        } else {
+            None
+        }
+    }
+
+    /// Evict old cells if the transcript is over the window budget,
+    /// summarizing them first with [`WindowStrategy::Summarize`]
+    async fn fit_window(&mut self) {
+        let Some(window) = &self.window else {
+            return;
+        };
+        let evict = crate::window::plan(&self.repl, window);
+        if evict.is_empty() {
+            return;
+        }
 
-        // Generate a partial Cell (with output set to None) from the LM
-        let cell = self.provider.generate(repl_snapshot).await?;
+        let summary = if window.strategy == WindowStrategy::Summarize {
+            let text = crate::window::summary_input(&self.repl, &evict);
+            match self
+                .provider
+                .complete_text(crate::window::SUMMARY_PROMPT, &text)
+                .await
+            {
+                Ok(summary) => Some(summary),
+                Err(e) => {
+                    tracing::warn!("Summarizing evicted cells failed ({e}), dropping them");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        tracing::info!("Evicted {} cells from the context window", evict.len());
+        self.repl.evict(&evict, summary);
+    }
+
+    /// Generate a partial Cell (with output set to None) from the LM,
+    /// retrying with exponential backoff on failure. Returns the cell and the
+    /// number of retries it took.
+    async fn generate(&mut self) -> Result<(crate::repl::Cell, u32), Box<dyn Error>> {
+        let mut retries = 0;
+        let mut backoff = self.retry_backoff;
+        loop {
+            // Create a snapshot of the REPL for input
+            let repl_snapshot = self
+                .repl
+                .snapshot()
+                .map_err(|e| format!("Failed to create REPL snapshot: {e}"))?;
 
-        // Preserve the final flag from the LM-generated cell
-        let is_final = cell.r#final;
+            // The error is dropped before sleeping so the future stays `Send`
+            match self.provider.generate(repl_snapshot).await {
+                Ok(cell) => return Ok((cell, retries)),
+                Err(e) if retries >= self.max_retries => return Err(e),
+                Err(e) => tracing::warn!(
+                    "Generation failed ({e}), retry {}/{} in {backoff:?}",
+                    retries + 1,
+                    self.max_retries
+                ),
+            }
+            retries += 1;
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
 
-        // Execute the code in the REPL
+    /// Execute a generated cell in the REPL and record how many retries it took
+    fn run(&mut self, cell: &crate::repl::Cell, retries: u32) {
         self.repl.eval(&cell.comment, &cell.code);
+        self.repl.entries.last_mut().unwrap().retries = retries;
+    }
+
+    /// Perform a single step: generate a Cell from the LM, execute it, and return the executed Cell
+    pub async fn step(&mut self) -> Result<crate::repl::Cell, Box<dyn Error>> {
+        self.fit_window().await;
+        self.repl.hint = self.repeat_hint().or_else(|| self.error_hint());
+
+        let (mut cell, retries) = self.generate().await?;
+        self.run(&cell, retries);
+
+        // Regenerate failed cells with the error pointed out. The failed cell
+        // is hidden from the model, since the hint repeats its code.
+        for attempt in 1..=self.fix_retries {
+            let failed = self.repl.entries.len() - 1;
+            if !self.repl.entries[failed].is_error() {
+                break;
+            }
+            tracing::info!(
+                "Cell failed, asking for a fix ({attempt}/{})",
+                self.fix_retries
+            );
+            self.repl.hint = Some(fix_hint(&self.repl.entries[failed]));
+            self.repl.entries[failed].evicted = true;
+            let (fix, retries) = match self.generate().await {
+                Ok(generated) => generated,
+                Err(e) => {
+                    self.repl.entries[failed].evicted = false;
+                    return Err(e);
+                }
+            };
+            cell = fix;
+            self.run(&cell, retries);
+        }
 
-        // Return the executed cell (with output computed) and restore the final flag
+        // Return the executed cell (with output computed) and restore the
+        // final flag from the LM-generated cell; calling final_answer also
+        // ends the run
         let mut executed_cell = self.repl.entries.last().unwrap().clone();
-        executed_cell.r#final = is_final;
+        executed_cell.r#final |= cell.r#final;
         Ok(executed_cell)
     }
 
     /// Create an iterator that yields executed Cells for up to max_iterations steps
-    pub fn execute(&mut self, max_iterations: usize) -> RlmIterator<P> {
+    pub fn execute(&mut self, max_iterations: usize) -> RlmIterator<'_, P> {
         RlmIterator {
             rlm: self,
             remaining: max_iterations,
         }
     }
 
-    /// Return the output of the final Cell, if it exists
+    /// Step until a cell is final or `max_iterations` steps have run, and
+    /// return the final output (see [`Rlm::final_output`])
+    pub async fn run_until_final(
+        &mut self,
+        max_iterations: usize,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let mut iter = self.execute(max_iterations);
+        while let Some(result) = iter.next().await {
+            if result?.r#final {
+                break;
+            }
+        }
+        Ok(self.final_output())
+    }
+
+    /// Tokens left in the run's token budget (None when unlimited)
+    pub fn tokens_left(&self) -> Option<u64> {
+        let used = self.usage_breakdown().total().total_tokens();
+        self.token_budget.map(|budget| budget.saturating_sub(used))
+    }
+
+    /// Tell the cells of the next step what is left of the run (see
+    /// [`crate::environment::Budget`])
+    fn update_budget(&self, iterations_left: Option<usize>) {
+        self.repl.set_budget(crate::environment::Budget {
+            iterations_left,
+            tokens_used: self.usage_breakdown().total().total_tokens(),
+            tokens_left: self.tokens_left(),
+            started: Some(self.started),
+        });
+    }
+
+    /// Notify `observer` as cells run (see [`crate::repl::ReplObserver`])
+    pub fn add_observer(&mut self, observer: Arc<dyn crate::repl::ReplObserver>) {
+        self.repl.add_observer(observer);
+    }
+
+    /// The REPL holding the transcript of executed cells
+    pub fn repl(&self) -> &crate::repl::Repl {
+        &self.repl
+    }
+
+    /// Token usage broken down by the subsystem that made the calls
+    pub fn usage_breakdown(&self) -> UsageBreakdown {
+        let mut breakdown = self.provider.usage();
+        breakdown.merge(&self.repl.usage().breakdown());
+        breakdown
+    }
+
+    /// Structured findings recorded with `emit_finding` during the run
+    pub fn findings(&self) -> &[crate::environment::Finding] {
+        &self.repl.findings
+    }
+
+    /// Return the answer recorded with `final_answer`, or else the output of
+    /// the final Cell, if it exists
     pub fn final_output(&self) -> Option<String> {
+        if let Some(answer) = self.repl.final_answer() {
+            return Some(answer.to_string());
+        }
         self.repl
             .entries
             .last()
@@ -208,6 +688,31 @@ where
     }
 }
 
+/// Hint asking the model to fix a failed cell, quoting the error and its code
+/// with the line the error points at marked
+fn fix_hint(cell: &crate::repl::Cell) -> String {
+    let error = cell.output.as_deref().unwrap_or_default();
+    let line = FAILING_LINE
+        .captures(error)
+        .and_then(|caps| caps[1].parse::<usize>().ok());
+    let code: Vec<String> = cell
+        .code
+        .lines()
+        .enumerate()
+        .map(|(i, text)| {
+            let marker = if line == Some(i + 1) { "-->" } else { "   " };
+            format!("{marker} {text}")
+        })
+        .collect();
+    format!(
+        "Your last cell failed and was discarded.\n{error}\nCode:\n```\n{}\n```\nWrite a corrected cell that achieves the same goal.",
+        code.join("\n")
+    )
+}
+
+/// The line number in a cell error such as `cell:3: attempt to call a nil value`
+static FAILING_LINE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\bcell:(\d+):").unwrap());
+
 /// Iterator for executing RLM steps
 pub struct RlmIterator<'a, P>
 where
@@ -221,13 +726,15 @@ impl<'a, P> RlmIterator<'a, P>
 where
     P: LmProvider<crate::repl::Repl, crate::repl::Cell>,
 {
-    /// Get the next Cell by executing one step
+    /// Get the next Cell by executing one step, or None once the iterations
+    /// or the token budget are used up
     pub async fn next(&mut self) -> Option<Result<crate::repl::Cell, Box<dyn Error>>> {
-        if self.remaining == 0 {
+        if self.remaining == 0 || self.rlm.tokens_left() == Some(0) {
             return None;
         }
 
         self.remaining -= 1;
+        self.rlm.update_budget(Some(self.remaining));
         Some(self.rlm.step().await)
     }
 
@@ -236,3 +743,292 @@ where
         self.remaining
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::LlmClient;
+    use crate::repl::{Cell, Repl};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Provider that fails a fixed number of times before returning a cell
+    struct FlakyProvider {
+        failures: AtomicU32,
+    }
+
+    #[async_trait]
+    impl LmProvider<Repl, Cell> for FlakyProvider {
+        fn with_system(self, _prompt: String) -> Self {
+            self
+        }
+
+        async fn generate(&self, _input: Repl) -> Result<Cell, Box<dyn Error>> {
+            if self.failures.load(Ordering::SeqCst) > 0 {
+                self.failures.fetch_sub(1, Ordering::SeqCst);
+                return Err("transient provider error".into());
+            }
+            Ok(Cell::parse(
+                "<comment>Answer</comment><code>print(42)</code><final>true</final>",
+            )?)
+        }
+    }
+
+    fn flaky_rlm(failures: u32, max_retries: u32) -> Rlm<FlakyProvider> {
+        let provider = FlakyProvider {
+            failures: AtomicU32::new(failures),
+        };
+        let config = RlmConfig::default().with_retries(max_retries, Duration::ZERO);
+        Rlm::with_config(
+            provider,
+            "prompt".to_string(),
+            String::new(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            config,
+        )
+        .unwrap()
+    }
+
+    /// Provider that replays fixed responses and records the prompts it was given
+    struct ScriptedProvider {
+        responses: std::sync::Mutex<Vec<&'static str>>,
+        prompts: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl LmProvider<Repl, Cell> for ScriptedProvider {
+        fn with_system(self, _prompt: String) -> Self {
+            self
+        }
+
+        async fn generate(&self, input: Repl) -> Result<Cell, Box<dyn Error>> {
+            self.prompts.lock().unwrap().push(input.format());
+            let response = self.responses.lock().unwrap().remove(0);
+            Cell::parse(response)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_step_fixes_failed_cells() {
+        let prompts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let provider = ScriptedProvider {
+            responses: std::sync::Mutex::new(vec![
+                "<comment>Count</comment><code>local n = 1\nprint(#missing)</code>",
+                "<comment>Count again</comment><code>print(#context)</code><final>true</final>",
+            ]),
+            prompts: prompts.clone(),
+        };
+        let config = RlmConfig::default().with_fix_retries(2);
+        let mut rlm = Rlm::with_config(
+            provider,
+            "prompt".to_string(),
+            "abc".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            config,
+        )
+        .unwrap();
+
+        let cell = rlm.step().await.unwrap();
+        assert_eq!(cell.output, Some("3".to_string()));
+        assert!(cell.r#final);
+
+        // The failed attempt is kept in the session but replaced by the hint
+        let entries = &rlm.repl().entries;
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].is_error() && entries[0].evicted);
+        let prompts = prompts.lock().unwrap();
+        assert!(prompts[1].contains("Your last cell failed and was discarded."));
+        assert!(prompts[1].contains("    local n = 1\n--> print(#missing)"));
+        assert!(!prompts[1].contains("# Count\n"));
+    }
+
+    #[tokio::test]
+    async fn test_budget() {
+        let scripted = |config: RlmConfig| {
+            let provider = ScriptedProvider {
+                responses: std::sync::Mutex::new(vec![
+                    "<comment>Look</comment><code>local b = budget()\nprint(b.iterations_left, b.tokens_left)</code>",
+                    "<comment>Again</comment><code>print(budget().iterations_left)</code>",
+                ]),
+                prompts: Arc::new(std::sync::Mutex::new(Vec::new())),
+            };
+            let client = LlmClient::Ollama("qwen3:30b".to_string());
+            Rlm::with_config(
+                provider,
+                "prompt".to_string(),
+                String::new(),
+                client,
+                config,
+            )
+            .unwrap()
+        };
+
+        let mut rlm = scripted(RlmConfig::default());
+        let mut iter = rlm.execute(3);
+        let first = iter.next().await.unwrap().unwrap();
+        assert_eq!(first.output.as_deref(), Some("2\tnil"));
+        let second = iter.next().await.unwrap().unwrap();
+        assert_eq!(second.output.as_deref(), Some("1"));
+
+        // A spent token budget ends the run before the next step
+        let mut rlm = scripted(RlmConfig::default().with_token_budget(Some(0)));
+        assert!(rlm.execute(3).next().await.is_none());
+        assert_eq!(rlm.tokens_left(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_step_final_answer() {
+        let provider = ScriptedProvider {
+            responses: std::sync::Mutex::new(vec![
+                "<comment>Answer</comment><code>print('checking') final_answer('It has ' .. #context .. ' characters')</code>",
+            ]),
+            prompts: Arc::new(std::sync::Mutex::new(Vec::new())),
+        };
+        let mut rlm = Rlm::with_config(
+            provider,
+            "prompt".to_string(),
+            "abc".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            RlmConfig::default(),
+        )
+        .unwrap();
+
+        let cell = rlm.step().await.unwrap();
+        assert!(cell.r#final);
+        assert_eq!(cell.output, Some("checking".to_string()));
+        assert_eq!(rlm.final_output(), Some("It has 3 characters".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_run_until_final_in_a_cell() {
+        // As in rlm_query, the nested Rlm runs inside a Lua callback, and its
+        // cells block on sub-queries in turn
+        let lua = mlua::Lua::new();
+        let nested = lua
+            .create_function(|_, ()| {
+                let provider = ScriptedProvider {
+                    responses: std::sync::Mutex::new(vec![
+                        "<comment>Look</comment><code>print(pcall(llm_query, 'hi'))</code>",
+                        "<comment>Answer</comment><code>final_answer('length ' .. #context)</code>",
+                        "<comment>Unused</comment><code>print('too far')</code>",
+                    ]),
+                    prompts: Arc::new(std::sync::Mutex::new(Vec::new())),
+                };
+                let mut rlm = Rlm::with_config(
+                    provider,
+                    "prompt".to_string(),
+                    "abcd".to_string(),
+                    LlmClient::Ollama("qwen3:30b".to_string()),
+                    RlmConfig::default(),
+                )
+                .unwrap();
+                let answer = crate::environment::block_on_until(None, async {
+                    Ok(rlm.run_until_final(5).await.unwrap())
+                })?;
+                Ok((answer, rlm.repl().entries.len()))
+            })
+            .unwrap();
+        let (answer, steps): (Option<String>, usize) = nested.call(()).unwrap();
+        assert_eq!(answer.as_deref(), Some("length 4"));
+        assert_eq!(steps, 2);
+    }
+
+    #[tokio::test]
+    async fn test_step_retries_provider_errors() {
+        let mut rlm = flaky_rlm(2, 3);
+        let cell = rlm.step().await.unwrap();
+        assert_eq!(cell.output, Some("42".to_string()));
+        assert_eq!(cell.retries, 2);
+        assert!(cell.r#final);
+        assert_eq!(rlm.repl().entries[0].retries, 2);
+    }
+
+    #[tokio::test]
+    async fn test_step_fails_after_max_retries() {
+        let mut rlm = flaky_rlm(3, 2);
+        let err = rlm.step().await.unwrap_err();
+        assert_eq!(err.to_string(), "transient provider error");
+        assert!(rlm.repl().entries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_error_hint_escalates_and_resets() {
+        let mut rlm = flaky_rlm(0, 0);
+        assert_eq!(rlm.error_hint(), None);
+
+        rlm.repl.eval("Fail", "error('boom')");
+        assert_eq!(rlm.error_hint(), None);
+        rlm.repl.eval("Fail again", "error('bang')");
+        assert!(
+            rlm.error_hint()
+                .unwrap()
+                .starts_with("Note: the last 2 cells failed. Read the error")
+        );
+
+        rlm.repl.eval("Fail a third time", "error('crash')");
+        assert!(
+            rlm.error_hint()
+                .unwrap()
+                .starts_with("WARNING: 3 consecutive errors")
+        );
+
+        // The hint is shown to the model and cleared after a successful cell
+        let cell = rlm.step().await.unwrap();
+        assert_eq!(cell.output, Some("42".to_string()));
+        assert!(
+            rlm.repl
+                .to_markdown()
+                .contains("WARNING: 3 consecutive errors")
+        );
+        assert_eq!(rlm.error_hint(), None);
+
+        // The advice depends on what kind of error the last cell raised
+        rlm.repl.eval("Fail", "error('boom')");
+        rlm.repl.eval("Fail to parse", "if x then");
+        assert!(rlm.error_hint().unwrap().contains("did not parse"));
+    }
+
+    #[tokio::test]
+    async fn test_repeat_hint() {
+        let mut rlm = flaky_rlm(0, 0);
+        rlm.repl.eval("Fail", "error('boom')");
+        assert_eq!(rlm.repeat_hint(), None);
+        rlm.repl
+            .eval("Fail the same way", "error( 'boom' ) -- again");
+        let hint = rlm.repeat_hint().unwrap();
+        assert!(hint.contains("(\"Fail\") that failed"));
+
+        // Takes precedence over the error hint and clears once the model moves on
+        let cell = rlm.step().await.unwrap();
+        assert_eq!(cell.output, Some("42".to_string()));
+        assert!(rlm.repl.to_markdown().contains(&hint));
+        assert_eq!(rlm.repeat_hint(), None);
+    }
+
+    #[tokio::test]
+    async fn test_step_evicts_old_cells() {
+        let provider = FlakyProvider {
+            failures: AtomicU32::new(0),
+        };
+        let window = WindowConfig::new(1, WindowStrategy::Summarize).with_keep_recent(1);
+        let config = RlmConfig::default().with_window(Some(window));
+        let mut rlm = Rlm::with_config(
+            provider,
+            "prompt".to_string(),
+            String::new(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            config,
+        )
+        .unwrap();
+
+        rlm.repl.eval("First", "x = 1");
+        rlm.repl.eval("Second", "y = 2");
+        rlm.step().await.unwrap();
+
+        // The provider cannot summarize, so the oldest cell is just dropped
+        let entries = &rlm.repl().entries;
+        assert_eq!(entries.len(), 3);
+        assert!(entries[0].evicted);
+        assert!(!entries[1].evicted && !entries[2].evicted);
+        assert!(!rlm.repl().to_markdown().contains("# First"));
+    }
+}
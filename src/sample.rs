@@ -0,0 +1,179 @@
+//! Stratified sampling of enormous contexts for quick triage.
+//!
+//! Instead of the whole context, the run sees its head, its tail, and a few
+//! slices taken from random positions in evenly sized strata of the middle.
+//! Skipped regions are replaced with markers so the model knows the context is
+//! incomplete. The budget is converted to bytes at the context's
+//! [`Tokenizer::bytes_per_token`], measured on its head, since tokenizing an
+//! enormous context just to sample it would defeat the purpose.
+
+use crate::tokens::Tokenizer;
+
+/// Configuration for [`sample`]
+#[derive(Debug, Clone)]
+pub struct SampleConfig {
+    /// Number of slices taken from the middle of the context
+    pub middle_slices: usize,
+    /// Seed for choosing slice positions within each stratum
+    pub seed: u64,
+    /// Tokenizer the budget is counted in
+    pub tokenizer: Tokenizer,
+}
+
+impl Default for SampleConfig {
+    fn default() -> Self {
+        Self {
+            middle_slices: 4,
+            seed: 0,
+            tokenizer: Tokenizer::default(),
+        }
+    }
+}
+
+/// Summary of a sampled context
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleReport {
+    /// Size of the original context in bytes
+    pub original_bytes: usize,
+    /// Byte ranges of the original context included in the sample
+    pub slices: Vec<(usize, usize)>,
+}
+
+impl SampleReport {
+    /// Number of bytes of the original context included in the sample
+    pub fn sampled_bytes(&self) -> usize {
+        self.slices.iter().map(|(start, end)| end - start).sum()
+    }
+
+    /// Fraction of the original context included in the sample
+    pub fn fraction(&self) -> f64 {
+        self.sampled_bytes() as f64 / self.original_bytes as f64
+    }
+}
+
+/// Sample roughly `max_tokens` worth of the context.
+///
+/// A quarter of the budget goes to the head, a quarter to the tail, and the
+/// rest is split between the middle slices. Returns the context unchanged and
+/// no report if it already fits.
+pub fn sample(
+    context: &str,
+    max_tokens: usize,
+    config: &SampleConfig,
+) -> (String, Option<SampleReport>) {
+    let bytes_per_token = config.tokenizer.bytes_per_token(context);
+    let budget = (max_tokens as f64 * bytes_per_token) as usize;
+    if context.len() <= budget {
+        return (context.to_string(), None);
+    }
+
+    let len = context.len();
+    let head = budget / 4;
+    let tail = budget / 4;
+    let slices = config.middle_slices.max(1);
+    let slice_len = (budget - head - tail) / slices;
+    let stratum_len = (len - head - tail) / slices;
+
+    let mut rng = config.seed;
+    let mut ranges = vec![(0, head)];
+    for i in 0..slices {
+        let stratum_start = head + i * stratum_len;
+        let offset = next_random(&mut rng) as usize % (stratum_len - slice_len + 1);
+        ranges.push((stratum_start + offset, stratum_start + offset + slice_len));
+    }
+    ranges.push((len - tail, len));
+
+    // Snap to character boundaries and drop empty slices
+    let ranges: Vec<(usize, usize)> = ranges
+        .into_iter()
+        .map(|(start, end)| {
+            (
+                context.floor_char_boundary(start),
+                context.floor_char_boundary(end),
+            )
+        })
+        .filter(|(start, end)| start < end)
+        .collect();
+
+    let mut sampled = String::with_capacity(budget + 100 * ranges.len());
+    let mut pos = 0;
+    for &(start, end) in &ranges {
+        if start > pos {
+            sampled.push_str(&format!(
+                "\n[... {} bytes skipped by sampling (offsets {pos}-{start}) ...]\n",
+                start - pos
+            ));
+        }
+        sampled.push_str(&context[start..end]);
+        pos = end;
+    }
+
+    let report = SampleReport {
+        original_bytes: len,
+        slices: ranges,
+    };
+    (sampled, Some(report))
+}
+
+/// xorshift64*, enough to spread slices without pulling in a dependency
+fn next_random(state: &mut u64) -> u64 {
+    if *state == 0 {
+        *state = 0x9E37_79B9_7F4A_7C15;
+    }
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_context_unchanged() {
+        let (sampled, report) = sample("short context", 100, &SampleConfig::default());
+        assert_eq!(sampled, "short context");
+        assert!(report.is_none());
+    }
+
+    #[test]
+    fn test_sample_includes_head_tail_and_markers() {
+        let context: String = (0..10_000).map(|i| format!("line {i}\n")).collect();
+        let (sampled, report) = sample(&context, 1_000, &SampleConfig::default());
+        let report = report.unwrap();
+
+        assert!(sampled.starts_with("line 0\n"));
+        assert!(sampled.ends_with("line 9999\n"));
+        assert!(sampled.contains("bytes skipped by sampling"));
+        assert_eq!(report.slices.len(), 6);
+        let tokenizer = Tokenizer::default();
+        let tokens: usize = (report.slices.iter())
+            .map(|&(start, end)| tokenizer.count(&context[start..end]))
+            .sum();
+        assert!(tokens <= 1_000 + report.slices.len(), "{tokens}");
+        assert!(report.fraction() < 0.1);
+        // Slices are ordered and don't overlap
+        assert!(report.slices.windows(2).all(|w| w[0].1 <= w[1].0));
+    }
+
+    #[test]
+    fn test_sample_is_deterministic_per_seed() {
+        let context = "abcdefghij".repeat(1_000);
+        let config = SampleConfig {
+            seed: 7,
+            ..Default::default()
+        };
+        let (first, _) = sample(&context, 200, &config);
+        let (second, _) = sample(&context, 200, &config);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_sample_respects_char_boundaries() {
+        let context = "é".repeat(5_000);
+        let (sampled, report) = sample(&context, 100, &SampleConfig::default());
+        assert!(report.is_some());
+        assert!(sampled.contains('é'));
+    }
+}
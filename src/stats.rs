@@ -0,0 +1,203 @@
+//! Aggregates over numbers for Lua code.
+//!
+//! Lua has no statistics library, and the model's hand-written medians and
+//! standard deviations are often subtly wrong (an unsorted median, an
+//! off-by-one in the variance). `stats(list)` summarizes a list of numbers,
+//! and `topk(t, k, keyfn)` ranks the entries of a list or a map.
+
+use mlua::Lua;
+
+/// Summary of a list of numbers; everything but the count and sum is None
+/// for an empty list
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub sum: f64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub mean: Option<f64>,
+    pub median: Option<f64>,
+    /// Sample standard deviation, 0 for a single number
+    pub stddev: Option<f64>,
+}
+
+pub fn summarize(values: &[f64]) -> Summary {
+    let count = values.len();
+    let sum: f64 = values.iter().sum();
+    if count == 0 {
+        return Summary {
+            count,
+            sum,
+            min: None,
+            max: None,
+            mean: None,
+            median: None,
+            stddev: None,
+        };
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let mean = sum / count as f64;
+    let median = if count % 2 == 1 {
+        sorted[count / 2]
+    } else {
+        (sorted[count / 2 - 1] + sorted[count / 2]) / 2.0
+    };
+    let variance = if count > 1 {
+        values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (count - 1) as f64
+    } else {
+        0.0
+    };
+    Summary {
+        count,
+        sum,
+        min: sorted.first().copied(),
+        max: sorted.last().copied(),
+        mean: Some(mean),
+        median: Some(median),
+        stddev: Some(variance.sqrt()),
+    }
+}
+
+/// Creates the `stats(list)` Lua function, which returns a table with the
+/// `count`, `sum`, `min`, `max`, `mean`, `median`, and sample `stddev` of a
+/// list of numbers (see [`summarize`]). Numeric strings such as `"12.5"`
+/// count as numbers; any other value is an error.
+pub fn create_stats_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(|lua, list: mlua::Table| {
+        let mut values = Vec::new();
+        for (i, value) in list.sequence_values::<mlua::Value>().enumerate() {
+            let value = value?;
+            let number = lua.coerce_number(value.clone())?.ok_or_else(|| {
+                mlua::Error::RuntimeError(format!(
+                    "stats: element {} is a {}, not a number",
+                    i + 1,
+                    value.type_name()
+                ))
+            })?;
+            values.push(number);
+        }
+        let summary = summarize(&values);
+        let result = lua.create_table()?;
+        result.set("count", summary.count)?;
+        result.set("sum", summary.sum)?;
+        result.set("min", summary.min)?;
+        result.set("max", summary.max)?;
+        result.set("mean", summary.mean)?;
+        result.set("median", summary.median)?;
+        result.set("stddev", summary.stddev)?;
+        Ok(result)
+    })
+}
+
+/// Creates the `topk(t, k, keyfn)` Lua function.
+///
+/// Returns the `k` entries of `t` with the largest keys, largest first;
+/// ties keep their order in a list. The key of an entry is the number
+/// `keyfn` returns for it, or the entry itself without `keyfn`. For a list,
+/// `keyfn(value)` is called and the values are returned; for a map such as
+/// word counts, `keyfn(value, key)` is called and `{key=, value=}` pairs are
+/// returned.
+pub fn create_topk_function(lua: &Lua) -> mlua::Result<mlua::Function> {
+    lua.create_function(
+        |lua, (t, k, keyfn): (mlua::Table, usize, Option<mlua::Function>)| {
+            let is_list = t.raw_len() > 0;
+            let mut entries: Vec<(f64, mlua::Value)> = Vec::new();
+            for pair in t.pairs::<mlua::Value, mlua::Value>() {
+                let (key, value) = pair?;
+                // Keys outside the list part of a list are skipped
+                if is_list && !matches!(key, mlua::Value::Integer(_)) {
+                    continue;
+                }
+                let score = match &keyfn {
+                    Some(f) if is_list => f.call::<mlua::Value>(value.clone())?,
+                    Some(f) => f.call::<mlua::Value>((value.clone(), key.clone()))?,
+                    None => value.clone(),
+                };
+                let score = lua.coerce_number(score.clone())?.ok_or_else(|| {
+                    mlua::Error::RuntimeError(format!(
+                        "topk: the key of an entry is a {}, not a number",
+                        score.type_name()
+                    ))
+                })?;
+                if is_list {
+                    entries.push((score, value));
+                } else {
+                    let entry = lua.create_table()?;
+                    entry.set("key", key)?;
+                    entry.set("value", value)?;
+                    entries.push((score, mlua::Value::Table(entry)));
+                }
+            }
+            // Stable, so equal keys keep the order of the list
+            entries.sort_by(|a, b| b.0.total_cmp(&a.0));
+            entries.truncate(k);
+            lua.create_sequence_from(entries.into_iter().map(|(_, value)| value))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lua() -> Lua {
+        let lua = Lua::new();
+        lua.globals()
+            .set("stats", create_stats_function(&lua).unwrap())
+            .unwrap();
+        lua.globals()
+            .set("topk", create_topk_function(&lua).unwrap())
+            .unwrap();
+        lua
+    }
+
+    #[test]
+    fn test_summarize() {
+        let summary = summarize(&[4.0, 1.0, 3.0, 2.0]);
+        assert_eq!(summary.count, 4);
+        assert_eq!(summary.sum, 10.0);
+        assert_eq!((summary.min, summary.max), (Some(1.0), Some(4.0)));
+        assert_eq!(summary.mean, Some(2.5));
+        assert_eq!(summary.median, Some(2.5));
+        assert!((summary.stddev.unwrap() - 1.2910).abs() < 1e-4);
+        assert_eq!(summarize(&[7.0]).stddev, Some(0.0));
+        assert_eq!(summarize(&[]).mean, None);
+    }
+
+    #[test]
+    fn test_stats() {
+        let lua = lua();
+        let (median, count, empty): (f64, usize, mlua::Value) = lua
+            .load(r#"local s = stats({3, "1", 2.5}) return s.median, s.count, stats({}).mean"#)
+            .eval()
+            .unwrap();
+        assert_eq!((median, count), (2.5, 3));
+        assert!(empty.is_nil());
+        let err = lua.load("stats({1, {}})").exec().unwrap_err();
+        assert!(err.to_string().contains("element 2 is a table"), "{err}");
+    }
+
+    #[test]
+    fn test_topk() {
+        let lua = lua();
+        let top: Vec<i64> = lua.load("return topk({5, 1, 9, 3, 9}, 3)").eval().unwrap();
+        assert_eq!(top, [9, 9, 5]);
+        let names: Vec<String> = lua
+            .load(
+                r#"
+                local people = {{name="a", age=30}, {name="b", age=40}, {name="c", age=20}}
+                local top = topk(people, 2, function(p) return p.age end)
+                return {top[1].name, top[2].name}
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(names, ["b", "a"]);
+        let (word, count): (String, i64) = lua
+            .load(r#"local top = topk({the=12, cat=3, sat=5}, 1) return top[1].key, top[1].value"#)
+            .eval()
+            .unwrap();
+        assert_eq!((word.as_str(), count), ("the", 12));
+    }
+}
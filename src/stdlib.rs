@@ -0,0 +1,227 @@
+//! Which parts of the Lua standard library cells can use.
+//!
+//! By default cells get only the libraries without side effects
+//! (`coroutine`, `math`, `string`, `table`, and `utf8`) and the base library
+//! without `dofile` and `loadfile`. A [`StdlibProfile`] can allow further
+//! libraries or single functions, such as `os.time` or `io.read`, deny
+//! functions of an allowed library, and cap the size of `string.rep`
+//! results.
+
+use mlua::{Lua, LuaOptions, StdLib};
+use std::collections::BTreeSet;
+
+/// Libraries allowed by [`StdlibProfile::default`]
+pub const SAFE_LIBRARIES: &[&str] = &["coroutine", "math", "string", "table", "utf8"];
+
+/// Libraries a profile can allow; `debug` is never available
+const LIBRARIES: &[(&str, StdLib)] = &[
+    ("coroutine", StdLib::COROUTINE),
+    ("io", StdLib::IO),
+    ("math", StdLib::MATH),
+    ("os", StdLib::OS),
+    ("package", StdLib::PACKAGE),
+    ("string", StdLib::STRING),
+    ("table", StdLib::TABLE),
+    ("utf8", StdLib::UTF8),
+];
+
+/// Base library functions that read files, removed unless allowed
+const FILE_FUNCTIONS: &[&str] = &["dofile", "loadfile"];
+
+/// The parts of the standard library loaded into an environment's Lua state.
+///
+/// Entries name a library (`"os"`) or a function in one (`"os.time"`);
+/// `dofile` and `loadfile` are entries of their own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StdlibProfile {
+    allowed: BTreeSet<String>,
+    denied: BTreeSet<String>,
+    max_rep_bytes: Option<usize>,
+}
+
+impl Default for StdlibProfile {
+    fn default() -> Self {
+        Self::new(SAFE_LIBRARIES.iter().copied())
+    }
+}
+
+impl StdlibProfile {
+    /// A profile allowing exactly `entries`
+    pub fn new(entries: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            allowed: entries.into_iter().map(Into::into).collect(),
+            denied: BTreeSet::new(),
+            max_rep_bytes: None,
+        }
+    }
+
+    /// Allow a library or a function
+    pub fn allow(mut self, entry: impl Into<String>) -> Self {
+        let entry = entry.into();
+        self.denied.remove(&entry);
+        self.allowed.insert(entry);
+        self
+    }
+
+    /// Remove a function, e.g. `"string.rep"`, from an allowed library
+    pub fn deny(mut self, entry: impl Into<String>) -> Self {
+        let entry = entry.into();
+        self.allowed.remove(&entry);
+        self.denied.insert(entry);
+        self
+    }
+
+    /// Make `string.rep` fail when its result would exceed `max` bytes
+    pub fn with_max_rep_bytes(mut self, max: Option<usize>) -> Self {
+        self.max_rep_bytes = max;
+        self
+    }
+
+    /// Whether cells can use `entry`, a library or a function in one
+    pub fn allows(&self, entry: &str) -> bool {
+        let library = entry.split_once('.').map(|(library, _)| library);
+        !self.denied.contains(entry)
+            && (self.allowed.contains(entry) || library.is_some_and(|l| self.allowed.contains(l)))
+    }
+
+    /// Create a Lua state with the allowed parts of the standard library.
+    ///
+    /// Fails if an entry names an unknown library or `debug`.
+    pub fn create_lua(&self) -> mlua::Result<Lua> {
+        let mut libs = StdLib::NONE;
+        for entry in &self.allowed {
+            let name = entry.split_once('.').map_or(entry.as_str(), |(l, _)| l);
+            match LIBRARIES.iter().find(|(library, _)| *library == name) {
+                Some((_, lib)) => libs |= *lib,
+                None if FILE_FUNCTIONS.contains(&name) => {}
+                None => {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "unknown or unavailable Lua library '{name}' in the stdlib profile"
+                    )));
+                }
+            }
+        }
+        let lua = Lua::new_with(libs, LuaOptions::default())?;
+
+        let globals = lua.globals();
+        for name in FILE_FUNCTIONS {
+            if !self.allows(name) {
+                globals.raw_remove(*name)?;
+            }
+        }
+        // Trim partly allowed libraries in place, which also trims the
+        // string metatable and `package.loaded`
+        for (name, _) in LIBRARIES {
+            let Ok(mlua::Value::Table(library)) = globals.raw_get(*name) else {
+                continue;
+            };
+            let functions: Vec<String> = library
+                .pairs::<String, mlua::Value>()
+                .filter_map(|pair| pair.ok().map(|(function, _)| function))
+                .collect();
+            for function in functions {
+                if !self.allows(&format!("{name}.{function}")) {
+                    library.raw_remove(function)?;
+                }
+            }
+        }
+
+        if let Some(max) = self.max_rep_bytes
+            && let Ok(mlua::Value::Table(string)) = globals.raw_get("string")
+            && let Ok(mlua::Value::Function(rep)) = string.raw_get("rep")
+        {
+            string.raw_set("rep", capped_rep(&lua, rep, max)?)?;
+        }
+        Ok(lua)
+    }
+}
+
+/// Wrap `string.rep` to fail before building a result over `max` bytes
+fn capped_rep(lua: &Lua, rep: mlua::Function, max: usize) -> mlua::Result<mlua::Function> {
+    lua.create_function(
+        move |_, (s, n, sep): (mlua::String, i64, Option<mlua::String>)| {
+            let n = n.max(0) as usize;
+            let sep_len = sep.as_ref().map_or(0, |sep| sep.as_bytes().len());
+            let len = s
+                .as_bytes()
+                .len()
+                .saturating_mul(n)
+                .saturating_add(sep_len.saturating_mul(n.saturating_sub(1)));
+            if len > max {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "string.rep: the result would be {len} bytes, over the limit of {max}"
+                )));
+            }
+            rep.call::<mlua::String>((s, n, sep))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn types(lua: &Lua, names: &str) -> String {
+        lua.load(format!("return {names}"))
+            .eval::<mlua::MultiValue>()
+            .unwrap()
+            .iter()
+            .map(|value| value.type_name())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn test_default_profile() {
+        let lua = StdlibProfile::default().create_lua().unwrap();
+        assert_eq!(
+            types(&lua, "os, io, package, require, dofile, loadfile, debug"),
+            "nil nil nil nil nil nil nil"
+        );
+        assert_eq!(
+            types(
+                &lua,
+                "string.rep, math.floor, table.concat, coroutine.wrap, utf8.char, load"
+            ),
+            "function function function function function function"
+        );
+    }
+
+    #[test]
+    fn test_allow_and_deny_functions() {
+        let profile = StdlibProfile::default()
+            .allow("os.time")
+            .allow("os.clock")
+            .deny("string.rep");
+        let lua = profile.create_lua().unwrap();
+        assert_eq!(
+            types(
+                &lua,
+                "os.time, os.clock, os.execute, os.remove, string.rep, ('x').rep"
+            ),
+            "function function nil nil nil nil"
+        );
+        assert!(profile.allows("os.time") && !profile.allows("os.exit"));
+
+        let err = StdlibProfile::default()
+            .allow("debug")
+            .create_lua()
+            .unwrap_err();
+        assert!(err.to_string().contains("'debug'"), "{err}");
+    }
+
+    #[test]
+    fn test_max_rep_bytes() {
+        let lua = StdlibProfile::default()
+            .with_max_rep_bytes(Some(10))
+            .create_lua()
+            .unwrap();
+        let s: String = lua.load("return ('ab'):rep(3, ',')").eval().unwrap();
+        assert_eq!(s, "ab,ab,ab");
+        let err = lua.load("return string.rep('ab', 6)").exec().unwrap_err();
+        assert!(
+            err.to_string().contains("12 bytes, over the limit of 10"),
+            "{err}"
+        );
+    }
+}
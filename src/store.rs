@@ -0,0 +1,207 @@
+//! A persistent key-value store for Lua code, so that separate runs can
+//! build on each other's results.
+//!
+//! The store is a JSON object in a file, rewritten after every change so a
+//! crashed run keeps what it stored. In Lua, `store_set(key, value)` saves
+//! any JSON-compatible value and `store_get(key)` reads it back, in this run
+//! or a later one opened on the same store.
+
+use mlua::{Lua, LuaSerdeExt};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The directory of named stores: `$MOONRAKER_STORE_DIR`, else
+/// `$XDG_DATA_HOME/moonraker/stores`, else `~/.local/share/moonraker/stores`
+pub fn default_store_dir() -> Option<PathBuf> {
+    let var = |name| std::env::var_os(name).filter(|v| !v.is_empty());
+    var("MOONRAKER_STORE_DIR")
+        .map(PathBuf::from)
+        .or_else(|| var("XDG_DATA_HOME").map(|dir| Path::new(&dir).join("moonraker/stores")))
+        .or_else(|| var("HOME").map(|home| Path::new(&home).join(".local/share/moonraker/stores")))
+}
+
+/// The file of a store given by name or path. A bare name such as
+/// `survey-2024` is kept in [`default_store_dir`]; anything else is a path.
+pub fn store_path(name_or_path: &str) -> Option<PathBuf> {
+    let is_name = !name_or_path.is_empty()
+        && name_or_path
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if is_name {
+        default_store_dir().map(|dir| dir.join(format!("{name_or_path}.json")))
+    } else {
+        Some(PathBuf::from(name_or_path))
+    }
+}
+
+/// A key-value store backed by a JSON file. Clones share the same values.
+#[derive(Debug, Clone)]
+pub struct Store {
+    path: PathBuf,
+    values: Arc<Mutex<BTreeMap<String, Value>>>,
+}
+
+impl Store {
+    /// Open the store in `path`; a missing file is an empty store
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let values = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not a store: {e}", path.display()),
+                )
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            values: Arc::new(Mutex::new(values)),
+        })
+    }
+
+    /// The file the store is saved in
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.values.lock().unwrap().get(key).cloned()
+    }
+
+    /// The keys in the store, sorted
+    pub fn keys(&self) -> Vec<String> {
+        self.values.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Set a key, or remove it if `value` is None, and save the store
+    pub fn set(&self, key: &str, value: Option<Value>) -> io::Result<()> {
+        let mut values = self.values.lock().unwrap();
+        match value {
+            Some(value) => values.insert(key.to_string(), value),
+            None => values.remove(key),
+        };
+        // Written to a temporary file first so a crash never leaves half a store
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, serde_json::to_string_pretty(&*values)?)?;
+        fs::rename(&temp, &self.path)
+    }
+}
+
+fn no_store(function: &str) -> mlua::Error {
+    mlua::Error::RuntimeError(format!(
+        "{function}: this run has no store (start moonraker with --store NAME)"
+    ))
+}
+
+/// Creates the `store_set(key, value)` Lua function, which saves a string,
+/// number, boolean, or table under `key`, replacing any earlier value, or
+/// removes the key if `value` is nil. Fails if the run has no store.
+pub fn create_store_set_function(lua: &Lua, store: Option<Store>) -> mlua::Result<mlua::Function> {
+    lua.create_function(move |lua, (key, value): (String, mlua::Value)| {
+        let store = store.as_ref().ok_or_else(|| no_store("store_set"))?;
+        let value = match value {
+            mlua::Value::Nil => None,
+            value => Some(lua.from_value::<Value>(value).map_err(|e| {
+                mlua::Error::RuntimeError(format!("store_set: cannot store this value: {e}"))
+            })?),
+        };
+        store.set(&key, value).map_err(|e| {
+            mlua::Error::RuntimeError(format!(
+                "store_set: failed to save {}: {e}",
+                store.path().display()
+            ))
+        })
+    })
+}
+
+/// Creates the `store_get(key)` Lua function, which returns the value saved
+/// under `key`, or nil if there is none. Called without a key, it returns
+/// the list of keys instead. Fails if the run has no store.
+pub fn create_store_get_function(lua: &Lua, store: Option<Store>) -> mlua::Result<mlua::Function> {
+    lua.create_function(move |lua, key: Option<String>| {
+        let store = store.as_ref().ok_or_else(|| no_store("store_get"))?;
+        let options = mlua::SerializeOptions::new()
+            .serialize_none_to_null(false)
+            .serialize_unit_to_null(false);
+        match key {
+            Some(key) => match store.get(&key) {
+                Some(value) => lua.to_value_with(&value, options),
+                None => Ok(mlua::Value::Nil),
+            },
+            None => lua.to_value(&store.keys()),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lua(store: Option<Store>) -> Lua {
+        let lua = Lua::new();
+        lua.globals()
+            .set(
+                "store_set",
+                create_store_set_function(&lua, store.clone()).unwrap(),
+            )
+            .unwrap();
+        lua.globals()
+            .set("store_get", create_store_get_function(&lua, store).unwrap())
+            .unwrap();
+        lua
+    }
+
+    #[test]
+    fn test_store_persists_across_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested/notes.json");
+
+        let first = lua(Some(Store::open(&path).unwrap()));
+        first
+            .load(
+                r#"
+                store_set("count", 3)
+                store_set("people", {"Ada", "Grace"})
+                store_set("scratch", "temporary")
+                store_set("scratch", nil)
+                "#,
+            )
+            .exec()
+            .unwrap();
+
+        // A later run opens the same file
+        let second = lua(Some(Store::open(&path).unwrap()));
+        let (count, person, scratch, keys): (i64, String, mlua::Value, Vec<String>) = second
+            .load(r#"return store_get("count"), store_get("people")[2], store_get("scratch"), store_get()"#)
+            .eval()
+            .unwrap();
+        assert_eq!((count, person.as_str()), (3, "Grace"));
+        assert!(scratch.is_nil());
+        assert_eq!(keys, ["count", "people"]);
+    }
+
+    #[test]
+    fn test_store_path() {
+        let path = store_path("survey-2024").unwrap();
+        assert!(path.ends_with("survey-2024.json"));
+        assert_eq!(
+            store_path("out/notes.json"),
+            Some(PathBuf::from("out/notes.json"))
+        );
+    }
+
+    #[test]
+    fn test_without_store() {
+        let err = lua(None).load("store_get('x')").exec().unwrap_err();
+        assert!(err.to_string().contains("--store"), "{err}");
+    }
+}
@@ -0,0 +1,155 @@
+//! Hierarchical pre-summarization of contexts over the token budget.
+//!
+//! The context is split into chunks at line breaks and each chunk is
+//! summarized with a single completion. While the joined summaries are still
+//! over the summary budget, consecutive summaries are grouped and summarized
+//! again. The raw context stays available; the summary gives the model an
+//! overview of the parts it will never read.
+
+use crate::prefilter::split_chunks;
+use crate::rlm::RigProvider;
+use crate::tokens::Tokenizer;
+use crate::usage::UsageCategory;
+use std::error::Error;
+use std::future::Future;
+
+const CHUNK_PROMPT: &str = "Summarize this part of a larger document. Keep names, numbers, dates, identifiers, and section titles; note anything unusual. Be concise and do not add commentary.";
+
+const COMBINE_PROMPT: &str = "Combine these summaries of consecutive parts of a document into a single summary. Keep names, numbers, dates, identifiers, and section titles. Be concise and do not add commentary.";
+
+/// Configuration for [`summarize`]
+#[derive(Debug, Clone)]
+pub struct SummarizeConfig {
+    /// Approximate tokens of context per summarization call
+    pub chunk_tokens: usize,
+    /// Summarizing stops once the summary is at most this many tokens
+    pub max_summary_tokens: usize,
+    /// Tokenizer both budgets are counted in
+    pub tokenizer: Tokenizer,
+}
+
+impl Default for SummarizeConfig {
+    fn default() -> Self {
+        Self {
+            chunk_tokens: 8_000,
+            max_summary_tokens: 4_000,
+            tokenizer: Tokenizer::default(),
+        }
+    }
+}
+
+/// Summarize a context with the provider.
+///
+/// Usage is recorded under [`UsageCategory::Summarization`].
+pub async fn summarize(
+    provider: &RigProvider,
+    context: &str,
+    config: &SummarizeConfig,
+) -> Result<String, Box<dyn Error>> {
+    summarize_with(context, config, |instructions, text| async move {
+        provider
+            .complete(Some(instructions), &text, UsageCategory::Summarization)
+            .await
+            .map(|summary| crate::parse::strip_reasoning(&summary).trim().to_string())
+    })
+    .await
+}
+
+/// Summarize hierarchically, calling `complete(instructions, text)` for each
+/// summarization
+async fn summarize_with<F, Fut>(
+    context: &str,
+    config: &SummarizeConfig,
+    mut complete: F,
+) -> Result<String, Box<dyn Error>>
+where
+    F: FnMut(&'static str, String) -> Fut,
+    Fut: Future<Output = Result<String, Box<dyn Error>>>,
+{
+    let bytes_per_token = config.tokenizer.bytes_per_token(context);
+    let chunk_bytes = (config.chunk_tokens as f64 * bytes_per_token) as usize;
+    let mut parts: Vec<String> = split_chunks(context, chunk_bytes)
+        .into_iter()
+        .map(|(start, end)| context[start..end].to_string())
+        .collect();
+    let mut instructions = CHUNK_PROMPT;
+    let mut level = 1;
+
+    loop {
+        let total = parts.len();
+        let mut summaries = Vec::with_capacity(total);
+        for (i, part) in parts.into_iter().enumerate() {
+            tracing::info!("Summarizing level {level} part {}/{total}", i + 1);
+            summaries.push(complete(instructions, part).await?);
+        }
+
+        let summary = summaries.join("\n\n");
+        if summaries.len() <= 1 || config.tokenizer.count(&summary) <= config.max_summary_tokens {
+            return Ok(summary);
+        }
+
+        // Group consecutive summaries, at least two per group so each level shrinks
+        parts = Vec::new();
+        let mut group = String::new();
+        let mut group_len = 0;
+        for summary in summaries {
+            if group_len >= 2 && group.len() + summary.len() > chunk_bytes {
+                parts.push(std::mem::take(&mut group));
+                group_len = 0;
+            }
+            group.push_str(&summary);
+            group.push_str("\n\n");
+            group_len += 1;
+        }
+        parts.push(group);
+        instructions = COMBINE_PROMPT;
+        level += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_summarize_hierarchically() {
+        let context = "line of context\n".repeat(100);
+        let config = SummarizeConfig {
+            chunk_tokens: 100,
+            max_summary_tokens: 5,
+            ..Default::default()
+        };
+
+        let mut calls = Vec::new();
+        let summary = summarize_with(&context, &config, |instructions, text| {
+            calls.push((instructions, text.len()));
+            async { Ok("summary".to_string()) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(summary, "summary");
+        // 1600 bytes in 400-byte chunks, then the four summaries combined once
+        let chunk_calls = calls.iter().filter(|(i, _)| *i == CHUNK_PROMPT).count();
+        assert_eq!(chunk_calls, 4);
+        assert_eq!(calls.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_small_summary_stops_after_one_level() {
+        let context = "line of context\n".repeat(10);
+        let config = SummarizeConfig {
+            chunk_tokens: 20,
+            max_summary_tokens: 1_000,
+            ..Default::default()
+        };
+        let mut calls = 0;
+        let summary = summarize_with(&context, &config, |_, _| {
+            calls += 1;
+            async move { Ok(format!("part {calls}")) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(summary, "part 1\n\npart 2");
+    }
+}
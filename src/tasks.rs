@@ -0,0 +1,220 @@
+//! Long-running Lua tasks that span multiple cells.
+//!
+//! A cell can start a task with `start_task(name, fn)`. The function runs as a
+//! coroutine: after each cell the [`Environment`](crate::environment::Environment)
+//! resumes every pending task for a bounded time slice, and whatever the task
+//! passes to `coroutine.yield(...)` is reported as its progress. When a task
+//! finishes, its first return value is stored in the `task_results` global.
+
+use crate::environment::CellLimits;
+use mlua::{Function, IntoLuaMulti, Lua, MultiValue, Result, Thread, ThreadStatus};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// State of a task as reported by `task_status(name)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    Finished,
+    Failed(String),
+}
+
+impl TaskState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TaskState::Running => "running",
+            TaskState::Finished => "finished",
+            TaskState::Failed(_) => "failed",
+        }
+    }
+}
+
+struct Task {
+    name: String,
+    thread: Thread,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    pending: Vec<Task>,
+    states: HashMap<String, TaskState>,
+}
+
+/// Holds pending tasks and resumes them between cells
+#[derive(Clone)]
+pub struct TaskScheduler {
+    state: Arc<Mutex<SchedulerState>>,
+    time_slice: Duration,
+    /// Yields after which a task's slice ends regardless of time, so that
+    /// tasks progress the same way on every run
+    max_yields: Option<usize>,
+}
+
+impl TaskScheduler {
+    /// Create a scheduler that runs each task for at most `time_slice` per resume
+    pub fn new(time_slice: Duration) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SchedulerState::default())),
+            time_slice,
+            max_yields: None,
+        }
+    }
+
+    /// End each slice after a task yields `max_yields` times instead of after
+    /// the time slice
+    pub fn with_max_yields(mut self, max_yields: Option<usize>) -> Self {
+        self.max_yields = max_yields;
+        self
+    }
+
+    /// Whether any tasks are still running
+    pub fn has_pending(&self) -> bool {
+        !self.state.lock().unwrap().pending.is_empty()
+    }
+
+    /// Resume every pending task, returning one progress line per task.
+    ///
+    /// Each task is resumed repeatedly until it finishes, fails, or its time
+    /// slice (or yield count, see [`TaskScheduler::with_max_yields`]) is used
+    /// up; the last yielded value is reported as progress. In an
+    /// [`Environment`](crate::environment::Environment), tasks run within the
+    /// cell's [`CellLimits`], and a task still running at the end of its time
+    /// slice fails.
+    pub fn resume_all(&self, lua: &Lua) -> Result<Vec<String>> {
+        let limits = CellLimits::of(lua);
+        // Take the tasks out so tasks can start other tasks without deadlocking
+        let tasks = std::mem::take(&mut self.state.lock().unwrap().pending);
+        let mut lines = Vec::new();
+        let mut still_pending = Vec::new();
+
+        for task in tasks {
+            let started = Instant::now();
+            let mut yields = 0;
+            let mut progress = None;
+            let mut run = || loop {
+                match task.thread.resume::<MultiValue>(()) {
+                    Ok(values) if task.thread.status() == ThreadStatus::Resumable => {
+                        progress = Some(format_values(&values));
+                        yields += 1;
+                        let slice_over = match self.max_yields {
+                            Some(max) => yields >= max,
+                            None => started.elapsed() >= self.time_slice,
+                        };
+                        if slice_over {
+                            break Ok(None);
+                        }
+                    }
+                    Ok(values) => break Ok(Some(values)),
+                    Err(e) => break Err(e),
+                }
+            };
+            let outcome = match &limits {
+                Some(limits) => limits.task_slice(self.time_slice, run),
+                None => run(),
+            };
+
+            let state = match outcome {
+                Ok(None) => {
+                    lines.push(match progress {
+                        Some(p) if !p.is_empty() => format!("[task {}] progress: {p}", task.name),
+                        _ => format!("[task {}] running", task.name),
+                    });
+                    still_pending.push(task);
+                    continue;
+                }
+                Ok(Some(values)) => {
+                    let results: mlua::Table = lua.globals().get("task_results")?;
+                    results.set(
+                        task.name.as_str(),
+                        values.into_iter().next().unwrap_or(mlua::Value::Nil),
+                    )?;
+                    lines.push(format!(
+                        "[task {}] finished, result in task_results[\"{}\"]",
+                        task.name, task.name
+                    ));
+                    TaskState::Finished
+                }
+                Err(e) => {
+                    lines.push(format!("[task {}] failed: {e}", task.name));
+                    TaskState::Failed(e.to_string())
+                }
+            };
+            self.state.lock().unwrap().states.insert(task.name, state);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        still_pending.append(&mut state.pending);
+        state.pending = still_pending;
+
+        Ok(lines)
+    }
+}
+
+fn format_values(values: &MultiValue) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string().unwrap_or_else(|_| format!("{v:?}")))
+        .collect::<Vec<_>>()
+        .join("\t")
+}
+
+/// Creates the `start_task(name, fn)` function.
+///
+/// # Lua Signature
+/// ```lua
+/// start_task(name, fn)
+/// ```
+///
+/// # Behavior
+/// - Runs `fn` as a coroutine, resumed after each cell until it finishes
+/// - `coroutine.yield(progress)` inside `fn` pauses it and reports `progress`
+/// - The first return value of `fn` is stored in `task_results[name]`
+/// - Errors if a task with the same name is still running
+///
+/// # Example
+/// ```lua
+/// start_task("count", function()
+///   n = 0
+///   for i = 1, #context, 100000 do
+///     n = n + select(2, string.gsub(string.sub(context, i, i + 99999), "ERROR", ""))
+///     coroutine.yield(math.floor(i / #context * 100) .. "%")
+///   end
+///   return n
+/// end)
+/// ```
+pub fn create_start_task_function(lua: &Lua, scheduler: TaskScheduler) -> Result<Function> {
+    lua.create_function(move |lua, (name, func): (String, Function)| {
+        let mut state = scheduler.state.lock().unwrap();
+        if state.pending.iter().any(|t| t.name == name) {
+            return Err(mlua::Error::RuntimeError(format!(
+                "task '{name}' is already running"
+            )));
+        }
+        let thread = lua.create_thread(func)?;
+        state.states.insert(name.clone(), TaskState::Running);
+        state.pending.push(Task { name, thread });
+        Ok(())
+    })
+}
+
+/// Creates the `task_status(name)` function.
+///
+/// # Lua Signature
+/// ```lua
+/// status, err = task_status(name)
+/// ```
+///
+/// # Returns
+/// - `"running"`, `"finished"`, or `"failed"` (with the error message), or nil
+///   for unknown tasks
+pub fn create_task_status_function(lua: &Lua, scheduler: TaskScheduler) -> Result<Function> {
+    lua.create_function(move |lua, name: String| {
+        let state = scheduler.state.lock().unwrap();
+        match state.states.get(&name) {
+            Some(TaskState::Failed(e)) => ("failed", e.clone()).into_lua_multi(lua),
+            Some(s) => s.as_str().into_lua_multi(lua),
+            None => mlua::Nil.into_lua_multi(lua),
+        }
+    })
+}
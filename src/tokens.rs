@@ -0,0 +1,128 @@
+//! Token counting shared by the REPL, the context window, and budget reporting.
+//!
+//! Each [`Tokenizer`] is loaded once, on first use, and shared by every
+//! environment and REPL in the process.
+
+use std::sync::LazyLock;
+use tiktoken_rs::CoreBPE;
+
+static P50K: LazyLock<Option<CoreBPE>> = LazyLock::new(|| tiktoken_rs::p50k_base().ok());
+static CL100K: LazyLock<Option<CoreBPE>> = LazyLock::new(|| tiktoken_rs::cl100k_base().ok());
+static O200K: LazyLock<Option<CoreBPE>> = LazyLock::new(|| tiktoken_rs::o200k_base().ok());
+
+/// Bytes of a text that [`Tokenizer::bytes_per_token`] measures
+const PROBE_BYTES: usize = 64 << 10;
+
+/// The BPE encoding used to count and truncate tokens
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Tokenizer {
+    /// GPT-3 era encoding
+    #[default]
+    P50k,
+    /// GPT-4 and GPT-3.5 encoding, a close approximation for most current open models
+    Cl100k,
+    /// GPT-4o, GPT-4.1, GPT-5, and o-series encoding
+    O200k,
+}
+
+impl Tokenizer {
+    /// The encoding of a model, e.g. `gpt-4o` or `openai/gpt-4.1-mini`.
+    /// Models without a known encoding, such as local Ollama models, use cl100k.
+    pub fn for_model(model: &str) -> Self {
+        // OpenRouter model names are prefixed with the vendor
+        let name = model.rsplit('/').next().unwrap_or(model);
+        match tiktoken_rs::tokenizer::get_tokenizer(name) {
+            Some(
+                tiktoken_rs::tokenizer::Tokenizer::O200kBase
+                | tiktoken_rs::tokenizer::Tokenizer::O200kHarmony,
+            ) => Tokenizer::O200k,
+            Some(
+                tiktoken_rs::tokenizer::Tokenizer::P50kBase
+                | tiktoken_rs::tokenizer::Tokenizer::P50kEdit
+                | tiktoken_rs::tokenizer::Tokenizer::R50kBase
+                | tiktoken_rs::tokenizer::Tokenizer::Gpt2,
+            ) => Tokenizer::P50k,
+            _ => Tokenizer::Cl100k,
+        }
+    }
+
+    /// The shared encoder, or None if it could not be loaded
+    pub fn bpe(self) -> Option<&'static CoreBPE> {
+        match self {
+            Tokenizer::P50k => P50K.as_ref(),
+            Tokenizer::Cl100k => CL100K.as_ref(),
+            Tokenizer::O200k => O200K.as_ref(),
+        }
+    }
+
+    /// Number of tokens in a text; estimated as 4 characters per token if
+    /// the encoder could not be loaded
+    pub fn count(self, text: &str) -> usize {
+        match self.bpe() {
+            Some(bpe) => bpe.encode_with_special_tokens(text).len(),
+            None => text.chars().count().div_ceil(4),
+        }
+    }
+
+    /// Average number of bytes per token of a text, measured on its first
+    /// 64 KB so that enormous texts are not tokenized whole; 4 for an empty
+    /// text
+    pub fn bytes_per_token(self, text: &str) -> f64 {
+        let probe = &text[..text.floor_char_boundary(PROBE_BYTES)];
+        match self.count(probe) {
+            0 => 4.0,
+            tokens => probe.len() as f64 / tokens as f64,
+        }
+    }
+
+    /// Number of tokens in a text, counted up to 64 KB and extrapolated
+    /// from [`Tokenizer::bytes_per_token`] beyond
+    pub fn estimate(self, text: &str) -> usize {
+        if text.len() <= PROBE_BYTES {
+            return self.count(text);
+        }
+        (text.len() as f64 / self.bytes_per_token(text)).ceil() as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_for_model() {
+        assert_eq!(Tokenizer::for_model("gpt-4o-mini"), Tokenizer::O200k);
+        assert_eq!(Tokenizer::for_model("openai/gpt-4.1"), Tokenizer::O200k);
+        assert_eq!(Tokenizer::for_model("gpt-4"), Tokenizer::Cl100k);
+        assert_eq!(Tokenizer::for_model("text-davinci-003"), Tokenizer::P50k);
+        assert_eq!(Tokenizer::for_model("qwen3:30b"), Tokenizer::Cl100k);
+    }
+
+    #[test]
+    fn test_count() {
+        let text = "The quick brown fox jumps over the lazy dog.";
+        assert_eq!(Tokenizer::P50k.count(text), 10);
+        assert_eq!(Tokenizer::Cl100k.count(text), 10);
+        assert_eq!(Tokenizer::O200k.count(text), 10);
+        // Non-English text is where the encodings differ most
+        let text = "Привет, как дела? Всё хорошо, спасибо.";
+        assert!(Tokenizer::O200k.count(text) < Tokenizer::Cl100k.count(text));
+        assert!(Tokenizer::Cl100k.count(text) < Tokenizer::P50k.count(text));
+    }
+
+    #[test]
+    fn test_estimate() {
+        let text = "The quick brown fox jumps over the lazy dog.\n";
+        let tokenizer = Tokenizer::Cl100k;
+        assert_eq!(tokenizer.estimate(text), tokenizer.count(text));
+        assert_eq!(tokenizer.bytes_per_token(""), 4.0);
+
+        let long = text.repeat(10_000);
+        let estimate = tokenizer.estimate(&long) as f64;
+        let count = tokenizer.count(&long) as f64;
+        assert!(
+            (estimate - count).abs() / count < 0.01,
+            "{estimate} vs {count}"
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use crate::environment::{HostFunction, LlmClient};
+use crate::inputs::{Input, canonical_dirs, resolve};
+use crate::prompt::{SYSTEM_PROMPT, system_prompt};
+use crate::rlm::{RigProvider, Rlm, RlmConfig};
+use rig::completion::ToolDefinition;
+use rig::tool::Tool;
+use serde::Deserialize;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
+pub struct AnalyzeArgs {
+    pub question: String,
+    pub document_path: String,
+}
+
+/// Runs a full RLM session over a document, so other rig agents can delegate
+/// long-context questions to moonraker.
+///
+/// Only documents inside the directories given to
+/// [`MoonrakerTool::with_allowed_dirs`] can be analyzed, since the path comes
+/// from the calling model.
+#[derive(Clone)]
+pub struct MoonrakerTool {
+    client: LlmClient,
+    system_prompt: String,
+    max_iterations: usize,
+    config: RlmConfig,
+    allowed_dirs: Vec<PathBuf>,
+}
+
+impl MoonrakerTool {
+    /// Create a tool that answers with the given model and the built-in system prompt
+    pub fn new(client: LlmClient) -> Self {
+        Self {
+            client,
+            system_prompt: SYSTEM_PROMPT.to_string(),
+            max_iterations: 10,
+            config: RlmConfig::default(),
+            allowed_dirs: Vec::new(),
+        }
+    }
+
+    /// Allow documents inside these directories, resolved like `load_file`
+    /// paths (none are allowed by default). Relative document paths are
+    /// tried against each directory in order.
+    pub fn with_allowed_dirs(mut self, dirs: &[PathBuf]) -> Self {
+        self.allowed_dirs = canonical_dirs(dirs);
+        self
+    }
+
+    /// Set the maximum number of iterations per question
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    /// Replace the built-in system prompt
+    pub fn with_system_prompt(mut self, system_prompt: impl Into<String>) -> Self {
+        self.system_prompt = system_prompt.into();
+        self
+    }
+
+    /// Set the configuration of each run
+    pub fn with_config(mut self, config: RlmConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    fn provider(&self, functions: &[HostFunction]) -> RigProvider {
+        let system_prompt = system_prompt(&self.system_prompt, functions);
+        RigProvider::from_llm_client(&self.client, system_prompt)
+    }
+}
+
+#[derive(Debug)]
+pub struct MoonrakerToolError(String);
+
+impl std::fmt::Display for MoonrakerToolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for MoonrakerToolError {}
+
+impl Tool for MoonrakerTool {
+    const NAME: &'static str = "analyze";
+
+    type Error = MoonrakerToolError;
+    type Args = AnalyzeArgs;
+    type Output = String;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        ToolDefinition {
+            name: Self::NAME.to_string(),
+            description: "Answer a question about a document too large to read directly (text, logs, PDF, etc.). A specialist agent explores the document by writing and running code over it, and returns its final answer.".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "question": {
+                        "type": "string",
+                        "description": "The question to answer about the document"
+                    },
+                    "document_path": {
+                        "type": "string",
+                        "description": "Path to the document to analyze"
+                    }
+                },
+                "required": ["question", "document_path"]
+            }),
+        }
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+        let path =
+            resolve(&self.allowed_dirs, Path::new(&args.document_path)).ok_or_else(|| {
+                MoonrakerToolError(format!(
+                    "Failed to load document: {} does not exist in an allowed directory",
+                    args.document_path
+                ))
+            })?;
+        let input = Input::from_file(&path)
+            .map_err(|e| MoonrakerToolError(format!("Failed to load document: {e}")))?;
+
+        // Give the run the input's structured data and source functions
+        let mut config = self.config.clone();
+        for (name, value) in input.globals() {
+            config.environment = config.environment.with_global(name.clone(), value.clone());
+        }
+        config
+            .environment
+            .functions
+            .extend(input.functions().iter().cloned());
+        if let Some(description) = input.description() {
+            config = config.with_context_description(Some(description));
+        }
+
+        let mut rlm = Rlm::with_config(
+            self.provider(&config.environment.functions),
+            args.question,
+            input.content().to_string(),
+            self.client.clone(),
+            config,
+        )
+        .map_err(|e| MoonrakerToolError(format!("Failed to create RLM: {e}")))?;
+
+        rlm.run_until_final(self.max_iterations)
+            .await
+            .map_err(|e| MoonrakerToolError(format!("Execution failed: {e}")))?
+            .ok_or_else(|| MoonrakerToolError("No answer was produced".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_definition() {
+        let tool = MoonrakerTool::new(LlmClient::Ollama("qwen3:30b".to_string()));
+        let definition = tool.definition(String::new()).await;
+        assert_eq!(definition.name, "analyze");
+        assert_eq!(
+            definition.parameters["required"],
+            json!(["question", "document_path"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_missing_document() {
+        let tool = MoonrakerTool::new(LlmClient::Ollama("qwen3:30b".to_string()));
+        let err = tool
+            .call(AnalyzeArgs {
+                question: "What is this?".to_string(),
+                document_path: "/nonexistent/document.txt".to_string(),
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Failed to load document"));
+    }
+
+    #[tokio::test]
+    async fn test_allowed_dirs() {
+        let root = tempfile::tempdir().unwrap();
+        let allowed = root.path().join("docs");
+        std::fs::create_dir(&allowed).unwrap();
+        std::fs::write(root.path().join("secret.txt"), "hunter2").unwrap();
+        let secret = root.path().join("secret.txt");
+        let question = |path: &str| AnalyzeArgs {
+            question: "What is this?".to_string(),
+            document_path: path.to_string(),
+        };
+
+        // Nothing is allowed by default
+        let tool = MoonrakerTool::new(LlmClient::Ollama("qwen3:30b".to_string()));
+        let err = tool
+            .call(question(secret.to_str().unwrap()))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("allowed directory"), "{err}");
+
+        let tool = tool.with_allowed_dirs(&[allowed]);
+        for path in ["../secret.txt", secret.to_str().unwrap()] {
+            let err = tool.call(question(path)).await.unwrap_err();
+            assert!(
+                err.to_string().contains("allowed directory"),
+                "{path}: {err}"
+            );
+        }
+    }
+}
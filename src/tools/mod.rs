@@ -1,3 +1,5 @@
+pub mod analyze;
 pub mod run_cell;
 
+pub use analyze::MoonrakerTool;
 pub use run_cell::RunCellTool;
@@ -0,0 +1,77 @@
+//! A JSON-lines log of executed cells, written as a run progresses so that
+//! crashed or interrupted runs still leave a usable trace.
+
+use crate::repl::{Cell, ReplObserver};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Writes each completed cell, with its metadata, as one JSON line.
+/// Register it with [`crate::repl::Repl::add_observer`].
+pub struct TraceWriter {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl TraceWriter {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    /// Write the trace to a file, replacing any earlier contents
+    pub fn create(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl ReplObserver for TraceWriter {
+    fn on_cell_complete(&self, cell: &Cell) {
+        let mut writer = self.writer.lock().unwrap();
+        // Flushed after every cell so the trace survives a crash
+        let result = serde_json::to_writer(&mut *writer, cell)
+            .map_err(std::io::Error::from)
+            .and_then(|()| writeln!(writer))
+            .and_then(|()| writer.flush());
+        if let Err(e) = result {
+            tracing::warn!("Failed to write cell to the trace: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::LlmClient;
+    use crate::repl::{DEFAULT_MAX_OUTPUT_TOKENS, Repl};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_trace_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.jsonl");
+        let mut repl = Repl::new(
+            "prompt".to_string(),
+            0,
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
+        repl.add_observer(Arc::new(TraceWriter::create(&path).unwrap()));
+        repl.eval("Print", "print('hi')");
+        repl.eval("Fail", "error('boom')");
+
+        // Readable while the REPL is still alive
+        let text = std::fs::read_to_string(&path).unwrap();
+        let cells: Vec<Cell> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(cells.len(), 2);
+        assert_eq!(cells[0].output.as_deref(), Some("hi"));
+        assert!(cells[0].metadata.is_some());
+        assert!(cells[1].is_error());
+    }
+}
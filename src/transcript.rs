@@ -0,0 +1,181 @@
+//! Formats of the transcript sent to the model for each generation.
+//!
+//! [`Markdown`] is the default. Some models follow a transcript much better
+//! when it reads like their own earlier turns, which [`Chat`] provides; the
+//! other formats trade readability for fewer tokens or machine-like input.
+//! Select one with [`Repl::formatter`] or implement [`TranscriptFormatter`].
+
+use crate::repl::{Repl, format_cell};
+use serde_json::json;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// Renders a [`Repl`] as the input of the next generation
+pub trait TranscriptFormatter: Debug + Send + Sync {
+    fn format(&self, repl: &Repl) -> String;
+}
+
+/// Shared handle to a formatter, as stored on a [`Repl`]
+pub type SharedFormatter = Arc<dyn TranscriptFormatter>;
+
+/// Markdown headings for comments and fenced code and output (see [`Repl::to_markdown`])
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Markdown;
+
+impl TranscriptFormatter for Markdown {
+    fn format(&self, repl: &Repl) -> String {
+        repl.to_markdown()
+    }
+}
+
+/// Numbered cells without fences: `[n] comment`, the code, and `=> output`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Compact;
+
+impl TranscriptFormatter for Compact {
+    fn format(&self, repl: &Repl) -> String {
+        let mut parts = repl.header();
+        for (i, cell) in repl.visible_cells().enumerate() {
+            let mut lines = vec![format!("[{}] {}", i + 1, cell.comment)];
+            if !cell.code.is_empty() {
+                lines.push(cell.code.clone());
+            }
+            if let Some(output) = &cell.output {
+                lines.push(format!("=> {output}"));
+            }
+            parts.push(format!("{}\n", lines.join("\n")));
+        }
+        if let Some(hint) = &repl.hint {
+            parts.push(format!("{hint}\n"));
+        }
+        parts.join("\n")
+    }
+}
+
+/// One JSON object per line: the prompt and context, findings, each cell, and the hint
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonLines;
+
+impl TranscriptFormatter for JsonLines {
+    fn format(&self, repl: &Repl) -> String {
+        let mut lines = vec![json!({
+            "prompt": repl.prompt,
+            "context": repl.context_description,
+            "context_info": repl.context_summary,
+        })];
+        if !repl.findings.is_empty() {
+            lines.push(json!({"findings": repl.findings}));
+        }
+        for cell in repl.visible_cells() {
+            lines.push(json!({
+                "comment": cell.comment,
+                "code": cell.code,
+                "output": cell.output,
+            }));
+        }
+        if let Some(hint) = &repl.hint {
+            lines.push(json!({"hint": hint}));
+        }
+        let mut text = lines
+            .iter()
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        text.push('\n');
+        text
+    }
+}
+
+/// Alternating `User:` and `Assistant:` turns. Each cell is an assistant
+/// turn in the response format (`<comment>` and `<code>` tags) followed by a
+/// user turn with its output; summaries and notes are user turns.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Chat;
+
+impl TranscriptFormatter for Chat {
+    fn format(&self, repl: &Repl) -> String {
+        let mut turns = vec![format!("User:\n{}", repl.header().join("\n"))];
+        for cell in repl.visible_cells() {
+            if cell.code.is_empty() {
+                if let Some(formatted) = format_cell(&cell) {
+                    turns.push(format!("User:\n{formatted}"));
+                }
+                continue;
+            }
+            turns.push(format!(
+                "Assistant:\n<comment>\n{}\n</comment>\n<code>\n{}\n</code>\n",
+                cell.comment, cell.code
+            ));
+            if let Some(output) = &cell.output {
+                turns.push(format!("User:\nOutput:\n```\n{output}\n```\n"));
+            }
+        }
+        if let Some(hint) = &repl.hint {
+            turns.push(format!("User:\n{hint}\n"));
+        }
+        turns.push("Assistant:\n".to_string());
+        turns.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::LlmClient;
+    use crate::repl::DEFAULT_MAX_OUTPUT_TOKENS;
+
+    fn repl() -> Repl {
+        let mut repl = Repl::new(
+            "Count the words".to_string(),
+            "one two",
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
+        repl.eval("Count", "print(#context)");
+        repl.eval("Store", "n = 2");
+        repl
+    }
+
+    #[test]
+    fn test_compact() {
+        let repl = repl();
+        assert_eq!(
+            Compact.format(&repl),
+            "Prompt:\nCount the words\n\n[1] Count\nprint(#context)\n=> 7\n\n[2] Store\nn = 2\n"
+        );
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let mut repl = repl();
+        repl.hint = Some("Finish up".to_string());
+        let text = JsonLines.format(&repl);
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["prompt"], "Count the words");
+        assert_eq!(lines[1]["output"], "7");
+        assert!(lines[2]["output"].is_null());
+        assert_eq!(lines[3]["hint"], "Finish up");
+    }
+
+    #[test]
+    fn test_chat() {
+        let mut repl = repl();
+        repl.evict(&[0], Some("Counted 7 characters.".to_string()));
+        let text = Chat.format(&repl);
+        assert!(text.starts_with("User:\nPrompt:\nCount the words\n"));
+        assert!(text.contains(
+            "User:\n# Summary of 1 earlier cells\nOutput:\n```\nCounted 7 characters.\n```\n"
+        ));
+        assert!(
+            text.contains("Assistant:\n<comment>\nStore\n</comment>\n<code>\nn = 2\n</code>\n")
+        );
+        assert!(text.ends_with("Assistant:\n"));
+        assert!(!text.contains("print(#context)"));
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// The subsystem a language model call was made on behalf of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum UsageCategory {
+    /// Main-loop cell generations made by the Rlm
+    Generation,
+    /// Sub-queries made from Lua via `llm_query`
+    LlmQuery,
+    /// Cell generations of nested Rlms started from Lua via `rlm_query`
+    SubRlm,
+    /// Single-completion answers that bypass the REPL
+    DirectAnswer,
+    /// Pre-summarization of contexts over the token budget
+    Summarization,
+    /// Embedding requests made from Lua via `embed`, `index_context`, and
+    /// `semantic_search`. Embedding endpoints report no usage, so input
+    /// tokens are counted with the environment's tokenizer.
+    Embedding,
+}
+
+impl fmt::Display for UsageCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsageCategory::Generation => write!(f, "generation"),
+            UsageCategory::LlmQuery => write!(f, "llm_query"),
+            UsageCategory::SubRlm => write!(f, "rlm_query"),
+            UsageCategory::DirectAnswer => write!(f, "direct_answer"),
+            UsageCategory::Summarization => write!(f, "summarization"),
+            UsageCategory::Embedding => write!(f, "embedding"),
+        }
+    }
+}
+
+/// Token counts accumulated for a single category.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    /// Number of completed calls
+    pub calls: u64,
+    /// Input ("prompt") tokens reported by the provider
+    pub input_tokens: u64,
+    /// Output ("completion") tokens reported by the provider
+    pub output_tokens: u64,
+}
+
+impl TokenUsage {
+    /// Total tokens (input plus output)
+    pub fn total_tokens(&self) -> u64 {
+        self.input_tokens + self.output_tokens
+    }
+
+    fn add(&mut self, other: &TokenUsage) {
+        self.calls += other.calls;
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+    }
+}
+
+impl From<rig::completion::Usage> for TokenUsage {
+    fn from(usage: rig::completion::Usage) -> Self {
+        TokenUsage {
+            calls: 1,
+            input_tokens: usage.input_tokens,
+            output_tokens: usage.output_tokens,
+        }
+    }
+}
+
+/// Usage broken down by [`UsageCategory`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsageBreakdown {
+    categories: BTreeMap<UsageCategory, TokenUsage>,
+}
+
+impl UsageBreakdown {
+    /// Get the usage recorded for a category (zero if nothing was recorded)
+    pub fn get(&self, category: UsageCategory) -> TokenUsage {
+        self.categories.get(&category).copied().unwrap_or_default()
+    }
+
+    /// Iterate over the categories that have recorded usage
+    pub fn iter(&self) -> impl Iterator<Item = (UsageCategory, TokenUsage)> + '_ {
+        self.categories.iter().map(|(c, u)| (*c, *u))
+    }
+
+    /// Sum of usage across all categories
+    pub fn total(&self) -> TokenUsage {
+        let mut total = TokenUsage::default();
+        for usage in self.categories.values() {
+            total.add(usage);
+        }
+        total
+    }
+
+    /// Add a single usage record to a category
+    pub fn record(&mut self, category: UsageCategory, usage: TokenUsage) {
+        self.categories.entry(category).or_default().add(&usage);
+    }
+
+    /// Merge another breakdown into this one
+    pub fn merge(&mut self, other: &UsageBreakdown) {
+        for (category, usage) in other.iter() {
+            self.record(category, usage);
+        }
+    }
+}
+
+/// Shared, cloneable handle for recording usage from multiple places.
+///
+/// Clones refer to the same underlying breakdown, so a tracker can be handed to
+/// the Lua environment and the provider while the Rlm reads the totals.
+#[derive(Debug, Clone, Default)]
+pub struct UsageTracker {
+    inner: Arc<Mutex<UsageBreakdown>>,
+}
+
+impl UsageTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record usage for a category
+    pub fn record(&self, category: UsageCategory, usage: impl Into<TokenUsage>) {
+        self.inner.lock().unwrap().record(category, usage.into());
+    }
+
+    /// Get a copy of the current breakdown
+    pub fn breakdown(&self) -> UsageBreakdown {
+        self.inner.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_total() {
+        let tracker = UsageTracker::new();
+        tracker.record(
+            UsageCategory::Generation,
+            TokenUsage {
+                calls: 1,
+                input_tokens: 100,
+                output_tokens: 20,
+            },
+        );
+        tracker.record(
+            UsageCategory::LlmQuery,
+            TokenUsage {
+                calls: 1,
+                input_tokens: 50,
+                output_tokens: 10,
+            },
+        );
+        tracker.record(
+            UsageCategory::LlmQuery,
+            TokenUsage {
+                calls: 1,
+                input_tokens: 5,
+                output_tokens: 1,
+            },
+        );
+
+        let breakdown = tracker.breakdown();
+        assert_eq!(breakdown.get(UsageCategory::Generation).total_tokens(), 120);
+        assert_eq!(breakdown.get(UsageCategory::LlmQuery).calls, 2);
+        assert_eq!(breakdown.get(UsageCategory::LlmQuery).input_tokens, 55);
+        assert_eq!(breakdown.total().total_tokens(), 186);
+    }
+
+    #[test]
+    fn test_clones_share_state() {
+        let tracker = UsageTracker::new();
+        let clone = tracker.clone();
+        let usage = TokenUsage {
+            calls: 1,
+            input_tokens: 12,
+            output_tokens: 3,
+        };
+        clone.record(UsageCategory::LlmQuery, usage);
+        assert_eq!(tracker.breakdown().get(UsageCategory::LlmQuery), usage);
+        assert_eq!(tracker.breakdown().total().total_tokens(), 15);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = UsageBreakdown::default();
+        a.record(
+            UsageCategory::Generation,
+            TokenUsage {
+                calls: 1,
+                input_tokens: 10,
+                output_tokens: 1,
+            },
+        );
+        let mut b = UsageBreakdown::default();
+        b.record(
+            UsageCategory::Generation,
+            TokenUsage {
+                calls: 2,
+                input_tokens: 5,
+                output_tokens: 5,
+            },
+        );
+        a.merge(&b);
+        assert_eq!(a.get(UsageCategory::Generation).calls, 3);
+        assert_eq!(a.get(UsageCategory::Generation).total_tokens(), 21);
+    }
+}
@@ -0,0 +1,174 @@
+//! Keeps the transcript shown to the model within a token budget.
+//!
+//! When the formatted [`Repl`] exceeds [`WindowConfig::max_tokens`], old cells
+//! are evicted: they stay in [`Repl::entries`] (and in session files) but are
+//! no longer shown to the model. Pinned cells and the most recent cells are
+//! never evicted.
+
+use crate::repl::{Repl, format_cell};
+
+/// Instructions for summarizing evicted cells with [`WindowStrategy::Summarize`]
+pub const SUMMARY_PROMPT: &str = "These are earlier steps of a Lua REPL session analyzing a document. Summarize what was tried and what was learned: keep variable and function names still in use, numbers, identifiers, intermediate results, and errors worth avoiding. Be concise and do not add commentary.";
+
+/// Tokens left free for the summary cell when summarizing
+const SUMMARY_RESERVE_TOKENS: usize = 500;
+
+/// How old cells leave the context window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WindowStrategy {
+    /// Evict the oldest cells until the transcript fits
+    #[default]
+    DropOldest,
+    /// Evict the oldest cells and replace them with a summary written by the model
+    Summarize,
+    /// Evict every cell that is neither pinned nor recent
+    KeepPinned,
+}
+
+/// Configuration of the context window
+#[derive(Debug, Clone)]
+pub struct WindowConfig {
+    /// Tokens of transcript above which cells are evicted
+    pub max_tokens: usize,
+    pub strategy: WindowStrategy,
+    /// Number of most recent cells that are never evicted
+    pub keep_recent: usize,
+}
+
+impl WindowConfig {
+    pub fn new(max_tokens: usize, strategy: WindowStrategy) -> Self {
+        Self {
+            max_tokens,
+            strategy,
+            keep_recent: 2,
+        }
+    }
+
+    /// Set how many of the most recent cells are never evicted
+    pub fn with_keep_recent(mut self, keep_recent: usize) -> Self {
+        self.keep_recent = keep_recent;
+        self
+    }
+}
+
+/// Indices of the cells to evict so the transcript fits, oldest first.
+/// Empty when the transcript already fits.
+pub fn plan(repl: &Repl, config: &WindowConfig) -> Vec<usize> {
+    let mut total = repl.token_usage().prompt_tokens;
+    if total <= config.max_tokens {
+        return Vec::new();
+    }
+
+    let visible: Vec<usize> = (0..repl.entries.len())
+        .filter(|&i| !repl.entries[i].evicted)
+        .collect();
+    let recent = visible.len().saturating_sub(config.keep_recent);
+    let candidates = visible[..recent]
+        .iter()
+        .copied()
+        .filter(|&i| !repl.entries[i].pinned);
+
+    let target = match config.strategy {
+        WindowStrategy::KeepPinned => return candidates.collect(),
+        WindowStrategy::DropOldest => config.max_tokens,
+        WindowStrategy::Summarize => config.max_tokens.saturating_sub(SUMMARY_RESERVE_TOKENS),
+    };
+    let mut evict = Vec::new();
+    for i in candidates {
+        if total <= target {
+            break;
+        }
+        let tokens = format_cell(&repl.entries[i]).map_or(0, |cell| repl.tokenizer.count(&cell));
+        total = total.saturating_sub(tokens);
+        evict.push(i);
+    }
+    evict
+}
+
+/// The evicted cells formatted as text to summarize
+pub fn summary_input(repl: &Repl, indices: &[usize]) -> String {
+    indices
+        .iter()
+        .filter_map(|&i| format_cell(&repl.entries[i]))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::LlmClient;
+    use crate::repl::DEFAULT_MAX_OUTPUT_TOKENS;
+
+    fn repl_with_cells(n: usize) -> Repl {
+        let mut repl = Repl::new(
+            "prompt".to_string(),
+            0,
+            "test-model".to_string(),
+            LlmClient::Ollama("qwen3:30b".to_string()),
+            DEFAULT_MAX_OUTPUT_TOKENS,
+        )
+        .unwrap();
+        for i in 0..n {
+            repl.eval(
+                &format!("Step {i}"),
+                &format!("print(string.rep('word ', 50) .. {i})"),
+            );
+        }
+        repl
+    }
+
+    #[test]
+    fn test_plan_fits() {
+        let repl = repl_with_cells(3);
+        let config = WindowConfig::new(10_000, WindowStrategy::DropOldest);
+        assert!(plan(&repl, &config).is_empty());
+    }
+
+    #[test]
+    fn test_plan_drop_oldest() {
+        let mut repl = repl_with_cells(6);
+        repl.entries[0].pinned = true;
+        let total = repl.tokenizer.count(&repl.to_markdown());
+        let cell = repl
+            .tokenizer
+            .count(&format_cell(&repl.entries[1]).unwrap());
+
+        // Just over budget: only the oldest unpinned cell goes
+        let config = WindowConfig::new(total - cell / 2, WindowStrategy::DropOldest);
+        assert_eq!(plan(&repl, &config), [1]);
+
+        // Nothing but pinned and recent cells survive a tiny budget
+        let config = WindowConfig::new(1, WindowStrategy::DropOldest);
+        let evict = plan(&repl, &config);
+        assert_eq!(evict, [1, 2, 3]);
+
+        repl.evict(&evict, None);
+        let markdown = repl.to_markdown();
+        assert!(markdown.contains("# Step 0") && markdown.contains("# Step 5"));
+        assert!(!markdown.contains("# Step 2"));
+        assert_eq!(repl.entries.len(), 6);
+    }
+
+    #[test]
+    fn test_plan_keep_pinned_and_summarize() {
+        let mut repl = repl_with_cells(5);
+        repl.entries[2].pinned = true;
+        let total = repl.tokenizer.count(&repl.to_markdown());
+
+        let config = WindowConfig::new(total - 1, WindowStrategy::KeepPinned).with_keep_recent(1);
+        assert_eq!(plan(&repl, &config), [0, 1, 3]);
+
+        let config = WindowConfig::new(total - 1, WindowStrategy::Summarize).with_keep_recent(1);
+        let evict = plan(&repl, &config);
+        assert_eq!(evict, [0, 1, 3]);
+        assert!(summary_input(&repl, &evict).starts_with("# Step 0\n"));
+
+        repl.evict(&evict, Some("Printed words.".to_string()));
+        assert_eq!(repl.entries[4].comment, "Summary of 3 earlier cells");
+        assert!(
+            repl.to_markdown()
+                .contains("# Summary of 3 earlier cells\nOutput:\n```\nPrinted words.\n```")
+        );
+    }
+}
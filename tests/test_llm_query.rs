@@ -2,6 +2,7 @@
 //!
 //! This test validates that the llm_query function works correctly with the
 //! RigProvider using qwen3:30b model.
+#![cfg(feature = "integration")]
 
 use moonraker::environment::{Environment, LlmClient};
 
@@ -4,6 +4,7 @@
 //! because of llama.cpp limitations. See README.md "Testing > Rig Structured
 //! Output Tests" section for full explanation of why these tests exist and
 //! when we can migrate to structured output.
+#![cfg(feature = "integration")]
 
 use mlua::{Lua, Result as LuaResult};
 use rig::client::CompletionClient;
@@ -224,13 +225,13 @@ Use string.gmatch or similar to iterate through lines."
 /// Helper to extract Lua code from XML-tagged response
 fn extract_lua_code_from_response(response: &str) -> String {
     // Try to extract from <code> XML tags
-    if let Some(start) = response.find("<code>") {
-        if let Some(end) = response[start..].find("</code>") {
-            let code_start = start + "<code>".len();
-            let code_end = start + end;
-            let code = &response[code_start..code_end];
-            return code.trim().to_string();
-        }
+    if let Some(start) = response.find("<code>")
+        && let Some(end) = response[start..].find("</code>")
+    {
+        let code_start = start + "<code>".len();
+        let code_end = start + end;
+        let code = &response[code_start..code_end];
+        return code.trim().to_string();
     }
 
     // Fallback: try markdown code blocks
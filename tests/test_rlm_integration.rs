@@ -2,6 +2,7 @@
 //!
 //! These tests validate that the RLM works correctly with the Rig provider
 //! using XML tag parsing (see README.md for why we use XML instead of structured output).
+#![cfg(feature = "integration")]
 
 use moonraker::rlm::{RigProvider, Rlm};
 